@@ -0,0 +1,181 @@
+//! A typed client for the backend, for Rust callers and the backend's own end-to-end tests.
+//!
+//! Every method comes out of the endpoint tables below, which name each route's path and the
+//! request and response types from this crate, so the client can only say what the server reads.
+//! Apps in other languages can get the same from the OpenAPI spec (`print-openapi`) and a
+//! generator like openapi-generator, rather than writing their networking by hand.
+use crate::{
+    AboutResponse, CompareRequest, CompareResponse, ErrorResponse, FavoritesRequest,
+    FavoritesResponse, GetLocationsRequest, GetLocationsResponse, OffRouteRequest,
+    OffRouteResponse, RerouteRequest, RerouteResponse, ReverseRequest, ReverseResponse,
+    RouteRequest, RouteResponse, ShareRequest, ShareResponse, SharedResponse, TripResponse,
+};
+use reqwest::{header, StatusCode, Url};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Why a call didn't get the answer it wanted
+#[derive(Debug)]
+pub enum Error {
+    /// The call didn't go through, or the answer wasn't what we expected
+    Transport(reqwest::Error),
+    /// The backend answered with an error status
+    Status {
+        status: StatusCode,
+        /// Most errors come with one. Missing if the body isn't one, like for a blown deadline
+        body: Option<ErrorResponse>,
+        /// From Retry-After, for the statuses that send it
+        retry_after_secs: Option<u64>,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport(err) => write!(f, "call failed: {err}"),
+            Error::Status {
+                status,
+                body: Some(body),
+                ..
+            } => write!(f, "{status}: {}", body.message),
+            Error::Status { status, .. } => write!(f, "{status}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(err) => Some(err),
+            Error::Status { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Transport(err)
+    }
+}
+
+/// Talks to one backend. Cheap to clone; clones share connections
+#[derive(Debug, Clone)]
+pub struct Client {
+    base: Url,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// For the backend at `base`, like `https://flipmap.example`
+    pub fn new(base: Url) -> Self {
+        Self::with_http(base, reqwest::Client::new())
+    }
+
+    /// With a [reqwest::Client] set up by the caller, for timeouts and the like
+    pub fn with_http(base: Url, http: reqwest::Client) -> Self {
+        Client { base, http }
+    }
+
+    /// `base` with `segments` appended, each escaped as needed
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("base URL should be http(s)")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    async fn post<Req: Serialize, Res: DeserializeOwned>(
+        &self,
+        segments: &[&str],
+        body: &Req,
+    ) -> Result<Res, Error> {
+        let response = self.http.post(self.url(segments)).json(body).send().await?;
+        read(response).await
+    }
+
+    async fn get<Res: DeserializeOwned>(&self, segments: &[&str]) -> Result<Res, Error> {
+        let response = self.http.get(self.url(segments)).send().await?;
+        read(response).await
+    }
+}
+
+impl Client {
+    /// `GET /about`
+    pub async fn about(&self) -> Result<AboutResponse, Error> {
+        self.get(&["about"]).await
+    }
+}
+
+async fn read<Res: DeserializeOwned>(response: reqwest::Response) -> Result<Res, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response.json().await?);
+    }
+    let retry_after_secs = response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    let body = response.json().await.ok();
+    Err(Error::Status {
+        status,
+        body,
+        retry_after_secs,
+    })
+}
+
+/// `POST`s taking a JSON body, as `method: ["path", "segments"], Request => Response`
+macro_rules! post_endpoints {
+    ($($(#[$doc:meta])* $name:ident: [$($segment:literal),+], $req:ty => $res:ty;)*) => {
+        impl Client {
+            $(
+                $(#[$doc])*
+                pub async fn $name(&self, body: &$req) -> Result<$res, Error> {
+                    self.post(&[$($segment),+], body).await
+                }
+            )*
+        }
+    };
+}
+
+/// `GET`s of one thing by id, as `method: ["path"], Response`. The id goes last in the path
+macro_rules! get_endpoints {
+    ($($(#[$doc:meta])* $name:ident: [$($segment:literal),+], $res:ty;)*) => {
+        impl Client {
+            $(
+                $(#[$doc])*
+                pub async fn $name(&self, id: &str) -> Result<$res, Error> {
+                    self.get(&[$($segment,)+ id]).await
+                }
+            )*
+        }
+    };
+}
+
+post_endpoints! {
+    /// `POST /route`
+    route: ["route"], RouteRequest => RouteResponse;
+    /// `POST /route/compare`
+    compare_routes: ["route", "compare"], CompareRequest => CompareResponse;
+    /// `POST /reroute`
+    reroute: ["reroute"], RerouteRequest => RerouteResponse;
+    /// `POST /off_route`
+    off_route: ["off_route"], OffRouteRequest => OffRouteResponse;
+    /// `POST /get_locations`
+    get_locations: ["get_locations"], GetLocationsRequest => GetLocationsResponse;
+    /// `POST /reverse`
+    reverse: ["reverse"], ReverseRequest => ReverseResponse;
+    /// `POST /favorites`
+    favorites: ["favorites"], FavoritesRequest => FavoritesResponse;
+    /// `POST /trips`. Only served with a trip database
+    save_trip: ["trips"], RouteRequest => TripResponse;
+    /// `POST /share`. Only served with a trip database
+    create_share: ["share"], ShareRequest => ShareResponse;
+}
+
+get_endpoints! {
+    /// `GET /trips/{id}`
+    get_trip: ["trips"], TripResponse;
+    /// `GET /share/{token}`
+    get_share: ["share"], SharedResponse;
+}
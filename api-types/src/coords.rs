@@ -0,0 +1,167 @@
+//! Positions in the forms third-party clients tend to have them in already, for the requests that
+//! take one as a single field: `"52.52,13.405"` (latitude first), a GeoJSON Point, or a geohash.
+//! Geohashes stand for the middle of their cell.
+//!
+//! Only the form is checked here. Whether the numbers are on the globe is left to the request's
+//! own validation, as for positions sent as separate `lat` and `lon`.
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+pub const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+/// Past this a cell is smaller than GPS can tell apart
+pub const MAX_GEOHASH_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Position {
+    /// `"lat,lon"`, or a geohash if there's no comma
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let Some((lat, lon)) = text.split_once(',') else {
+            let (lat, lon) = decode_geohash(text)?;
+            return Ok(Position { lat, lon });
+        };
+        let number = |s: &str| {
+            s.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("{s:?} isn't a coordinate; expected \"lat,lon\""))
+        };
+        Ok(Position {
+            lat: number(lat)?,
+            lon: number(lon)?,
+        })
+    }
+
+    /// Longitude first, as GeoJSON has it. An altitude is allowed and ignored
+    fn from_point(kind: &str, coordinates: &[f64]) -> Result<Self, String> {
+        if kind != "Point" {
+            return Err(format!("a GeoJSON {kind} isn't a position; send a Point"));
+        }
+        match coordinates {
+            [lon, lat] | [lon, lat, _] if lat.is_finite() && lon.is_finite() => Ok(Position {
+                lat: *lat,
+                lon: *lon,
+            }),
+            _ => Err("a GeoJSON Point's coordinates are [lon, lat]".to_owned()),
+        }
+    }
+}
+
+/// `(lat, lon)` at the middle of `hash`'s cell. Case doesn't matter
+pub fn decode_geohash(hash: &str) -> Result<(f64, f64), String> {
+    if hash.is_empty() || hash.len() > MAX_GEOHASH_LEN {
+        return Err(format!(
+            "a geohash is 1 to {MAX_GEOHASH_LEN} characters, not {}",
+            hash.len()
+        ));
+    }
+    let (mut lat, mut lon) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut even = true;
+    for c in hash.bytes() {
+        let bits = GEOHASH_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_lowercase())
+            .ok_or_else(|| format!("{:?} can't be in a geohash", c as char))?;
+        for shift in (0..5).rev() {
+            // Bits alternate between longitude and latitude, longitude first
+            let range: &mut (f64, f64) = if even { &mut lon } else { &mut lat };
+            let mid = (range.0 + range.1) / 2.0;
+            if bits >> shift & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+    }
+    Ok(((lat.0 + lat.1) / 2.0, (lon.0 + lon.1) / 2.0))
+}
+
+impl<'de> Deserialize<'de> for Position {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Text(String),
+            Point {
+                #[serde(rename = "type")]
+                kind: String,
+                coordinates: Vec<f64>,
+            },
+        }
+        let raw = Raw::deserialize(deserializer).map_err(|_| {
+            de::Error::custom("expected \"lat,lon\", a geohash, or a GeoJSON Point")
+        })?;
+        match raw {
+            Raw::Text(text) => Position::parse(&text),
+            Raw::Point { kind, coordinates } => Position::from_point(&kind, &coordinates),
+        }
+        .map_err(de::Error::custom)
+    }
+}
+
+/// Whichever of a single position field and separate latitude and longitude fields was sent, for
+/// the request types' wire forms. `names` are the three fields, in that order
+pub(crate) fn either(
+    [name, lat_name, lon_name]: [&str; 3],
+    position: Option<Position>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+) -> Result<Position, String> {
+    match (position, lat, lon) {
+        (Some(position), None, None) => Ok(position),
+        (None, Some(lat), Some(lon)) => Ok(Position { lat, lon }),
+        (Some(_), _, _) => Err(format!(
+            "send {name} or {lat_name} and {lon_name}, not both"
+        )),
+        _ => Err(format!("send {name}, or both {lat_name} and {lon_name}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(json: &str) -> Result<Position, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn forms() {
+        let expected = Position {
+            lat: 52.52,
+            lon: 13.405,
+        };
+        assert_eq!(position(r#"" 52.52, 13.405""#), Ok(expected));
+        assert_eq!(
+            position(r#"{"type": "Point", "coordinates": [13.405, 52.52, 34.0]}"#),
+            Ok(expected)
+        );
+        let hashed = position(r#""U33DC0""#).unwrap();
+        assert!((hashed.lat - 52.52).abs() < 0.01 && (hashed.lon - 13.405).abs() < 0.01);
+
+        assert!(position(r#""52.52,13.405,2""#).is_err());
+        assert!(position(r#""52.52,east""#).is_err());
+        assert!(position(r#""u33a""#).unwrap_err().contains("'a'"));
+        assert!(position(r#""""#).is_err());
+        assert!(position(r#"{"type": "LineString", "coordinates": [1.0, 2.0]}"#).is_err());
+        assert!(position(r#"{"type": "Point", "coordinates": [1.0]}"#).is_err());
+        assert!(position("[13.405, 52.52]")
+            .unwrap_err()
+            .contains("GeoJSON Point"));
+    }
+
+    #[test]
+    fn geohash_cells() {
+        // The whole world's middle, and a well-known one
+        assert_eq!(decode_geohash("s"), Ok((22.5, 22.5)));
+        let (lat, lon) = decode_geohash("ezs42").unwrap();
+        assert!((lat - 42.605).abs() < 0.01 && (lon + 5.603).abs() < 0.01);
+        assert!(decode_geohash("0123456789bcd").is_err());
+    }
+}
@@ -0,0 +1,1012 @@
+//! Request and response bodies of the flipmap backend's public API, shared with the app so the
+//! two can't disagree on field names.
+//!
+//! Everything is plain serde. The backend turns on `validate` and `openapi`; the app build can
+//! turn on `ts` for TypeScript definitions, and Rust callers `client` for a typed client.
+//!
+//! Names on the wire are snake_case: fields as they're named here, and enum values with
+//! `rename_all = "snake_case"`, so a later `OnFoot` goes out as `on_foot`. The only renames are
+//! to spell things the way an upstream does, like [OsmType]. The backend's OpenAPI tests check
+//! every name against this and keep a snapshot of every shape, so a new field can't quietly come
+//! out camelCase.
+#[cfg(feature = "client")]
+pub mod client;
+pub mod coords;
+
+use coords::Position;
+use serde::{Deserialize, Serialize};
+// The derive's nested validation calls it unqualified
+#[cfg(feature = "validate")]
+use validator::Validate;
+
+/// `POST /route`. Either end can instead be sent as one field, `src` or `dst`, in any of the
+/// forms in [coords]: `"lat,lon"`, a GeoJSON Point, or a geohash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "RouteRequestWire")]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct RouteRequest {
+    #[cfg_attr(feature = "validate", validate(range(min=-90.0, max=90.0)))]
+    pub src_lat: f64,
+    #[cfg_attr(feature = "validate", validate(range(min=-180.0, max=180.0)))]
+    pub src_lon: f64,
+    #[cfg_attr(feature = "validate", validate(range(min=-90.0, max=90.0)))]
+    pub dst_lat: f64,
+    #[cfg_attr(feature = "validate", validate(range(min=-180.0, max=180.0)))]
+    pub dst_lon: f64,
+    /// Which way the user is heading at the start, in degrees clockwise from north. Keeps the
+    /// route from starting on the wrong side of a divided road and U-turning
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    #[cfg_attr(feature = "validate", validate(range(min = 0.0, max = 360.0)))]
+    pub heading: Option<f64>,
+    /// Also send [RouteAnnotations] for the route
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<bool>", optional))]
+    pub annotations: bool,
+    /// Also send the [BorderCrossing]s on the route
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<bool>", optional))]
+    pub borders: bool,
+    /// Also send the route's [ElevationSummary]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<bool>", optional))]
+    pub elevation: bool,
+}
+
+/// [RouteRequest] as it may come in
+#[derive(Deserialize)]
+struct RouteRequestWire {
+    src: Option<Position>,
+    src_lat: Option<f64>,
+    src_lon: Option<f64>,
+    dst: Option<Position>,
+    dst_lat: Option<f64>,
+    dst_lon: Option<f64>,
+    #[serde(default)]
+    heading: Option<f64>,
+    #[serde(default)]
+    annotations: bool,
+    #[serde(default)]
+    borders: bool,
+    #[serde(default)]
+    elevation: bool,
+}
+
+impl TryFrom<RouteRequestWire> for RouteRequest {
+    type Error = String;
+
+    fn try_from(wire: RouteRequestWire) -> Result<Self, Self::Error> {
+        let src = coords::either(
+            ["src", "src_lat", "src_lon"],
+            wire.src,
+            wire.src_lat,
+            wire.src_lon,
+        )?;
+        let dst = coords::either(
+            ["dst", "dst_lat", "dst_lon"],
+            wire.dst,
+            wire.dst_lat,
+            wire.dst_lon,
+        )?;
+        Ok(RouteRequest {
+            src_lat: src.lat,
+            src_lon: src.lon,
+            dst_lat: dst.lat,
+            dst_lon: dst.lon,
+            heading: wire.heading,
+            annotations: wire.annotations,
+            borders: wire.borders,
+            elevation: wire.elevation,
+        })
+    }
+}
+
+/// How the user gets there
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub enum Profile {
+    /// By car. What `/route` gives
+    #[default]
+    Driving,
+    Cycling,
+    Walking,
+}
+
+/// `POST /route/compare`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct CompareRequest {
+    #[serde(flatten)]
+    #[cfg_attr(feature = "validate", validate(nested))]
+    pub route: RouteRequest,
+    /// Which to compare. All of them if left out
+    #[cfg_attr(feature = "validate", validate(length(min = 1, max = 3)))]
+    pub profiles: Option<Vec<Profile>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct CompareResponse {
+    /// One per profile, in the order asked for
+    pub modes: Vec<ModeSummary>,
+    /// Credits for the data this came from, to show with it as its licences ask. One per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct ModeSummary {
+    pub profile: Profile,
+    /// Both missing if there's no route this way, or it couldn't be looked up right now
+    pub distance_m: Option<f64>,
+    pub duration_s: Option<f64>,
+    /// Why there's no summary. The whole answer is an HTTP 207 when any mode has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub error: Option<ErrorResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct RouteResponse {
+    /// This is just a flattened LineString. Requested for easier processing on app.
+    pub route: Vec<f64>,
+    /// Only if asked for, with [RouteRequest::annotations]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub annotations: Option<RouteAnnotations>,
+    /// Only if asked for, with [RouteRequest::borders], and left out if no country on the route
+    /// could be told. Empty if it stays in one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub borders: Option<Vec<BorderCrossing>>,
+    /// Only if asked for, with [RouteRequest::elevation], and left out if ORS had no heights
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub elevation: Option<ElevationSummary>,
+    /// Where the route really starts and ends. Only left out for an empty route
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub snapped: Option<SnappedEnds>,
+    /// Credits for the data this came from, to show with it as its licences ask. One per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+/// [RouteResponse] for clients asking for v2, with `Accept: application/vnd.flipmap.v2+json`
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct RouteResponseV2 {
+    pub route: Vec<RoutePoint>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub annotations: Option<RouteAnnotations>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub borders: Option<Vec<BorderCrossing>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub elevation: Option<ElevationSummary>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub snapped: Option<SnappedEnds>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+/// What the route runs along, stretch by stretch. Each stretch runs between two positions in the
+/// route, counting positions rather than numbers, and stretches of one kind cover the whole route
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct RouteAnnotations {
+    /// `asphalt`, `gravel`, `dirt` and so on. `paved` and `unpaved` when that's all that's known
+    pub surface: Vec<NamedStretch>,
+    /// `street`, `track`, `cycleway`, `steps` and so on
+    pub waytype: Vec<NamedStretch>,
+    pub steepness: Vec<GradeStretch>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct NamedStretch {
+    pub from: u32,
+    pub to: u32,
+    /// `unknown` if OSM doesn't say
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct GradeStretch {
+    pub from: u32,
+    pub to: u32,
+    /// From -5 (16% or more downhill) through 0 (under 1% either way) to 5 (16% or more uphill),
+    /// as ORS grades it
+    pub grade: i8,
+}
+
+/// Where routing put the ends asked for: on the nearest road it could route along, which may be
+/// some way off, like from the middle of a park
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct SnappedEnds {
+    pub src: SnappedPoint,
+    pub dst: SnappedPoint,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct SnappedPoint {
+    pub lat: f64,
+    pub lon: f64,
+    /// How far it is from the position asked for, to the meter
+    pub deviation_m: f64,
+}
+
+/// How much a route climbs and descends, in the direction it's driven
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct ElevationSummary {
+    /// Every rise added up
+    pub ascent_m: f64,
+    /// Every fall added up, as a positive number
+    pub descent_m: f64,
+    /// Steepest uphill, 0 if there's none
+    pub max_grade_percent: f64,
+    /// Steepest downhill, as a negative number, 0 if there's none
+    pub min_grade_percent: f64,
+}
+
+/// Where a route goes from one country into another, for warning about tolls, visas and roaming
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct BorderCrossing {
+    /// ISO 3166-1 alpha-2, like `DE`
+    pub from: String,
+    pub to: String,
+    /// The first position in the route found to be in `to`, counting positions rather than
+    /// numbers. The border itself is a little before it
+    pub at: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct RoutePoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// `POST /reroute`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "validate", validate(schema(function = "flat_line")))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct RerouteRequest {
+    /// Where the user is now
+    #[cfg_attr(feature = "validate", validate(range(min=-90.0, max=90.0)))]
+    pub lat: f64,
+    #[cfg_attr(feature = "validate", validate(range(min=-180.0, max=180.0)))]
+    pub lon: f64,
+    /// The route being followed, flattened as in [RouteResponse]. It ends at the destination
+    #[cfg_attr(feature = "validate", validate(length(min = 2, max = 100_000)))]
+    pub route: Vec<f64>,
+    /// How far off the route, in meters, still counts as on it. 30 if left out
+    #[cfg_attr(feature = "validate", validate(range(min = 5.0, max = 1000.0)))]
+    pub tolerance_m: Option<f64>,
+    /// As in [RouteRequest], for the new route
+    #[cfg_attr(feature = "validate", validate(range(min = 0.0, max = 360.0)))]
+    pub heading: Option<f64>,
+}
+
+#[cfg(feature = "validate")]
+fn flat_line(req: &RerouteRequest) -> Result<(), validator::ValidationError> {
+    if req.route.len().is_multiple_of(2) {
+        Ok(())
+    } else {
+        Err(validator::ValidationError::new("flat_line")
+            .with_message("route is lon, lat pairs".into()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct RerouteResponse {
+    /// Close enough to carry on. Then there's nothing else
+    pub on_route: bool,
+    /// The new way from where the user is, flattened as in [RouteResponse], up to where it
+    /// rejoins the old route
+    pub route: Vec<f64>,
+    /// Carry on along the old route from this position (counting positions, not numbers). Missing
+    /// if `route` goes all the way to the destination
+    pub rejoin_at: Option<u32>,
+    /// Credits for the data this came from, to show with it as its licences ask. One per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+/// [RerouteResponse] for clients asking for v2
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct RerouteResponseV2 {
+    pub on_route: bool,
+    pub route: Vec<RoutePoint>,
+    pub rejoin_at: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+/// `POST /off_route`. Send either `route` or `trip_id`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "validate", validate(schema(function = "one_route")))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct OffRouteRequest {
+    #[cfg_attr(feature = "validate", validate(range(min=-90.0, max=90.0)))]
+    pub lat: f64,
+    #[cfg_attr(feature = "validate", validate(range(min=-180.0, max=180.0)))]
+    pub lon: f64,
+    /// Flattened as in [RouteResponse]
+    #[cfg_attr(feature = "validate", validate(length(min = 2, max = 100_000)))]
+    pub route: Option<Vec<f64>>,
+    /// A trip saved with `POST /trips`, to check against its route
+    pub trip_id: Option<String>,
+    /// More than this many meters from the route is off it
+    #[cfg_attr(feature = "validate", validate(range(min = 0.0, max = 100_000.0)))]
+    pub max_off_m: f64,
+}
+
+#[cfg(feature = "validate")]
+fn one_route(req: &OffRouteRequest) -> Result<(), validator::ValidationError> {
+    match (&req.route, &req.trip_id) {
+        (Some(route), None) if route.len().is_multiple_of(2) => Ok(()),
+        (Some(_), None) => Err(validator::ValidationError::new("flat_line")
+            .with_message("route is lon, lat pairs".into())),
+        (None, Some(_)) => Ok(()),
+        _ => Err(validator::ValidationError::new("one_route")
+            .with_message("send either route or trip_id".into())),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct OffRouteResponse {
+    /// Further than `max_off_m` from the route
+    pub off_route: bool,
+    /// To the nearest point on the route
+    pub distance_m: f64,
+    /// The part of the route that point is on. 0 is between its first two positions
+    pub segment: u32,
+}
+
+/// `POST /get_locations`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "validate", validate(schema(function = "position_pair")))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct GetLocationsRequest {
+    /// Where to search around, with `lon`. Leave both out for the server's default
+    #[cfg_attr(feature = "validate", validate(range(min=-90.0, max=90.0)))]
+    pub lat: Option<f64>,
+    #[cfg_attr(feature = "validate", validate(range(min=-180.0, max=180.0)))]
+    pub lon: Option<f64>,
+    pub query: String,
+    /// Maximum bound. Photon may return less than this.
+    #[cfg_attr(feature = "validate", validate(range(min = 1, max = 20)))]
+    pub amount: u8,
+    /// Results to skip, for "show more". Send the same search with `offset` moved on by `amount`
+    #[serde(default)]
+    #[cfg_attr(feature = "validate", validate(range(max = 40)))]
+    pub offset: u8,
+    /// Zoom of the map the app is showing, from 0 (whole world) to 18 (a few streets). Lower
+    /// finds places further from the position, favoring towns over streets
+    #[cfg_attr(feature = "validate", validate(range(max = 18)))]
+    pub zoom: Option<u8>,
+    /// Language for place names, such as `"en"`. The best of `Accept-Language` the server's
+    /// Photon has if left out, or names as they're known locally if it has none of them
+    #[serde(default)]
+    #[cfg_attr(feature = "validate", validate(length(min = 2, max = 3)))]
+    pub lang: Option<String>,
+}
+
+#[cfg(feature = "validate")]
+fn position_pair(req: &GetLocationsRequest) -> Result<(), validator::ValidationError> {
+    if req.lat.is_some() == req.lon.is_some() {
+        Ok(())
+    } else {
+        Err(validator::ValidationError::new("position_pair")
+            .with_message("lat and lon go together".into()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct GetLocationsResponse {
+    pub results: Vec<PlaceResult>,
+    /// Whether there are results past these
+    pub has_more: bool,
+    /// Credits for the data this came from, to show with it as its licences ask. One per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct PlaceResult {
+    pub lat: f64,
+    pub lon: f64,
+    pub name: String,
+    /// How sure we are this is what was searched for, from 0 to 1. A lone result at 0.85 or more
+    /// can be picked without asking
+    pub score: f64,
+}
+
+/// `POST /reverse`. The position can instead be sent as one field, `position`, as for
+/// [RouteRequest]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "ReverseRequestWire")]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct ReverseRequest {
+    #[cfg_attr(feature = "validate", validate(range(min=-90.0, max=90.0)))]
+    pub lat: f64,
+    #[cfg_attr(feature = "validate", validate(range(min=-180.0, max=180.0)))]
+    pub lon: f64,
+    #[serde(default)]
+    pub granularity: Granularity,
+    /// As in [GetLocationsRequest]
+    #[cfg_attr(feature = "validate", validate(length(min = 2, max = 3)))]
+    pub lang: Option<String>,
+}
+
+/// [ReverseRequest] as it may come in
+#[derive(Deserialize)]
+struct ReverseRequestWire {
+    position: Option<Position>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    #[serde(default)]
+    granularity: Granularity,
+    lang: Option<String>,
+}
+
+impl TryFrom<ReverseRequestWire> for ReverseRequest {
+    type Error = String;
+
+    fn try_from(wire: ReverseRequestWire) -> Result<Self, Self::Error> {
+        let position = coords::either(
+            ["position", "lat", "lon"],
+            wire.position,
+            wire.lat,
+            wire.lon,
+        )?;
+        Ok(ReverseRequest {
+            lat: position.lat,
+            lon: position.lon,
+            granularity: wire.granularity,
+            lang: wire.lang,
+        })
+    }
+}
+
+/// How specific a reverse lookup should be, from "what building is this" to "what city am I in"
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub enum Granularity {
+    /// A shop, building, park...
+    #[default]
+    Poi,
+    Street,
+    /// A neighbourhood or district
+    Locality,
+    /// Includes towns and villages
+    City,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct ReverseResponse {
+    /// Missing if there's nothing named nearby at the asked granularity or any coarser one
+    pub place: Option<ReversePlace>,
+    /// Credits for the data this came from, to show with it as its licences ask. One per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct ReversePlace {
+    pub lat: f64,
+    pub lon: f64,
+    pub name: String,
+    /// What was found. Coarser than asked when there was nothing that specific
+    pub granularity: Granularity,
+}
+
+/// `POST /favorites`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct FavoritesRequest {
+    #[cfg_attr(feature = "validate", validate(length(min = 1, max = 50), nested))]
+    pub places: Vec<SavedPlace>,
+}
+
+/// A place as the app saved it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct SavedPlace {
+    pub osm_type: OsmType,
+    #[cfg_attr(feature = "ts", ts(type = "number"))]
+    pub osm_id: u64,
+    /// Where it was last known to be. It's looked for around here
+    #[cfg_attr(feature = "validate", validate(range(min=-90.0, max=90.0)))]
+    pub lat: f64,
+    #[cfg_attr(feature = "validate", validate(range(min=-180.0, max=180.0)))]
+    pub lon: f64,
+    pub name: String,
+}
+
+/// Spelled the way Photon spells it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub enum OsmType {
+    #[serde(rename = "N")]
+    Node,
+    #[serde(rename = "W")]
+    Way,
+    #[serde(rename = "R")]
+    Relation,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct FavoritesResponse {
+    /// One per saved place, in the same order
+    pub places: Vec<FavoriteStatus>,
+    /// Credits for the data this came from, to show with it as its licences ask. One per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct FavoriteStatus {
+    pub osm_type: OsmType,
+    #[cfg_attr(feature = "ts", ts(type = "number"))]
+    pub osm_id: u64,
+    pub status: Freshness,
+    /// The place's name and position now. Only when `changed`
+    pub name: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    /// How old our knowledge of the place is. Missing when `unchecked`
+    #[cfg_attr(feature = "ts", ts(type = "number | null"))]
+    pub checked_secs_ago: Option<u64>,
+    /// Why it's `unchecked`. The whole answer is an HTTP 207 when any place has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional))]
+    pub error: Option<ErrorResponse>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub enum Freshness {
+    Unchanged,
+    /// Renamed or moved
+    Changed,
+    /// Not found near where it was saved. Deleted from OSM, or moved far
+    Missing,
+    /// Couldn't be looked up right now. Try again later
+    Unchecked,
+}
+
+/// `GET /trips/{id}`, and `POST /trips` with a [RouteRequest] to save one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct TripResponse {
+    /// For `GET /trips/{id}`. Safe to put in a link
+    pub id: String,
+    /// The trip is gone after this long
+    #[cfg_attr(feature = "ts", ts(type = "number"))]
+    pub expires_in_secs: u64,
+    #[serde(flatten)]
+    pub request: RouteRequest,
+    /// As in [RouteResponse]
+    pub route: Vec<f64>,
+    /// Credits for the data this came from, to show with it as its licences ask. One per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+/// [TripResponse] for clients asking for v2
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct TripResponseV2 {
+    pub id: String,
+    #[cfg_attr(feature = "ts", ts(type = "number"))]
+    pub expires_in_secs: u64,
+    #[serde(flatten)]
+    pub request: RouteRequest,
+    pub route: Vec<RoutePoint>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[cfg_attr(feature = "ts", ts(as = "Option<Vec<String>>", optional))]
+    pub attribution: Vec<String>,
+}
+
+/// `POST /share`. Either `{"kind": "trip", "id": ...}` or `{"kind": "place", ...}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub enum ShareRequest {
+    /// A trip saved with `POST /trips`. The link lasts as long as the trip
+    Trip {
+        id: String,
+    },
+    Place(SharedPlace),
+}
+
+// The derive doesn't do enums
+#[cfg(feature = "validate")]
+impl Validate for ShareRequest {
+    fn validate(&self) -> Result<(), validator::ValidationErrors> {
+        match self {
+            ShareRequest::Trip { .. } => Ok(()),
+            ShareRequest::Place(place) => place.validate(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "validate", derive(validator::Validate))]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct SharedPlace {
+    #[cfg_attr(feature = "validate", validate(range(min=-90.0, max=90.0)))]
+    pub lat: f64,
+    #[cfg_attr(feature = "validate", validate(range(min=-180.0, max=180.0)))]
+    pub lon: f64,
+    #[cfg_attr(feature = "validate", validate(length(min = 1, max = 200)))]
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct ShareResponse {
+    /// For `GET /share/{token}`. Short enough to put in a link
+    pub token: String,
+    #[cfg_attr(feature = "ts", ts(type = "number"))]
+    pub expires_in_secs: u64,
+}
+
+/// `GET /share/{token}`. Tagged with `kind` like [ShareRequest]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub enum SharedResponse {
+    Trip(TripResponse),
+    Place(SharedPlace),
+}
+
+/// [SharedResponse] for clients asking for v2
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub enum SharedResponseV2 {
+    Trip(TripResponseV2),
+    Place(SharedPlace),
+}
+
+/// `GET /about`: what the app's settings screen shows about whoever runs this deployment
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct AboutResponse {
+    /// Terms of use. Missing if the deployment has none
+    pub terms: Option<Document>,
+    pub privacy: Privacy,
+    /// Where the data in answers comes from
+    pub data_sources: Vec<DataSource>,
+    /// Missing if the deployment doesn't give one
+    pub contact: Option<Contact>,
+}
+
+/// A link, a text to show in the app, or both
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct Document {
+    pub url: Option<String>,
+    pub text: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct Privacy {
+    /// The full policy
+    pub policy: Option<Document>,
+    /// What's logged about each request, one item each
+    pub logged: Vec<String>,
+    /// How long anything tied to the client's IP address is kept. 0 if it isn't kept at all,
+    /// missing if the deployment doesn't say
+    pub ip_retention_days: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct DataSource {
+    pub name: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// The credit to show for it, as in the answers' `attribution`
+    #[serde(default)]
+    pub attribution: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct Contact {
+    pub email: Option<String>,
+    pub url: Option<String>,
+}
+
+/// Body of every error response. The 504 for a blown deadline adds what happened before it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "ts", derive(ts_rs::TS), ts(export))]
+pub struct ErrorResponse {
+    /// Which kind of error, for telling them apart in code. `GET /errors` lists them all
+    pub code: String,
+    /// Fine to show to a developer. Vague about anything upstream
+    pub message: String,
+    /// Whether the same request might work later. Don't retry if not
+    pub retryable: bool,
+    /// When it's worth retrying, in milliseconds from now, if we know. Matches Retry-After, which
+    /// rounds down to seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "ts", ts(optional, type = "number"))]
+    pub retry_after_ms: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The app sends and reads exactly these names
+    #[test]
+    fn field_names() {
+        let request: RouteRequest = serde_json::from_str(
+            r#"{"src_lat": 1.0, "src_lon": 2.0, "dst_lat": 3.0, "dst_lon": 4.0}"#,
+        )
+        .unwrap();
+        assert_eq!(request.dst_lon, 4.0);
+        let response = serde_json::to_value(RouteResponseV2 {
+            route: vec![RoutePoint { lat: 1.0, lon: 2.0 }],
+            annotations: None,
+            borders: None,
+            elevation: None,
+            snapped: None,
+            attribution: vec![],
+        })
+        .unwrap();
+        assert_eq!(response.to_string(), r#"{"route":[{"lat":1.0,"lon":2.0}]}"#);
+    }
+
+    #[test]
+    fn positions_in_one_field() {
+        let request: RouteRequest = serde_json::from_str(
+            r#"{"src": "1.0,2.0", "dst": {"type": "Point", "coordinates": [4.0, 3.0]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            (
+                request.src_lat,
+                request.src_lon,
+                request.dst_lat,
+                request.dst_lon
+            ),
+            (1.0, 2.0, 3.0, 4.0)
+        );
+        // Still sent the usual way
+        assert!(serde_json::to_string(&request)
+            .unwrap()
+            .contains(r#""src_lat":1.0"#));
+
+        let both = serde_json::from_str::<RouteRequest>(
+            r#"{"src": "1.0,2.0", "src_lat": 1.0, "dst": "3.0,4.0"}"#,
+        );
+        assert!(both.unwrap_err().to_string().contains("not both"));
+        let half = serde_json::from_str::<ReverseRequest>(r#"{"lat": 1.0}"#);
+        assert!(half.unwrap_err().to_string().contains("both lat and lon"));
+        let reverse: ReverseRequest = serde_json::from_str(r#"{"position": "s"}"#).unwrap();
+        assert_eq!((reverse.lat, reverse.lon), (22.5, 22.5));
+    }
+
+    /// Every exported type's TypeScript declaration, by name, starting from the bodies that go
+    /// over the wire
+    #[cfg(feature = "ts")]
+    fn typescript() -> std::collections::BTreeMap<String, String> {
+        use ts_rs::{TypeVisitor, TS};
+        struct Decls(std::collections::BTreeMap<String, String>);
+        impl TypeVisitor for Decls {
+            fn visit<T: TS + 'static + ?Sized>(&mut self) {
+                if T::output_path().is_none() || self.0.contains_key(&T::name()) {
+                    return;
+                }
+                self.0.insert(T::name(), T::decl());
+                T::visit_dependencies(self);
+            }
+        }
+        let mut decls = Decls(Default::default());
+        decls.visit::<RouteRequest>();
+        decls.visit::<RouteResponse>();
+        decls.visit::<RouteResponseV2>();
+        decls.visit::<CompareRequest>();
+        decls.visit::<CompareResponse>();
+        decls.visit::<RerouteRequest>();
+        decls.visit::<RerouteResponse>();
+        decls.visit::<RerouteResponseV2>();
+        decls.visit::<OffRouteRequest>();
+        decls.visit::<OffRouteResponse>();
+        decls.visit::<GetLocationsRequest>();
+        decls.visit::<GetLocationsResponse>();
+        decls.visit::<ReverseRequest>();
+        decls.visit::<ReverseResponse>();
+        decls.visit::<FavoritesRequest>();
+        decls.visit::<FavoritesResponse>();
+        decls.visit::<TripResponse>();
+        decls.visit::<TripResponseV2>();
+        decls.visit::<ShareRequest>();
+        decls.visit::<ShareResponse>();
+        decls.visit::<SharedResponse>();
+        decls.visit::<SharedResponseV2>();
+        decls.visit::<AboutResponse>();
+        decls.visit::<ErrorResponse>();
+        decls.0
+    }
+
+    /// `(type, field, TypeScript type)` for every field of every exported object type, with `?`
+    /// on the field for ones that can be left out
+    #[cfg(feature = "ts")]
+    fn typescript_fields() -> Vec<(String, String, String)> {
+        let mut fields = vec![];
+        for (name, decl) in typescript() {
+            let mut decl = decl.as_str();
+            let mut bare = String::new();
+            while let Some((before, doc)) = decl.split_once("/**") {
+                bare += before;
+                decl = doc.split_once("*/").unwrap().1;
+            }
+            bare += decl;
+            let Some(body) = bare.split_once("= {").map(|(_, body)| body) else {
+                continue;
+            };
+            let body = body.trim_end().trim_end_matches(';').trim_end_matches('}');
+            let (mut depth, mut field) = (0, String::new());
+            for c in body.chars().chain([',']) {
+                match c {
+                    '<' | '[' | '{' | '(' => depth += 1,
+                    '>' | ']' | '}' | ')' => depth -= 1,
+                    ',' if depth == 0 => {
+                        if let Some((field, ty)) = field.split_once(':') {
+                            fields.push((
+                                name.clone(),
+                                field.trim().to_owned(),
+                                ty.trim().to_owned(),
+                            ));
+                        }
+                        field.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+                field.push(c);
+            }
+        }
+        fields
+    }
+
+    // ts-rs ignores serde's skip_serializing_if, and makes u64s bigints where JSON.parse gives
+    // numbers. Each field says otherwise with `ts(...)`, checked here against the source
+    #[cfg(feature = "ts")]
+    #[test]
+    fn typescript_matches_wire() {
+        let decls = typescript();
+        let derived = include_str!("lib.rs")
+            .lines()
+            .filter(|line| line.starts_with("#[cfg_attr(feature = \"ts\", derive(ts_rs::TS)"))
+            .count();
+        assert_eq!(
+            decls.len(),
+            derived,
+            "every exported type is reachable from typescript()"
+        );
+        for (name, decl) in decls {
+            assert!(!decl.contains("bigint"), "{name}: {decl}");
+        }
+        let mut left_out = std::collections::HashMap::new();
+        let (mut ty, mut skipped) = ("", false);
+        for line in include_str!("lib.rs").lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix("pub struct ") {
+                ty = name.split_once(' ').map_or(name, |(name, _)| name);
+            } else if line.contains("skip_serializing_if") {
+                skipped = true;
+            } else if let Some((field, _)) =
+                line.strip_prefix("pub ").and_then(|f| f.split_once(':'))
+            {
+                left_out.insert((ty.to_owned(), field.to_owned()), skipped);
+                skipped = false;
+            }
+        }
+        // Flattened fields are checked where they're declared
+        for (ty, field, ts_type) in typescript_fields() {
+            let optional = field.ends_with('?');
+            let field = field.trim_end_matches('?').to_owned();
+            if let Some(skipped) = left_out.get(&(ty.clone(), field.clone())) {
+                assert_eq!(optional, *skipped, "{ty}.{field}: {ts_type}");
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! The paths every request goes through, so claims about them can be checked: the lock-free rate
+//! limits under contention, and pulling a route out of ORS's answer.
+//!
+//! `cargo bench`, or `cargo bench -- ratelimit` for just some.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use flipmap_backend::geojson_ext;
+use flipmap_backend::ratelimit::{LimitChain, RateLimit};
+use geojson::FeatureCollection;
+use std::hint::black_box;
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(3600);
+
+fn limit(n: u32) -> RateLimit {
+    RateLimit::new(n, WINDOW, "bench".to_owned())
+}
+
+fn try_consume(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ratelimit");
+
+    let open = limit(u32::MAX);
+    group.bench_function("uncontended", |b| {
+        b.iter(|| black_box(open.try_consume(black_box(1))))
+    });
+    let full = limit(1);
+    full.try_consume(1).unwrap();
+    group.bench_function("exhausted", |b| {
+        b.iter(|| black_box(full.try_consume(black_box(1))))
+    });
+
+    // Every thread hammers the same counter, so compare-exchange retries show up here
+    for threads in [2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::new("contended", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_custom(|iters| {
+                    let shared = limit(u32::MAX);
+                    let start = Barrier::new(threads);
+                    let per_thread = iters / threads as u64 + 1;
+                    let begun = Instant::now();
+                    std::thread::scope(|s| {
+                        for _ in 0..threads {
+                            s.spawn(|| {
+                                start.wait();
+                                for _ in 0..per_thread {
+                                    let _ = black_box(shared.try_consume(1));
+                                }
+                            });
+                        }
+                    });
+                    begun.elapsed()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn chain_undo(c: &mut Criterion) {
+    let mut group = c.benchmark_group("limit_chain");
+
+    let limits = [limit(u32::MAX), limit(u32::MAX)];
+    let chain = LimitChain::new_from(&limits);
+    group.bench_function("all_pass", |b| {
+        b.iter(|| black_box(chain.try_consume(black_box(1))))
+    });
+
+    // The last limit is spent, so every call consumes from the others and then undoes it
+    for len in [2, 4] {
+        let mut limits: Vec<_> = (1..len).map(|_| limit(u32::MAX)).collect();
+        let spent = limit(1);
+        spent.try_consume(1).unwrap();
+        limits.push(spent);
+        let chain = LimitChain::new_from(&limits);
+        group.bench_with_input(BenchmarkId::new("undo_storm", len), &len, |b, _| {
+            b.iter(|| black_box(chain.try_consume(black_box(1))))
+        });
+    }
+    group.finish();
+}
+
+/// An ORS-style answer with one `points`-long route
+fn ors_body(points: usize) -> String {
+    let coordinates: Vec<[f64; 2]> = (0..points)
+        .map(|i| [-123.28 + i as f64 * 1e-5, 44.56 + i as f64 * 1e-5])
+        .collect();
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "features": [{
+            "type": "Feature",
+            "properties": { "summary": { "distance": 1000.0, "duration": 100.0 } },
+            "geometry": { "type": "LineString", "coordinates": coordinates }
+        }]
+    })
+    .to_string()
+}
+
+fn routes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route");
+    for points in [100, 10_000] {
+        let body = ors_body(points);
+        let fc: FeatureCollection = body
+            .parse::<geojson::GeoJson>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        group.throughput(Throughput::Elements(points as u64));
+
+        group.bench_with_input(BenchmarkId::new("parse", points), &body, |b, body| {
+            b.iter(|| serde_json::from_str::<FeatureCollection>(black_box(body)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("extract", points), &fc, |b, fc| {
+            b.iter(|| {
+                geojson_ext::extract_first_linestring(black_box(fc))
+                    .unwrap()
+                    .len()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("flatten", points), &fc, |b, fc| {
+            let line = geojson_ext::extract_first_linestring(fc).unwrap();
+            b.iter(|| geojson_ext::flatten(black_box(line)))
+        });
+        // What a handler does with a fresh answer, parse included
+        group.bench_with_input(BenchmarkId::new("end_to_end", points), &body, |b, body| {
+            b.iter_batched(
+                || body.clone(),
+                |body| {
+                    let fc: FeatureCollection = serde_json::from_str(&body).unwrap();
+                    geojson_ext::flatten(geojson_ext::extract_first_linestring(&fc).unwrap())
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, try_consume, chain_undo, routes);
+criterion_main!(benches);
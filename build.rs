@@ -0,0 +1,21 @@
+//! Embeds the commit being built as `FLIPMAP_GIT_SHA`, for `GET /version`. Builds without a
+//! checkout (say, a Docker context without `.git`) can pass it in the environment instead.
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=FLIPMAP_GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    if std::env::var_os("FLIPMAP_GIT_SHA").is_some() {
+        return;
+    }
+    let sha = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok());
+    if let Some(sha) = sha {
+        println!("cargo:rustc-env=FLIPMAP_GIT_SHA={}", sha.trim());
+    }
+}
@@ -0,0 +1,113 @@
+//! Spherical geometry on plain lat/lon degrees, as the backend does it. Good to a fraction of a
+//! percent, which is plenty for ranking and thresholds.
+//!
+//! `no_std` and allocation-free, so the app can build the very same code for WASM (or anything
+//! else) and never disagree with the backend over how far something is or whether the user's still
+//! on their route. Lines are flattened `[lon, lat, lon, lat, ...]`, as routes go out to the app;
+//! single points are `(lat, lon)`.
+#![no_std]
+
+pub mod route;
+
+use libm::{asin, cos, hypot, sin, sqrt};
+
+/// Mean Earth radius
+pub const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// Great-circle distance in meters between two `(lat, lon)` points
+pub fn haversine_m(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (a.0.to_radians(), b.0.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.1 - a.1).to_radians();
+    let (sin_dlat, sin_dlon) = (sin(dlat / 2.0), sin(dlon / 2.0));
+    let h = sin_dlat * sin_dlat + cos(lat1) * cos(lat2) * sin_dlon * sin_dlon;
+    2.0 * EARTH_RADIUS_M * asin(sqrt(h).min(1.0))
+}
+
+/// Length in meters of a flattened `[lon, lat, lon, lat, ...]` line
+pub fn flat_line_length_m(line: &[f64]) -> f64 {
+    line.chunks_exact(2)
+        .zip(line.chunks_exact(2).skip(1))
+        .map(|(a, b)| haversine_m((a[1], a[0]), (b[1], b[0])))
+        .sum()
+}
+
+/// Distance in meters from `point` (`(lat, lon)`) to the nearest part of a flattened
+/// `[lon, lat, ...]` line, and the index of the segment that's on. `None` for an empty line.
+///
+/// Flattens the Earth around `point`, which is plenty near the line and only rough far from it.
+pub fn distance_to_flat_line_m(point: (f64, f64), line: &[f64]) -> Option<(f64, usize)> {
+    let scale = cos(point.0.to_radians());
+    // Meters east and north of `point`
+    let local = move |pos: &[f64]| {
+        let dlon = (pos[0] - point.1 + 540.0) % 360.0;
+        let dlon = if dlon < 0.0 { dlon + 360.0 } else { dlon } - 180.0;
+        let x = dlon.to_radians() * scale * EARTH_RADIUS_M;
+        let y = (pos[1] - point.0).to_radians() * EARTH_RADIUS_M;
+        (x, y)
+    };
+    let positions = line.chunks_exact(2).map(local);
+    let lone = (line.len() / 2 == 1).then(|| {
+        let only = local(line);
+        (only, only)
+    });
+    positions
+        .clone()
+        .zip(positions.skip(1))
+        .chain(lone)
+        .map(|((ax, ay), (bx, by))| {
+            let (dx, dy) = (bx - ax, by - ay);
+            let len2 = dx * dx + dy * dy;
+            let t = if len2 == 0.0 {
+                0.0
+            } else {
+                (-(ax * dx + ay * dy) / len2).clamp(0.0, 1.0)
+            };
+            hypot(ax + t * dx, ay + t * dy)
+        })
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, d)| (d, i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_distances() {
+        assert_eq!(haversine_m((44.56, -123.27), (44.56, -123.27)), 0.0);
+        // Corvallis to Portland, about 115 km
+        let d = haversine_m((44.5646, -123.2620), (45.5152, -122.6784));
+        assert!((d - 115_200.0).abs() < 1_000.0, "{d}");
+
+        let there_and_back = [-123.2620, 44.5646, -122.6784, 45.5152, -123.2620, 44.5646];
+        assert!((flat_line_length_m(&there_and_back) - 2.0 * d).abs() < 1e-6);
+        assert_eq!(flat_line_length_m(&[-123.2620, 44.5646]), 0.0);
+    }
+
+    #[test]
+    fn distance_to_line() {
+        // East along a parallel, then north
+        let line = [-123.0, 44.0, -122.99, 44.0, -122.99, 44.01];
+        let (d, segment) = distance_to_flat_line_m((44.0001, -122.995), &line).unwrap();
+        assert!((d - 11.1).abs() < 0.1, "{d}");
+        assert_eq!(segment, 0);
+        // Past the corner, nearest the second leg
+        let (d, segment) = distance_to_flat_line_m((44.005, -122.98), &line).unwrap();
+        assert!((d - haversine_m((44.005, -122.98), (44.005, -122.99))).abs() < 1.0);
+        assert_eq!(segment, 1);
+        // Beyond the end is distance to the end
+        let (d, _) = distance_to_flat_line_m((44.0, -123.01), &line).unwrap();
+        assert!((d - haversine_m((44.0, -123.01), (44.0, -123.0))).abs() < 1.0);
+        // Across the antimeridian is the short way round
+        let (d, _) = distance_to_flat_line_m((0.0, 179.9999), &[-180.0, 0.0]).unwrap();
+        assert!((d - 11.1).abs() < 0.1, "{d}");
+
+        assert_eq!(
+            distance_to_flat_line_m((44.0, -123.0), &line[..2]),
+            Some((0.0, 0))
+        );
+        assert_eq!(distance_to_flat_line_m((44.0, -123.0), &[]), None);
+    }
+}
@@ -0,0 +1,53 @@
+//! Following a route: whether the user's still on it, and where a new one rejoins it. The
+//! backend answers `POST /reroute` with these; the app can check the first itself before asking.
+use crate::{distance_to_flat_line_m, haversine_m};
+
+/// How far off the route counts as still on it, unless the app says otherwise. Roughly GPS
+/// error in a city
+pub const DEFAULT_TOLERANCE_M: f64 = 30.0;
+/// Positions closer than this are the same. ORS answers with the same vertices for the same road,
+/// so this only has to absorb rounding
+const SAME_POSITION_M: f64 = 1.0;
+
+/// Whether `position` (`(lat, lon)`) is within `tolerance_m` of the flattened `route`
+pub fn on_route(position: (f64, f64), route: &[f64], tolerance_m: f64) -> bool {
+    distance_to_flat_line_m(position, route).is_some_and(|(d, _)| d <= tolerance_m)
+}
+
+/// Where the `fresh` route rejoins `previous`, both flattened `[lon, lat, ...]`: how many of
+/// `fresh`'s positions lead up to it, and the position in `previous` it carries on from. The
+/// whole of `fresh` and `None` if they only share the destination, or not even that
+pub fn rejoin(previous: &[f64], fresh: &[f64]) -> (usize, Option<usize>) {
+    let same = |a: &[f64], b: &[f64]| haversine_m((a[1], a[0]), (b[1], b[0])) < SAME_POSITION_M;
+    let shared = previous
+        .chunks_exact(2)
+        .rev()
+        .zip(fresh.chunks_exact(2).rev())
+        .take_while(|(a, b)| same(a, b))
+        .count();
+    let fresh_len = fresh.len() / 2;
+    // Just the destination in common is nothing worth splicing
+    if shared < 2 {
+        return (fresh_len, None);
+    }
+    (fresh_len - shared, Some(previous.len() / 2 - shared))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejoins_shared_tail() {
+        let previous = [0.0, 0.0, 0.001, 0.0, 0.002, 0.0, 0.003, 0.0];
+        // Detour through a side street, back on at the third position
+        let fresh = [0.0005, 0.001, 0.002, 0.001, 0.002, 0.0, 0.003, 0.0];
+        assert_eq!(rejoin(&previous, &fresh), (2, Some(2)));
+        // Different way all the way to the destination
+        let fresh = [0.0005, 0.001, 0.003, 0.001, 0.003, 0.0];
+        assert_eq!(rejoin(&previous, &fresh), (3, None));
+
+        assert!(on_route((0.0001, 0.0015), &previous, DEFAULT_TOLERANCE_M));
+        assert!(!on_route((0.001, 0.0015), &previous, DEFAULT_TOLERANCE_M));
+    }
+}
@@ -0,0 +1,115 @@
+//! `GET /about`: the deployment's terms, privacy notes, data sources and contact details, for the
+//! app's settings screen.
+//!
+//! `--about-file` is an [AboutResponse] as JSON, every part of it optional, and is re-read
+//! whenever it changes (see [watched]). If it lists no data sources, the providers are listed with
+//! their credits from [crate::attribution]. Without a file, that's all there is.
+use crate::{
+    attribution::{self, Credits},
+    watched,
+};
+use arc_swap::ArcSwap;
+use axum::{extract::State, Json};
+use flipmap_api_types::{AboutResponse, DataSource};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Cheap to clone; clones see the same reloads
+#[derive(Debug, Clone, Default)]
+pub struct About {
+    /// As the file has it
+    file: Arc<ArcSwap<AboutResponse>>,
+    /// For the providers, if the file lists none
+    credits: Credits,
+}
+
+impl About {
+    /// From `path`, then spawns a task reloading it when it changes
+    pub fn watch(path: PathBuf) -> Result<Self, String> {
+        let about = About::default();
+        about.file.store(Arc::new(read(&path)?));
+        let file = about.file.clone();
+        watched::spawn(path, "about page", read, move |about| {
+            file.store(Arc::new(about))
+        });
+        Ok(about)
+    }
+
+    /// Credits the providers with `credits` rather than the defaults
+    pub fn with_credits(mut self, credits: Credits) -> Self {
+        self.credits = credits;
+        self
+    }
+
+    fn response(&self) -> AboutResponse {
+        let mut about = AboutResponse::clone(&self.file.load());
+        if about.data_sources.is_empty() {
+            about.data_sources = providers(&self.credits);
+        }
+        about
+    }
+}
+
+pub fn read(path: &Path) -> Result<AboutResponse, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("couldn't read {path:?}: {e}"))?;
+    serde_json::from_str(&text).map_err(|e| format!("couldn't parse {path:?}: {e}"))
+}
+
+fn providers(credits: &Credits) -> Vec<DataSource> {
+    let source = |name: &str, url: &str, credits: Vec<String>| DataSource {
+        name: name.to_owned(),
+        url: Some(url.to_owned()),
+        attribution: (!credits.is_empty()).then(|| credits.join("\n")),
+    };
+    vec![
+        source(
+            "openrouteservice",
+            "https://openrouteservice.org",
+            attribution::ors(credits, None),
+        ),
+        source(
+            "Photon",
+            "https://photon.komoot.io",
+            attribution::photon(credits, None),
+        ),
+    ]
+}
+
+/// Terms of use, what's logged and for how long, where the data comes from, and who runs this.
+/// As the deployment wrote it, so it may be partly or entirely empty.
+#[utoipa::path(
+    get,
+    path = "/about",
+    responses((status = 200, body = AboutResponse)),
+)]
+pub async fn get_about(State(about): State<About>) -> Json<AboutResponse> {
+    Json(about.response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_with_providers_filled_in() {
+        let path = std::env::temp_dir().join(format!("about-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"{"privacy": {"logged": ["route and status"], "ip_retention_days": 0},
+                "contact": {"email": "ops@example.com"}}"#,
+        )
+        .unwrap();
+        let about = About::watch(path.clone()).unwrap().response();
+        assert_eq!(about.privacy.ip_retention_days, Some(0));
+        assert_eq!(about.contact.unwrap().email.unwrap(), "ops@example.com");
+        assert!(about.terms.is_none());
+        let names: Vec<_> = about.data_sources.iter().map(|s| &s.name).collect();
+        assert_eq!(names, ["openrouteservice", "Photon"]);
+
+        std::fs::write(&path, r#"{"data_sources": [{"name": "Our own map"}]}"#).unwrap();
+        assert_eq!(read(&path).unwrap().data_sources.len(), 1);
+        std::fs::write(&path, r#"{"terms": 3}"#).unwrap();
+        assert!(About::watch(path.clone()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
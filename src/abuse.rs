@@ -0,0 +1,272 @@
+//! Temporary bans for clients that hammer us, so one broken or hostile client can't burn through
+//! the upstream quotas everyone shares.
+//!
+//! Clients are tracked by their [AccessLog] pseudonym over fixed windows. A client that sends too
+//! many requests, too many bad requests (4xx), or the same request over and over in one window is
+//! banned with a 429. Each ban is twice as long as the last, up to a cap. Since pseudonyms change
+//! daily, so does everyone's record.
+use crate::access_log::AccessLog;
+use crate::error::RouteError;
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::JsonRejection, FromRequest, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Arc, Mutex};
+use tokio::time::{interval, Duration, Instant};
+
+/// When a client gets banned. Counts are per [Thresholds::window]
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    pub window: Duration,
+    pub max_requests: u32,
+    /// 4xx responses, not counting our own 429s and 503s
+    pub max_client_errors: u32,
+    /// Identical requests (same path and body) in a row
+    pub max_repeats: u32,
+    pub first_ban: Duration,
+    pub max_ban: Duration,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            window: Duration::from_secs(60),
+            max_requests: 600,
+            max_client_errors: 100,
+            max_repeats: 30,
+            first_ban: Duration::from_secs(60),
+            max_ban: Duration::from_secs(86400),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClientRecord {
+    window_start: Instant,
+    requests: u32,
+    client_errors: u32,
+    last_fingerprint: u64,
+    repeats: u32,
+    /// Bans so far, for escalating
+    strikes: u32,
+    banned_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl ClientRecord {
+    fn new(now: Instant) -> Self {
+        ClientRecord {
+            window_start: now,
+            requests: 0,
+            client_errors: 0,
+            last_fingerprint: 0,
+            repeats: 0,
+            strikes: 0,
+            banned_until: None,
+            last_seen: now,
+        }
+    }
+}
+
+/// Router state for [guard]. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct AbuseGuard {
+    clients: AccessLog,
+    thresholds: Thresholds,
+    hasher: RandomState,
+    records: Arc<Mutex<HashMap<String, ClientRecord>>>,
+}
+
+impl AbuseGuard {
+    /// Spawns a task that forgets clients who've gone quiet
+    pub fn new(clients: AccessLog, thresholds: Thresholds) -> Self {
+        let guard = AbuseGuard {
+            clients,
+            thresholds,
+            hasher: RandomState::new(),
+            records: Arc::new(Mutex::new(HashMap::new())),
+        };
+        tokio::spawn(Self::prune_task(guard.clone()));
+        guard
+    }
+
+    async fn prune_task(guard: AbuseGuard) {
+        let mut interval = interval(guard.thresholds.window);
+        loop {
+            interval.tick().await;
+            guard.prune(Instant::now());
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        // Keep strikes around long enough to matter for escalation
+        let keep = self.thresholds.max_ban;
+        let mut records = self.records.lock().expect("abuse records lock poisoned");
+        records.retain(|_, r| {
+            r.banned_until.is_some_and(|until| until > now) || now - r.last_seen < keep
+        });
+    }
+
+    /// `Err` with when the ban lifts if `client` is banned
+    fn check(&self, client: &str, now: Instant) -> Result<(), Instant> {
+        let records = self.records.lock().expect("abuse records lock poisoned");
+        match records.get(client).and_then(|r| r.banned_until) {
+            Some(until) if until > now => Err(until),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a finished request against `client`, banning them if it tips them over
+    fn record(&self, client: &str, fingerprint: u64, status: StatusCode, now: Instant) {
+        let t = &self.thresholds;
+        let mut records = self.records.lock().expect("abuse records lock poisoned");
+        let record = records
+            .entry(client.to_owned())
+            .or_insert_with(|| ClientRecord::new(now));
+        record.last_seen = now;
+        if now - record.window_start >= t.window {
+            record.window_start = now;
+            record.requests = 0;
+            record.client_errors = 0;
+            record.repeats = 0;
+        }
+
+        record.requests += 1;
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            record.client_errors += 1;
+        }
+        if fingerprint == record.last_fingerprint {
+            record.repeats += 1;
+        } else {
+            record.last_fingerprint = fingerprint;
+            record.repeats = 0;
+        }
+
+        let reason = if record.requests > t.max_requests {
+            "requests"
+        } else if record.client_errors > t.max_client_errors {
+            "client errors"
+        } else if record.repeats > t.max_repeats {
+            "repeated requests"
+        } else {
+            return;
+        };
+        let ban = t
+            .first_ban
+            .saturating_mul(2u32.saturating_pow(record.strikes))
+            .min(t.max_ban);
+        record.strikes += 1;
+        record.banned_until = Some(now + ban);
+        // Start over once the ban lifts
+        record.window_start = now + ban;
+        record.requests = 0;
+        record.client_errors = 0;
+        record.repeats = 0;
+        tracing::warn!(
+            client,
+            reason,
+            strikes = record.strikes,
+            "banning client for {ban:?}"
+        );
+    }
+}
+
+/// Middleware turning away banned clients and keeping tabs on everyone else. Clients that can't
+/// be identified are let through untracked.
+pub async fn guard(State(guard): State<AbuseGuard>, request: Request, next: Next) -> Response {
+    let client = guard.clients.client_id_of(&request);
+    if client == "unknown" {
+        return next.run(request).await;
+    }
+    if let Err(until) = guard.check(&client, Instant::now()) {
+        return RouteError::Banned(crate::clock::system_at(until)).into_response();
+    }
+
+    // Bodies are read to spot repeats, within the limit [axum::Json] keeps to. One over it gets
+    // the same answer it would from the handler
+    let (parts, body) = request.into_parts();
+    let body = match Bytes::from_request(Request::from_parts(parts.clone(), body), &()).await {
+        Ok(body) => body,
+        Err(rejection) => {
+            return RouteError::from(JsonRejection::from(rejection)).into_response();
+        }
+    };
+    let fingerprint = guard.hasher.hash_one((parts.uri.path(), &body[..]));
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    guard.record(&client, fingerprint, response.status(), Instant::now());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict() -> AbuseGuard {
+        AbuseGuard::new(
+            AccessLog::new(false),
+            Thresholds {
+                window: Duration::from_secs(60),
+                max_requests: 10,
+                max_client_errors: 3,
+                max_repeats: 2,
+                first_ban: Duration::from_secs(60),
+                max_ban: Duration::from_secs(150),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn repeated_bad_requests_get_banned() {
+        let guard = strict();
+        let now = Instant::now();
+        for i in 0..4 {
+            assert!(guard.check("a", now).is_ok());
+            guard.record("a", i, StatusCode::UNPROCESSABLE_ENTITY, now);
+        }
+        assert_eq!(guard.check("a", now), Err(now + Duration::from_secs(60)));
+        // Someone else is unaffected
+        assert!(guard.check("b", now).is_ok());
+        assert!(guard.check("a", now + Duration::from_secs(61)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn identical_requests_get_banned() {
+        let guard = strict();
+        let now = Instant::now();
+        for _ in 0..4 {
+            guard.record("a", 7, StatusCode::OK, now);
+        }
+        assert!(guard.check("a", now).is_err());
+    }
+
+    #[tokio::test]
+    async fn bans_escalate_up_to_cap() {
+        let guard = strict();
+        let mut now = Instant::now();
+        for expected in [60, 120, 150] {
+            for i in 0..11 {
+                guard.record("a", i, StatusCode::OK, now);
+            }
+            assert_eq!(
+                guard.check("a", now),
+                Err(now + Duration::from_secs(expected))
+            );
+            now += Duration::from_secs(expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn quiet_clients_forgotten() {
+        let guard = strict();
+        let now = Instant::now();
+        guard.record("a", 1, StatusCode::OK, now);
+        guard.prune(now + Duration::from_secs(151));
+        assert!(guard.records.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+//! Access log that identifies clients without keeping their IPs around.
+//!
+//! Clients are logged as a keyed hash of their IP, and the key is thrown away at the end of every
+//! UTC day. The same phone keeps the same id all day, which is enough to spot one misbehaving, but
+//! nobody (us included) can get back to the IP or link ids across days.
+//!
+//! With a GeoIP database, the client's country is logged too, and added to the request span and
+//! metrics.
+use crate::geoip::GeoIp;
+use crate::metrics;
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, FromRef, FromRequestParts, MatchedPath, Request, State},
+    http::{request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use std::convert::Infallible;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// Router state for [log_access]. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    trust_forwarded: bool,
+    /// UTC day number and the hash key for that day
+    salt: Arc<Mutex<(u64, RandomState)>>,
+    geoip: Option<GeoIp>,
+}
+
+impl AccessLog {
+    /// With `trust_forwarded`, the client IP comes from `X-Forwarded-For` rather than the socket.
+    /// Only turn that on behind a reverse proxy, otherwise clients can pick their own id.
+    pub fn new(trust_forwarded: bool) -> Self {
+        AccessLog {
+            trust_forwarded,
+            salt: Arc::new(Mutex::new((today(), RandomState::new()))),
+            geoip: None,
+        }
+    }
+
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// Best guess at who sent the request. `None` without connect info (i.e. in tests)
+    pub fn client_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+        if self.trust_forwarded {
+            // Proxies append, so the last entry is what our proxy saw. Earlier ones are the
+            // client's word
+            let forwarded = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .filter_map(|ip| ip.trim().parse().ok())
+                .next_back();
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        peer.map(|p| p.ip())
+    }
+
+    /// Today's pseudonym for `ip`
+    pub fn client_id(&self, ip: IpAddr) -> String {
+        self.client_id_on(ip, today())
+    }
+
+    fn client_id_on(&self, ip: IpAddr, day: u64) -> String {
+        let mut salt = self.salt.lock().expect("access log salt lock poisoned");
+        if salt.0 != day {
+            *salt = (day, RandomState::new());
+        }
+        format!("{:016x}", salt.1.hash_one(ip))
+    }
+
+    /// Pseudonym for whoever sent `request`, or `unknown`
+    pub fn client_id_of(&self, request: &Request) -> String {
+        self.client_ip(request.headers(), peer(request))
+            .map(|ip| self.client_id(ip))
+            .unwrap_or_else(|| "unknown".to_owned())
+    }
+
+    /// Country code of whoever sent `request`, if there's a GeoIP database and it knows
+    pub fn country_of(&self, request: &Request) -> Option<String> {
+        let geoip = self.geoip.as_ref()?;
+        geoip.country(self.client_ip(request.headers(), peer(request))?)
+    }
+}
+
+/// Handler argument with today's pseudonym for the client, from the [AccessLog] in router state.
+/// `None` when the client can't be told apart
+pub struct ClientId(pub Option<String>);
+
+impl<S> FromRequestParts<S> for ClientId
+where
+    AccessLog: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let log = AccessLog::from_ref(state);
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|c| c.0);
+        Ok(ClientId(
+            log.client_ip(&parts.headers, peer)
+                .map(|ip| log.client_id(ip)),
+        ))
+    }
+}
+
+fn peer(request: &Request) -> Option<SocketAddr> {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0)
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / SECS_PER_DAY
+}
+
+/// Middleware logging one line per request. Runs inside the request span, so the request id
+/// comes along.
+pub async fn log_access(State(log): State<AccessLog>, request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| request.uri().path().to_owned());
+    let client = log.client_id_of(&request);
+    let country = log.country_of(&request);
+    if let Some(country) = &country {
+        tracing::Span::current().record("country", country.as_str());
+    }
+
+    let response = next.run(request).await;
+    metrics::registry().inc_counter(
+        "requests_by_country_total",
+        &[("country", country.as_deref().unwrap_or("unknown"))],
+        1,
+    );
+    tracing::info!(
+        %method,
+        path,
+        status = response.status().as_u16(),
+        latency_ms = start.elapsed().as_millis() as u64,
+        size = response.body().size_hint().exact(),
+        client,
+        country,
+        "access"
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_stable_within_a_day() {
+        let log = AccessLog::new(false);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "203.0.113.8".parse().unwrap();
+        assert_eq!(log.client_id_on(ip, 1), log.client_id_on(ip, 1));
+        assert_ne!(log.client_id_on(ip, 1), log.client_id_on(other, 1));
+        let yesterday = log.client_id_on(ip, 1);
+        assert_ne!(yesterday, log.client_id_on(ip, 2));
+    }
+
+    #[test]
+    fn forwarded_for_only_when_trusted() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1, 198.51.100.2".parse().unwrap());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        let trusting = AccessLog::new(true);
+        assert_eq!(
+            trusting.client_ip(&headers, Some(peer)),
+            Some("198.51.100.2".parse().unwrap())
+        );
+        let untrusting = AccessLog::new(false);
+        assert_eq!(untrusting.client_ip(&headers, Some(peer)), Some(peer.ip()));
+        assert_eq!(
+            trusting.client_ip(&HeaderMap::new(), Some(peer)),
+            Some(peer.ip())
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Operational endpoints under `/admin`: maintenance, cached-results-only mode, log levels, usage,
+//! anonymous stats, limits, the route cache, the outbound audit trail, ORS key rotation, and
+//! feature flags. With the `pprof` feature, CPU and heap profiles too, under `/debug/pprof` where
+//! the tools expect them (see [crate::profiling]).
+//!
+//! They get their own [Router], served on its own listener (`--admin-listen` or `--admin-socket`)
+//! so they never sit next to the public routes, and every request needs the admin token as
+//! `Authorization: Bearer <token>`.
+use crate::audit::{self, Audit};
+use crate::degraded::{self, Degraded};
+use crate::flags::{self, Flags};
+use crate::log_level::{self, LogLevels};
+use crate::maintenance::{self, Maintenance};
+use crate::requester::{ExternalApi, Limits};
+use crate::rotation::{self, Rotating};
+use crate::usage::{self, Ledger};
+use crate::RouteCache;
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+
+/// Everything the admin endpoints look at or change. Shared with the public side
+pub struct AdminParts {
+    pub client: Arc<dyn ExternalApi>,
+    pub maintenance: Maintenance,
+    pub degraded: Degraded,
+    pub ledger: Ledger,
+    pub route_cache: RouteCache,
+    /// Served at /admin/log_level if present
+    pub log_levels: Option<LogLevels>,
+    /// Served at /admin/outbound if present
+    pub audit: Option<Audit>,
+    /// Served at /admin/ors_key if present
+    pub rotating: Option<Arc<Rotating>>,
+    pub flags: Flags,
+    pub token: SecretString,
+}
+
+pub fn router(parts: AdminParts) -> Router {
+    let AdminParts {
+        client,
+        maintenance,
+        degraded,
+        ledger,
+        route_cache,
+        log_levels,
+        audit,
+        rotating,
+        flags,
+        token,
+    } = parts;
+    let mut app = Router::new()
+        .route(
+            "/admin/maintenance",
+            get(maintenance::get_maintenance)
+                .put(maintenance::put_maintenance)
+                .delete(maintenance::delete_maintenance),
+        )
+        .with_state(maintenance)
+        .route(
+            "/admin/degraded",
+            get(degraded::get_degraded)
+                .put(degraded::put_degraded)
+                .delete(degraded::delete_degraded),
+        )
+        .with_state(degraded)
+        .route("/admin/usage", get(usage::get_usage))
+        .route("/admin/stats", get(usage::get_stats))
+        .with_state(ledger)
+        .route("/admin/limits", get(get_limits))
+        .with_state(client)
+        .route("/admin/cache", get(get_cache).delete(delete_cache))
+        .with_state(route_cache)
+        .route("/admin/flags", get(flags::get_flags))
+        .with_state(flags);
+    if let Some(log_levels) = log_levels {
+        app = app.route(
+            "/admin/log_level",
+            get(log_level::get_filter)
+                .put(log_level::put_filter)
+                .delete(log_level::reset_filter)
+                .with_state(log_levels),
+        );
+    }
+    if let Some(audit) = audit {
+        app = app.route(
+            "/admin/outbound",
+            get(audit::get_outbound).with_state(audit),
+        );
+    }
+    if let Some(rotating) = rotating {
+        app = app.route(
+            "/admin/ors_key",
+            axum::routing::put(rotation::put_key).with_state(rotating),
+        );
+    }
+    #[cfg(feature = "pprof")]
+    {
+        app = app.merge(crate::profiling::router());
+    }
+    app.layer(axum::middleware::from_fn_with_state(
+        Arc::new(token),
+        require_token,
+    ))
+}
+
+/// Equal length and contents, taking the same time wherever they differ
+fn same_token(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Middleware turning away anyone without the admin token. Unmatched paths get the same 401, so
+/// there's nothing to learn about which endpoints are switched on
+async fn require_token(
+    State(token): State<Arc<SecretString>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        Some(given) if same_token(given.as_bytes(), token.expose_secret().as_bytes()) => {
+            next.run(request).await
+        }
+        _ => {
+            tracing::warn!(
+                method = %request.method(),
+                path = request.uri().path(),
+                "admin request without a valid token"
+            );
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                Json(json!({ "message": "admin token required" })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// `GET /admin/limits`: our own rate limits and any upstream backoffs
+async fn get_limits(State(client): State<Arc<dyn ExternalApi>>) -> Json<Limits> {
+    Json(client.limits())
+}
+
+#[derive(Serialize, Debug)]
+struct CacheStatus {
+    entries: usize,
+    capacity: usize,
+}
+
+/// `GET /admin/cache`
+async fn get_cache(State(cache): State<RouteCache>) -> Json<CacheStatus> {
+    Json(CacheStatus {
+        entries: cache.len(),
+        capacity: cache.capacity(),
+    })
+}
+
+/// `DELETE /admin/cache`: forget every cached route, e.g. after ORS fixed its map data
+async fn delete_cache(State(cache): State<RouteCache>) -> Json<serde_json::Value> {
+    let cleared = cache.clear();
+    tracing::info!(cleared, "route cache cleared from admin endpoint");
+    Json(json!({ "cleared": cleared }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::CannedApi;
+    use crate::RouteResponse;
+    use axum::body::Body;
+    use tokio::time::Duration;
+    use tower::ServiceExt;
+
+    fn admin(cache: RouteCache) -> Router {
+        let maintenance = Maintenance::default();
+        router(AdminParts {
+            client: Arc::new(CannedApi::default()),
+            degraded: Degraded::new(Arc::new(CannedApi::default())),
+            ledger: Ledger::new(Default::default(), maintenance.clone()),
+            maintenance,
+            route_cache: cache,
+            log_levels: None,
+            audit: None,
+            rotating: None,
+            flags: Flags::default(),
+            token: SecretString::from("hunter2"),
+        })
+    }
+
+    async fn call(app: &Router, method: &str, path: &str, token: Option<&str>) -> Response {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        app.clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn json_of(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn needs_token() {
+        let app = admin(RouteCache::new(Duration::from_secs(60), 10));
+        for token in [None, Some("hunter"), Some("hunter22")] {
+            let response = call(&app, "GET", "/admin/usage", token).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{token:?}");
+        }
+        // Even where there's nothing
+        let response = call(&app, "GET", "/admin/log_level", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = call(&app, "GET", "/admin/usage", Some("hunter2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call(&app, "GET", "/admin/limits", Some("hunter2")).await;
+        assert_eq!(json_of(response).await["photon"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn cache_clears() {
+        let cache = RouteCache::new(Duration::from_secs(60), 10);
+        cache.insert(
+            1,
+            RouteResponse {
+                route: vec![],
+                annotations: None,
+                borders: None,
+                elevation: None,
+                snapped: None,
+                attribution: vec![],
+            },
+        );
+        let app = admin(cache.clone());
+
+        let response = call(&app, "GET", "/admin/cache", Some("hunter2")).await;
+        assert_eq!(
+            json_of(response).await,
+            json!({ "entries": 1, "capacity": 10 })
+        );
+        let response = call(&app, "DELETE", "/admin/cache", Some("hunter2")).await;
+        assert_eq!(json_of(response).await, json!({ "cleared": 1 }));
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,73 @@
+//! Credits for the data behind each answer, for the app to show as the providers' licences ask.
+//!
+//! Each provider has a configured credit (`--ors-attribution`, `--photon-attribution`), OSM's
+//! by default, and answers built from its data carry it in `attribution`. Whatever credit the
+//! provider sends along with the data (ORS's `metadata.attribution`, an `attribution` member on a
+//! Photon collection) goes after it, unless it's the same. An empty configured credit leaves just
+//! the upstream one.
+use geojson::FeatureCollection;
+
+pub const DEFAULT_ORS: &str = "© openrouteservice.org by HeiGIT | © OpenStreetMap contributors";
+pub const DEFAULT_PHOTON: &str = "Photon by komoot | © OpenStreetMap contributors";
+
+/// Configured credits, one per provider. Handlers get them from the app state
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credits {
+    pub ors: String,
+    pub photon: String,
+}
+
+impl Default for Credits {
+    fn default() -> Self {
+        Credits {
+            ors: DEFAULT_ORS.to_owned(),
+            photon: DEFAULT_PHOTON.to_owned(),
+        }
+    }
+}
+
+/// For an answer from ORS data, with whatever credit ORS sent
+pub fn ors(credits: &Credits, upstream: Option<&str>) -> Vec<String> {
+    merge(&credits.ors, upstream)
+}
+
+/// For an answer from Photon data, with whatever credit Photon sent
+pub fn photon(credits: &Credits, upstream: Option<&str>) -> Vec<String> {
+    merge(&credits.photon, upstream)
+}
+
+/// The credit on a Photon collection, if it has one. Plain Photon doesn't, but some instances in
+/// front of it add one
+pub fn of_collection(fc: &FeatureCollection) -> Option<&str> {
+    fc.foreign_members
+        .as_ref()
+        .and_then(|m| m.get("attribution"))
+        .and_then(|a| a.as_str())
+}
+
+fn merge(configured: &str, upstream: Option<&str>) -> Vec<String> {
+    let configured = configured.trim();
+    let upstream = upstream
+        .map(str::trim)
+        .filter(|u| !u.is_empty() && *u != configured);
+    [configured]
+        .into_iter()
+        .filter(|c| !c.is_empty())
+        .chain(upstream)
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_follows_configured() {
+        assert_eq!(merge("ours", None), vec!["ours"]);
+        assert_eq!(merge("ours", Some(" theirs ")), vec!["ours", "theirs"]);
+        assert_eq!(merge("ours", Some("ours")), vec!["ours"]);
+        assert_eq!(merge("", Some("theirs")), vec!["theirs"]);
+        assert!(merge("", Some("")).is_empty());
+    }
+}
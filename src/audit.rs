@@ -0,0 +1,245 @@
+//! Keeps the last few outbound requests around, for answering "what exactly did we send ORS"
+//! without packet captures.
+//!
+//! Only what's needed to reproduce a call by hand is kept: method, URL, headers, and how it went.
+//! Authorization headers are dropped and `lat`/`lon` query values moved to the middle of their
+//! ~1km geohash cell, so the trail can be shared in bug reports.
+use crate::geo;
+use crate::memory::{self, Freed};
+use crate::requester::Provider;
+use axum::{extract::State, Json};
+use reqwest::header;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+/// Geohash length coordinate query values are blurred to. 6 is roughly a kilometer
+const COORD_GEOHASH: usize = 6;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OutboundRecord {
+    /// Unix time in milliseconds the call was sent
+    pub sent_at_ms: u64,
+    pub provider: Provider,
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// `None` if no response came back
+    pub status: Option<u16>,
+    /// What went wrong, if no response came back
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+impl OutboundRecord {
+    /// Roughly what it takes up, for the [memory::Budget]
+    fn weight(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.method.len()
+            + self.url.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>()
+            + self.error.as_ref().map_or(0, String::len)
+    }
+}
+
+/// Ring buffer of [OutboundRecord]s. Cheap to clone; clones share entries
+#[derive(Debug, Clone)]
+pub struct Audit {
+    capacity: usize,
+    records: Arc<Mutex<VecDeque<OutboundRecord>>>,
+    charge: Option<memory::Charge>,
+}
+
+impl Audit {
+    /// `capacity` must be at least 1
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "audit trail needs room for at least one call");
+        Audit {
+            capacity,
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            charge: None,
+        }
+    }
+
+    /// Charges records to `budget`, giving up the oldest when it runs over
+    pub fn with_budget(mut self, budget: &memory::Budget) -> Self {
+        let records = Arc::downgrade(&self.records);
+        self.charge = Some(budget.join("audit", move |bytes| {
+            let Some(records) = records.upgrade() else {
+                return Freed::default();
+            };
+            let mut records = records.lock().expect("audit lock poisoned");
+            let mut freed = Freed::default();
+            while freed.bytes < bytes {
+                let Some(record) = records.pop_front() else {
+                    break;
+                };
+                freed.bytes += record.weight();
+                freed.entries += 1;
+            }
+            freed
+        }));
+        self
+    }
+
+    /// Starts a record for `req`. It's kept once [Pending::finish] says how the call went
+    pub fn start(&self, provider: Provider, req: &reqwest::Request) -> Pending {
+        let record = OutboundRecord {
+            sent_at_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            provider,
+            method: req.method().to_string(),
+            url: redact_url(req.url()),
+            headers: req
+                .headers()
+                .iter()
+                .filter(|(name, value)| {
+                    *name != header::AUTHORIZATION
+                        && *name != header::PROXY_AUTHORIZATION
+                        && !value.is_sensitive()
+                })
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    )
+                })
+                .collect(),
+            status: None,
+            error: None,
+            latency_ms: 0,
+        };
+        Pending {
+            audit: self.clone(),
+            record,
+            started: Instant::now(),
+        }
+    }
+
+    fn push(&self, record: OutboundRecord) {
+        let weight = record.weight();
+        let mut records = self.records.lock().expect("audit lock poisoned");
+        let dropped = if records.len() >= self.capacity {
+            records.pop_front()
+        } else {
+            None
+        };
+        records.push_back(record);
+        drop(records);
+        if let Some(charge) = &self.charge {
+            charge.sub(dropped.map_or(0, |r| r.weight()));
+            charge.add(weight);
+            charge.enforce();
+        }
+    }
+
+    /// Newest first
+    pub fn recent(&self) -> Vec<OutboundRecord> {
+        let records = self.records.lock().expect("audit lock poisoned");
+        records.iter().rev().cloned().collect()
+    }
+}
+
+/// A call that's been sent but hasn't come back yet
+#[derive(Debug)]
+pub struct Pending {
+    audit: Audit,
+    record: OutboundRecord,
+    started: Instant,
+}
+
+impl Pending {
+    pub fn finish(mut self, res: &reqwest::Result<reqwest::Response>) {
+        self.record.status = res.as_ref().ok().map(|r| r.status().as_u16());
+        self.record.error = res.as_ref().err().map(|e| e.to_string());
+        self.record.latency_ms = self.started.elapsed().as_millis() as u64;
+        self.audit.push(self.record);
+    }
+}
+
+/// `url` with `lat`/`lon` query values blurred to their [COORD_GEOHASH] cell. Either alone is
+/// dropped
+fn redact_url(url: &reqwest::Url) -> String {
+    if url.query().is_none() {
+        return url.to_string();
+    }
+    let coord = |name| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .and_then(|(_, v)| v.parse::<f64>().ok())
+    };
+    let cell = coord("lat")
+        .zip(coord("lon"))
+        .map(|point| geo::cell(point, COORD_GEOHASH));
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter_map(|(k, v)| {
+            let v = match (&*k, cell) {
+                ("lat", Some((lat, _))) => format!("{lat:.3}"),
+                ("lon", Some((_, lon))) => format!("{lon:.3}"),
+                ("lat" | "lon", None) => return None,
+                _ => v.into_owned(),
+            };
+            Some((k.into_owned(), v))
+        })
+        .collect();
+    let mut url = url.clone();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    url.to_string()
+}
+
+/// `GET /admin/outbound`
+pub async fn get_outbound(State(audit): State<Audit>) -> Json<Vec<OutboundRecord>> {
+    Json(audit.recent())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_coordinates_and_auth() {
+        let audit = Audit::new(5);
+        let req = reqwest::Client::new()
+            .get("http://photon.test/reverse?lon=13.404954&lat=52.520008&q=Alexanderplatz")
+            .header(header::AUTHORIZATION, "secret-key")
+            .header(header::ACCEPT, "application/json")
+            .build()
+            .unwrap();
+        let res = Err(reqwest::Client::new().get("not a url").build().unwrap_err());
+        audit.start(Provider::Photon, &req).finish(&res);
+
+        let record = &audit.recent()[0];
+        assert_eq!(
+            record.url,
+            "http://photon.test/reverse?lon=13.409&lat=52.517&q=Alexanderplatz"
+        );
+        assert_eq!(
+            record.headers,
+            vec![("accept".to_owned(), "application/json".to_owned())]
+        );
+        assert!(record.status.is_none() && record.error.is_some());
+    }
+
+    #[test]
+    fn oldest_pushed_out() {
+        let audit = Audit::new(2);
+        let res = Err(reqwest::Client::new().get("nope").build().unwrap_err());
+        for path in ["a", "b", "c"] {
+            let req = reqwest::Client::new()
+                .get(format!("http://ors.test/{path}"))
+                .build()
+                .unwrap();
+            audit.start(Provider::OpenRouteService, &req).finish(&res);
+        }
+        let urls: Vec<_> = audit.recent().into_iter().map(|r| r.url).collect();
+        assert_eq!(urls, ["http://ors.test/c", "http://ors.test/b"]);
+    }
+}
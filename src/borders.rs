@@ -0,0 +1,227 @@
+//! Which countries a route goes through, so the app can warn about tolls, visas and roaming
+//! before the user sets off.
+//!
+//! With [RouteRequest::borders], positions along the route are reverse geocoded through Photon,
+//! one every [SPACING_M] or so, at most [MAX_SAMPLES] of them, both ends included. Each change of
+//! country from one to the next is a [BorderCrossing]. Borders don't move, so countries are kept
+//! for [COUNTRY_CACHE_TTL] by [CELL]-character geohash cell, and routes through the same area
+//! share them. Lookups only go out while at least [MIN_PHOTON_LEFT] of Photon's quota is left,
+//! both in our politeness limits and under the daily cap, so routes never crowd out searches.
+//! Positions Photon can't place, or can't be asked about for lack of quota, are skipped. A
+//! crossing can then be found late, and a short visit to a country can be missed entirely. With nothing placed at all, there's no answer rather than an empty one.
+use crate::cache::TtlCache;
+use crate::geo;
+use crate::geojson_ext::ParseMode;
+use crate::photon::PhotonProperties;
+use crate::requester::{ExternalApi, PhotonRevGeocodeRequest};
+use crate::usage::Ledger;
+use flipmap_api_types::{BorderCrossing, RouteRequest, RouteResponse};
+use futures_util::StreamExt;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+/// How far apart positions are looked up, on routes short enough
+pub const SPACING_M: f64 = 10_000.0;
+/// Lookups for one route, however long. Longer routes are looked up more sparsely
+pub const MAX_SAMPLES: usize = 50;
+/// About 5 km across, so few cells straddle a border
+const CELL: usize = 5;
+pub const COUNTRY_CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub const COUNTRY_CACHE_CAPACITY: usize = 100_000;
+/// Lookups in flight at once for one route
+const LOOKUPS: usize = 4;
+/// Share of Photon's quota, 0 to 1, left for searches. A few lookups in flight can dip under it
+pub const MIN_PHOTON_LEFT: f64 = 0.5;
+
+/// Countries by geohash cell. `None` for cells Photon has nothing in, like open sea
+pub type CountryCache = TtlCache<String, Option<String>>;
+
+/// Cheap to clone; clones share the cache
+#[derive(Clone)]
+pub struct Borders {
+    client: Arc<dyn ExternalApi>,
+    cache: CountryCache,
+    ledger: Ledger,
+    mode: ParseMode,
+}
+
+impl Borders {
+    pub fn new(
+        client: Arc<dyn ExternalApi>,
+        cache: CountryCache,
+        ledger: Ledger,
+        mode: ParseMode,
+    ) -> Self {
+        Borders {
+            client,
+            cache,
+            ledger,
+            mode,
+        }
+    }
+
+    /// Fills in `res.borders`, if `params` asked for them
+    pub async fn annotate(&self, params: &RouteRequest, res: &mut RouteResponse) {
+        if params.borders {
+            res.borders = self.crossings(&res.route).await;
+        }
+    }
+
+    /// Along `route`, a flattened LineString
+    pub async fn crossings(&self, route: &[f64]) -> Option<Vec<BorderCrossing>> {
+        let countries: Vec<_> = futures_util::stream::iter(samples(route))
+            .map(|at| async move {
+                let i = at as usize * 2;
+                (at, self.country(route[i + 1], route[i]).await)
+            })
+            .buffered(LOOKUPS)
+            .collect()
+            .await;
+        crossings(&countries)
+    }
+
+    /// ISO 3166-1 alpha-2, if Photon can tell
+    async fn country(&self, lat: f64, lon: f64) -> Option<String> {
+        let cell = geo::geohash((lat, lon), CELL);
+        if let Some(country) = self.cache.get_fresh(&cell) {
+            return country;
+        }
+        if self.ledger.photon_left(&*self.client) < MIN_PHOTON_LEFT {
+            tracing::debug!("not placing route position, Photon's quota is short");
+            return None;
+        }
+        let req = PhotonRevGeocodeRequest {
+            lat,
+            lon,
+            limit: None,
+            lang: None,
+        };
+        let features = match self.client.photon_reverse_send(&req).await {
+            Ok(features) => features,
+            Err(e) => {
+                tracing::debug!("couldn't place route position: {e}");
+                return None;
+            }
+        };
+        let country = features
+            .features
+            .first()
+            .and_then(|f| PhotonProperties::of(f, self.mode).ok()?.countrycode)
+            .map(|c| c.to_ascii_uppercase());
+        self.cache.insert(cell, country.clone());
+        country
+    }
+}
+
+/// Which positions in `route` to look up, in order
+fn samples(route: &[f64]) -> Vec<u32> {
+    let positions: Vec<_> = route.chunks_exact(2).map(|p| (p[1], p[0])).collect();
+    let Some(last) = positions.len().checked_sub(1) else {
+        return vec![];
+    };
+    let spacing = SPACING_M.max(geo::flat_line_length_m(route) / (MAX_SAMPLES - 2) as f64);
+    let mut samples = vec![0];
+    let mut since = 0.0;
+    for (i, pair) in positions.windows(2).enumerate() {
+        since += geo::haversine_m(pair[0], pair[1]);
+        if since >= spacing && i + 1 < last {
+            samples.push(i as u32 + 1);
+            since = 0.0;
+        }
+    }
+    if last > 0 {
+        samples.push(last as u32);
+    }
+    samples
+}
+
+/// Changes of country in `countries`, by position. `None` if none are known
+fn crossings(countries: &[(u32, Option<String>)]) -> Option<Vec<BorderCrossing>> {
+    let mut placed = countries
+        .iter()
+        .filter_map(|(at, country)| Some((*at, country.as_ref()?)));
+    let (_, mut current) = placed.next()?;
+    let mut crossings = vec![];
+    for (at, country) in placed {
+        if country != current {
+            crossings.push(BorderCrossing {
+                from: current.clone(),
+                to: country.clone(),
+                at,
+            });
+            current = country;
+        }
+    }
+    Some(crossings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{collection, CannedApi};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn crossings_between_placed_positions() {
+        let country = |c: &str| Some(c.to_owned());
+        let found = crossings(&[
+            (0, country("DE")),
+            (4, None),
+            (9, country("DE")),
+            (12, country("AT")),
+            (20, None),
+            (31, country("DE")),
+        ])
+        .unwrap();
+        let found: Vec<_> = found
+            .iter()
+            .map(|c| (c.from.as_str(), c.to.as_str(), c.at))
+            .collect();
+        assert_eq!(found, [("DE", "AT", 12), ("AT", "DE", 31)]);
+        assert_eq!(crossings(&[(0, country("DE"))]), Some(vec![]));
+        assert_eq!(crossings(&[(0, None), (3, None)]), None);
+    }
+
+    #[test]
+    fn samples_spaced_and_capped() {
+        // Due east along the equator, 0.01 degrees (about 1.1 km) apart
+        let line = |n: usize| -> Vec<f64> { (0..n).flat_map(|i| [i as f64 * 0.01, 0.0]).collect() };
+        assert_eq!(samples(&line(20)), [0, 9, 18, 19]);
+        assert_eq!(samples(&line(1)), [0]);
+        assert!(samples(&[]).is_empty());
+        // About 5500 km, so spaced further apart than SPACING_M
+        let long = samples(&line(5000));
+        assert!(long.len() <= MAX_SAMPLES);
+        assert_eq!(long.last(), Some(&4999));
+    }
+
+    #[tokio::test]
+    async fn looks_up_each_cell_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let api = CannedApi::default().with_reverse(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(collection(json!({
+                "type": "FeatureCollection",
+                "features": [{
+                    "type": "Feature",
+                    "geometry": { "type": "Point", "coordinates": [0.0, 0.0] },
+                    "properties": { "countrycode": "gh" },
+                }],
+            })))
+        });
+        let borders = Borders::new(
+            Arc::new(api),
+            CountryCache::new(COUNTRY_CACHE_TTL, COUNTRY_CACHE_CAPACITY),
+            Ledger::new(Default::default(), Default::default()),
+            ParseMode::Lenient,
+        );
+        // Two positions a few meters apart, both ends of a short route
+        let route = [0.0, 0.0, 0.00001, 0.0];
+        assert_eq!(borders.crossings(&route).await, Some(vec![]));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(borders.country(0.0, 0.0).await.as_deref(), Some("GH"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,263 @@
+//! Small in-memory cache for upstream results, so repeat questions don't cost quota.
+//!
+//! Entries are fresh for a fixed time after being stored. When full, expired entries go first,
+//! then the oldest. The same goes when the [memory::Budget] it's charged to runs over.
+use crate::memory::{self, Freed};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    stored: Instant,
+    /// What it's charged to the budget
+    weight: usize,
+}
+
+type Entries<K, V> = Mutex<HashMap<K, Entry<V>>>;
+
+/// Where a cache's entries are charged, and how much each
+struct Charged<V> {
+    charge: memory::Charge,
+    weigh: fn(&V) -> usize,
+}
+
+impl<V> Clone for Charged<V> {
+    fn clone(&self) -> Self {
+        Charged {
+            charge: self.charge.clone(),
+            weigh: self.weigh,
+        }
+    }
+}
+
+impl<V> std::fmt::Debug for Charged<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.charge.fmt(f)
+    }
+}
+
+/// Cheap to clone; clones share entries
+#[derive(Debug)]
+pub struct TtlCache<K, V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: Arc<Entries<K, V>>,
+    charged: Option<Charged<V>>,
+}
+
+impl<K, V> Clone for TtlCache<K, V> {
+    fn clone(&self) -> Self {
+        TtlCache {
+            ttl: self.ttl,
+            capacity: self.capacity,
+            entries: self.entries.clone(),
+            charged: self.charged.clone(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        TtlCache {
+            ttl,
+            capacity,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            charged: None,
+        }
+    }
+
+    /// Charges entries to `budget` as `store`, at what `weigh` says each value is worth on top of
+    /// its slot, and sheds them when asked
+    pub fn with_budget(
+        mut self,
+        budget: &memory::Budget,
+        store: &'static str,
+        weigh: fn(&V) -> usize,
+    ) -> Self
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        let entries = Arc::downgrade(&self.entries);
+        let ttl = self.ttl;
+        let charge = budget.join(store, move |bytes| shed(&entries, ttl, bytes));
+        self.charged = Some(Charged { charge, weigh });
+        self
+    }
+
+    /// What an entry for `value` is charged
+    fn weigh(&self, value: &V) -> usize {
+        self.charged.as_ref().map_or(0, |charged| {
+            std::mem::size_of::<(K, Entry<V>)>() + (charged.weigh)(value)
+        })
+    }
+
+    /// Takes `entries`' weight back off the budget
+    fn release<'a>(&self, entries: impl IntoIterator<Item = &'a Entry<V>>)
+    where
+        V: 'a,
+    {
+        if let Some(charged) = &self.charged {
+            charged
+                .charge
+                .sub(entries.into_iter().map(|e| e.weight).sum());
+        }
+    }
+
+    /// The value for `key`, if there is one and it's still fresh
+    pub fn get_fresh(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        entries
+            .get(key)
+            .filter(|e| e.stored.elapsed() < self.ttl)
+            .map(|e| e.value.clone())
+    }
+
+    /// The value for `key` however old, with its age. For when nothing fresher can be had; see
+    /// [crate::degraded]
+    pub fn get_any(&self, key: &K) -> Option<(V, Duration)> {
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        entries
+            .get(key)
+            .map(|e| (e.value.clone(), e.stored.elapsed()))
+    }
+
+    /// Entries held, fresh or not
+    pub fn len(&self) -> usize {
+        self.entries.lock().expect("cache lock poisoned").len()
+    }
+
+    /// How long entries stay fresh
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Forgets everything. Returns how many entries there were
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let cleared = entries.len();
+        self.release(entries.values());
+        entries.clear();
+        cleared
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let weight = self.weigh(&value);
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let (fresh, expired) = std::mem::take(&mut *entries)
+                .into_iter()
+                .partition(|(_, e)| e.stored.elapsed() < self.ttl);
+            *entries = fresh;
+            let expired: HashMap<K, Entry<V>> = expired;
+            self.release(expired.values());
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.stored)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest.and_then(|k| entries.remove(&k)) {
+                    self.release([&oldest]);
+                }
+            }
+        }
+        let replaced = entries.insert(
+            key,
+            Entry {
+                value,
+                stored: Instant::now(),
+                weight,
+            },
+        );
+        drop(entries);
+        if let Some(charged) = &self.charged {
+            self.release(&replaced);
+            charged.charge.add(weight);
+            charged.charge.enforce();
+        }
+    }
+}
+
+/// Drops expired entries, then the oldest, until `bytes` are freed or there's nothing left
+fn shed<K: Hash + Eq + Clone, V>(
+    entries: &Weak<Entries<K, V>>,
+    ttl: Duration,
+    bytes: usize,
+) -> Freed {
+    let Some(entries) = entries.upgrade() else {
+        return Freed::default();
+    };
+    let mut entries = entries.lock().expect("cache lock poisoned");
+    let mut by_age: Vec<(K, Instant)> =
+        entries.iter().map(|(k, e)| (k.clone(), e.stored)).collect();
+    by_age.sort_by_key(|(_, stored)| *stored);
+    let mut freed = Freed::default();
+    // Oldest first takes care of expired first, since every entry has the same TTL
+    for (key, stored) in by_age {
+        if freed.bytes >= bytes && stored.elapsed() < ttl {
+            break;
+        }
+        if let Some(entry) = entries.remove(&key) {
+            freed.bytes += entry.weight;
+            freed.entries += 1;
+        }
+    }
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_expire() {
+        let cache = TtlCache::new(Duration::from_secs(60), 10);
+        cache.insert(1, "a");
+        assert_eq!(cache.get_fresh(&1), Some("a"));
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(cache.get_fresh(&1), None);
+        assert_eq!(cache.get_any(&1), Some(("a", Duration::from_secs(61))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oldest_evicted_when_full() {
+        let cache = TtlCache::new(Duration::from_secs(60), 2);
+        cache.insert(1, "a");
+        tokio::time::advance(Duration::from_secs(1)).await;
+        cache.insert(2, "b");
+        cache.insert(3, "c");
+        assert_eq!(cache.get_fresh(&1), None);
+        assert_eq!(cache.get_fresh(&2), Some("b"));
+        assert_eq!(cache.get_fresh(&3), Some("c"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sheds_oldest_over_budget() {
+        let slot = std::mem::size_of::<(u32, Entry<String>)>();
+        // Room for 3 entries of 100 bytes, so the 4th sheds down to 3/4 of that
+        let budget = memory::Budget::new(3 * (slot + 100));
+        let cache = TtlCache::new(Duration::from_secs(60), 10).with_budget(
+            &budget,
+            "test",
+            |v: &String| v.len(),
+        );
+        for key in 0..4 {
+            cache.insert(key, "x".repeat(100));
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get_fresh(&1), None);
+        assert!(cache.get_fresh(&3).is_some());
+        assert_eq!(budget.used(), 2 * (slot + 100));
+
+        cache.clear();
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,76 @@
+//! `Cache-Control` on requests, so the app can get a fresh route when the user explicitly retries.
+//!
+//! `no-cache` skips our caches and asks upstream; `max-age=<secs>` takes cached answers only up to
+//! that old. The answer is cached as usual either way. Without either, anything fresh or
+//! revalidating (see [crate::revalidate]) goes. While upstream is out (see [crate::degraded]) the
+//! cache is all there is, so these are ignored.
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+};
+use std::convert::Infallible;
+use tokio::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheDirectives {
+    no_cache: bool,
+    max_age: Option<Duration>,
+}
+
+impl CacheDirectives {
+    /// From a `Cache-Control` value. Directives we don't know or can't read are ignored
+    pub fn parse(value: &str) -> Self {
+        let mut directives = CacheDirectives::default();
+        for directive in value.split(',').map(str::trim) {
+            let (name, arg) = directive.split_once('=').unwrap_or((directive, ""));
+            if name.eq_ignore_ascii_case("no-cache") {
+                directives.no_cache = true;
+            } else if name.eq_ignore_ascii_case("max-age") {
+                if let Ok(secs) = arg.trim_matches('"').parse() {
+                    let max_age = Duration::from_secs(secs);
+                    // The strictest one, if there are several
+                    directives.max_age =
+                        Some(directives.max_age.map_or(max_age, |m| m.min(max_age)));
+                }
+            }
+        }
+        directives
+    }
+
+    /// Whether a cached answer `age` old will do
+    pub fn accepts(&self, age: Duration) -> bool {
+        !self.no_cache && self.max_age.is_none_or(|max_age| age <= max_age)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for CacheDirectives {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let value = parts
+            .headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        Ok(CacheDirectives::parse(&value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directives() {
+        let none = CacheDirectives::parse("");
+        assert!(none.accepts(Duration::from_secs(86400)));
+        assert!(!CacheDirectives::parse("No-Cache").accepts(Duration::ZERO));
+
+        let fussy = CacheDirectives::parse("max-age=\"60\", no-transform, max-age=30");
+        assert!(fussy.accepts(Duration::from_secs(30)));
+        assert!(!fussy.accepts(Duration::from_secs(31)));
+        assert_eq!(CacheDirectives::parse("max-age=soon"), none);
+    }
+}
@@ -0,0 +1,76 @@
+//! One way of writing requests that mean the same thing, for cache keys. Clients send `-0.0`,
+//! float noise like `52.520000000001`, headings of 360, and searches in whatever case the user
+//! typed; none of that should miss the cache.
+//!
+//! Only keys go by these. Upstream still gets the request as sent.
+use crate::RouteRequest;
+
+/// Decimal places coordinates are rounded to. 7 is about a centimeter, well under GPS error, and
+/// leaves anything a client typed in as it was
+const COORD_DECIMALS: i32 = 7;
+
+/// `c` rounded to [COORD_DECIMALS], with no negative zero
+pub fn coord(c: f64) -> f64 {
+    let scale = 10f64.powi(COORD_DECIMALS);
+    let rounded = (c * scale).round() / scale;
+    // -0.0 == 0.0, but their bits differ
+    if rounded == 0.0 {
+        0.0
+    } else {
+        rounded
+    }
+}
+
+/// Degrees clockwise from north, within `[0, 360)`
+pub fn heading(h: f64) -> f64 {
+    coord(h.rem_euclid(360.0)) % 360.0
+}
+
+pub fn route(params: &RouteRequest) -> RouteRequest {
+    RouteRequest {
+        src_lat: coord(params.src_lat),
+        src_lon: coord(params.src_lon),
+        dst_lat: coord(params.dst_lat),
+        dst_lon: coord(params.dst_lon),
+        heading: params.heading.map(heading),
+        annotations: params.annotations,
+        borders: params.borders,
+        elevation: params.elevation,
+    }
+}
+
+/// Lowercase, with runs of whitespace as single spaces and none at either end. Photon doesn't
+/// mind case either
+pub fn query(q: &str) -> String {
+    q.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_meaning_same_form() {
+        let sent = RouteRequest {
+            src_lat: 44.567648,
+            src_lon: -0.0,
+            dst_lat: 44.5687630000001,
+            dst_lon: -123.277635,
+            heading: Some(360.0),
+            annotations: false,
+            borders: false,
+            elevation: false,
+        };
+        let canonical = route(&sent);
+        assert_eq!(canonical.src_lat.to_bits(), 44.567648f64.to_bits());
+        assert_eq!(canonical.src_lon.to_bits(), 0f64.to_bits());
+        assert_eq!(canonical.dst_lat, 44.568763);
+        assert_eq!(canonical.heading, Some(0.0));
+        assert_eq!(heading(-90.0), 270.0);
+
+        assert_eq!(query("  Cafe\tCentral  berlin "), "cafe central berlin");
+    }
+}
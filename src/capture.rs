@@ -0,0 +1,243 @@
+//! Opt-in capture of requests whose upstream answers couldn't be parsed or used, so the failure
+//! can be reproduced offline instead of guessed at from a log snippet.
+//!
+//! [capture_failures] keeps a note of every upstream exchange made while handling a request. If
+//! the request ends in an [ExternalAPIJson] or [ExternalAPIContent] error, the client's request and
+//! those exchanges are written to one JSON file. Only the newest few files are kept.
+//!
+//! Captures are anonymized in that client headers, addresses and request ids are left out, and
+//! API keys are scrubbed. The coordinates and search terms themselves are kept, since the
+//! failure can't be reproduced without them.
+//!
+//! [ExternalAPIJson]: crate::error::RouteError::ExternalAPIJson
+//! [ExternalAPIContent]: crate::error::RouteError::ExternalAPIContent
+use crate::report::ErrorSummary;
+use crate::requester::Provider;
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Client bodies bigger than this aren't ours anyway
+const MAX_BODY: usize = 64 * 1024;
+
+tokio::task_local! {
+    static CURRENT: Arc<Mutex<Vec<UpstreamExchange>>>;
+}
+
+/// One upstream call and what came back
+#[derive(Serialize, Debug, Clone)]
+pub struct UpstreamExchange {
+    pub provider: Provider,
+    pub endpoint: String,
+    pub url: String,
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ClientRequest {
+    method: String,
+    uri: String,
+    body: String,
+}
+
+/// What goes in a capture file
+#[derive(Serialize, Debug)]
+struct Capture {
+    captured_at_ms: u64,
+    route: Option<String>,
+    causes: Vec<String>,
+    request: ClientRequest,
+    upstream: Vec<UpstreamExchange>,
+}
+
+/// Where captures go and how many to keep
+#[derive(Debug, Clone)]
+pub struct Captures {
+    dir: PathBuf,
+    keep: usize,
+}
+
+impl Captures {
+    pub fn new(dir: PathBuf, keep: usize) -> Self {
+        Captures { dir, keep }
+    }
+
+    /// Writes `capture`, then deletes the oldest files past [Captures::keep]. Failures are logged
+    /// and otherwise ignored; capturing should never be why a request fails.
+    async fn save(&self, capture: &Capture) {
+        let text = match serde_json::to_string_pretty(capture) {
+            Ok(text) => text,
+            Err(e) => return tracing::warn!("couldn't serialize failure capture: {e}"),
+        };
+        // Zero-padded so the names sort oldest first
+        let path = self
+            .dir
+            .join(format!("{:016}.json", capture.captured_at_ms));
+        if let Err(e) = tokio::fs::create_dir_all(&self.dir).await {
+            return tracing::warn!("couldn't create capture directory {:?}: {e}", self.dir);
+        }
+        match tokio::fs::write(&path, text + "\n").await {
+            Ok(_) => tracing::info!("captured failed request to {}", path.display()),
+            Err(e) => return tracing::warn!("couldn't write failure capture {path:?}: {e}"),
+        }
+
+        let mut files = vec![];
+        if let Ok(mut entries) = tokio::fs::read_dir(&self.dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.ends_with(".json") {
+                    files.push(name);
+                }
+            }
+        }
+        files.sort();
+        for old in files.iter().take(files.len().saturating_sub(self.keep)) {
+            if let Err(e) = tokio::fs::remove_file(self.dir.join(old)).await {
+                tracing::warn!("couldn't prune failure capture {old}: {e}");
+            }
+        }
+    }
+}
+
+/// Whether the current request is being captured, so upstream bodies need to be kept whole
+pub fn capturing() -> bool {
+    CURRENT.try_with(|_| ()).is_ok()
+}
+
+/// Notes an upstream exchange against the current request, if it's being captured. `secrets`
+/// are scrubbed from the URL and body first
+pub fn note_upstream(
+    provider: Provider,
+    endpoint: &str,
+    url: &reqwest::Url,
+    status: StatusCode,
+    body: &[u8],
+    secrets: &[&str],
+) {
+    let _ = CURRENT.try_with(|exchanges| {
+        exchanges
+            .lock()
+            .expect("capture lock poisoned")
+            .push(UpstreamExchange {
+                provider,
+                endpoint: endpoint.to_owned(),
+                url: crate::upstream_error::redact(url.as_str().as_bytes(), secrets),
+                status: status.as_u16(),
+                body: crate::upstream_error::redact(body, secrets),
+            })
+    });
+}
+
+/// Middleware for the public API routes. See the [module docs](self)
+pub async fn capture_failures(
+    State(captures): State<Captures>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned());
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_BODY).await {
+        Ok(body) => body,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    let client_request = ClientRequest {
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    };
+
+    let exchanges = Arc::new(Mutex::new(vec![]));
+    let response = CURRENT
+        .scope(
+            exchanges.clone(),
+            next.run(Request::from_parts(parts, Body::from(body))),
+        )
+        .await;
+
+    let Some(summary) = response
+        .extensions()
+        .get::<ErrorSummary>()
+        .filter(|s| s.unusable_response)
+    else {
+        return response;
+    };
+    let upstream = std::mem::take(&mut *exchanges.lock().expect("capture lock poisoned"));
+    let capture = Capture {
+        captured_at_ms: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default(),
+        route,
+        causes: summary.causes.clone(),
+        request: client_request,
+        upstream,
+    };
+    captures.save(&capture).await;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RouteError;
+    use axum::{routing::post, Router};
+    use tower::ServiceExt;
+
+    async fn unusable() -> crate::Result<()> {
+        note_upstream(
+            Provider::Photon,
+            "photon_geocode",
+            &"http://photon.test/api/?q=Alexanderplatz&key=hunter2"
+                .parse()
+                .unwrap(),
+            StatusCode::OK,
+            br#"{"features":"hunter2"}"#,
+            &["hunter2"],
+        );
+        Err(RouteError::ExternalAPIContent("no features".into()))
+    }
+
+    async fn fine() -> &'static str {
+        "fine"
+    }
+
+    #[tokio::test]
+    async fn keeps_newest_failures() {
+        let dir = std::env::temp_dir().join(format!("flipmap-capture-{}", std::process::id()));
+        let app = Router::new()
+            .route("/unusable", post(unusable))
+            .route("/fine", post(fine))
+            .layer(axum::middleware::from_fn_with_state(
+                Captures::new(dir.clone(), 2),
+                capture_failures,
+            ));
+        for path in ["/unusable", "/fine", "/unusable", "/unusable"] {
+            let request = Request::post(path).body(Body::from("{}")).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+            // Captures are named by the millisecond
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 2);
+        let written = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(!written.contains("hunter2"));
+        let capture: serde_json::Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(capture["route"], "/unusable");
+        assert_eq!(capture["request"]["body"], "{}");
+        assert_eq!(capture["upstream"][0]["endpoint"], "photon_geocode");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
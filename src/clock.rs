@@ -0,0 +1,43 @@
+//! Where rate limiting and backoff get the time from, so tests can move it by hand instead of
+//! sleeping or pausing Tokio.
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::time::Instant;
+
+pub trait Clock: Debug + Send + Sync {
+    /// Monotonic time, for deadlines
+    fn now(&self) -> Instant;
+    /// Wall-clock time, for comparing against HTTP dates
+    fn system_now(&self) -> SystemTime;
+
+    /// The wall-clock time at monotonic `at`, for anything that leaves the process: headers,
+    /// logs, files. Past instants come out as now
+    fn system_at(&self, at: Instant) -> SystemTime {
+        self.system_now() + at.saturating_duration_since(self.now())
+    }
+}
+
+/// The real time. Follows Tokio's paused clock in tests that use one
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Shorthand for the default clock
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// [Clock::system_at] on the real time
+pub fn system_at(at: Instant) -> SystemTime {
+    SystemClock.system_at(at)
+}
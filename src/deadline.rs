@@ -0,0 +1,194 @@
+//! Per-request time budgets, so our timeouts line up with the app's.
+//!
+//! The app says how long it's willing to wait in `X-Request-Deadline-Ms`. [enforce] keeps the
+//! budget in a task-local for the rest of the request, which [ExternalRequester] checks to shorten
+//! its upstream timeouts. If the budget runs out anyway the answer is a 504 listing the upstream
+//! calls made so far, so it's clear where the time went.
+//!
+//! [ExternalRequester]: crate::requester::ExternalRequester
+use crate::error::RouteError;
+use axum::{
+    extract::Request,
+    http::HeaderName,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+pub const REQUEST_DEADLINE: HeaderName = HeaderName::from_static("x-request-deadline-ms");
+/// Nobody gets to hold a request open longer than this, whatever they ask for
+const MAX_BUDGET: Duration = Duration::from_secs(60);
+
+tokio::task_local! {
+    static CURRENT: Arc<Budget>;
+}
+
+/// One upstream call made while handling a request
+#[derive(Serialize, Debug, Clone)]
+pub struct CallRecord {
+    pub endpoint: &'static str,
+    pub elapsed_ms: u64,
+    /// `ok`, or a short description of what went wrong
+    pub outcome: String,
+}
+
+#[derive(Debug)]
+struct Budget {
+    start: Instant,
+    deadline: Instant,
+    calls: Mutex<Vec<CallRecord>>,
+}
+
+impl Budget {
+    fn report(&self) -> DeadlineReport {
+        DeadlineReport {
+            budget_ms: (self.deadline - self.start).as_millis() as u64,
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            calls: self.calls.lock().expect("budget lock poisoned").clone(),
+        }
+    }
+}
+
+/// Sent back with a 504 when a budget runs out
+#[derive(Serialize, Debug, Clone)]
+pub struct DeadlineReport {
+    pub budget_ms: u64,
+    pub elapsed_ms: u64,
+    pub calls: Vec<CallRecord>,
+}
+
+/// Time left in the current request's budget. `None` if it didn't set one (or outside requests)
+pub fn remaining() -> Option<Duration> {
+    CURRENT
+        .try_with(|b| b.deadline.saturating_duration_since(Instant::now()))
+        .ok()
+}
+
+/// The 504 error for the current request. Only meaningful when [remaining] is `Some`
+pub fn exceeded() -> RouteError {
+    let report = CURRENT.try_with(|b| b.report()).unwrap_or(DeadlineReport {
+        budget_ms: 0,
+        elapsed_ms: 0,
+        calls: vec![],
+    });
+    tracing::warn!(
+        budget_ms = report.budget_ms,
+        calls = report.calls.len(),
+        "request deadline exceeded"
+    );
+    RouteError::DeadlineExceeded(Box::new(report))
+}
+
+/// Notes an upstream call against the current request, if it has a budget
+pub fn record_call(endpoint: &'static str, elapsed: Duration, outcome: String) {
+    let _ = CURRENT.try_with(|b| {
+        b.calls
+            .lock()
+            .expect("budget lock poisoned")
+            .push(CallRecord {
+                endpoint,
+                elapsed_ms: elapsed.as_millis() as u64,
+                outcome,
+            })
+    });
+}
+
+/// Runs `fut` as if its request came with a `budget`
+#[cfg(test)]
+pub async fn with_budget<F: std::future::Future>(budget: Duration, fut: F) -> F::Output {
+    let start = Instant::now();
+    let budget = Arc::new(Budget {
+        start,
+        deadline: start + budget,
+        calls: Mutex::new(vec![]),
+    });
+    CURRENT.scope(budget, fut).await
+}
+
+/// Middleware reading [REQUEST_DEADLINE]. Requests without one (or with nonsense in it) run
+/// under the usual timeouts.
+pub async fn enforce(request: Request, next: Next) -> Response {
+    let budget_ms = request
+        .headers()
+        .get(REQUEST_DEADLINE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let Some(budget_ms) = budget_ms else {
+        return next.run(request).await;
+    };
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_millis(budget_ms).min(MAX_BUDGET);
+    let budget = Arc::new(Budget {
+        start,
+        deadline,
+        calls: Mutex::new(vec![]),
+    });
+    let run = CURRENT.scope(budget.clone(), next.run(request));
+    match tokio::time::timeout_at(deadline, run).await {
+        Ok(response) => response,
+        Err(_) => CURRENT.sync_scope(budget, exceeded).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn slow() -> &'static str {
+        record_call("ors_directions", Duration::from_millis(5), "ok".to_owned());
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        "done"
+    }
+
+    async fn has_budget() -> String {
+        remaining().is_some().to_string()
+    }
+
+    async fn call(path: &str, deadline: Option<&str>) -> Response {
+        let app = Router::new()
+            .route("/slow", get(slow))
+            .route("/has_budget", get(has_budget))
+            .layer(axum::middleware::from_fn(enforce));
+        let mut request = Request::builder().uri(path);
+        if let Some(deadline) = deadline {
+            request = request.header(REQUEST_DEADLINE, deadline);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body_of(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn blown_budget_is_504_with_calls() {
+        let response = call("/slow", Some("1000")).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body: serde_json::Value = serde_json::from_slice(&body_of(response).await).unwrap();
+        assert_eq!(body["budget_ms"], 1000);
+        assert_eq!(body["calls"][0]["endpoint"], "ors_directions");
+    }
+
+    #[tokio::test]
+    async fn budget_only_with_header() {
+        assert_eq!(
+            body_of(call("/has_budget", Some("500")).await).await,
+            b"true"
+        );
+        assert_eq!(body_of(call("/has_budget", None).await).await, b"false");
+        assert_eq!(
+            body_of(call("/has_budget", Some("soon")).await).await,
+            b"false"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Cached-results-only mode, so a brief upstream outage doesn't take the app down with it.
+//!
+//! It's on whenever every provider is backing off (see [crate::retry_after]), or while switched on
+//! at `/admin/degraded`. Then whatever the fresh cache doesn't have is answered from whatever's
+//! cached, however old, with a 200 marked [STALE_RESULT] and an `Age`. What isn't cached at all is
+//! a 503 saying so, rather than a doomed upstream call.
+use crate::error::RouteError;
+use crate::requester::{ExternalApi, Provider};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    Json,
+};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+/// `true` on answers that came from the cache past their freshness, because upstream is out or
+/// while [crate::revalidate] refreshes them
+pub const STALE_RESULT: HeaderName = HeaderName::from_static("x-stale-result");
+/// Retry-After for misses while switched on by hand, since there's no backoff to go by
+const FORCED_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Cheap to clone; clones share the switch
+#[derive(Debug, Clone)]
+pub struct Degraded {
+    /// Asked whether everyone's backing off
+    client: Arc<dyn ExternalApi>,
+    forced: Arc<AtomicBool>,
+}
+
+impl Degraded {
+    pub fn new(client: Arc<dyn ExternalApi>) -> Self {
+        Degraded {
+            client,
+            forced: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// When the upstream might be back, if only cached results are being served
+    pub fn check(&self) -> Option<Duration> {
+        if self.forced.load(Ordering::Relaxed) {
+            return Some(FORCED_RETRY_AFTER);
+        }
+        self.automatic()
+    }
+
+    /// On because every provider is backing off, until the first stops
+    fn automatic(&self) -> Option<Duration> {
+        let limits = self.client.limits();
+        [Provider::OpenRouteService, Provider::Photon]
+            .iter()
+            .map(|p| limits.backing_off.get(p).copied())
+            .collect::<Option<Vec<u64>>>()?
+            .into_iter()
+            .min()
+            .map(Duration::from_secs)
+    }
+
+    /// For a cache miss while [Degraded::check] says it's on
+    pub fn miss(retry_after: Duration) -> RouteError {
+        RouteError::Degraded(SystemTime::now() + retry_after)
+    }
+
+    /// Switches it on or off by hand. Backoffs still switch it on by themselves
+    pub fn set(&self, on: bool) {
+        tracing::warn!(on, "cached-results-only mode switched by hand");
+        self.forced.store(on, Ordering::Relaxed);
+    }
+
+    fn status(&self) -> DegradedStatus {
+        let forced = self.forced.load(Ordering::Relaxed);
+        let automatic = self.automatic().is_some();
+        DegradedStatus {
+            on: forced || automatic,
+            forced,
+            automatic,
+        }
+    }
+}
+
+/// For an answer served stale, `age` after it was fetched
+pub fn stale_headers(age: Duration) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(STALE_RESULT, HeaderValue::from_static("true"));
+    headers.insert(header::AGE, HeaderValue::from(age.as_secs()));
+    headers
+}
+
+#[derive(Serialize, Debug)]
+pub struct DegradedStatus {
+    pub on: bool,
+    /// Switched on at `/admin/degraded`
+    pub forced: bool,
+    /// Every provider is backing off
+    pub automatic: bool,
+}
+
+/// `GET /admin/degraded`
+pub async fn get_degraded(State(degraded): State<Degraded>) -> Json<DegradedStatus> {
+    Json(degraded.status())
+}
+
+/// `PUT /admin/degraded`
+pub async fn put_degraded(State(degraded): State<Degraded>) -> Json<DegradedStatus> {
+    degraded.set(true);
+    Json(degraded.status())
+}
+
+/// `DELETE /admin/degraded`. Backoffs still switch it on by themselves
+pub async fn delete_degraded(State(degraded): State<Degraded>) -> Json<DegradedStatus> {
+    degraded.set(false);
+    Json(degraded.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requester::Limits;
+    use crate::test_utils::CannedApi;
+
+    /// Reports backoffs for `providers` and nothing else
+    #[derive(Debug)]
+    struct BackingOff(Vec<(Provider, u64)>);
+
+    #[async_trait::async_trait]
+    impl ExternalApi for BackingOff {
+        async fn ors_send(
+            &self,
+            req: &crate::requester::OpenRouteRequest,
+        ) -> crate::Result<geojson::FeatureCollection> {
+            CannedApi::default().ors_send(req).await
+        }
+
+        async fn photon_reverse_send(
+            &self,
+            req: &crate::requester::PhotonRevGeocodeRequest,
+        ) -> crate::Result<geojson::FeatureCollection> {
+            CannedApi::default().photon_reverse_send(req).await
+        }
+
+        async fn photon_send(
+            &self,
+            req: &crate::requester::PhotonGeocodeRequest,
+        ) -> crate::Result<geojson::FeatureCollection> {
+            CannedApi::default().photon_send(req).await
+        }
+
+        fn limits(&self) -> Limits {
+            Limits {
+                backing_off: self.0.iter().copied().collect(),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[test]
+    fn on_when_everyone_backs_off_or_forced() {
+        let ors_only = Degraded::new(Arc::new(BackingOff(vec![(Provider::OpenRouteService, 30)])));
+        assert_eq!(ors_only.check(), None);
+        ors_only.set(true);
+        assert_eq!(ors_only.check(), Some(FORCED_RETRY_AFTER));
+        assert!(ors_only.status().forced && !ors_only.status().automatic);
+
+        let both = Degraded::new(Arc::new(BackingOff(vec![
+            (Provider::OpenRouteService, 30),
+            (Provider::Photon, 10),
+        ])));
+        assert_eq!(both.check(), Some(Duration::from_secs(10)));
+        both.set(false);
+        assert!(both.status().on);
+    }
+}
@@ -0,0 +1,669 @@
+//! The whole [Router](axum::Router) from [assemble], served on a real port and talked to over
+//! HTTP, with the providers played by [MockUpstream]. For what the app actually sees: status
+//! codes, headers, and bodies after every layer has had its say.
+use crate::geojson_ext::ParseMode;
+use crate::maintenance::Maintenance;
+use crate::mock_upstream::{MockUpstream, Reply};
+use crate::requester::{
+    ExternalRequesterBuilder, ORS_DIRECTIONS_PATH, PHOTON_PATH, PHOTON_REVERSE_PATH,
+};
+use crate::test_utils::MockClock;
+use crate::trips::TripStore;
+use crate::vcr::fixture;
+use crate::{assemble, AppParts};
+use reqwest::{header, StatusCode};
+use secrecy::SecretString;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+struct Harness {
+    base: String,
+    http: reqwest::Client,
+    upstream: MockUpstream,
+    maintenance: Maintenance,
+    server: JoinHandle<()>,
+}
+
+impl Harness {
+    async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// With optional parts filled in by `configure`
+    async fn start_with(configure: impl FnOnce(&mut AppParts)) -> Self {
+        let upstream = MockUpstream::start().await;
+        let client = ExternalRequesterBuilder::new(
+            upstream.base(),
+            upstream.base(),
+            SecretString::from("foo"),
+        )
+        .build();
+        let mut parts = AppParts::new(Arc::new(client));
+        configure(&mut parts);
+        let maintenance = parts.maintenance.clone();
+        let app = assemble(parts).public;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        Harness {
+            base,
+            http: reqwest::Client::new(),
+            upstream,
+            maintenance,
+            server,
+        }
+    }
+
+    async fn post(&self, path: &str, body: Value) -> reqwest::Response {
+        self.http
+            .post(format!("{}{path}", self.base))
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+fn route_body() -> Value {
+    json!({ "src_lat": 44.56720205, "src_lon": -123.27963174780633,
+            "dst_lat": 44.5687606, "dst_lon": -123.27788489405276 })
+}
+
+fn retry_after(res: &reqwest::Response) -> u64 {
+    res.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn route_round_trip() {
+    let h = Harness::start().await;
+    h.upstream.on(
+        ORS_DIRECTIONS_PATH,
+        [Reply::geojson(fixture("ors_directions"))],
+    );
+
+    let res = h.post("/route", route_body()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().contains_key("x-request-id"));
+    let hash = res.headers()["x-route-hash"].clone();
+    let body: Value = res.json().await.unwrap();
+    let route = body["route"].as_array().unwrap();
+    assert_eq!(route.len() % 2, 0);
+    assert!(route.iter().all(Value::is_f64));
+    // Ours, then what ORS sent
+    assert_eq!(
+        body["attribution"],
+        json!([
+            crate::attribution::DEFAULT_ORS,
+            "openrouteservice.org | OpenStreetMap contributors"
+        ])
+    );
+
+    // Cached now, so the app's background refresh is free
+    let res = h
+        .http
+        .post(format!("{}/route", h.base))
+        .header("if-route-unchanged", hash)
+        .json(&route_body())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(h.upstream.hits(ORS_DIRECTIONS_PATH), 1);
+}
+
+#[tokio::test]
+async fn compare_modes() {
+    let h = Harness::start().await;
+    h.upstream.on(
+        ORS_DIRECTIONS_PATH,
+        [Reply::geojson(fixture("ors_directions"))],
+    );
+    h.upstream.on(
+        "/v2/directions/foot-walking/geojson",
+        [Reply::geojson(fixture("ors_directions"))],
+    );
+    // Cycling's not set up, so that one fails
+
+    let mut body = route_body();
+    body["profiles"] = json!(["walking", "cycling", "walking"]);
+    let res = h.post("/route/compare", body).await;
+    assert_eq!(res.status(), StatusCode::MULTI_STATUS);
+    let body: Value = res.json().await.unwrap();
+    let modes = body["modes"].as_array().unwrap();
+    assert_eq!(modes.len(), 2);
+    assert_eq!(modes[0]["profile"], "walking");
+    assert!(modes[0]["duration_s"].as_f64().unwrap() > 0.0);
+    assert!(modes[0].get("error").is_none());
+    assert_eq!(modes[1]["profile"], "cycling");
+    assert!(modes[1]["distance_m"].is_null());
+    assert_eq!(modes[1]["error"]["code"], "upstream_request");
+    assert_eq!(h.upstream.hits("/v2/directions/foot-walking/geojson"), 1);
+
+    // All three by default
+    let res = h.post("/route/compare", route_body()).await;
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["modes"][0]["profile"], "driving");
+    assert_eq!(h.upstream.hits(ORS_DIRECTIONS_PATH), 1);
+}
+
+#[tokio::test]
+async fn trip_round_trip() {
+    let h = Harness::start_with(|parts| {
+        parts.trips = Some(TripStore::in_memory(
+            Duration::from_secs(3600),
+            1 << 20,
+            MockClock::new(),
+        ))
+    })
+    .await;
+    h.upstream.on(
+        ORS_DIRECTIONS_PATH,
+        [Reply::geojson(fixture("ors_directions"))],
+    );
+
+    let res = h.post("/trips", route_body()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let saved: Value = res.json().await.unwrap();
+    assert_eq!(saved["expires_in_secs"], 3600);
+    let id = saved["id"].as_str().unwrap();
+
+    let res = h
+        .http
+        .get(format!("{}/trips/{id}", h.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let got: Value = res.json().await.unwrap();
+    assert_eq!(got["route"], saved["route"]);
+    assert_eq!(got["dst_lat"], route_body()["dst_lat"]);
+
+    let res = h
+        .http
+        .get(format!("{}/trips/nope", h.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(h.upstream.hits(ORS_DIRECTIONS_PATH), 1);
+}
+
+#[tokio::test]
+async fn off_route_by_trip_or_route() {
+    let h = Harness::start_with(|parts| {
+        parts.trips = Some(TripStore::in_memory(
+            Duration::from_secs(3600),
+            1 << 20,
+            MockClock::new(),
+        ))
+    })
+    .await;
+    h.upstream.on(
+        ORS_DIRECTIONS_PATH,
+        [Reply::geojson(fixture("ors_directions"))],
+    );
+    let saved: Value = h.post("/trips", route_body()).await.json().await.unwrap();
+    let route = saved["route"].clone();
+    let (lon, lat) = (route[0].as_f64().unwrap(), route[1].as_f64().unwrap());
+
+    let res = h
+        .post(
+            "/off_route",
+            json!({ "lat": lat, "lon": lon, "trip_id": saved["id"], "max_off_m": 20 }),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["off_route"], false);
+    assert_eq!(body["segment"], 0);
+
+    // About 110 m south, away from where it heads
+    let body: Value = h
+        .post(
+            "/off_route",
+            json!({ "lat": lat - 0.001, "lon": lon, "route": route, "max_off_m": 20 }),
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["off_route"], true);
+
+    let res = h
+        .post(
+            "/off_route",
+            json!({ "lat": lat, "lon": lon, "route": route, "trip_id": "x", "max_off_m": 20 }),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(h.upstream.hits(ORS_DIRECTIONS_PATH), 1);
+}
+
+#[tokio::test]
+async fn get_locations_round_trip() {
+    let h = Harness::start().await;
+    h.upstream
+        .on(PHOTON_PATH, [Reply::geojson(fixture("photon_geocode"))]);
+
+    let res = h
+        .post(
+            "/get_locations",
+            json!({ "lat": 44.56, "lon": -123.27, "query": "downward", "amount": 5 }),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    let first = &body["results"][0];
+    assert_eq!(first["name"], "Downward Dog");
+    assert!(first["lat"].is_f64() && first["lon"].is_f64());
+}
+
+#[tokio::test]
+async fn parse_mode_per_app() {
+    // Photon always sends OSM ids. Strict won't do without them, lenient fills in defaults
+    let bare = json!({ "type": "FeatureCollection", "features": [{ "type": "Feature",
+        "properties": { "name": "Downward Dog" },
+        "geometry": { "type": "Point", "coordinates": [-123.2778, 44.5687] } }] });
+    let strict = Harness::start_with(|parts| parts.parse_mode = ParseMode::Strict).await;
+    let lenient = Harness::start().await;
+    for (h, status) in [
+        (&strict, StatusCode::INTERNAL_SERVER_ERROR),
+        (&lenient, StatusCode::OK),
+    ] {
+        h.upstream
+            .on(PHOTON_PATH, [Reply::geojson(bare.to_string())]);
+        let res = h
+            .post(
+                "/get_locations",
+                json!({ "lat": 44.56, "lon": -123.27, "query": "downward", "amount": 5 }),
+            )
+            .await;
+        assert_eq!(res.status(), status);
+    }
+}
+
+#[tokio::test]
+async fn credits_per_app() {
+    let ours = Harness::start_with(|parts| parts.credits.ors = "Our own ORS".into()).await;
+    let default = Harness::start().await;
+    for (h, credit) in [
+        (&ours, "Our own ORS"),
+        (&default, crate::attribution::DEFAULT_ORS),
+    ] {
+        h.upstream.on(
+            ORS_DIRECTIONS_PATH,
+            [Reply::geojson(fixture("ors_directions"))],
+        );
+        let body: Value = h.post("/route", route_body()).await.json().await.unwrap();
+        assert_eq!(body["attribution"][0], credit);
+    }
+}
+
+#[tokio::test]
+async fn border_lookups_leave_photon_for_searches() {
+    let h = Harness::start().await;
+    // About 2200 km due north, so as many lookups as a route gets
+    let line: Vec<_> = (0..200)
+        .map(|i| json!([10.0, 40.0 + i as f64 * 0.1]))
+        .collect();
+    let long = json!({ "type": "FeatureCollection", "features": [{ "type": "Feature",
+        "properties": { "summary": { "distance": 2_200_000.0, "duration": 80_000.0 } },
+        "geometry": { "type": "LineString", "coordinates": line } }] });
+    h.upstream
+        .on(ORS_DIRECTIONS_PATH, [Reply::geojson(long.to_string())]);
+    h.upstream.on(
+        PHOTON_REVERSE_PATH,
+        [Reply::geojson(fixture("photon_reverse"))],
+    );
+    h.upstream
+        .on(PHOTON_PATH, [Reply::geojson(fixture("photon_geocode"))]);
+
+    let mut body = route_body();
+    body["borders"] = json!(true);
+    let res = h.post("/route", body).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.json::<Value>().await.unwrap()["borders"].is_array());
+    // Photon allows 40 a minute by default. Border lookups stop at half, give or take a few in
+    // flight, where there'd otherwise be one per sample
+    let lookups = h.upstream.hits(PHOTON_REVERSE_PATH);
+    assert!(lookups <= 20 + 4, "{lookups}");
+
+    for i in 0..15 {
+        let res = h
+            .post(
+                "/get_locations",
+                json!({ "lat": 44.56, "lon": -123.27, "query": format!("downward {i}"), "amount": 2 }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "search {i}");
+    }
+}
+
+#[tokio::test]
+async fn bad_input() {
+    let h = Harness::start().await;
+
+    let mut body = route_body();
+    body["src_lat"] = json!(91.0);
+    let res = h.post("/route", body).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert!(body["message"].as_str().unwrap().contains("src_lat"));
+
+    let res = h.post("/route", json!({ "src_lat": 1.0 })).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let res = h
+        .http
+        .post(format!("{}/route", h.base))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body("{")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = h
+        .http
+        .get(format!("{}/route", h.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(h.upstream.hits(ORS_DIRECTIONS_PATH), 0);
+}
+
+#[tokio::test]
+async fn oversized_body_same_with_abuse_guard() {
+    let body = json!({ "padding": "x".repeat(3 * 1024 * 1024) });
+    let mut answers = vec![];
+    for ban_abusers in [false, true] {
+        let h = Harness::start_with(|parts| parts.ban_abusers = ban_abusers).await;
+        let res = h.post("/route", body.clone()).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let answer: Value = res.json().await.unwrap();
+        assert_eq!(answer["code"], "request_json");
+        answers.push(answer);
+    }
+    assert_eq!(answers[0], answers[1]);
+}
+
+#[tokio::test]
+async fn upstream_limit_passes_retry_after_on() {
+    let h = Harness::start().await;
+    h.upstream.on(
+        ORS_DIRECTIONS_PATH,
+        [Reply::status(StatusCode::TOO_MANY_REQUESTS).with_header("retry-after", "120")],
+    );
+
+    let res = h.post("/route", route_body()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let secs = retry_after(&res);
+    assert!((100..=120).contains(&secs));
+    let body: Value = res.json().await.unwrap();
+    assert!(body["message"].is_string());
+    assert_eq!(body["retryable"], true);
+    let ms = body["retry_after_ms"].as_u64().unwrap();
+    assert!((secs * 1000..(secs + 1) * 1000).contains(&ms));
+}
+
+#[tokio::test]
+async fn unusable_upstream_is_vague() {
+    let h = Harness::start().await;
+    h.upstream.on(PHOTON_PATH, [Reply::malformed()]);
+
+    let res = h
+        .post(
+            "/get_locations",
+            json!({ "lat": 0.0, "lon": 0.0, "query": "x", "amount": 1 }),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({ "code": "upstream_json", "message": "problem deserializing external API response",
+                "retryable": false })
+    );
+}
+
+#[tokio::test]
+async fn maintenance_mode() {
+    let h = Harness::start().await;
+    h.maintenance
+        .enable(None, "back soon".to_owned(), Duration::from_secs(600));
+
+    let res = h.post("/route", route_body()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!((590..=600).contains(&retry_after(&res)));
+    assert_eq!(res.json::<Value>().await.unwrap()["message"], "back soon");
+
+    let res = h
+        .http
+        .get(format!("{}/health", h.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.json::<Value>().await.unwrap()["status"], "maintenance");
+    assert_eq!(h.upstream.hits(ORS_DIRECTIONS_PATH), 0);
+
+    // Still says what's deployed
+    let res = h
+        .http
+        .get(format!("{}/version", h.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let version = res.json::<Value>().await.unwrap();
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(version["providers"][1]["provider"], "Photon");
+}
+
+#[tokio::test]
+async fn optional_routes_are_off() {
+    let h = Harness::start().await;
+    for path in ["/metrics", "/admin/usage", "/admin/outbound"] {
+        let res = h
+            .http
+            .get(format!("{}{path}", h.base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn flagged_route_is_dark() {
+    let path = std::env::temp_dir().join(format!("flags-{}.json", uuid::Uuid::new_v4()));
+    std::fs::write(&path, r#"{"reroute": {"keys": ["partner"]}}"#).unwrap();
+    let flags = crate::flags::Flags::watch(path.clone()).unwrap();
+    let h = Harness::start_with(|parts| parts.flags = flags).await;
+    let body = json!({"lat": 0.0, "lon": 0.0005, "route": [0.0, 0.0, 0.001, 0.0]});
+
+    assert_eq!(
+        h.post("/reroute", body.clone()).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let res = h
+        .http
+        .post(format!("{}/reroute", h.base))
+        .header("x-api-key", "partner")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<Value>().await.unwrap()["on_route"], true);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn typed_client() {
+    use flipmap_api_types::client::{Client, Error};
+    let h = Harness::start_with(|parts| {
+        parts.trips = Some(TripStore::in_memory(
+            Duration::from_secs(3600),
+            1 << 20,
+            MockClock::new(),
+        ))
+    })
+    .await;
+    h.upstream.on(
+        ORS_DIRECTIONS_PATH,
+        [Reply::geojson(fixture("ors_directions"))],
+    );
+    let client = Client::new(h.base.parse().unwrap());
+    let request: crate::RouteRequest = serde_json::from_value(route_body()).unwrap();
+
+    let route = client.route(&request).await.unwrap();
+    assert!(!route.route.is_empty());
+    let trip = client.save_trip(&request).await.unwrap();
+    let got = client.get_trip(&trip.id).await.unwrap();
+    assert_eq!(got.route.len(), trip.route.len());
+
+    match client.get_trip("no/such trip").await.unwrap_err() {
+        Error::Status { status, body, .. } => {
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert!(body.is_some());
+        }
+        err => panic!("{err}"),
+    }
+    assert_eq!(h.upstream.hits(ORS_DIRECTIONS_PATH), 1);
+}
+
+/// `title`, then the response's status, content type and body. The body too, parsed
+async fn shown(title: &str, res: reqwest::Response) -> (String, Value) {
+    let status = res.status();
+    let content_type = res.headers()[header::CONTENT_TYPE].clone();
+    let body: Value = res.json().await.unwrap();
+    let shown = format!(
+        "== {title}\n{status}\ncontent-type: {}\n{}\n\n",
+        content_type.to_str().unwrap(),
+        serde_json::to_string_pretty(&body).unwrap()
+    );
+    (shown, body)
+}
+
+// Every public endpoint's answer, as the app gets it. A change here is a change to what the app
+// has to read: if it's meant, update the snapshot with UPDATE_EXPECT=1 and say so in the README
+#[tokio::test]
+async fn bodies_unchanged() {
+    let h = Harness::start_with(|parts| {
+        parts.trips = Some(TripStore::in_memory(
+            Duration::from_secs(3600),
+            1 << 20,
+            MockClock::new(),
+        ))
+    })
+    .await;
+    h.upstream.on(
+        ORS_DIRECTIONS_PATH,
+        [Reply::geojson(fixture("ors_directions"))],
+    );
+    h.upstream
+        .on(PHOTON_PATH, [Reply::geojson(fixture("photon_geocode"))]);
+    h.upstream.on(
+        PHOTON_REVERSE_PATH,
+        [Reply::geojson(fixture("photon_reverse"))],
+    );
+    let get = |path: String, accept: &'static str| {
+        h.http
+            .get(format!("{}{path}", h.base))
+            .header(header::ACCEPT, accept)
+            .send()
+    };
+    let v2 = crate::versioning::V2_MEDIA_TYPE;
+    let route = json!([0.0, 0.0, 0.001, 0.0]);
+    let mut compare = route_body();
+    compare["profiles"] = json!(["driving"]);
+    let place = json!({ "osm_type": "W", "osm_id": 384119068, "lat": 44.5687606,
+                        "lon": -123.27788489405276, "name": "Downward Dog" });
+    let posts = [
+        ("/route", route_body()),
+        ("/route/compare", compare),
+        (
+            "/reroute",
+            json!({ "lat": 0.0, "lon": 0.0005, "route": route }),
+        ),
+        (
+            "/off_route",
+            json!({ "lat": 0.001, "lon": 0.0005, "route": route, "max_off_m": 20 }),
+        ),
+        (
+            "/get_locations",
+            json!({ "lat": 44.56, "lon": -123.27, "query": "downward", "amount": 2 }),
+        ),
+        ("/reverse", json!({ "lat": 44.5687, "lon": -123.2778 })),
+        ("/favorites", json!({ "places": [place] })),
+    ];
+    let mut out = String::new();
+    for (path, body) in posts {
+        out += &shown(&format!("POST {path}"), h.post(path, body).await)
+            .await
+            .0;
+    }
+    let res = h
+        .http
+        .post(format!("{}/route", h.base))
+        .header(header::ACCEPT, v2)
+        .json(&route_body())
+        .send()
+        .await
+        .unwrap();
+    out += &shown("POST /route, v2", res).await.0;
+
+    let (shown_trip, trip) = shown("POST /trips", h.post("/trips", route_body()).await).await;
+    out += &shown_trip;
+    let id = trip["id"].as_str().unwrap();
+    for (title, accept) in [("GET /trips/{id}", "*/*"), ("GET /trips/{id}, v2", v2)] {
+        out += &shown(title, get(format!("/trips/{id}"), accept).await.unwrap())
+            .await
+            .0;
+    }
+    let res = h.post("/share", json!({ "kind": "trip", "id": id })).await;
+    let (shown_share, share) = shown("POST /share", res).await;
+    out += &shown_share;
+    let token = share["token"].as_str().unwrap();
+    out += &shown(
+        "GET /share/{token}",
+        get(format!("/share/{token}"), "*/*").await.unwrap(),
+    )
+    .await
+    .0;
+    for path in ["/about", "/errors"] {
+        out += &shown(
+            &format!("GET {path}"),
+            get(path.to_owned(), "*/*").await.unwrap(),
+        )
+        .await
+        .0;
+    }
+
+    // Made up afresh each run
+    let out = out.replace(id, "<trip id>").replace(token, "<share token>");
+    expect_test::expect_file!["../snapshots/responses.txt"].assert_eq(&out);
+}
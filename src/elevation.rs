@@ -0,0 +1,87 @@
+//! How much a route climbs and descends, worked out here so the app doesn't go through every
+//! position's height itself.
+//!
+//! With [RouteRequest::elevation] ORS sends a height with each position (see
+//! [crate::ors::OrsRoute::heights]). Ascent and descent add up every rise and fall between
+//! positions. Grades are only taken over at least [GRADE_OVER_M] of road: heights come from a
+//! coarse elevation model, and over a few meters they can be wildly steep.
+//!
+//! [RouteRequest::elevation]: flipmap_api_types::RouteRequest::elevation
+use crate::geo;
+use flipmap_api_types::ElevationSummary;
+
+/// Shortest distance a grade is taken over. Routes shorter than this have just the one
+pub const GRADE_OVER_M: f64 = 100.0;
+
+/// For `route`, a flattened LineString, with one of `heights` per position. `None` if they
+/// don't match up
+pub fn summary(route: &[f64], heights: &[f64]) -> Option<ElevationSummary> {
+    let positions: Vec<_> = route.chunks_exact(2).map(|p| (p[1], p[0])).collect();
+    if positions.is_empty() || positions.len() != heights.len() {
+        return None;
+    }
+    let mut along = vec![0.0];
+    let (mut ascent, mut descent) = (0.0, 0.0);
+    for (pair, rise) in positions.windows(2).zip(heights.windows(2)) {
+        along.push(along[along.len() - 1] + geo::haversine_m(pair[0], pair[1]));
+        let rise = rise[1] - rise[0];
+        if rise > 0.0 {
+            ascent += rise;
+        } else {
+            descent -= rise;
+        }
+    }
+    let (mut max_grade, mut min_grade) = (0.0_f64, 0.0_f64);
+    let mut grade = |from: usize, to: usize| {
+        let run = along[to] - along[from];
+        if run > 0.0 {
+            let grade = (heights[to] - heights[from]) / run * 100.0;
+            max_grade = max_grade.max(grade);
+            min_grade = min_grade.min(grade);
+        }
+    };
+    let last = positions.len() - 1;
+    if along[last] < GRADE_OVER_M {
+        grade(0, last);
+    } else {
+        let mut to = 0;
+        for from in 0..last {
+            while to < last && along[to] - along[from] < GRADE_OVER_M {
+                to += 1;
+            }
+            if along[to] - along[from] < GRADE_OVER_M {
+                break;
+            }
+            grade(from, to);
+        }
+    }
+    let tenths = |n: f64| (n * 10.0).round() / 10.0;
+    Some(ElevationSummary {
+        ascent_m: tenths(ascent),
+        descent_m: tenths(descent),
+        max_grade_percent: tenths(max_grade),
+        min_grade_percent: tenths(min_grade),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn climbs_and_grades() {
+        // Due north, 0.0005 degrees (about 55.6 m) apart
+        let line: Vec<f64> = (0..6).flat_map(|i| [0.0, i as f64 * 0.0005]).collect();
+        let summary = summary(&line, &[10.0, 20.0, 21.0, 15.0, 15.0, 30.0]).unwrap();
+        assert_eq!(summary.ascent_m, 26.0);
+        assert_eq!(summary.descent_m, 6.0);
+        // Over two steps at a time, never one. The last is 15 m in one, but only 13.5%
+        assert_eq!(summary.max_grade_percent, 13.5);
+        assert_eq!(summary.min_grade_percent, -5.4);
+
+        let short = super::summary(&line[..4], &[10.0, 12.0]).unwrap();
+        assert_eq!(short.max_grade_percent, 3.6);
+        assert_eq!(short.min_grade_percent, 0.0);
+        assert_eq!(super::summary(&line, &[10.0]), None);
+    }
+}
@@ -2,7 +2,8 @@
 //!
 //! On creation, it should trace all information that's safe and relevant
 //! It can also be serialized into a response that won't give too much information to the client
-use tokio::time::Instant;
+use crate::clock::{Clock, SystemClock};
+use std::time::{Duration, SystemTime};
 
 use axum::{
     extract::rejection::JsonRejection,
@@ -13,100 +14,304 @@ use axum::{
 use serde::Serialize;
 use validator::ValidationErrors;
 
-/// All expectable errors. Internal tuple values represent information that's safe to send in
-/// response. Most relevant information should be traced rather than placed inside.
-#[derive(Debug)]
+/// Whatever caused an error we don't send to the client. Kept so the cause chain can be inspected.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// All expectable errors. The request variants hold information that's safe to send in response.
+/// The external ones hold their cause as a [std::error::Error::source], which never goes out in a
+/// response. Most relevant information should still be traced when the error is created.
+#[derive(Debug, thiserror::Error)]
 pub enum RouteError {
     /// HTTP 422(always?): Produced by [axum::Json] when it doesn't like the request. Includes error.
-    RequestJson(Box<JsonRejection>),
+    #[error("rejected request JSON")]
+    RequestJson(#[source] Box<JsonRejection>),
     /// HTTP 422: Produced by [validator::Validate] when the response can be deserialized, but isn't O.K
     /// semantically (example: lat/lon is a float, but out of bounds)
-    RequestConstraint(Box<ValidationErrors>),
-    /// HTTP 500: Produced when [serde] (via [reqwest::Response::json]) fails to deserialize an external API response body
-    ExternalAPIJson,
+    #[error("request failed validation")]
+    RequestConstraint(#[source] Box<ValidationErrors>),
+    /// HTTP 500: Produced when [serde] fails to deserialize an external API response body
+    #[error("couldn't deserialize external API response")]
+    ExternalAPIJson(#[source] BoxError),
     /// HTTP 500: Produced when the external API is deserialized, but lacks content or has unexpected
     /// content that disrupts processing afterwards.
-    ExternalAPIContent,
-    /// HTTP 500: Produced when a Photon or ORS request fails entirely in [crate::ExternalRequester]
-    ExternalAPIRequest,
+    #[error("unusable external API response content")]
+    ExternalAPIContent(#[source] BoxError),
+    /// HTTP 500: Produced when a Photon or ORS request fails entirely in [crate::ExternalRequester],
+    /// or the API answers with an error status
+    #[error("external API request failed")]
+    ExternalAPIRequest(#[source] BoxError),
+    /// HTTP 500: Produced when an external API response is bigger than we're willing to read. It's
+    /// dropped as soon as that's clear, rather than read into memory
+    #[error("external API response too large")]
+    ExternalAPITooLarge(#[source] BoxError),
     /// HTTP 503: Produced when we (maybe this client, maybe another) makes too many calls with [crate::ExternalRequester]
     ///
-    /// Contains an instant that gets seralized into a Retry-After header. Not guaranteed it'll be
-    /// available 'after', but it is a good-faith estimate.
-    ExternalAPILimit(Instant),
+    /// Contains a wall-clock time that gets seralized into a Retry-After header. Not guaranteed
+    /// it'll be available 'after', but it is a good-faith estimate. Wall-clock rather than
+    /// [Instant](tokio::time::Instant) so it means the same thing in logs, on disk, and to clients;
+    /// see [Clock::system_at](crate::clock::Clock::system_at)
+    #[error("external API limit reached")]
+    ExternalAPILimit(SystemTime),
+    /// HTTP 429: Produced by [crate::abuse] when this client is temporarily banned. Contains when
+    /// the ban lifts, sent as Retry-After.
+    #[error("client temporarily banned")]
+    Banned(SystemTime),
+    /// HTTP 503: Produced by [crate::maintenance] when the service or a provider is switched off.
+    /// The message is set by ops and goes to the client as-is.
+    #[error("under maintenance: {message}")]
+    Maintenance {
+        message: String,
+        retry_after: SystemTime,
+    },
+    /// HTTP 503: Produced by [crate::degraded] for what isn't cached while only cached results are
+    /// served. Contains when upstream might be back, sent as Retry-After.
+    #[error("upstream unavailable and nothing cached")]
+    Degraded(SystemTime),
+    /// HTTP 500: Produced by [crate::panics::handle_panic] when a handler panics. Details are
+    /// traced there.
+    #[error("handler panicked")]
+    Panicked,
+    /// HTTP 504: Produced by [crate::deadline] when the client's time budget runs out. The report
+    /// of upstream calls made so far goes out in the response.
+    #[error("request deadline exceeded")]
+    DeadlineExceeded(Box<crate::deadline::DeadlineReport>),
+    /// HTTP 404: Produced by [crate::trips] for trip ids or share tokens it doesn't have, or that
+    /// have expired
+    #[error("not found")]
+    NotFound,
+    /// HTTP 507: Produced by [crate::trips] when saving a trip would go over the size quota
+    #[error("trip storage full")]
+    StorageFull,
+    /// HTTP 500: Produced when [crate::trips] can't read or write its database
+    #[error("trip storage failed")]
+    Storage(#[source] BoxError),
 }
 
-impl IntoResponse for RouteError {
-    fn into_response(self) -> Response {
-        #[derive(Serialize)]
-        struct ErrorResponse {
-            message: String,
-        }
-        match self {
-            RouteError::RequestJson(err) => {
-                let status = err.status();
-                let message = err.body_text();
-                (status, Json(ErrorResponse { message })).into_response()
+pub use flipmap_api_types::ErrorResponse;
+
+/// What a client can know about one kind of [RouteError] ahead of time. Served at `GET /errors`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct ErrorDoc {
+    /// Sent as `code` in [ErrorResponse]
+    pub code: &'static str,
+    #[serde(serialize_with = "status_number")]
+    pub status: StatusCode,
+    /// Whether the same request might work later. For `upstream_request`, only some of the time:
+    /// see [RouteError::retryable]
+    pub retryable: bool,
+    pub description: &'static str,
+}
+
+fn status_number<S: serde::Serializer>(status: &StatusCode, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u16(status.as_u16())
+}
+
+/// Declares [CATALOG] and [RouteError::doc] from one table, so what we document is what we send.
+/// Matching needs every variant, so a new one can't go undocumented
+macro_rules! error_catalog {
+    ($($pat:pat => $code:literal, $status:ident, retryable: $retryable:literal,
+       $description:literal;)*) => {
+        /// Every kind of [RouteError], for `GET /errors`
+        pub const CATALOG: &[ErrorDoc] = &[$(ErrorDoc {
+            code: $code,
+            status: StatusCode::$status,
+            retryable: $retryable,
+            description: $description,
+        },)*];
+
+        impl RouteError {
+            /// This error's entry in [CATALOG]
+            pub fn doc(&self) -> ErrorDoc {
+                match self {
+                    $($pat => ErrorDoc {
+                        code: $code,
+                        status: StatusCode::$status,
+                        retryable: $retryable,
+                        description: $description,
+                    },)*
+                }
             }
+        }
+    };
+}
+
+error_catalog! {
+    RouteError::RequestJson(_) => "request_json", BAD_REQUEST, retryable: false,
+        "The body isn't JSON of the right shape. 415 without a JSON Content-Type, 413 if it's too \
+         big, and 422 if fields are missing or of the wrong type";
+    RouteError::RequestConstraint(_) => "request_constraint", UNPROCESSABLE_ENTITY,
+        retryable: false, "The body parsed, but a value is out of range or fields conflict. The \
+         message says which";
+    RouteError::ExternalAPIJson(_) => "upstream_json", INTERNAL_SERVER_ERROR, retryable: false,
+        "A provider answered with something we couldn't parse";
+    RouteError::ExternalAPIContent(_) => "upstream_content", INTERNAL_SERVER_ERROR,
+        retryable: false, "A provider's answer parsed, but had nothing we could use";
+    RouteError::ExternalAPIRequest(_) => "upstream_request", INTERNAL_SERVER_ERROR,
+        retryable: true, "A call to a provider failed, or it answered with an error. Not \
+         retryable when the provider turned down what was asked, like a point too far from any road";
+    RouteError::ExternalAPITooLarge(_) => "upstream_too_large", INTERNAL_SERVER_ERROR,
+        retryable: false, "A provider's answer was bigger than we'll read";
+    RouteError::ExternalAPILimit(_) => "upstream_limit", SERVICE_UNAVAILABLE, retryable: true,
+        "We're calling a provider as much as we may. Retry-After says when to try again";
+    RouteError::Banned(_) => "banned", TOO_MANY_REQUESTS, retryable: true,
+        "This client sent too many requests and is turned away until Retry-After";
+    RouteError::Maintenance { .. } => "maintenance", SERVICE_UNAVAILABLE, retryable: true,
+        "Switched off by whoever runs this. The message says why, Retry-After for how long";
+    RouteError::Degraded(_) => "degraded", SERVICE_UNAVAILABLE, retryable: true,
+        "A provider is down and this wasn't cached. Retry-After says when it might be back";
+    RouteError::Panicked => "internal", INTERNAL_SERVER_ERROR, retryable: false,
+        "Something broke on our side";
+    RouteError::DeadlineExceeded(_) => "deadline_exceeded", GATEWAY_TIMEOUT, retryable: true,
+        "X-Request-Deadline-Ms ran out. The body lists the provider calls made so far";
+    RouteError::NotFound => "not_found", NOT_FOUND, retryable: false,
+        "No trip or share link by that id, or it expired";
+    RouteError::StorageFull => "storage_full", INSUFFICIENT_STORAGE, retryable: true,
+        "Too many trips are saved right now. Space frees up as they expire";
+    RouteError::Storage(_) => "storage", INTERNAL_SERVER_ERROR, retryable: true,
+        "Trip storage failed";
+}
+
+/// `GET /errors`
+#[derive(Serialize, Debug)]
+pub struct ErrorCatalog {
+    pub errors: &'static [ErrorDoc],
+}
+
+pub async fn get_errors() -> Json<ErrorCatalog> {
+    Json(ErrorCatalog { errors: CATALOG })
+}
+
+/// [ErrorResponse], plus the report of a blown deadline
+#[derive(Serialize)]
+struct ErrorBody {
+    #[serde(flatten)]
+    error: ErrorResponse,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    report: Option<crate::deadline::DeadlineReport>,
+}
+
+impl RouteError {
+    /// What the client gets told, as a whole response or one failed part of a [crate::partial]
+    /// answer
+    pub fn public(&self) -> ErrorResponse {
+        self.public_at(&SystemClock)
+    }
+
+    /// [RouteError::public], with Retry-After counted from `clock`'s time
+    pub fn public_at(&self, clock: &dyn Clock) -> ErrorResponse {
+        let doc = self.doc();
+        let message = match self {
+            RouteError::RequestJson(err) => err.body_text(),
             RouteError::RequestConstraint(err) => {
-                let status = StatusCode::UNPROCESSABLE_ENTITY;
-                let message = format!("good json, bad request semantics: {}", err);
-                (status, Json(ErrorResponse { message })).into_response()
+                format!("good json, bad request semantics: {}", err)
             }
-            RouteError::ExternalAPIJson => {
-                let status = StatusCode::INTERNAL_SERVER_ERROR;
-                let message = "problem deserializing external API response".to_owned();
-                (status, Json(ErrorResponse { message })).into_response()
+            RouteError::ExternalAPIJson(_) => {
+                "problem deserializing external API response".to_owned()
             }
-            RouteError::ExternalAPIContent => {
-                let status = StatusCode::INTERNAL_SERVER_ERROR;
-                let message = "problem with content of external API response".to_owned();
-                (status, Json(ErrorResponse { message })).into_response()
+            RouteError::ExternalAPIContent(_) => {
+                "problem with content of external API response".to_owned()
             }
-            RouteError::ExternalAPIRequest => {
-                let status = StatusCode::INTERNAL_SERVER_ERROR;
-                let message = "problem making call to external API".to_owned();
-                (status, Json(ErrorResponse { message })).into_response()
+            RouteError::ExternalAPIRequest(_) => "problem making call to external API".to_owned(),
+            RouteError::ExternalAPITooLarge(_) => "external API response too large".to_owned(),
+            RouteError::NotFound => "nothing saved under that, or it expired".to_owned(),
+            RouteError::StorageFull => "too many saved trips right now, try again later".to_owned(),
+            RouteError::Storage(_) => "problem with trip storage".to_owned(),
+            RouteError::Panicked => "internal server error".to_owned(),
+            RouteError::ExternalAPILimit(_) => "server is overusing external API".to_owned(),
+            RouteError::Banned(_) => "too many requests, slow down".to_owned(),
+            RouteError::Maintenance { message, .. } => message.clone(),
+            RouteError::Degraded(_) => {
+                "upstream unavailable right now, and this isn't cached; try again later".to_owned()
             }
-            RouteError::ExternalAPILimit(retry_instant) => {
-                let status = StatusCode::SERVICE_UNAVAILABLE;
-                let message = "server is overusing external API".to_owned();
+            RouteError::DeadlineExceeded(_) => "ran out of time before finishing".to_owned(),
+        };
+        let retry_at = match self {
+            RouteError::ExternalAPILimit(at)
+            | RouteError::Banned(at)
+            | RouteError::Degraded(at)
+            | RouteError::Maintenance {
+                retry_after: at, ..
+            } => Some(*at),
+            _ => None,
+        };
+        // Times already past say 0
+        let retry_after =
+            retry_at.map(|at| at.duration_since(clock.system_now()).unwrap_or_default());
+        ErrorResponse {
+            code: doc.code.to_owned(),
+            message,
+            retryable: self.retryable(),
+            retry_after_ms: retry_after.map(|delay| delay.as_millis() as u64),
+        }
+    }
+}
 
-                // Create the basic response first
-                let mut response = (status, Json(ErrorResponse { message })).into_response();
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        self.into_response_at(&SystemClock)
+    }
+}
 
-                // Seconds are preferable to return in retry-after header
-                let delay_duration = retry_instant.saturating_duration_since(Instant::now());
-                let delay_seconds = delay_duration.as_secs();
-                //TODO: Does this work reasonably with improper past instances?
+impl RouteError {
+    /// [IntoResponse::into_response], with Retry-After counted from `clock`'s time
+    pub fn into_response_at(self, clock: &dyn Clock) -> Response {
+        let summary = crate::report::ErrorSummary::of(&self);
+        let error = self.public_at(clock);
+        let retry_after = error.retry_after_ms.map(Duration::from_millis);
+        let (status, report) = match self {
+            // Syntax, content type and size each have their own
+            RouteError::RequestJson(err) => (err.status(), None),
+            RouteError::DeadlineExceeded(report) => (StatusCode::GATEWAY_TIMEOUT, Some(*report)),
+            err => (err.doc().status, None),
+        };
+        let mut response = (status, Json(ErrorBody { error, report })).into_response();
+        if let Some(retry_after) = retry_after {
+            response = with_retry_after(response, retry_after);
+        }
+        // Picked up by the reporting middleware, if it's enabled
+        if let Some(summary) = summary {
+            response.extensions_mut().insert(summary);
+        }
+        response
+    }
+}
 
-                // Using expect as the conversion from u64 string to HeaderValue should never fail.
-                let header_value = HeaderValue::from_str(&delay_seconds.to_string())
-                    .expect("Seconds value should always be representable as HeaderValue");
+fn with_retry_after(mut response: Response, delay: Duration) -> Response {
+    // Seconds are preferable to return in retry-after header
+    let delay_seconds = delay.as_secs();
 
-                response
-                    .headers_mut()
-                    .insert(header::RETRY_AFTER, header_value);
+    // Using expect as the conversion from u64 string to HeaderValue should never fail.
+    let header_value = HeaderValue::from_str(&delay_seconds.to_string())
+        .expect("Seconds value should always be representable as HeaderValue");
 
-                response // Return the modified response
-            }
-        }
-    }
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, header_value);
+    response
 }
 
 impl RouteError {
+    /// Whether the same request might work later. As the [CATALOG] says, except that a provider
+    /// answering 4xx will answer the same again
+    pub fn retryable(&self) -> bool {
+        match self {
+            RouteError::ExternalAPIRequest(source) => !source
+                .downcast_ref::<crate::requester::ProviderError>()
+                .and_then(|e| e.status)
+                .is_some_and(|status| status.is_client_error()),
+            err => err.doc().retryable,
+        }
+    }
+
     pub fn new_external_parse_failure(msg: String) -> Self {
         tracing::error!("external API content error: {}", msg);
-        RouteError::ExternalAPIContent
+        RouteError::ExternalAPIContent(msg.into())
     }
 
-    // Ensure this constructor receives the Instant
-    pub fn new_external_api_limit_failure(retry_after: Instant) -> Self {
-        // Kind of silly we do this twice
-        let duration = retry_after.saturating_duration_since(Instant::now());
+    pub fn new_external_api_limit_failure(retry_after: SystemTime) -> Self {
         tracing::error!(
-            "external API ratelimit reached, retry suggested after {:?}",
-            duration
+            "external API ratelimit reached, retry suggested at {}",
+            httpdate::fmt_http_date(retry_after)
         );
         RouteError::ExternalAPILimit(retry_after)
     }
@@ -117,14 +322,28 @@ impl From<reqwest::Error> for RouteError {
         if err.is_decode() {
             //TODO: Can't test rn. Make sure bad JSON responses actually hit this path
             tracing::error!("external API call JSON deserializing error: {}", err);
-            RouteError::ExternalAPIJson
+            RouteError::ExternalAPIJson(Box::new(err))
         } else {
             tracing::error!("external API call error: {}", err);
-            RouteError::ExternalAPIRequest
+            RouteError::ExternalAPIRequest(Box::new(err))
         }
     }
 }
 
+impl From<serde_json::Error> for RouteError {
+    fn from(err: serde_json::Error) -> Self {
+        tracing::error!("external API response JSON deserializing error: {}", err);
+        RouteError::ExternalAPIJson(Box::new(err))
+    }
+}
+
+impl From<rusqlite::Error> for RouteError {
+    fn from(err: rusqlite::Error) -> Self {
+        tracing::error!("trip storage error: {}", err);
+        RouteError::Storage(Box::new(err))
+    }
+}
+
 impl From<axum::extract::rejection::JsonRejection> for RouteError {
     fn from(rejection: JsonRejection) -> Self {
         // Not necessarily that important
@@ -141,3 +360,203 @@ impl From<validator::ValidationErrors> for RouteError {
         RouteError::RequestConstraint(Box::new(rejections))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn source_survives_conversion() {
+        let json_err = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let err = RouteError::from(json_err);
+        let source = err.source().expect("JSON errors should keep their cause");
+        assert!(source.downcast_ref::<serde_json::Error>().is_some());
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        let later = SystemTime::now() + std::time::Duration::from_secs(90);
+        let response = RouteError::ExternalAPILimit(later).into_response();
+        let secs: u64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((89..=90).contains(&secs));
+
+        let past = SystemTime::now() - std::time::Duration::from_secs(90);
+        let response = RouteError::Banned(past).into_response();
+        assert_eq!(response.headers()[header::RETRY_AFTER], "0");
+    }
+
+    /// As [crate::ExternalRequester] fails, from ORS
+    fn upstream(status: Option<StatusCode>, source: &str) -> RouteError {
+        RouteError::ExternalAPIRequest(Box::new(crate::requester::ProviderError::new(
+            crate::requester::Provider::OpenRouteService,
+            status,
+            source.into(),
+        )))
+    }
+
+    #[test]
+    fn upstream_4xx_not_retryable() {
+        assert!(upstream(None, "connection refused").retryable());
+        assert!(upstream(Some(StatusCode::BAD_GATEWAY), "bad gateway").retryable());
+        assert!(
+            !upstream(Some(StatusCode::NOT_FOUND), "could not find routable point").retryable()
+        );
+    }
+
+    #[test]
+    fn catalog_matches_responses() {
+        let mut codes: Vec<_> = CATALOG.iter().map(|doc| doc.code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), CATALOG.len(), "codes should be unique");
+
+        let err = RouteError::StorageFull;
+        assert!(CATALOG.contains(&err.doc()));
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+    }
+
+    /// The status line, the headers the app reads, and the body, as the app gets them
+    async fn rendered(err: RouteError, clock: &dyn Clock) -> String {
+        let response = err.into_response_at(clock);
+        let mut out = format!("{}\n", response.status());
+        for name in [header::CONTENT_TYPE, header::RETRY_AFTER] {
+            if let Some(value) = response.headers().get(&name) {
+                out += &format!("{name}: {}\n", value.to_str().unwrap());
+            }
+        }
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        out + &serde_json::to_string_pretty(&body).unwrap()
+    }
+
+    async fn rejection<T: serde::de::DeserializeOwned>(
+        content_type: Option<&str>,
+        body: &'static str,
+    ) -> RouteError {
+        use axum::extract::FromRequest;
+        let mut request = axum::http::Request::builder().method("POST");
+        if let Some(content_type) = content_type {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        let request = request.body(axum::body::Body::from(body)).unwrap();
+        match Json::<T>::from_request(request, &()).await {
+            Ok(_) => panic!("{body} should have been rejected"),
+            Err(rejection) => rejection.into(),
+        }
+    }
+
+    // What the app gets for each error. A change here is a change to what it has to handle; if
+    // it's meant, update the snapshot with UPDATE_EXPECT=1 and tell the app team
+    #[tokio::test]
+    async fn responses_unchanged() {
+        let clock = crate::test_utils::MockClock::new();
+        let now = clock.system_now();
+        let ahead = now + Duration::from_millis(90_500);
+        let past = now - Duration::from_secs(90);
+        let mut invalid = ValidationErrors::new();
+        invalid.add("src_lat", validator::ValidationError::new("range"));
+        let json = rejection::<serde_json::Value>(Some("application/json"), "{").await;
+        let cases = [
+            ("request_json, bad syntax", json),
+            (
+                "request_json, no content type",
+                rejection::<serde_json::Value>(None, "{}").await,
+            ),
+            (
+                "request_json, missing field",
+                rejection::<crate::RouteRequest>(Some("application/json"), "{}").await,
+            ),
+            ("request_constraint", invalid.into()),
+            (
+                "upstream_json",
+                serde_json::from_str::<serde_json::Value>("{")
+                    .unwrap_err()
+                    .into(),
+            ),
+            (
+                "upstream_content",
+                RouteError::ExternalAPIContent("no features".into()),
+            ),
+            (
+                "upstream_request",
+                RouteError::ExternalAPIRequest("connection refused".into()),
+            ),
+            (
+                "upstream_request, no answer",
+                upstream(None, "connection refused"),
+            ),
+            (
+                "upstream_request, 502",
+                upstream(Some(StatusCode::BAD_GATEWAY), "bad gateway"),
+            ),
+            (
+                "upstream_request, 404",
+                upstream(Some(StatusCode::NOT_FOUND), "could not find routable point"),
+            ),
+            (
+                "upstream_too_large",
+                RouteError::ExternalAPITooLarge("over 32 MiB".into()),
+            ),
+            (
+                "upstream_limit, 90.5 s ahead",
+                RouteError::ExternalAPILimit(ahead),
+            ),
+            ("banned, 90 s past", RouteError::Banned(past)),
+            (
+                "maintenance",
+                RouteError::Maintenance {
+                    message: "back soon".to_owned(),
+                    retry_after: ahead,
+                },
+            ),
+            ("degraded", RouteError::Degraded(ahead)),
+            ("internal", RouteError::Panicked),
+            (
+                "deadline_exceeded",
+                RouteError::DeadlineExceeded(Box::new(crate::deadline::DeadlineReport {
+                    budget_ms: 2000,
+                    elapsed_ms: 2004,
+                    calls: vec![crate::deadline::CallRecord {
+                        endpoint: "ors_directions",
+                        elapsed_ms: 1990,
+                        outcome: "timed out".to_owned(),
+                    }],
+                })),
+            ),
+            ("not_found", RouteError::NotFound),
+            ("storage_full", RouteError::StorageFull),
+            ("storage", RouteError::Storage("disk I/O error".into())),
+        ];
+        let mut covered: Vec<_> = cases.iter().map(|(_, err)| err.doc().code).collect();
+        covered.dedup();
+        let all: Vec<_> = CATALOG.iter().map(|doc| doc.code).collect();
+        assert_eq!(covered, all, "every kind of error, in catalog order");
+
+        let mut out = String::new();
+        for (name, err) in cases {
+            out += &format!("== {name}\n{}\n\n", rendered(err, &*clock).await);
+        }
+        expect_test::expect_file!["../snapshots/errors.txt"].assert_eq(&out);
+    }
+
+    #[tokio::test]
+    async fn source_stays_out_of_response() {
+        let err = RouteError::new_external_parse_failure("secret internals".to_owned());
+        assert_eq!(err.source().unwrap().to_string(), "secret internals");
+
+        let body = axum::body::to_bytes(err.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(!String::from_utf8_lossy(&body).contains("secret internals"));
+    }
+}
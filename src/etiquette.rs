@@ -0,0 +1,85 @@
+//! Politeness presets for the geocoder, so deployers don't have to dig through each provider's
+//! usage policy. `--photon-preset` picks one; it sets our own rate limits on Photon calls, how
+//! many may be in flight at once, and whether the User-Agent has to carry a contact.
+//!
+//! ORS has no preset. It counts calls against the key and says so with 429s, which
+//! [crate::retry_after] already honours.
+use tokio::time::Duration;
+
+/// A geocoder we know the rules for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Preset {
+    /// photon.komoot.io. Free for fair use, with no numbers given, so we stay on par with ORS's
+    /// free tier and keep bursts small
+    #[default]
+    KomootPublic,
+    /// A Photon-compatible geocoder run under the public Nominatim policy: at most one request a
+    /// second, from one thread, from an identifiable client
+    NominatimPublic,
+    /// Our own instance. No limits but its capacity
+    SelfHosted,
+}
+
+/// What a [Preset] asks of us
+#[derive(Clone, Debug, PartialEq)]
+pub struct Etiquette {
+    /// Calls allowed per window, with a name for /admin/limits
+    pub limits: Vec<(u32, Duration, String)>,
+    /// Calls in flight at once, if capped
+    pub concurrency: Option<usize>,
+    /// The User-Agent has to say how to reach us
+    pub needs_contact: bool,
+}
+
+impl Preset {
+    pub fn etiquette(self) -> Etiquette {
+        match self {
+            Preset::KomootPublic => Etiquette {
+                limits: vec![
+                    (40, Duration::from_secs(60), "Photon Minutely".to_owned()),
+                    (2000, Duration::from_secs(86400), "Photon Daily".to_owned()),
+                ],
+                concurrency: Some(4),
+                needs_contact: true,
+            },
+            Preset::NominatimPublic => Etiquette {
+                limits: vec![(1, Duration::from_secs(1), "Nominatim Secondly".to_owned())],
+                concurrency: Some(1),
+                needs_contact: true,
+            },
+            Preset::SelfHosted => Etiquette {
+                limits: vec![],
+                concurrency: None,
+                needs_contact: false,
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "komoot-public" => Ok(Preset::KomootPublic),
+            "nominatim-public" => Ok(Preset::NominatimPublic),
+            "self-hosted" => Ok(Preset::SelfHosted),
+            other => Err(format!(
+                "expected komoot-public, nominatim-public or self-hosted, got {other}"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_parse() {
+        assert_eq!("Self-Hosted".parse(), Ok(Preset::SelfHosted));
+        assert_eq!("nominatim-public".parse(), Ok(Preset::NominatimPublic));
+        assert!("nominatim".parse::<Preset>().is_err());
+        assert!(Preset::SelfHosted.etiquette().limits.is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+//! Keeping the app's saved places up to date as OSM edits land, for `POST /favorites`.
+//!
+//! Photon can't look places up by OSM id, so each one is looked for among what's near where it
+//! was saved. What's found is cached by OSM id for a day, whoever asked, since favorites are
+//! shared between a lot of users and rarely change. Lookups that fail come back `unchecked`
+//! with their error rather than failing the batch; see [crate::partial].
+use crate::cache::TtlCache;
+use crate::geo;
+use crate::geojson_ext::ParseMode;
+use crate::photon::PhotonProperties;
+use crate::requester::{ExternalApi, PhotonRevGeocodeRequest};
+use crate::{geojson_ext, Result};
+use flipmap_api_types::{FavoriteStatus, Freshness, OsmType, SavedPlace};
+use futures_util::stream::{self, StreamExt};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+pub const FAVORITES_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+pub const FAVORITES_CACHE_CAPACITY: usize = 50_000;
+/// Photon lookups in flight at once for one batch
+const CONCURRENCY: usize = 4;
+/// Nearby places searched for the saved one. Busy streets have a lot of POIs
+const NEARBY: u8 = 20;
+/// Closer than this to where it was saved counts as not moved. Node edits that just tidy up a
+/// position shouldn't bother the user
+const MOVED_M: f64 = 25.0;
+
+/// What a lookup found, and when
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    at: Instant,
+    found: Option<Found>,
+}
+
+#[derive(Debug, Clone)]
+struct Found {
+    lat: f64,
+    lon: f64,
+    name: Option<String>,
+}
+
+impl Lookup {
+    /// What the name takes up, for the [crate::memory::Budget]
+    pub fn weight(&self) -> usize {
+        self.found
+            .as_ref()
+            .and_then(|found| found.name.as_ref())
+            .map_or(0, String::len)
+    }
+}
+
+pub type FavoritesCache = TtlCache<(OsmType, u64), Lookup>;
+
+/// How each of `places` has changed, in order
+pub async fn refresh(
+    client: Arc<dyn ExternalApi>,
+    cache: FavoritesCache,
+    places: Vec<SavedPlace>,
+    mode: ParseMode,
+) -> Vec<FavoriteStatus> {
+    // Not spawned, so lookups stay inside the request's deadline. Owned rather than borrowed
+    // because borrows here make the handler's future not Send
+    stream::iter(places)
+        .map(|place| check(client.clone(), cache.clone(), place, mode))
+        .buffered(CONCURRENCY)
+        .collect()
+        .await
+}
+
+async fn check(
+    client: Arc<dyn ExternalApi>,
+    cache: FavoritesCache,
+    place: SavedPlace,
+    mode: ParseMode,
+) -> FavoriteStatus {
+    let key = (place.osm_type, place.osm_id);
+    let lookup = match cache.get_fresh(&key) {
+        Some(lookup) => lookup,
+        None => match look_up(&*client, &place, mode).await {
+            Ok(found) => {
+                let lookup = Lookup {
+                    at: Instant::now(),
+                    found,
+                };
+                cache.insert(key, lookup.clone());
+                lookup
+            }
+            Err(e) => {
+                tracing::warn!(osm_id = place.osm_id, "favorite lookup failed: {e}");
+                return FavoriteStatus {
+                    osm_type: place.osm_type,
+                    osm_id: place.osm_id,
+                    status: Freshness::Unchecked,
+                    name: None,
+                    lat: None,
+                    lon: None,
+                    checked_secs_ago: None,
+                    error: Some(e.public()),
+                };
+            }
+        },
+    };
+    compare(&place, &lookup)
+}
+
+/// The saved place among what Photon has near where it was saved
+async fn look_up(
+    client: &dyn ExternalApi,
+    place: &SavedPlace,
+    mode: ParseMode,
+) -> Result<Option<Found>> {
+    let req = PhotonRevGeocodeRequest {
+        lat: place.lat,
+        lon: place.lon,
+        limit: Some(NEARBY),
+        lang: None,
+    };
+    let features = client.photon_reverse_send(&req).await?;
+    for feature in &features.features {
+        let props = PhotonProperties::of(feature, mode)?;
+        if props.osm_id == Some(place.osm_id)
+            && props.osm_type.as_deref() == Some(osm_type_letter(place.osm_type))
+        {
+            let point = geojson_ext::extract_point(feature)?;
+            return Ok(Some(Found {
+                lat: point[1],
+                lon: point[0],
+                name: props.name,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+fn osm_type_letter(osm_type: OsmType) -> &'static str {
+    match osm_type {
+        OsmType::Node => "N",
+        OsmType::Way => "W",
+        OsmType::Relation => "R",
+    }
+}
+
+fn compare(place: &SavedPlace, lookup: &Lookup) -> FavoriteStatus {
+    let mut status = FavoriteStatus {
+        osm_type: place.osm_type,
+        osm_id: place.osm_id,
+        status: Freshness::Missing,
+        name: None,
+        lat: None,
+        lon: None,
+        checked_secs_ago: Some(lookup.at.elapsed().as_secs()),
+        error: None,
+    };
+    let Some(found) = &lookup.found else {
+        return status;
+    };
+    // Lost its name in OSM isn't a new name worth telling the user about
+    let renamed = found.name.as_ref().is_some_and(|n| *n != place.name);
+    let moved = geo::haversine_m((place.lat, place.lon), (found.lat, found.lon)) >= MOVED_M;
+    if renamed || moved {
+        status.status = Freshness::Changed;
+        status.name = Some(found.name.clone().unwrap_or_else(|| place.name.clone()));
+        status.lat = Some(found.lat);
+        status.lon = Some(found.lon);
+    } else {
+        status.status = Freshness::Unchanged;
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RouteError;
+    use crate::test_utils::{collection, CannedApi};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn saved(osm_id: u64, name: &str) -> SavedPlace {
+        SavedPlace {
+            osm_type: OsmType::Node,
+            osm_id,
+            lat: 44.5636,
+            lon: -123.2625,
+            name: name.to_owned(),
+        }
+    }
+
+    fn nearby() -> geojson::FeatureCollection {
+        let place = |osm_id: u64, name: &str, lon: f64| {
+            json!({
+                "type": "Feature",
+                "properties": { "osm_type": "N", "osm_id": osm_id, "name": name },
+                "geometry": { "type": "Point", "coordinates": [lon, 44.5636] }
+            })
+        };
+        collection(json!({
+            "type": "FeatureCollection",
+            "features": [
+                place(1, "Downward Dog", -123.2625),
+                place(2, "Block 15 Brewery", -123.2625),
+                // ~80 m east
+                place(3, "Interzone", -123.2615),
+            ]
+        }))
+    }
+
+    #[tokio::test]
+    async fn statuses() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let api: Arc<dyn ExternalApi> = Arc::new(CannedApi::default().with_reverse(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(nearby())
+        }));
+        let cache = FavoritesCache::new(FAVORITES_CACHE_TTL, 10);
+        let places = vec![
+            saved(1, "Downward Dog"),
+            saved(2, "Block 15"),
+            saved(3, "Interzone"),
+            saved(4, "Closed Cafe"),
+        ];
+
+        let statuses = refresh(
+            api.clone(),
+            cache.clone(),
+            places.clone(),
+            ParseMode::Lenient,
+        )
+        .await;
+        let kinds: Vec<_> = statuses.iter().map(|s| s.status).collect();
+        assert_eq!(
+            kinds,
+            [
+                Freshness::Unchanged,
+                Freshness::Changed,
+                Freshness::Changed,
+                Freshness::Missing
+            ]
+        );
+        assert_eq!(statuses[1].name.as_deref(), Some("Block 15 Brewery"));
+        assert_eq!(statuses[2].lon, Some(-123.2615));
+        assert_eq!(statuses[0].name, None);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // All cached, including the missing one
+        refresh(api, cache, places, ParseMode::Lenient).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_unchecked_and_not_cached() {
+        let api = CannedApi::default()
+            .with_reverse(|| Err(RouteError::ExternalAPIRequest("connection reset".into())));
+        let cache = FavoritesCache::new(FAVORITES_CACHE_TTL, 10);
+        let statuses = refresh(
+            Arc::new(api),
+            cache.clone(),
+            vec![saved(1, "Downward Dog")],
+            ParseMode::Lenient,
+        )
+        .await;
+        assert_eq!(statuses[0].status, Freshness::Unchecked);
+        assert_eq!(statuses[0].checked_secs_ago, None);
+        let error = statuses[0].error.as_ref().unwrap();
+        assert_eq!(error.code, "upstream_request");
+        assert!(error.retryable);
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,150 @@
+//! Feature flags, so new endpoints can ship dark and be switched on per deployment, or just for
+//! some clients, without a redeploy.
+//!
+//! `--flags-file` is a JSON object from flag name to `true`, `false`, or `{"keys": [...]}` for
+//! only the requests whose [API_KEY] header is one of those. The key just says who's asking; it
+//! isn't authentication. Flags left out of the file are at their [FLAGS] default. The file is
+//! re-read whenever it changes (see [watched]), and one that can't be read leaves the flags as
+//! they were. `GET /admin/flags` shows where they stand.
+use crate::watched;
+use arc_swap::ArcSwap;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Says which client is asking, for flags switched on by key
+pub const API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+/// Every flag there is, and whether it's on unless the file says otherwise
+pub const FLAGS: &[(&str, bool)] = &[
+    ("route_compare", true),
+    ("reroute", true),
+    ("off_route", true),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Flag {
+    Everyone(bool),
+    /// Only for these keys
+    Keys {
+        keys: Vec<String>,
+    },
+}
+
+impl Flag {
+    fn allows(&self, key: Option<&str>) -> bool {
+        match self {
+            Flag::Everyone(on) => *on,
+            Flag::Keys { keys } => key.is_some_and(|key| keys.iter().any(|k| k == key)),
+        }
+    }
+}
+
+/// Cheap to clone; clones see the same reloads
+#[derive(Debug, Clone, Default)]
+pub struct Flags {
+    /// Only what the file set
+    set: Arc<ArcSwap<HashMap<String, Flag>>>,
+}
+
+impl Flags {
+    /// From `path`, then spawns a task reloading it when it changes
+    pub fn watch(path: PathBuf) -> Result<Self, String> {
+        let flags = Flags::default();
+        flags.set.store(Arc::new(read(&path)?));
+        let set = flags.set.clone();
+        watched::spawn(path, "feature flags", read, move |flags| {
+            set.store(Arc::new(flags))
+        });
+        Ok(flags)
+    }
+
+    /// Whether `name` is on for a request with `key`
+    pub fn enabled(&self, name: &str, key: Option<&str>) -> bool {
+        match self.set.load().get(name) {
+            Some(flag) => flag.allows(key),
+            None => default(name),
+        }
+    }
+
+    /// Every flag, set or not
+    fn status(&self) -> BTreeMap<&'static str, Flag> {
+        let set = self.set.load();
+        FLAGS
+            .iter()
+            .map(|&(name, on)| (name, set.get(name).cloned().unwrap_or(Flag::Everyone(on))))
+            .collect()
+    }
+}
+
+fn default(name: &str) -> bool {
+    FLAGS.iter().any(|&(flag, on)| flag == name && on)
+}
+
+fn read(path: &Path) -> Result<HashMap<String, Flag>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("couldn't read {path:?}: {e}"))?;
+    let set: HashMap<String, Flag> =
+        serde_json::from_str(&text).map_err(|e| format!("couldn't parse {path:?}: {e}"))?;
+    for name in set.keys() {
+        if !FLAGS.iter().any(|&(flag, _)| flag == name) {
+            tracing::warn!("{path:?} sets {name:?}, which isn't a flag");
+        }
+    }
+    Ok(set)
+}
+
+/// Router state for [require]
+#[derive(Debug, Clone)]
+pub struct Gate {
+    pub flags: Flags,
+    pub name: &'static str,
+}
+
+/// Middleware for a flagged route: a 404 while it's off for the caller, as if it weren't there
+pub async fn require(State(gate): State<Gate>, request: Request, next: Next) -> Response {
+    let key = request.headers().get(API_KEY).and_then(|v| v.to_str().ok());
+    if gate.flags.enabled(gate.name, key) {
+        next.run(request).await
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// `GET /admin/flags`
+pub async fn get_flags(State(flags): State<Flags>) -> Json<BTreeMap<&'static str, Flag>> {
+    Json(flags.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_overrides_defaults() {
+        let path = std::env::temp_dir().join(format!("flags-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"{"reroute": false, "off_route": {"keys": ["partner"]}}"#,
+        )
+        .unwrap();
+        let flags = Flags::watch(path.clone()).unwrap();
+        assert!(flags.enabled("route_compare", None));
+        assert!(!flags.enabled("reroute", Some("partner")));
+        assert!(flags.enabled("off_route", Some("partner")));
+        assert!(!flags.enabled("off_route", None));
+        assert!(!flags.enabled("isochrones", None));
+        assert_eq!(flags.status()["reroute"], Flag::Everyone(false));
+
+        std::fs::write(&path, "{not json").unwrap();
+        assert!(Flags::watch(path.clone()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
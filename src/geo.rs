@@ -0,0 +1,76 @@
+//! Spherical geometry on plain lat/lon degrees, from [flipmap_geometry] so the app can share it.
+//!
+//! Also geohashes, for bucketing nearby positions together: cache keys (see [KeyPrecision]) and
+//! anything that shows positions without giving them away.
+use flipmap_api_types::coords::GEOHASH_ALPHABET;
+pub use flipmap_api_types::coords::{decode_geohash, MAX_GEOHASH_LEN};
+pub use flipmap_geometry::{distance_to_flat_line_m, flat_line_length_m, haversine_m};
+
+/// The geohash `len` characters long (at most [MAX_GEOHASH_LEN]) of the cell `(lat, lon)` is in
+pub fn geohash(point: (f64, f64), len: usize) -> String {
+    let (mut lat, mut lon) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(len);
+    let mut even = true;
+    for _ in 0..len.min(MAX_GEOHASH_LEN) {
+        let mut bits = 0;
+        for _ in 0..5 {
+            // Longitude first, then alternating, as in decode_geohash
+            let (range, value): (&mut (f64, f64), f64) = if even {
+                (&mut lon, point.1)
+            } else {
+                (&mut lat, point.0)
+            };
+            let mid = (range.0 + range.1) / 2.0;
+            bits <<= 1;
+            if value >= mid {
+                bits |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+        hash.push(GEOHASH_ALPHABET[bits] as char);
+    }
+    hash
+}
+
+/// The middle of the `len`-character geohash cell `(lat, lon)` is in. Everything in the cell
+/// comes out the same
+pub fn cell(point: (f64, f64), len: usize) -> (f64, f64) {
+    decode_geohash(&geohash(point, len)).unwrap_or(point)
+}
+
+/// How coarsely cache keys take positions, as geohash lengths. `None` for exact positions, so
+/// only the very same request shares an entry
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KeyPrecision {
+    /// Both ends of a route. Keys with it unset stay what they were, so X-Route-Hash survives
+    pub route: Option<usize>,
+    /// The position searches are biased to
+    pub search: Option<usize>,
+}
+
+/// `point` as a cache key should take it at `len`: itself, or its cell's middle
+pub fn key_position(point: (f64, f64), len: Option<usize>) -> (f64, f64) {
+    len.map_or(point, |len| cell(point, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geohash_round_trip() {
+        assert_eq!(geohash((42.605, -5.603), 5), "ezs42");
+        assert_eq!(geohash((52.520008, 13.404954), 9), "u33dc0cpn");
+        let (lat, lon) = decode_geohash(&geohash((44.5646, -123.262), 12)).unwrap();
+        assert!(haversine_m((lat, lon), (44.5646, -123.262)) < 0.1);
+
+        // A few meters apart, same 7-character cell but not the same 9-character one
+        let (a, b) = ((52.52001, 13.40495), (52.52003, 13.40499));
+        assert_eq!(cell(a, 7), cell(b, 7));
+        assert_eq!(key_position(a, None), a);
+        assert_ne!(key_position(a, Some(9)), key_position(b, Some(9)));
+    }
+}
@@ -0,0 +1,53 @@
+//! Coarse, country-level origin of requests from a MaxMind-style database (GeoLite2-Country or
+//! compatible). Only the country code is ever kept; see [crate::access_log] for the IP side.
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Cheap to clone. The database is read into memory once at startup
+#[derive(Clone)]
+pub struct GeoIp {
+    reader: Arc<Reader<Vec<u8>>>,
+}
+
+impl std::fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("database", &self.reader.metadata.database_type)
+            .field("build_epoch", &self.reader.metadata.build_epoch)
+            .finish()
+    }
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self, MaxMindDBError> {
+        Ok(GeoIp {
+            reader: Arc::new(Reader::open_readfile(path)?),
+        })
+    }
+
+    /// ISO 3166-1 alpha-2 code for `ip`, if the database knows it. Private and reserved ranges
+    /// won't be found
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record
+            .country
+            .or(record.registered_country)
+            .and_then(|c| c.iso_code)
+            .map(str::to_owned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn garbage_database_rejected() {
+        let path = std::env::temp_dir().join("flipmap-geoip-garbage.mmdb");
+        std::fs::write(&path, b"definitely not a maxmind database").unwrap();
+        assert!(GeoIp::open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+//! Checked access to the parts of upstream GeoJSON that handlers use. Anything missing or of the
+//! wrong type is an [ExternalAPIContent](RouteError::ExternalAPIContent) error with a consistent
+//! message, never a panic.
+//!
+//! Typed properties are read according to a [ParseMode], which handlers get from the app state.
+use crate::error::RouteError;
+use crate::Result;
+use geojson::{Feature, FeatureCollection, JsonObject, LineStringType, PointType, Value};
+use serde::de::DeserializeOwned;
+
+/// How picky to be about upstream properties and metadata
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Anything missing or malformed is an error. For staging, to catch provider API drift early
+    Strict,
+    /// Missing or malformed things are warned about and filled with defaults
+    #[default]
+    Lenient,
+}
+
+impl std::str::FromStr for ParseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "strict" => Ok(ParseMode::Strict),
+            "lenient" => Ok(ParseMode::Lenient),
+            other => Err(format!("expected strict or lenient, got {other}")),
+        }
+    }
+}
+
+/// A typed view of some upstream JSON object
+pub trait Properties: DeserializeOwned + Default {
+    /// What this is, for messages
+    const WHAT: &'static str;
+    /// Keys the provider always sends. Anything else may legitimately be missing
+    const EXPECTED: &'static [&'static str];
+}
+
+fn geometry(feature: &Feature) -> Result<&Value> {
+    feature
+        .geometry
+        .as_ref()
+        .map(|g| &g.value)
+        .ok_or_else(|| RouteError::new_external_parse_failure("feature has no geometry".to_owned()))
+}
+
+fn wrong_type(expected: &str, found: &Value) -> RouteError {
+    RouteError::new_external_parse_failure(format!(
+        "found {} geojson datatype instead of {expected}",
+        found.type_name()
+    ))
+}
+
+/// The Point geometry of `feature`. Guaranteed to have at least lon and lat, so indexing is fine
+pub fn extract_point(feature: &Feature) -> Result<&PointType> {
+    match geometry(feature)? {
+        Value::Point(p) if p.len() >= 2 => Ok(p),
+        Value::Point(p) => Err(RouteError::new_external_parse_failure(format!(
+            "point has {} dimension(s)",
+            p.len()
+        ))),
+        v => Err(wrong_type("Point", v)),
+    }
+}
+
+/// The LineString geometry of `feature`
+pub fn extract_linestring(feature: &Feature) -> Result<&LineStringType> {
+    match geometry(feature)? {
+        Value::LineString(l) => Ok(l),
+        v => Err(wrong_type("LineString", v)),
+    }
+}
+
+/// The LineString geometry of the first feature. ORS puts the route there
+pub fn extract_first_linestring(fc: &FeatureCollection) -> Result<&LineStringType> {
+    let feature = fc.features.first().ok_or_else(|| {
+        RouteError::new_external_parse_failure("feature collection is empty".to_owned())
+    })?;
+    extract_linestring(feature)
+}
+
+/// `line`'s positions one after another, the way the app wants routes
+pub fn flatten(line: &LineStringType) -> Vec<f64> {
+    line.iter().flatten().copied().collect()
+}
+
+/// The properties of `feature` as `T`, under `mode`. A feature without properties reads as an
+/// empty map.
+pub fn extract_properties<T: Properties>(feature: &Feature, mode: ParseMode) -> Result<T> {
+    let empty = JsonObject::new();
+    parse_map(feature.properties.as_ref().unwrap_or(&empty), mode)
+}
+
+/// `object` as `T`, under `mode`
+pub fn parse_object<T: Properties>(object: &serde_json::Value, mode: ParseMode) -> Result<T> {
+    match object {
+        serde_json::Value::Object(map) => parse_map(map, mode),
+        other => lenient_or_fail(
+            mode,
+            format!("{} should be an object, found {other}", T::WHAT),
+        ),
+    }
+}
+
+fn parse_map<T: Properties>(object: &JsonObject, mode: ParseMode) -> Result<T> {
+    let missing: Vec<_> = T::EXPECTED
+        .iter()
+        .filter(|key| !object.contains_key(**key))
+        .collect();
+    if !missing.is_empty() {
+        let msg = format!("{} is missing {missing:?}", T::WHAT);
+        if mode == ParseMode::Strict {
+            return Err(RouteError::new_external_parse_failure(msg));
+        }
+        tracing::warn!("{msg}, using defaults");
+    }
+    match T::deserialize(object) {
+        Ok(parsed) => Ok(parsed),
+        Err(e) => lenient_or_fail(mode, format!("bad {}: {e}", T::WHAT)),
+    }
+}
+
+fn lenient_or_fail<T: Default>(mode: ParseMode, msg: String) -> Result<T> {
+    match mode {
+        ParseMode::Strict => Err(RouteError::new_external_parse_failure(msg)),
+        ParseMode::Lenient => {
+            tracing::warn!("{msg}, using defaults");
+            Ok(T::default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::collection;
+    use serde_json::json;
+
+    fn feature(value: serde_json::Value) -> Feature {
+        serde_json::from_value(value).expect("test feature should be valid GeoJSON")
+    }
+
+    fn content_error<T>(res: Result<T>) -> bool {
+        res.is_err_and(|x| matches!(x, RouteError::ExternalAPIContent(_)))
+    }
+
+    #[test]
+    fn empty_collection_is_an_error() {
+        let fc = collection(json!({ "type": "FeatureCollection", "features": [] }));
+        assert!(content_error(extract_first_linestring(&fc)));
+    }
+
+    #[test]
+    fn missing_geometry() {
+        let f = feature(json!({ "type": "Feature", "properties": {}, "geometry": null }));
+        assert!(content_error(extract_point(&f)));
+        assert!(content_error(extract_linestring(&f)));
+    }
+
+    #[test]
+    fn wrong_geometry_type() {
+        let point = feature(json!({
+            "type": "Feature",
+            "properties": {},
+            "geometry": { "type": "Point", "coordinates": [1.0, 2.0] }
+        }));
+        let line = feature(json!({
+            "type": "Feature",
+            "properties": {},
+            "geometry": { "type": "LineString", "coordinates": [[1.0, 2.0], [3.0, 4.0]] }
+        }));
+        assert!(content_error(extract_linestring(&point)));
+        assert!(content_error(extract_point(&line)));
+        assert_eq!(extract_point(&point).unwrap(), &vec![1.0, 2.0]);
+        assert_eq!(extract_linestring(&line).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn one_dimensional_point() {
+        // geojson won't deserialize this, but nothing stops one being built
+        let f = Feature {
+            geometry: Some(geojson::Geometry::new(Value::Point(vec![1.0]))),
+            ..Default::default()
+        };
+        assert!(content_error(extract_point(&f)));
+    }
+
+    #[derive(serde::Deserialize, Debug, Default, PartialEq)]
+    struct Props {
+        name: Option<String>,
+        street: Option<String>,
+    }
+
+    impl Properties for Props {
+        const WHAT: &'static str = "test properties";
+        const EXPECTED: &'static [&'static str] = &["name"];
+    }
+
+    #[test]
+    fn typed_properties() {
+        let f = feature(json!({
+            "type": "Feature",
+            "properties": { "name": "Downward Dog", "osm_id": 384119068 },
+            "geometry": null
+        }));
+        assert_eq!(
+            extract_properties::<Props>(&f, ParseMode::Lenient).unwrap(),
+            Props {
+                name: Some("Downward Dog".to_owned()),
+                street: None
+            }
+        );
+
+        let bare = feature(json!({ "type": "Feature", "properties": null, "geometry": null }));
+        assert_eq!(
+            extract_properties::<Props>(&bare, ParseMode::Lenient)
+                .unwrap()
+                .name,
+            None
+        );
+
+        let wrong =
+            feature(json!({ "type": "Feature", "properties": { "name": 5 }, "geometry": null }));
+        assert!(content_error(extract_properties::<Props>(
+            &wrong,
+            ParseMode::Strict
+        )));
+    }
+
+    #[test]
+    fn lenient_fills_defaults() {
+        let wrong =
+            feature(json!({ "type": "Feature", "properties": { "name": 5 }, "geometry": null }));
+        assert_eq!(
+            extract_properties::<Props>(&wrong, ParseMode::Lenient).unwrap(),
+            Props::default()
+        );
+        let bare = feature(json!({ "type": "Feature", "properties": {}, "geometry": null }));
+        assert!(content_error(extract_properties::<Props>(
+            &bare,
+            ParseMode::Strict
+        )));
+        assert!("STRICT".parse::<ParseMode>() == Ok(ParseMode::Strict));
+    }
+
+    fn json_leaf() -> impl proptest::strategy::Strategy<Value = serde_json::Value> {
+        use proptest::prelude::*;
+        prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            ".*".prop_map(serde_json::Value::from),
+        ]
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn points_of_any_dimension(coords in proptest::collection::vec(-180.0..180.0f64, 0..5)) {
+            let f = Feature {
+                geometry: Some(geojson::Geometry::new(Value::Point(coords.clone()))),
+                ..Default::default()
+            };
+            match extract_point(&f) {
+                Ok(p) => proptest::prop_assert!(p.len() >= 2),
+                Err(e) => {
+                    proptest::prop_assert!(coords.len() < 2);
+                    proptest::prop_assert!(matches!(e, RouteError::ExternalAPIContent(_)));
+                }
+            }
+            proptest::prop_assert!(content_error(extract_linestring(&f)));
+        }
+
+        #[test]
+        fn any_properties_are_handled(
+            props in proptest::collection::hash_map("name|street|.*", json_leaf(), 0..4)
+        ) {
+            let f = Feature {
+                properties: Some(props.into_iter().collect()),
+                ..Default::default()
+            };
+            proptest::prop_assert!(extract_properties::<Props>(&f, ParseMode::Lenient).is_ok());
+            let strict = extract_properties::<Props>(&f, ParseMode::Strict);
+            proptest::prop_assert!(strict.is_ok() || content_error(strict));
+        }
+    }
+}
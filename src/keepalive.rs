@@ -0,0 +1,26 @@
+//! Keeping upstream connections warm between bursts, so the first request after a quiet spell
+//! doesn't pay for a fresh TLS handshake.
+//!
+//! With `--keepalive-secs`, each provider's base gets a `HEAD` that often, through the same
+//! pooled clients real calls use (see [crate::requester::ExternalRequester::keepalive]). It's off
+//! by default: the pings are free as far as our limits and daily caps go, but the providers still
+//! see them. Pick an interval under `--pool-idle-timeout-secs`, or the connection is closed before
+//! it's pinged.
+use crate::rotation::Rotating;
+use std::sync::Arc;
+use tokio::time::{Duration, MissedTickBehavior};
+
+/// Pings through whichever requester is current, every `every`
+pub fn spawn(rotating: Arc<Rotating>, every: Duration) {
+    tracing::info!("keeping upstream connections warm every {every:?}");
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick is straight away, before anything's connected
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            rotating.keepalive().await;
+        }
+    });
+}
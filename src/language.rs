@@ -0,0 +1,164 @@
+//! Which language Photon names places in, so searches and reverse lookups read naturally to the
+//! user without the app having to say.
+//!
+//! A request's `lang` picks it. Otherwise it's the best of the client's `Accept-Language` that
+//! Photon has, as listed with `--photon-languages` (photon.komoot.io's by default), and failing
+//! that, Photon's default of names as they're known locally. The choice is part of the cache key
+//! and goes back in `Content-Language`. Only primary subtags count: `en-GB` is `en`.
+use crate::error::RouteError;
+use crate::Result;
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+};
+use std::convert::Infallible;
+
+/// What photon.komoot.io has
+pub const DEFAULT_PHOTON: &str = "en,de,fr";
+
+/// The languages Photon has names in. Handlers get them from the app state
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhotonLanguages(Vec<String>);
+
+impl PhotonLanguages {
+    /// As configured. Case and blanks don't matter
+    pub fn new(languages: &[String]) -> Self {
+        PhotonLanguages(
+            languages
+                .iter()
+                .map(|lang| lang.trim().to_ascii_lowercase())
+                .filter(|lang| !lang.is_empty())
+                .collect(),
+        )
+    }
+
+    fn has(&self, lang: &str) -> bool {
+        self.0.iter().any(|l| l == lang)
+    }
+}
+
+impl Default for PhotonLanguages {
+    /// [DEFAULT_PHOTON]
+    fn default() -> Self {
+        PhotonLanguages(DEFAULT_PHOTON.split(',').map(str::to_owned).collect())
+    }
+}
+
+/// The languages in `Accept-Language`, most wanted first. Ones with `q=0` are left out
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AcceptLanguage(Vec<String>);
+
+impl AcceptLanguage {
+    /// From an `Accept-Language` value. Anything unreadable is skipped
+    pub fn parse(value: &str) -> Self {
+        let mut ranked: Vec<(f64, String)> = value
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let tag = parts.next().filter(|t| !t.is_empty() && *t != "*")?;
+                let q = parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse().ok())?;
+                let primary = tag.split('-').next()?.to_ascii_lowercase();
+                (q > 0.0).then_some((q, primary))
+            })
+            .collect();
+        // Stable, so ties stay in the order sent
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        AcceptLanguage(ranked.into_iter().map(|(_, lang)| lang).collect())
+    }
+
+    /// From every `Accept-Language` in `headers`
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = headers
+            .get_all(header::ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        AcceptLanguage::parse(&value)
+    }
+
+    /// The most wanted language Photon has, if any
+    pub fn photon(&self, languages: &PhotonLanguages) -> Option<String> {
+        self.0.iter().find(|lang| languages.has(lang)).cloned()
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AcceptLanguage {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        Ok(AcceptLanguage::from_headers(&parts.headers))
+    }
+}
+
+/// The language to ask Photon for: `explicit` if the request set one, else
+/// [AcceptLanguage::photon]. `None` for Photon's default. A 422 if `explicit` is one Photon
+/// doesn't have
+pub fn photon(
+    explicit: Option<&str>,
+    accepted: &AcceptLanguage,
+    languages: &PhotonLanguages,
+) -> Result<Option<String>> {
+    if let Some(lang) = explicit {
+        let lang = lang.to_ascii_lowercase();
+        if !languages.has(&lang) {
+            let mut errors = validator::ValidationErrors::new();
+            errors.add(
+                "lang",
+                validator::ValidationError::new("lang")
+                    .with_message(format!("no place names in {lang:?} here").into()),
+            );
+            return Err(RouteError::from(errors));
+        }
+        return Ok(Some(lang));
+    }
+    Ok(accepted.photon(languages))
+}
+
+/// `Content-Language` for an answer in `lang`. None for Photon's default, which is a mix
+pub fn content_language(lang: Option<&str>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(value) = lang.and_then(|lang| HeaderValue::from_str(lang).ok()) {
+        headers.insert(header::CONTENT_LANGUAGE, value);
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn best_language_photon_has() {
+        let komoot = PhotonLanguages::default();
+        let accepted = AcceptLanguage::parse("nl-BE, fr;q=0.5, EN-gb;q=0.8, de;q=0, *;q=0.1");
+        assert_eq!(accepted.0, ["nl", "en", "fr"]);
+        assert_eq!(
+            photon(None, &accepted, &komoot).unwrap().as_deref(),
+            Some("en")
+        );
+        assert_eq!(
+            photon(Some("FR"), &accepted, &komoot).unwrap().as_deref(),
+            Some("fr")
+        );
+        assert!(photon(Some("nl"), &accepted, &komoot).is_err());
+        assert_eq!(
+            photon(None, &AcceptLanguage::parse("nl"), &komoot).unwrap(),
+            None
+        );
+        let dutch = PhotonLanguages::new(&[" NL ".into(), "".into()]);
+        assert_eq!(
+            photon(None, &accepted, &dutch).unwrap().as_deref(),
+            Some("nl")
+        );
+        assert_eq!(
+            AcceptLanguage::parse("en;q=high, ;q=1").0,
+            Vec::<String>::new()
+        );
+    }
+}
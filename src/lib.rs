@@ -0,0 +1,3058 @@
+use axum::{
+    extract::{rejection::JsonRejection, FromRef, FromRequest, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use core::net;
+use geojson::Position;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::sync::Arc;
+use tokio::time::Duration;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::request_id::{
+    MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer,
+};
+use tower_http::trace::TraceLayer;
+use tracing::instrument;
+use tracing_subscriber::{
+    fmt::format::FmtSpan, layer::SubscriberExt, reload, util::SubscriberInitExt, Layer,
+};
+use validator::Validate;
+
+mod about;
+mod abuse;
+mod access_log;
+mod admin;
+mod attribution;
+mod audit;
+mod borders;
+mod cache;
+mod cache_control;
+mod canonical;
+mod capture;
+mod clock;
+mod deadline;
+mod degraded;
+#[cfg(test)]
+mod e2e_tests;
+mod elevation;
+mod error;
+mod etiquette;
+mod favorites;
+mod flags;
+mod geo;
+mod geoip;
+pub mod geojson_ext;
+mod keepalive;
+mod language;
+#[cfg(all(test, feature = "live-tests"))]
+mod live_tests;
+mod log_level;
+mod maintenance;
+mod memory;
+mod metrics;
+#[cfg(test)]
+mod mock_upstream;
+mod openapi;
+mod ors;
+mod ors_body;
+mod panics;
+mod partial;
+mod photon;
+mod prefetch;
+mod probe;
+#[cfg(feature = "pprof")]
+mod profiling;
+pub mod ratelimit;
+mod regions;
+mod report;
+#[allow(dead_code)]
+mod requester;
+mod reroute;
+mod retry_after;
+mod revalidate;
+mod reverse;
+mod rotation;
+mod scoring;
+mod search;
+mod shadow;
+mod shape;
+mod share;
+mod stream_json;
+#[cfg(test)]
+mod test_utils;
+mod timing;
+mod trips;
+mod upstream_error;
+mod usage;
+mod vcr;
+mod version;
+mod versioning;
+mod watched;
+mod workers;
+use crate::error::RouteError;
+use crate::geojson_ext::ParseMode;
+pub use crate::log_level::FilterHandle;
+use crate::partial::Partial;
+use crate::requester::{
+    ExternalApi, ExternalRequesterBuilder, OpenRouteRequest, PhotonGeocodeRequest, Provider,
+};
+use crate::shape::{Expectation, GeometryKind};
+use crate::versioning::{ApiVersion, Negotiated};
+pub use crate::workers::runtime;
+pub use flipmap_api_types::{
+    CompareRequest, CompareResponse, FavoriteStatus, FavoritesRequest, FavoritesResponse,
+    Freshness, GetLocationsRequest, GetLocationsResponse, Granularity, ModeSummary,
+    OffRouteRequest, OffRouteResponse, OsmType, PlaceResult, Profile, RerouteRequest,
+    RerouteResponse, RerouteResponseV2, ReversePlace, ReverseRequest, ReverseResponse, RoutePoint,
+    RouteRequest, RouteResponse, RouteResponseV2, SavedPlace, ShareRequest, ShareResponse,
+    SharedPlace, SharedResponse, SharedResponseV2, SnappedEnds, SnappedPoint, TripResponse,
+    TripResponseV2,
+};
+
+pub(crate) type Result<T> = std::result::Result<T, RouteError>;
+
+/// ORS's own instance, whose terms want a contact in the User-Agent
+const PUBLIC_ORS_HOST: &str = "api.openrouteservice.org";
+
+/// How long a computed route is served without asking ORS again
+const ROUTE_CACHE_TTL: Duration = Duration::from_secs(600);
+const ROUTE_CACHE_CAPACITY: usize = 10_000;
+
+/// Sent with every route. Clients send it back in [IF_ROUTE_UNCHANGED] when refreshing
+const ROUTE_HASH: HeaderName = HeaderName::from_static("x-route-hash");
+/// If this matches the request's [ROUTE_HASH] and we still have the route cached, the answer is a
+/// bodiless 304 and no quota is spent
+const IF_ROUTE_UNCHANGED: HeaderName = HeaderName::from_static("if-route-unchanged");
+
+pub type RouteCache = cache::TtlCache<u64, RouteResponse>;
+
+/// How long a search's results are kept for paging through
+const SEARCH_CACHE_TTL: Duration = Duration::from_secs(300);
+const SEARCH_CACHE_CAPACITY: usize = 10_000;
+
+/// Places Photon found for a search, as many as were asked for so far. See [get_locations]
+#[derive(Debug)]
+struct Search {
+    places: Vec<PlaceResult>,
+    /// How many Photon was asked for. Fewer than that may be left after [search::SearchDefaults]
+    /// drops some
+    asked: usize,
+    /// Photon returned fewer than asked, so there's nothing more to fetch
+    exhausted: bool,
+    attribution: Vec<String>,
+}
+
+type SearchCache = cache::TtlCache<u64, Arc<Search>>;
+
+/// Everything the public routes need. Handlers pull out the parts they use
+#[derive(Clone, FromRef)]
+struct AppState {
+    client: Arc<dyn ExternalApi>,
+    route_cache: RouteCache,
+    search_cache: SearchCache,
+    search_defaults: search::SearchDefaults,
+    favorites_cache: favorites::FavoritesCache,
+    ledger: usage::Ledger,
+    staleness: Staleness,
+    /// For /off_route's trip ids, with `--trips-db`
+    trips: Option<trips::TripStore>,
+    /// With `--shadow-osrm`
+    shadow: Option<shadow::Shadow>,
+    /// Photon's answers for /reverse, some looked up ahead by [prefetch]
+    reverse_cache: prefetch::ReverseCache,
+    /// With `--prefetch-destinations`
+    prefetch: Option<prefetch::Prefetch>,
+    /// For routes asking which borders they cross
+    borders: borders::Borders,
+    /// How upstream answers are read
+    parse_mode: ParseMode,
+    /// How much nearby requests share cached answers
+    key_precision: geo::KeyPrecision,
+    credits: attribution::Credits,
+    photon_languages: language::PhotonLanguages,
+}
+
+/// When cached answers go out past their freshness. See [degraded] and [revalidate]
+#[derive(Clone)]
+struct Staleness {
+    degraded: degraded::Degraded,
+    revalidator: revalidate::Revalidator,
+}
+
+/// What the trip and share routes need. Only routed with a [trips::TripStore]
+#[derive(Clone, FromRef)]
+struct TripState {
+    client: Arc<dyn ExternalApi>,
+    route_cache: RouteCache,
+    trips: trips::TripStore,
+    share_limiter: share::ShareLimiter,
+    access_log: access_log::AccessLog,
+    ledger: usage::Ledger,
+    parse_mode: ParseMode,
+    key_precision: geo::KeyPrecision,
+    credits: attribution::Credits,
+}
+
+/// Wraps [axum::Json] so that we can validate requests with [validator::Validate] after
+/// deserialization. Rejection at either stage sends a response back before hitting routes
+struct ValidatedJson<T>(T);
+// Pass-through. There's no derive macro so we have to impl. Response formatting is via error
+impl<T> IntoResponse for ValidatedJson<T>
+where
+    axum::Json<T>: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+    axum::Json<T>: FromRequest<S, Rejection = JsonRejection>,
+{
+    type Rejection = RouteError; // Why is this required? Compiler made me. 'ate generics.
+    async fn from_request(
+        req: axum::extract::Request,
+        state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let axum::Json(data) = axum::Json::<T>::from_request(req, state).await?;
+        data.validate()?;
+        Ok(ValidatedJson(data))
+    }
+}
+
+/// The binary's command line. Everything but `serve` is for deploy pipelines, and exits nonzero
+/// when something's wrong
+#[derive(clap::Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Run the service
+    Serve(Config),
+    /// Check the configuration the way `serve` would, then exit without listening
+    CheckConfig(Config),
+    /// Call each upstream once with the configured client and report how it went
+    Probe(Config),
+    /// Print the OpenAPI spec for the public endpoints
+    PrintOpenapi,
+}
+
+/// How to run the service. Usually parsed by [clap] from the command line, see [build_app]
+#[derive(clap::Parser, Debug)]
+pub struct Config {
+    // Every option can be set from a FLIPMAP_BACKEND_* env variable, for containers. Tried to make
+    // the prefix compile-time dynamic to crate name. Seems impossible w/ stdlib
+    // Optional so `probe` doesn't need them. `serve` and `check-config` want these or --listen
+    #[arg(env = "FLIPMAP_BACKEND_IP", value_parser = clap::value_parser!(net::IpAddr),
+          requires = "port")]
+    ip: Option<net::IpAddr>,
+    #[arg(env = "FLIPMAP_BACKEND_PORT", value_parser = clap::value_parser!(u16).range(1..=65535),
+          requires = "ip")]
+    port: Option<u16>,
+    /// Listen on ADDR:PORT as well as (or instead of) IP PORT. Repeat for more, e.g.
+    /// `--listen 0.0.0.0:80 --listen [::]:80` for dual-stack
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        env = "FLIPMAP_BACKEND_LISTEN",
+        value_delimiter = ','
+    )]
+    listen: Vec<net::SocketAddr>,
+    #[arg(short,long, env = "FLIPMAP_BACKEND_ORS_BASE", value_parser = clap::value_parser!(reqwest::Url), default_value = "https://api.openrouteservice.org")]
+    ors_base: reqwest::Url,
+    /// More ORS instances to route through, like the hosted API behind a self-hosted --ors-base.
+    /// Each is probed every 30 seconds and routes go to the fastest that's up
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_ORS_FALLBACK",
+        value_name = "URL",
+        value_delimiter = ','
+    )]
+    ors_fallback: Vec<reqwest::Url>,
+    #[arg(short, long, env = "FLIPMAP_BACKEND_PHOTON_BASE", value_parser = clap::value_parser!(reqwest::Url), default_value = "https://photon.komoot.io")]
+    photon_base: reqwest::Url,
+    // I'd put the API key here but clap purposely seems to deny the ability to ONLY allow w/ env
+    /// POST a JSON report here whenever an external API call fails or something panics
+    #[arg(long, env = "FLIPMAP_BACKEND_ERROR_WEBHOOK", value_parser = clap::value_parser!(reqwest::Url))]
+    error_webhook: Option<reqwest::Url>,
+    /// Development only: write upstream response bodies into this directory as test fixtures
+    #[arg(long, env = "FLIPMAP_BACKEND_RECORD_FIXTURES", value_name = "DIR")]
+    record_fixtures: Option<std::path::PathBuf>,
+    /// How to treat upstream answers missing fields we expect: strict rejects them (for staging,
+    /// to catch provider API changes early), lenient warns and fills in defaults
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_PARSE_MODE",
+        value_name = "MODE",
+        default_value = "lenient"
+    )]
+    parse_mode: geojson_ext::ParseMode,
+    /// Write requests that failed on an unparsable or unusable upstream answer into this
+    /// directory, with the upstream responses, for reproducing offline
+    #[arg(long, env = "FLIPMAP_BACKEND_CAPTURE_FAILURES", value_name = "DIR")]
+    capture_failures: Option<std::path::PathBuf>,
+    /// How many failure captures to keep
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_CAPTURE_KEEP",
+        value_name = "N",
+        default_value_t = 20
+    )]
+    capture_keep: usize,
+    /// Serve Prometheus-style metrics (runtime task counts etc.) at /metrics
+    #[arg(long, env = "FLIPMAP_BACKEND_METRICS")]
+    metrics: bool,
+    /// Serve the operational endpoints under /admin (maintenance, log levels, usage, limits, route
+    /// cache) on this address, away from the public routes. Needs --admin-token
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_ADMIN_LISTEN",
+        value_name = "ADDR:PORT",
+        conflicts_with = "admin_socket"
+    )]
+    admin_listen: Option<net::SocketAddr>,
+    /// Like --admin-listen, but on a unix socket
+    #[arg(long, env = "FLIPMAP_BACKEND_ADMIN_SOCKET", value_name = "PATH")]
+    admin_socket: Option<std::path::PathBuf>,
+    /// What the admin endpoints want after `Authorization: Bearer`. Better set in the environment,
+    /// since arguments show up in `ps`
+    #[arg(long, env = "FLIPMAP_BACKEND_ADMIN_TOKEN", value_name = "TOKEN", hide_env_values = true,
+          value_parser = |s: &str| Ok::<_, std::convert::Infallible>(secrecy::SecretString::from(s)))]
+    admin_token: Option<secrecy::SecretString>,
+    /// Keep the last N outbound calls (redacted) for GET /admin/outbound. Needs admin endpoints
+    #[arg(long, env = "FLIPMAP_BACKEND_AUDIT_OUTBOUND", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    audit_outbound: Option<u64>,
+    /// Take client IPs from X-Forwarded-For (only do this behind a reverse proxy!)
+    #[arg(long, env = "FLIPMAP_BACKEND_TRUST_FORWARDED_FOR")]
+    trust_forwarded_for: bool,
+    /// MaxMind-style country database (e.g. GeoLite2-Country.mmdb) to tag requests with their
+    /// country of origin
+    #[arg(long, env = "FLIPMAP_BACKEND_GEOIP_DB", value_name = "FILE")]
+    geoip_db: Option<std::path::PathBuf>,
+    /// Temporarily ban clients sending too many (bad or identical) requests. Behind a reverse
+    /// proxy this needs --trust-forwarded-for, or everyone looks like the same client
+    #[arg(long, env = "FLIPMAP_BACKEND_BAN_ABUSERS")]
+    ban_abusers: bool,
+    /// Hard cap on ORS calls per UTC day. ORS goes into maintenance mode when it's reached
+    #[arg(long, env = "FLIPMAP_BACKEND_ORS_DAILY_CAP", value_name = "CALLS")]
+    ors_daily_cap: Option<u64>,
+    /// Hard cap on Photon calls per UTC day. Photon goes into maintenance mode when it's reached
+    #[arg(long, env = "FLIPMAP_BACKEND_PHOTON_DAILY_CAP", value_name = "CALLS")]
+    photon_daily_cap: Option<u64>,
+    /// ORS connect, read, and total timeouts as <CONNECT_MS>,<READ_MS>,<TOTAL_MS>
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_ORS_TIMEOUTS",
+        value_name = "TIMEOUTS",
+        default_value = "3000,10000,10000"
+    )]
+    ors_timeouts: requester::Timeouts,
+    /// Photon connect, read, and total timeouts as <CONNECT_MS>,<READ_MS>,<TOTAL_MS>
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_PHOTON_TIMEOUTS",
+        value_name = "TIMEOUTS",
+        default_value = "2000,3000,3000"
+    )]
+    photon_timeouts: requester::Timeouts,
+    /// Idle upstream connections kept open per host
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_POOL_MAX_IDLE_PER_HOST",
+        default_value_t = 32
+    )]
+    pool_max_idle_per_host: usize,
+    /// Seconds before an idle upstream connection is closed
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_POOL_IDLE_TIMEOUT_SECS",
+        default_value_t = 90
+    )]
+    pool_idle_timeout_secs: u64,
+    /// Seconds between TCP keepalive probes on upstream connections. 0 disables them
+    #[arg(long, env = "FLIPMAP_BACKEND_TCP_KEEPALIVE_SECS", default_value_t = 60)]
+    tcp_keepalive_secs: u64,
+    /// Megabytes the caches and the outbound audit trail may take between them, roughly. Past it,
+    /// they shed their oldest entries. Unlimited if unset
+    #[arg(long, env = "FLIPMAP_BACKEND_MEMORY_BUDGET_MB", value_name = "MB", value_parser = clap::value_parser!(u64).range(1..))]
+    memory_budget_mb: Option<u64>,
+    /// Worker threads to serve on. The blocking pool and background jobs scale with it. One per
+    /// core if unset
+    #[arg(long, env = "FLIPMAP_BACKEND_WORKERS", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    workers: Option<u64>,
+    /// Seconds between HEADs to each provider's base, keeping a connection warm for the next
+    /// request. Off if unset
+    #[arg(long, env = "FLIPMAP_BACKEND_KEEPALIVE_SECS", value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    keepalive_secs: Option<u64>,
+    /// HTTP version for ORS: auto (negotiated), http1, or http2 (prior knowledge, falls back to
+    /// auto if the upstream doesn't speak it)
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_ORS_HTTP",
+        value_name = "VERSION",
+        default_value = "auto"
+    )]
+    ors_http: requester::HttpVersion,
+    /// HTTP version for Photon. See --ors-http
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_PHOTON_HTTP",
+        value_name = "VERSION",
+        default_value = "auto"
+    )]
+    photon_http: requester::HttpVersion,
+    /// Log a warning for requests taking longer than this many milliseconds
+    #[arg(long, env = "FLIPMAP_BACKEND_SLOW_REQUEST_MS", value_name = "MS", default_value_t = timing::DEFAULT_SLOW_REQUEST.as_millis() as u64)]
+    slow_request_ms: u64,
+    /// Search around here when the app doesn't send a position
+    #[arg(long, env = "FLIPMAP_BACKEND_SEARCH_BIAS", value_name = "LAT,LON")]
+    search_bias: Option<search::Point>,
+    /// Only find places inside this box
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_SEARCH_BBOX",
+        value_name = "MIN_LON,MIN_LAT,MAX_LON,MAX_LAT"
+    )]
+    search_bbox: Option<search::Bbox>,
+    /// Only find places in this country (ISO 3166-1 alpha-2, like US)
+    #[arg(long, env = "FLIPMAP_BACKEND_SEARCH_COUNTRY", value_name = "CODE")]
+    search_country: Option<String>,
+    /// Largest upstream response body we'll read, in bytes. Bigger ones fail the request
+    #[arg(long, env = "FLIPMAP_BACKEND_MAX_UPSTREAM_BODY", value_name = "BYTES", default_value_t = requester::DEFAULT_MAX_BODY)]
+    max_upstream_body: usize,
+    /// Save trips in this SQLite database, and serve /trips
+    #[arg(long, env = "FLIPMAP_BACKEND_TRIPS_DB", value_name = "FILE")]
+    trips_db: Option<std::path::PathBuf>,
+    /// How long saved trips are kept
+    #[arg(long, env = "FLIPMAP_BACKEND_TRIP_TTL_HOURS", value_name = "HOURS", default_value_t = trips::DEFAULT_TTL.as_secs() / 3600)]
+    trip_ttl_hours: u64,
+    /// Most trip data kept at once, in megabytes. Saving fails past it
+    #[arg(long, env = "FLIPMAP_BACKEND_TRIPS_MAX_MB", value_name = "MB", default_value_t = trips::DEFAULT_MAX_BYTES / (1024 * 1024))]
+    trips_max_mb: u64,
+    /// How politely to use Photon: komoot-public (photon.komoot.io), nominatim-public (one call a
+    /// second, one at a time) or self-hosted (no limits). Sets our limits on Photon calls
+    #[arg(
+        long,
+        env = "FLIPMAP_BACKEND_PHOTON_PRESET",
+        value_name = "PRESET",
+        default_value = "komoot-public"
+    )]
+    photon_preset: etiquette::Preset,
+    /// ORS calls in flight at once. Later ones wait for a slot. Uncapped if unset
+    #[arg(long, env = "FLIPMAP_BACKEND_ORS_CONCURRENCY", value_name = "CALLS", value_parser = clap::value_parser!(u64).range(1..))]
+    ors_concurrency: Option<u64>,
+    /// Photon calls in flight at once, in place of --photon-preset's
+    #[arg(long, env = "FLIPMAP_BACKEND_PHOTON_CONCURRENCY", value_name = "CALLS", value_parser = clap::value_parser!(u64).range(1..))]
+    photon_concurrency: Option<u64>,
+    /// Hold back from each Photon limit window as many calls as were given back in the one before
+    /// (when a later limit turned them away), so contention can't push us over
+    #[arg(long, env = "FLIPMAP_BACKEND_COMPENSATE_UNDO")]
+    compensate_undo: bool,
+    /// Names us to ORS and Photon in the User-Agent, before --contact
+    #[arg(long, env = "FLIPMAP_BACKEND_USER_AGENT", value_name = "PRODUCT", default_value = requester::DEFAULT_PRODUCT)]
+    user_agent: String,
+    /// Email address or http(s) URL ORS and Photon can reach whoever runs this at, sent in the
+    /// User-Agent. Required to serve, unless both are self-hosted; their usage policies ask for it
+    #[arg(long, env = "FLIPMAP_BACKEND_CONTACT", value_name = "EMAIL_OR_URL")]
+    contact: Option<requester::Contact>,
+    /// Keep backoffs ORS and Photon ask for (429s, 503s) in this file, so a restart doesn't
+    /// retry before they're up
+    #[arg(long, env = "FLIPMAP_BACKEND_BACKOFF_FILE", value_name = "FILE")]
+    backoff_file: Option<std::path::PathBuf>,
+    /// Answer cached routes and searches up to this long past their freshness straight away,
+    /// refreshing them in the background. 0 turns it off
+    #[arg(long, env = "FLIPMAP_BACKEND_MAX_STALE_SECS", value_name = "SECS", default_value_t = revalidate::DEFAULT_MAX_STALE.as_secs())]
+    max_stale_secs: u64,
+    /// Feature flags, as JSON. Re-read whenever it changes. Without it every flag is at its
+    /// default
+    #[arg(long, env = "FLIPMAP_BACKEND_FLAGS_FILE", value_name = "FILE")]
+    flags_file: Option<std::path::PathBuf>,
+    /// Terms, privacy notes, data sources and contact details served at /about, as JSON. Re-read
+    /// whenever it changes. Without it /about only lists the providers
+    #[arg(long, env = "FLIPMAP_BACKEND_ABOUT_FILE", value_name = "FILE")]
+    about_file: Option<std::path::PathBuf>,
+    /// Also ask this OSRM-compatible engine for some of the routes fetched from ORS, comparing
+    /// the two in logs and metrics. For trying out an engine before switching to it
+    #[arg(long, env = "FLIPMAP_BACKEND_SHADOW_OSRM", value_name = "URL")]
+    shadow_osrm: Option<reqwest::Url>,
+    /// Share of routes fetched from ORS to shadow, in percent
+    #[arg(long, env = "FLIPMAP_BACKEND_SHADOW_PERCENT", value_name = "PERCENT", default_value_t = shadow::DEFAULT_PERCENT)]
+    shadow_percent: f64,
+    /// Most shadow calls a day
+    #[arg(long, env = "FLIPMAP_BACKEND_SHADOW_DAILY_CAP", value_name = "N", default_value_t = shadow::DEFAULT_DAILY_CAP)]
+    shadow_daily_cap: u32,
+    /// Route requests whose ends share geohash cells this many characters long (1 to 12; 9 is
+    /// about 5 meters) share a cached route. Exact positions only if unset
+    #[arg(long, env = "FLIPMAP_BACKEND_ROUTE_KEY_GEOHASH", value_name = "LEN", value_parser = clap::value_parser!(u64).range(1..=12))]
+    route_key_geohash: Option<u64>,
+    /// Ditto for searches around positions (6 is about a kilometer)
+    #[arg(long, env = "FLIPMAP_BACKEND_SEARCH_KEY_GEOHASH", value_name = "LEN", value_parser = clap::value_parser!(u64).range(1..=12))]
+    search_key_geohash: Option<u64>,
+    /// Look up where each route fetched from ORS ends in the background, so /reverse has it
+    /// cached by arrival, while at least this percent of Photon's quota is left
+    #[arg(long, env = "FLIPMAP_BACKEND_PREFETCH_DESTINATIONS", value_name = "MIN_QUOTA_PERCENT",
+          value_parser = clap::value_parser!(u8).range(0..=100))]
+    prefetch_destinations: Option<u8>,
+    /// Share links each client may make per hour
+    #[arg(long, env = "FLIPMAP_BACKEND_SHARES_PER_HOUR", value_name = "N", default_value_t = share::DEFAULT_PER_HOUR)]
+    shares_per_hour: u32,
+    /// Credit for ORS's data in answers built from it, before any ORS sends itself. Empty for
+    /// just ORS's own
+    #[arg(long, env = "FLIPMAP_BACKEND_ORS_ATTRIBUTION", value_name = "TEXT", default_value = attribution::DEFAULT_ORS)]
+    ors_attribution: String,
+    /// Ditto for Photon's
+    #[arg(long, env = "FLIPMAP_BACKEND_PHOTON_ATTRIBUTION", value_name = "TEXT", default_value = attribution::DEFAULT_PHOTON)]
+    photon_attribution: String,
+    /// Languages our Photon has place names in, for `Accept-Language` to pick from. Empty to
+    /// always use Photon's default, names as they're known locally
+    #[arg(long, env = "FLIPMAP_BACKEND_PHOTON_LANGUAGES", value_name = "LANG", value_delimiter = ',', default_value = language::DEFAULT_PHOTON)]
+    photon_languages: Vec<String>,
+    /// The binary leaves this empty and reads `ORS_API_KEY`. Embedders can fill it in instead
+    #[arg(skip)]
+    pub ors_key: Option<secrecy::SecretString>,
+    /// Lets /admin/log_level change the filter. See [tracing_subscribe]
+    #[arg(skip)]
+    pub log_filter: Option<FilterHandle>,
+}
+
+/// Location independent (just checks environment variable) tracing setup that can be called from
+/// unit tests if desired. Returns a handle for swapping the filter at runtime, see [log_level]
+pub fn tracing_subscribe() -> FilterHandle {
+    // Filter only the fmt layer. The console layer wants tokio's trace-level spans regardless
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        format!(
+            "{}=debug,tower_http=debug,axum=trace,hyper_util=warn",
+            env!("CARGO_CRATE_NAME")
+        )
+        .into()
+    });
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+            .with_thread_ids(true)
+            .with_filter(filter),
+    );
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
+    handle
+}
+
+/// What [assemble] puts together. [build_app] fills it in from [Config]
+struct AppParts {
+    /// The providers, before maintenance gating and metering are added
+    client: Arc<dyn ExternalApi>,
+    maintenance: maintenance::Maintenance,
+    ledger: usage::Ledger,
+    captures: Option<capture::Captures>,
+    metrics: bool,
+    /// Serve the /admin endpoints, to holders of this token
+    admin_token: Option<secrecy::SecretString>,
+    /// Served at /admin/log_level, if there are admin endpoints
+    log_levels: Option<log_level::LogLevels>,
+    /// Served at /admin/outbound, if there are admin endpoints
+    audit: Option<audit::Audit>,
+    /// Served at /admin/ors_key, if there are admin endpoints
+    rotating: Option<Arc<rotation::Rotating>>,
+    reporter: Option<report::Reporter>,
+    access_log: access_log::AccessLog,
+    ban_abusers: bool,
+    /// Requests slower than this get a warning. See [timing]
+    slow_request: Duration,
+    search_defaults: search::SearchDefaults,
+    /// Serves /trips and /share, if set
+    trips: Option<trips::TripStore>,
+    shares_per_hour: u32,
+    /// See [revalidate]
+    max_stale: Duration,
+    /// Served at /version
+    build_info: version::BuildInfo,
+    /// Gates the newer endpoints
+    flags: flags::Flags,
+    /// Served at /about
+    about: about::About,
+    shadow: Option<shadow::Shadow>,
+    /// Least percent of Photon's quota left for [prefetch] to go ahead. Off if unset
+    prefetch_destinations: Option<u8>,
+    /// What the caches are charged to. See [memory]
+    budget: memory::Budget,
+    /// How upstream answers are read. See [geojson_ext]
+    parse_mode: ParseMode,
+    /// How much nearby requests share cached answers
+    key_precision: geo::KeyPrecision,
+    /// Credited on answers. See [attribution]
+    credits: attribution::Credits,
+    /// What Accept-Language is matched against. See [language]
+    photon_languages: language::PhotonLanguages,
+}
+
+#[cfg(test)]
+impl AppParts {
+    /// The bare service, with everything optional left off
+    fn new(client: Arc<dyn ExternalApi>) -> Self {
+        let maintenance = maintenance::Maintenance::default();
+        AppParts {
+            client,
+            ledger: usage::Ledger::new(Default::default(), maintenance.clone()),
+            maintenance,
+            captures: None,
+            metrics: false,
+            admin_token: None,
+            log_levels: None,
+            audit: None,
+            rotating: None,
+            reporter: None,
+            access_log: access_log::AccessLog::new(false),
+            ban_abusers: false,
+            slow_request: timing::DEFAULT_SLOW_REQUEST,
+            search_defaults: Default::default(),
+            trips: None,
+            shares_per_hour: share::DEFAULT_PER_HOUR,
+            max_stale: revalidate::DEFAULT_MAX_STALE,
+            build_info: version::BuildInfo::new(&[], &"http://photon.test".parse().unwrap()),
+            flags: Default::default(),
+            about: Default::default(),
+            shadow: None,
+            prefetch_destinations: None,
+            budget: Default::default(),
+            parse_mode: ParseMode::Lenient,
+            key_precision: Default::default(),
+            credits: Default::default(),
+            photon_languages: Default::default(),
+        }
+    }
+}
+
+/// The service's two routers. They share state, so build them together
+pub struct App {
+    /// Serve with connect info so clients can be told apart
+    pub public: Router,
+    /// The /admin endpoints, if configured. Keep them off the public listener
+    pub admin: Option<Router>,
+}
+
+/// The whole service, minus the listeners
+fn assemble(parts: AppParts) -> App {
+    let AppParts {
+        client,
+        maintenance,
+        ledger,
+        captures,
+        metrics,
+        admin_token,
+        log_levels,
+        audit,
+        rotating,
+        reporter,
+        access_log,
+        ban_abusers,
+        slow_request,
+        search_defaults,
+        trips,
+        shares_per_hour,
+        max_stale,
+        build_info,
+        flags,
+        about,
+        shadow,
+        prefetch_destinations,
+        budget,
+        parse_mode,
+        key_precision,
+        credits,
+        photon_languages,
+    } = parts;
+    // Maintenance goes outside so calls it turns away aren't counted
+    let client: Arc<dyn ExternalApi> = Arc::new(maintenance::Gated::new(
+        Arc::new(usage::Metered::new(client, ledger.clone())),
+        maintenance.clone(),
+    ));
+    tracing::trace!("created reqwest client: {:?}", &client);
+
+    let route_cache = RouteCache::new(ROUTE_CACHE_TTL, ROUTE_CACHE_CAPACITY).with_budget(
+        &budget,
+        "route",
+        memory::json_size,
+    );
+    let degraded = degraded::Degraded::new(client.clone());
+    let admin = admin_token.map(|token| {
+        admin::router(admin::AdminParts {
+            client: client.clone(),
+            degraded: degraded.clone(),
+            maintenance: maintenance.clone(),
+            ledger: ledger.clone(),
+            route_cache: route_cache.clone(),
+            log_levels,
+            audit,
+            rotating,
+            flags: flags.clone(),
+            token,
+        })
+    });
+    let trip_state = trips.clone().map(|trips| TripState {
+        client: client.clone(),
+        route_cache: route_cache.clone(),
+        trips,
+        share_limiter: share::ShareLimiter::new(shares_per_hour),
+        access_log: access_log.clone(),
+        ledger: ledger.clone(),
+        parse_mode,
+        key_precision,
+        credits: credits.clone(),
+    });
+    let reverse_cache = prefetch::ReverseCache::new(
+        prefetch::REVERSE_CACHE_TTL,
+        prefetch::REVERSE_CACHE_CAPACITY,
+    )
+    .with_budget(&budget, "reverse", |features| {
+        memory::json_size(&**features)
+    });
+    let borders = borders::Borders::new(
+        client.clone(),
+        borders::CountryCache::new(borders::COUNTRY_CACHE_TTL, borders::COUNTRY_CACHE_CAPACITY)
+            .with_budget(&budget, "borders", |country| {
+                country.as_ref().map_or(0, String::len)
+            }),
+        ledger.clone(),
+        parse_mode,
+    );
+    let prefetch = prefetch_destinations.map(|percent| {
+        prefetch::Prefetch::new(
+            client.clone(),
+            reverse_cache.clone(),
+            ledger.clone(),
+            percent.into(),
+        )
+    });
+    let state = AppState {
+        client,
+        route_cache,
+        search_cache: SearchCache::new(SEARCH_CACHE_TTL, SEARCH_CACHE_CAPACITY).with_budget(
+            &budget,
+            "search",
+            |search| memory::json_size(&search.places),
+        ),
+        search_defaults,
+        favorites_cache: favorites::FavoritesCache::new(
+            favorites::FAVORITES_CACHE_TTL,
+            favorites::FAVORITES_CACHE_CAPACITY,
+        )
+        .with_budget(&budget, "favorites", favorites::Lookup::weight),
+        ledger: ledger.clone(),
+        staleness: Staleness {
+            degraded,
+            revalidator: revalidate::Revalidator::new(max_stale),
+        },
+        trips,
+        shadow,
+        reverse_cache,
+        prefetch,
+        borders,
+        parse_mode,
+        key_precision,
+        credits: credits.clone(),
+        photon_languages,
+    };
+    let flagged = |name| {
+        axum::middleware::from_fn_with_state(
+            flags::Gate {
+                flags: flags.clone(),
+                name,
+            },
+            flags::require,
+        )
+    };
+    let mut api = Router::new()
+        .route("/route", post(route))
+        .route(
+            "/route/compare",
+            post(compare_routes).route_layer(flagged("route_compare")),
+        )
+        .route("/reroute", post(reroute).route_layer(flagged("reroute")))
+        .route(
+            "/off_route",
+            post(off_route).route_layer(flagged("off_route")),
+        )
+        .route("/get_locations", post(get_locations))
+        .route("/reverse", post(reverse))
+        .route("/favorites", post(favorites))
+        .with_state(state);
+    if let Some(trip_state) = trip_state {
+        api = api.merge(
+            Router::new()
+                .route("/trips", post(save_trip))
+                .route("/trips/{id}", get(get_trip))
+                .route("/share", post(create_share))
+                .route("/share/{token}", get(get_share))
+                .with_state(trip_state),
+        );
+    }
+    let mut api = api
+        .route_layer(axum::middleware::from_fn(deadline::enforce))
+        .route_layer(axum::middleware::from_fn_with_state(
+            ledger.clone(),
+            usage::count_requests,
+        ));
+    if let Some(captures) = captures {
+        api = api.route_layer(axum::middleware::from_fn_with_state(
+            captures,
+            capture::capture_failures,
+        ));
+    }
+    let mut app: Router = api
+        .route_layer(axum::middleware::from_fn_with_state(
+            maintenance.clone(),
+            maintenance::gate,
+        ))
+        .route("/health", get(maintenance::health))
+        .with_state(maintenance.clone())
+        .route("/version", get(version::get_version).with_state(build_info))
+        .route(
+            "/about",
+            get(about::get_about).with_state(about.with_credits(credits)),
+        )
+        .route("/errors", get(error::get_errors));
+    if metrics {
+        app = app.route("/metrics", get(metrics::serve_metrics));
+    }
+    let mut app = app
+        .route_layer(axum::middleware::from_fn_with_state(
+            slow_request,
+            timing::track,
+        ))
+        .layer(CatchPanicLayer::custom(panics::handle_panic));
+    if let Some(reporter) = reporter {
+        app = app.layer(axum::middleware::from_fn_with_state(
+            reporter,
+            report::report_errors,
+        ));
+    }
+    if ban_abusers {
+        app = app.layer(axum::middleware::from_fn_with_state(
+            abuse::AbuseGuard::new(access_log.clone(), abuse::Thresholds::default()),
+            abuse::guard,
+        ));
+    }
+    let public = app
+        .layer(axum::middleware::from_fn_with_state(
+            access_log,
+            access_log::log_access,
+        ))
+        // Everything logged while handling a request lands in this span, request id included
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|req: &Request<_>| {
+                let request_id = req
+                    .extensions()
+                    .get::<RequestId>()
+                    .and_then(|id| id.header_value().to_str().ok())
+                    .unwrap_or("none");
+                tracing::debug_span!(
+                    "request",
+                    method = %req.method(),
+                    uri = %req.uri(),
+                    request_id,
+                    // Filled in by access_log if there's a GeoIP database
+                    country = tracing::field::Empty,
+                )
+            }),
+        )
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+    App { public, admin }
+}
+
+/// The whole service as configured, minus the listener. Needs a Tokio runtime for its background
+/// tasks, and installs the process-wide panic hook, so build one per process.
+///
+/// Panics if there's no ORS key in `config` or `ORS_API_KEY`.
+pub fn build_app(opts: Config) -> App {
+    tracing::trace!("building app from: {:?}", &opts);
+    let ors_key = opts
+        .ors_key()
+        .expect("Place an Open Route Service API key in ORS_API_KEY env variable!");
+
+    // Re-used Reqwest client for external API calls
+    let mut builder = opts.requester(ors_key);
+    if let Some(dir) = opts.record_fixtures {
+        tracing::warn!("recording upstream responses to {dir:?}. don't do this in production!");
+        builder = builder.with_recorder(dir);
+    }
+    let ors_bases: Vec<_> = std::iter::once(opts.ors_base.clone())
+        .chain(opts.ors_fallback.iter().cloned())
+        .collect();
+    let build_info = version::BuildInfo::new(&ors_bases, &opts.photon_base);
+    if !opts.ors_fallback.is_empty() {
+        let regions = regions::OrsRegions::new(&ors_bases);
+        regions.spawn_prober(requester::user_agent(
+            &opts.user_agent,
+            opts.contact.as_ref(),
+        ));
+        builder = builder.with_ors_regions(regions);
+    }
+    if let Some(path) = opts.backoff_file {
+        builder = builder.with_backoff_file(retry_after::BackoffFile::load(path));
+    }
+    let budget = opts.memory_budget_mb.map_or_else(Default::default, |mb| {
+        memory::Budget::new(mb as usize * 1024 * 1024)
+    });
+    let audit = opts
+        .audit_outbound
+        .map(|n| audit::Audit::new(n as usize).with_budget(&budget));
+    if let Some(audit) = &audit {
+        builder = builder.with_audit(audit.clone());
+    }
+    let maintenance = maintenance::Maintenance::default();
+    let caps = [
+        (Provider::OpenRouteService, opts.ors_daily_cap),
+        (Provider::Photon, opts.photon_daily_cap),
+    ]
+    .into_iter()
+    .filter_map(|(provider, cap)| Some((provider, cap?)))
+    .collect();
+    let captures = opts.capture_failures.map(|dir| {
+        tracing::warn!("capturing failed requests to {dir:?}. they include locations and searches");
+        capture::Captures::new(dir, opts.capture_keep)
+    });
+    // Only with somewhere to serve them. check() complains about the token
+    let admin_token = opts
+        .admin_token
+        .filter(|_| opts.admin_listen.is_some() || opts.admin_socket.is_some());
+    if admin_token.is_none() && audit.is_some() {
+        tracing::warn!("--audit-outbound does nothing without admin endpoints");
+    }
+    panics::install_backtrace_hook();
+    let reporter = opts.error_webhook.map(|webhook| {
+        tracing::info!(
+            "reporting errors to webhook at {}",
+            webhook.origin().ascii_serialization()
+        );
+        let reporter = report::Reporter::new(webhook);
+        reporter.install_panic_hook();
+        reporter
+    });
+    let mut access_log = access_log::AccessLog::new(opts.trust_forwarded_for);
+    if let Some(path) = opts.geoip_db {
+        let geoip = geoip::GeoIp::open(&path)
+            .unwrap_or_else(|e| panic!("couldn't load GeoIP database {path:?}: {e}"));
+        tracing::info!("tagging requests by origin using {geoip:?}");
+        access_log = access_log.with_geoip(geoip);
+    }
+    let trips = opts.trips_db.map(|path| {
+        let store = trips::TripStore::open(
+            &path,
+            Duration::from_secs(opts.trip_ttl_hours * 3600),
+            opts.trips_max_mb * 1024 * 1024,
+        )
+        .unwrap_or_else(|e| panic!("couldn't open trip database {path:?}: {e}"));
+        tracing::info!("saving trips to {path:?}");
+        store
+    });
+    let flags = opts.flags_file.map_or_else(Default::default, |path| {
+        flags::Flags::watch(path.clone())
+            .unwrap_or_else(|e| panic!("couldn't load feature flags from {path:?}: {e}"))
+    });
+    let about = opts.about_file.map_or_else(Default::default, |path| {
+        about::About::watch(path.clone())
+            .unwrap_or_else(|e| panic!("couldn't load the about page from {path:?}: {e}"))
+    });
+    let shadow = opts.shadow_osrm.map(|base| {
+        shadow::Shadow::new(
+            base,
+            opts.shadow_percent,
+            opts.shadow_daily_cap,
+            &requester::user_agent(&opts.user_agent, opts.contact.as_ref()),
+        )
+    });
+    let rotating = Arc::new(rotation::Rotating::new(builder));
+    rotating.spawn_window_reports();
+    if let Some(secs) = opts.keepalive_secs {
+        if secs >= opts.pool_idle_timeout_secs {
+            tracing::warn!("--keepalive-secs is past --pool-idle-timeout-secs, so connections will close between pings");
+        }
+        keepalive::spawn(rotating.clone(), Duration::from_secs(secs));
+    }
+    assemble(AppParts {
+        client: rotating.clone(),
+        ledger: usage::Ledger::new(caps, maintenance.clone()),
+        maintenance,
+        captures,
+        metrics: opts.metrics,
+        admin_token,
+        log_levels: opts.log_filter.map(log_level::LogLevels::new),
+        audit,
+        rotating: Some(rotating),
+        reporter,
+        access_log,
+        ban_abusers: opts.ban_abusers,
+        slow_request: Duration::from_millis(opts.slow_request_ms),
+        search_defaults: search::SearchDefaults {
+            bias: opts.search_bias,
+            bbox: opts.search_bbox,
+            country: opts.search_country.map(|c| c.to_ascii_uppercase()),
+        },
+        trips,
+        shares_per_hour: opts.shares_per_hour,
+        max_stale: Duration::from_secs(opts.max_stale_secs),
+        build_info,
+        flags,
+        about,
+        shadow,
+        prefetch_destinations: opts.prefetch_destinations,
+        budget,
+        parse_mode: opts.parse_mode,
+        key_precision: geo::KeyPrecision {
+            route: opts.route_key_geohash.map(|n| n as usize),
+            search: opts.search_key_geohash.map(|n| n as usize),
+        },
+        credits: attribution::Credits {
+            ors: opts.ors_attribution,
+            photon: opts.photon_attribution,
+        },
+        photon_languages: language::PhotonLanguages::new(&opts.photon_languages),
+    })
+}
+
+impl Config {
+    /// The key from [Config::ors_key], or failing that `ORS_API_KEY`
+    fn ors_key(&self) -> Option<secrecy::SecretString> {
+        self.ors_key
+            .clone()
+            .or_else(|| env::var("ORS_API_KEY").ok().map(Into::into))
+    }
+
+    /// The upstream client as configured, minus recording and auditing
+    fn requester(&self, ors_key: secrecy::SecretString) -> ExternalRequesterBuilder {
+        let builder =
+            ExternalRequesterBuilder::new(self.ors_base.clone(), self.photon_base.clone(), ors_key)
+                .with_photon_etiquette(self.photon_preset)
+                .with_undo_compensation(self.compensate_undo);
+        let builder = match &self.contact {
+            Some(contact) => builder.with_user_agent(&self.user_agent, contact),
+            None => builder,
+        };
+        let builder = [
+            (Provider::OpenRouteService, self.ors_concurrency),
+            (Provider::Photon, self.photon_concurrency),
+        ]
+        .into_iter()
+        .fold(builder, |builder, (provider, calls)| match calls {
+            Some(calls) => builder.with_concurrency(provider, calls as usize),
+            None => builder,
+        });
+        builder
+            .with_timeouts(Provider::OpenRouteService, self.ors_timeouts)
+            .with_timeouts(Provider::Photon, self.photon_timeouts)
+            .with_http_version(Provider::OpenRouteService, self.ors_http)
+            .with_http_version(Provider::Photon, self.photon_http)
+            .with_max_body(self.max_upstream_body)
+            .with_pool(requester::PoolOptions {
+                max_idle_per_host: self.pool_max_idle_per_host,
+                idle_timeout: Duration::from_secs(self.pool_idle_timeout_secs),
+                tcp_keepalive: (self.tcp_keepalive_secs > 0)
+                    .then(|| Duration::from_secs(self.tcp_keepalive_secs)),
+            })
+    }
+
+    /// For [runtime], which has to be built before anything else runs
+    pub fn workers(&self) -> Option<usize> {
+        self.workers.map(|n| n as usize)
+    }
+
+    /// Whatever would stop [run] short of failing to listen, one line each. Empty if it's fine
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.listen_addrs().is_empty() {
+            problems.push("nowhere to listen: give IP PORT or --listen".to_owned());
+        }
+        if self.ors_key().is_none() {
+            problems.push("no Open Route Service key: set ORS_API_KEY".to_owned());
+        }
+        let user_agent = requester::user_agent(&self.user_agent, self.contact.as_ref());
+        let public_ors = self.ors_base.host_str() == Some(PUBLIC_ORS_HOST);
+        let needs_contact = public_ors || self.photon_preset.etiquette().needs_contact;
+        if self.contact.is_none() && needs_contact {
+            problems.push(
+                "no contact for the upstream User-Agent: set FLIPMAP_BACKEND_CONTACT".to_owned(),
+            );
+        } else if HeaderValue::from_str(&user_agent).is_err() {
+            problems.push(format!("{user_agent:?} can't be sent as a User-Agent"));
+        }
+        if let Some(path) = &self.geoip_db {
+            if let Err(e) = geoip::GeoIp::open(path) {
+                problems.push(format!("couldn't load GeoIP database {path:?}: {e}"));
+            }
+        }
+        if let Some(path) = &self.trips_db {
+            let (ttl, max) = (Duration::ZERO, 0);
+            if let Err(e) = trips::TripStore::open(path, ttl, max) {
+                problems.push(format!("couldn't open trip database {path:?}: {e}"));
+            }
+        }
+        if let Some(path) = &self.about_file {
+            if let Err(e) = about::read(path) {
+                problems.push(e);
+            }
+        }
+        let admin = self.admin_listen.is_some() || self.admin_socket.is_some();
+        if admin && self.admin_token.is_none() {
+            problems
+                .push("admin endpoints need a token: set FLIPMAP_BACKEND_ADMIN_TOKEN".to_owned());
+        }
+        if cfg!(not(unix)) && self.admin_socket.is_some() {
+            problems.push("--admin-socket only works on unix".to_owned());
+        }
+        problems
+    }
+
+    /// Everywhere to listen: IP PORT, then each --listen
+    pub fn listen_addrs(&self) -> Vec<net::SocketAddr> {
+        let mut addrs: Vec<net::SocketAddr> =
+            self.ip.zip(self.port).map(Into::into).into_iter().collect();
+        for addr in &self.listen {
+            if !addrs.contains(addr) {
+                addrs.push(*addr);
+            }
+        }
+        addrs
+    }
+}
+
+/// Binds `addr`. IPv6 sockets are made IPv6-only when `v6_only`, otherwise `[::]` would also take
+/// IPv4 on most systems and collide with a `0.0.0.0` on the same port
+fn bind(addr: net::SocketAddr, v6_only: bool) -> std::io::Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // Same as Tokio does, so restarts don't trip over TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Binds a unix socket at `path`, first clearing out one left over from an earlier run
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+/// Calls each upstream once as [build_app] would set it up, printing how each call went. False if
+/// any failed, or there's no ORS key to try with
+pub async fn probe(config: &Config) -> bool {
+    let Some(ors_key) = config.ors_key() else {
+        println!("no Open Route Service key: set ORS_API_KEY");
+        return false;
+    };
+    let client = config.requester(ors_key).build();
+    let outcomes = probe::call_each(&client).await;
+    for outcome in &outcomes {
+        println!("{outcome}");
+    }
+    outcomes.iter().all(|o| o.result.is_ok())
+}
+
+/// The OpenAPI spec for the public endpoints, as JSON
+pub fn openapi_json() -> String {
+    openapi::spec_json()
+}
+
+/// Serves [build_app] on every configured address, and the admin endpoints on theirs, until
+/// something goes badly wrong with any of them
+pub async fn run(config: Config) {
+    let addrs = config.listen_addrs();
+    let (admin_listen, admin_socket) = (config.admin_listen, config.admin_socket.clone());
+    let App { public, admin } = build_app(config);
+    let mut servers = tokio::task::JoinSet::new();
+    for &addr in &addrs {
+        let listener = bind(addr, addrs.len() > 1)
+            .unwrap_or_else(|e| panic!("couldn't listen on {addr}: {e}"));
+        tracing::info!("starting server on {addr}");
+        let service = public
+            .clone()
+            .into_make_service_with_connect_info::<net::SocketAddr>();
+        servers.spawn(async move { axum::serve(listener, service).await });
+    }
+    if let Some(admin) = admin {
+        if let Some(addr) = admin_listen {
+            let listener = bind(addr, false)
+                .unwrap_or_else(|e| panic!("couldn't listen on {addr} for admin: {e}"));
+            tracing::info!("serving admin endpoints on {addr}");
+            servers.spawn(async move { axum::serve(listener, admin).await });
+        } else if let Some(path) = admin_socket {
+            #[cfg(unix)]
+            {
+                let listener = bind_unix(&path)
+                    .unwrap_or_else(|e| panic!("couldn't listen on {path:?} for admin: {e}"));
+                tracing::info!("serving admin endpoints on {path:?}");
+                servers.spawn(async move { axum::serve(listener, admin).await });
+            }
+            #[cfg(not(unix))]
+            panic!("--admin-socket {path:?} only works on unix");
+        }
+    }
+    if let Some(stopped) = servers.join_next().await {
+        stopped
+            .expect("server task panicked")
+            .expect("server stopped");
+    }
+}
+
+/// Stable across restarts, unlike [std::hash::DefaultHasher], since clients hold on to it.
+/// FNV-1a over the [canonical] coordinates' bits, in cells `len` long if set (see
+/// [geo::KeyPrecision])
+fn cache_key(params: &RouteRequest, len: Option<usize>) -> u64 {
+    let params = canonical::route(params);
+    let src = geo::key_position((params.src_lat, params.src_lon), len);
+    let dst = geo::key_position((params.dst_lat, params.dst_lon), len);
+    [src.0, src.1, dst.0, dst.1]
+        .iter()
+        // Only when there is one, so keys without stay what they were
+        .chain(&params.heading)
+        .flat_map(|c| c.to_bits().to_le_bytes())
+        // Likewise
+        .chain(params.annotations.then_some(1))
+        .chain(params.borders.then_some(2))
+        .chain(params.elevation.then_some(3))
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+impl versioning::Versioned for RouteResponse {
+    type V2 = RouteResponseV2;
+    fn has_route(&self) -> bool {
+        true
+    }
+    fn into_v2(self) -> RouteResponseV2 {
+        // Flattened positions are lon, lat
+        let route = self
+            .route
+            .chunks_exact(2)
+            .map(|pos| RoutePoint {
+                lat: pos[1],
+                lon: pos[0],
+            })
+            .collect();
+        RouteResponseV2 {
+            route,
+            annotations: self.annotations,
+            borders: self.borders,
+            elevation: self.elevation,
+            snapped: self.snapped,
+            attribution: self.attribution,
+        }
+    }
+}
+
+/// Simple point-to-point route that takes a single starting and ending position.
+///
+/// Routes are cached for a while. See [IF_ROUTE_UNCHANGED] for the app's background refreshes.
+#[utoipa::path(
+    post,
+    path = "/route",
+    request_body = RouteRequest,
+    params(
+        ("if-route-unchanged" = Option<String>, Header,
+         description = "x-route-hash from an earlier answer. Gets a 304 if the route is still cached"),
+        ("cache-control" = Option<String>, Header,
+         description = "no-cache for a fresh route, or max-age=<secs> to cap the cached one's age")
+    ),
+    responses(
+        (status = 200,
+         content(
+             (RouteResponse = "application/json"),
+             (RouteResponseV2 = "application/vnd.flipmap.v2+json"),
+         ),
+         headers(("x-route-hash" = String, description = "Send back in if-route-unchanged"))),
+        (status = 304, description = "Same route as if-route-unchanged"),
+        (status = 422, body = error::ErrorResponse, description = "Bad coordinates"),
+        (status = 500, body = error::ErrorResponse, description = "ORS failed us"),
+        (status = 503, body = error::ErrorResponse,
+         description = "Rate limited, in maintenance, or upstream is out and this isn't cached. \
+                        Has Retry-After"),
+        (status = 504, description = "X-Request-Deadline-Ms ran out"),
+    )
+)]
+#[instrument(
+    level = "debug",
+    skip(
+        client,
+        cache,
+        precision,
+        ledger,
+        degraded,
+        revalidator,
+        shadow,
+        prefetch,
+        borders,
+        mode,
+        credits,
+        languages,
+        headers
+    )
+)]
+#[allow(clippy::too_many_arguments)] // Extractors
+async fn route(
+    State(client): State<Arc<dyn ExternalApi>>,
+    State(cache): State<RouteCache>,
+    State(precision): State<geo::KeyPrecision>,
+    State(ledger): State<usage::Ledger>,
+    State(Staleness {
+        degraded,
+        revalidator,
+    }): State<Staleness>,
+    State(shadow): State<Option<shadow::Shadow>>,
+    State(prefetch): State<Option<prefetch::Prefetch>>,
+    State(borders): State<borders::Borders>,
+    State(mode): State<ParseMode>,
+    State(credits): State<attribution::Credits>,
+    State(languages): State<language::PhotonLanguages>,
+    version: ApiVersion,
+    directives: cache_control::CacheDirectives,
+    headers: HeaderMap,
+    ValidatedJson(params): ValidatedJson<RouteRequest>,
+) -> Result<Response> {
+    let key = cache_key(&params, precision.route);
+    let hash = format!("{key:016x}");
+    // Coarse keys share routes between nearby ends, so how far off they are is this request's
+    let cached = cache.get_any(&key).map(|(mut res, age)| {
+        res.snapped = snapped(&params, &res.route);
+        (res, age)
+    });
+    let fresh = cached
+        .as_ref()
+        .is_some_and(|(_, age)| *age < cache.ttl() && directives.accepts(*age));
+    let servable = cached.as_ref().is_some_and(|(_, age)| {
+        revalidator.servable(*age, cache.ttl()) && directives.accepts(*age)
+    });
+    ledger.count_cache("route", fresh || servable);
+    if let Some((cached, _)) = cached.as_ref().filter(|_| fresh) {
+        let unchanged = headers
+            .get(IF_ROUTE_UNCHANGED)
+            .is_some_and(|v| v.as_bytes() == hash.as_bytes());
+        if unchanged {
+            tracing::debug!("route unchanged, answering 304");
+            return Ok((StatusCode::NOT_MODIFIED, [(ROUTE_HASH, hash)]).into_response());
+        }
+        ledger.count_route(geo::flat_line_length_m(&cached.route));
+        return Ok(([(ROUTE_HASH, hash)], Negotiated(version, cached.clone())).into_response());
+    }
+    // Upstream's out: stale or nothing, however old. Otherwise stale only while revalidating
+    let retry_after = degraded.check();
+    let (stale, age) = match cached {
+        Some(stale) if servable => {
+            if retry_after.is_none() {
+                let (client, cache) = (client.clone(), cache.clone());
+                let credits = credits.clone();
+                revalidator.spawn(key, async move {
+                    match fetch_route(&*client, &params, mode, &credits).await {
+                        Ok(mut res) => {
+                            borders.annotate(&params, &mut res).await;
+                            cache.insert(key, res)
+                        }
+                        Err(e) => tracing::debug!("couldn't revalidate route: {e}"),
+                    }
+                });
+            }
+            stale
+        }
+        Some(stale) if retry_after.is_some() => stale,
+        _ => {
+            if let Some(retry_after) = retry_after {
+                return Err(degraded::Degraded::miss(retry_after));
+            }
+            let (mut res, summary) =
+                fetch_summarized_route(&*client, &params, mode, &credits).await?;
+            borders.annotate(&params, &mut res).await;
+            if let Some(shadow) = &shadow {
+                shadow.compare(&params, summary);
+            }
+            if let Some(prefetch) = &prefetch {
+                // In the language the app would ask /reverse in
+                let lang = language::AcceptLanguage::from_headers(&headers).photon(&languages);
+                prefetch.destination(&params, lang);
+            }
+            cache.insert(key, res.clone());
+            ledger.count_route(geo::flat_line_length_m(&res.route));
+            return Ok(([(ROUTE_HASH, hash)], Negotiated(version, res)).into_response());
+        }
+    };
+    tracing::debug!(age_s = age.as_secs(), "answering stale route");
+    ledger.count_route(geo::flat_line_length_m(&stale.route));
+    Ok((
+        degraded::stale_headers(age),
+        [(ROUTE_HASH, hash)],
+        Negotiated(version, stale),
+    )
+        .into_response())
+}
+
+/// How far off [RouteRequest::heading] a road may go and still be started on. Compasses and GPS
+/// headings wander, but not by more than this
+const HEADING_DEVIATION_DEG: f64 = 45.0;
+
+/// The ORS request for `params` by `profile`
+fn ors_request(params: &RouteRequest, profile: Profile) -> OpenRouteRequest {
+    let start_coord: Position = vec![params.src_lon, params.src_lat];
+    let end_coord: Position = vec![params.dst_lon, params.dst_lat];
+    let bearings = params
+        .heading
+        .map(|heading| vec![vec![heading, HEADING_DEVIATION_DEG], vec![]]);
+    OpenRouteRequest {
+        instructions: false,
+        coordinates: vec![start_coord, end_coord],
+        bearings,
+        profile,
+        extra_info: params.annotations,
+        elevation: params.elevation,
+    }
+}
+
+/// Asks ORS for the route, skipping the cache
+async fn fetch_route(
+    client: &dyn ExternalApi,
+    params: &RouteRequest,
+    mode: ParseMode,
+    credits: &attribution::Credits,
+) -> Result<RouteResponse> {
+    let (res, _) = fetch_summarized_route(client, params, mode, credits).await?;
+    Ok(res)
+}
+
+/// [fetch_route], plus ORS's distance and duration for [shadow] to compare against
+async fn fetch_summarized_route(
+    client: &dyn ExternalApi,
+    params: &RouteRequest,
+    mode: ParseMode,
+    credits: &attribution::Credits,
+) -> Result<(RouteResponse, shadow::Summary)> {
+    let req = ors_request(params, Profile::Driving);
+    let route = client.ors_route(&req).await?;
+    let metadata = route.metadata(mode)?;
+    if let Some(metadata) = &metadata {
+        tracing::debug!(
+            engine = metadata.engine.version,
+            graph_date = metadata.engine.graph_date,
+            "ORS engine"
+        );
+    }
+    let props = route.properties(mode)?;
+    tracing::debug!(
+        distance_m = props.summary.distance,
+        duration_s = props.summary.duration,
+        "ORS route"
+    );
+    let summary = shadow::Summary {
+        distance_m: props.summary.distance,
+        duration_s: props.summary.duration,
+        route: route.route.clone(),
+    };
+    let attribution = attribution::ors(credits, metadata.as_ref().map(|m| m.attribution.as_str()));
+    let elevation = params
+        .elevation
+        .then(|| elevation::summary(&route.route, &route.heights))
+        .flatten();
+    let snapped = snapped(params, &route.route);
+    Ok((
+        RouteResponse {
+            route: route.route,
+            annotations: params.annotations.then(|| props.annotations()),
+            // Filled in by the route handler, which has the countries cached
+            borders: None,
+            elevation,
+            snapped,
+            attribution,
+        },
+        summary,
+    ))
+}
+
+/// Where `route` starts and ends, against where `params` asked for
+fn snapped(params: &RouteRequest, route: &[f64]) -> Option<SnappedEnds> {
+    let point = |asked: (f64, f64), pos: &[f64]| {
+        let (lat, lon) = (pos[1], pos[0]);
+        SnappedPoint {
+            lat,
+            lon,
+            deviation_m: geo::haversine_m(asked, (lat, lon)).round(),
+        }
+    };
+    Some(SnappedEnds {
+        src: point((params.src_lat, params.src_lon), route.get(..2)?),
+        dst: point(
+            (params.dst_lat, params.dst_lon),
+            route.get(route.len().checked_sub(2)?..)?,
+        ),
+    })
+}
+
+/// Same in v2
+impl versioning::Versioned for CompareResponse {
+    type V2 = Self;
+    fn into_v2(self) -> Self {
+        self
+    }
+}
+
+/// How long and how far the same trip is by car, bike and on foot, for a mode chooser.
+///
+/// The profiles are asked for at once. One that can't be routed just has no summary but an error,
+/// making the answer a 207; only if none can is it an error. See [partial].
+#[utoipa::path(
+    post,
+    path = "/route/compare",
+    request_body = CompareRequest,
+    responses(
+        (status = 200, body = CompareResponse),
+        (status = 207, body = CompareResponse, description = "Some modes have an error instead"),
+        (status = 422, body = error::ErrorResponse, description = "Bad coordinates or profiles"),
+        (status = 500, body = error::ErrorResponse, description = "ORS failed us"),
+        (status = 503, body = error::ErrorResponse,
+         description = "Rate limited or in maintenance. Has Retry-After"),
+        (status = 504, description = "X-Request-Deadline-Ms ran out"),
+    )
+)]
+#[instrument(level = "debug", skip(client, mode, credits))]
+async fn compare_routes(
+    State(client): State<Arc<dyn ExternalApi>>,
+    State(mode): State<ParseMode>,
+    State(credits): State<attribution::Credits>,
+    version: ApiVersion,
+    ValidatedJson(params): ValidatedJson<CompareRequest>,
+) -> Result<Partial<Negotiated<CompareResponse>>> {
+    let mut profiles = params
+        .profiles
+        .unwrap_or_else(|| vec![Profile::Driving, Profile::Cycling, Profile::Walking]);
+    let mut seen = std::collections::HashSet::new();
+    profiles.retain(|p| seen.insert(*p));
+    let lookups = profiles.iter().map(|&profile| {
+        let req = ors_request(&params.route, profile);
+        let client = &*client;
+        async move {
+            let summary = client.ors_route(&req).await.and_then(|route| {
+                let credit = route.metadata(mode).ok().flatten().map(|m| m.attribution);
+                Ok((route.properties(mode)?.summary, credit))
+            });
+            (profile, summary)
+        }
+    });
+    let results = futures_util::future::join_all(lookups).await;
+    if results.iter().all(|(_, summary)| summary.is_err()) {
+        let (_, first) = results.into_iter().next().expect("at least one profile");
+        return Err(first.expect_err("all failed"));
+    }
+    let failed = results.iter().any(|(_, summary)| summary.is_err());
+    // The profiles' data is all the same ORS's
+    let credit = results
+        .iter()
+        .find_map(|(_, summary)| summary.as_ref().ok().and_then(|(_, credit)| credit.clone()));
+    let modes = results
+        .into_iter()
+        .map(|(profile, summary)| {
+            let summary = summary
+                .map(|(summary, _)| summary)
+                .inspect_err(|e| tracing::debug!(?profile, "no route to compare: {e}"));
+            ModeSummary {
+                profile,
+                distance_m: summary.as_ref().ok().map(|s| s.distance),
+                duration_s: summary.as_ref().ok().map(|s| s.duration),
+                error: summary.as_ref().err().map(RouteError::public),
+            }
+        })
+        .collect();
+    let attribution = attribution::ors(&credits, credit.as_deref());
+    Ok(Partial::new(
+        "/route/compare",
+        failed,
+        Negotiated(version, CompareResponse { modes, attribution }),
+    ))
+}
+
+impl versioning::Versioned for RerouteResponse {
+    type V2 = RerouteResponseV2;
+    fn has_route(&self) -> bool {
+        true
+    }
+    fn into_v2(self) -> RerouteResponseV2 {
+        let RouteResponseV2 { route, .. } = RouteResponse {
+            route: self.route,
+            annotations: None,
+            borders: None,
+            elevation: None,
+            snapped: None,
+            attribution: vec![],
+        }
+        .into_v2();
+        RerouteResponseV2 {
+            on_route: self.on_route,
+            route,
+            rejoin_at: self.rejoin_at,
+            attribution: self.attribution,
+        }
+    }
+}
+
+/// Whether the user is still on the route they're following, and if not, a new one.
+///
+/// Only the new route up to where it rejoins the old one is sent. See [reroute].
+#[utoipa::path(
+    post,
+    path = "/reroute",
+    request_body = RerouteRequest,
+    responses(
+        (status = 200,
+         content(
+             (RerouteResponse = "application/json"),
+             (RerouteResponseV2 = "application/vnd.flipmap.v2+json"),
+         )),
+        (status = 422, body = error::ErrorResponse, description = "Bad position or route"),
+        (status = 500, body = error::ErrorResponse, description = "ORS failed us"),
+        (status = 503, body = error::ErrorResponse,
+         description = "Rate limited or in maintenance. Has Retry-After"),
+        (status = 504, description = "X-Request-Deadline-Ms ran out"),
+    )
+)]
+#[instrument(level = "debug", skip(client, ledger, mode, credits, params))]
+async fn reroute(
+    State(client): State<Arc<dyn ExternalApi>>,
+    State(ledger): State<usage::Ledger>,
+    State(mode): State<ParseMode>,
+    State(credits): State<attribution::Credits>,
+    version: ApiVersion,
+    ValidatedJson(params): ValidatedJson<RerouteRequest>,
+) -> Result<Negotiated<RerouteResponse>> {
+    let position = (params.lat, params.lon);
+    let tolerance_m = params.tolerance_m.unwrap_or(reroute::DEFAULT_TOLERANCE_M);
+    if reroute::on_route(position, &params.route, tolerance_m) {
+        tracing::debug!("still on route");
+        let on_route = RerouteResponse {
+            on_route: true,
+            route: vec![],
+            rejoin_at: None,
+            attribution: vec![],
+        };
+        return Ok(Negotiated(version, on_route));
+    }
+    let destination = &params.route[params.route.len() - 2..];
+    let fresh = fetch_route(
+        &*client,
+        &RouteRequest {
+            src_lat: params.lat,
+            src_lon: params.lon,
+            dst_lat: destination[1],
+            dst_lon: destination[0],
+            heading: params.heading,
+            annotations: false,
+            borders: false,
+            elevation: false,
+        },
+        mode,
+        &credits,
+    )
+    .await?;
+    ledger.count_route(geo::flat_line_length_m(&fresh.route));
+    let (lead_up, rejoin_at) = reroute::rejoin(&params.route, &fresh.route);
+    tracing::debug!(lead_up, rejoin_at, "rerouted");
+    let mut route = fresh.route;
+    route.truncate(lead_up * 2);
+    Ok(Negotiated(
+        version,
+        RerouteResponse {
+            on_route: false,
+            route,
+            rejoin_at: rejoin_at.map(|i| i as u32),
+            attribution: fresh.attribution,
+        },
+    ))
+}
+
+/// Same in v2
+impl versioning::Versioned for OffRouteResponse {
+    type V2 = Self;
+    fn into_v2(self) -> Self {
+        self
+    }
+}
+
+/// How far the user is from a route, so the app doesn't need the geometry to tell if they've
+/// strayed.
+///
+/// The route is sent along, or is that of a trip saved with `POST /trips` (with `--trips-db`).
+/// Never calls upstream.
+#[utoipa::path(
+    post,
+    path = "/off_route",
+    request_body = OffRouteRequest,
+    responses(
+        (status = 200, body = OffRouteResponse),
+        (status = 404, body = error::ErrorResponse, description = "No such trip, or expired"),
+        (status = 422, body = error::ErrorResponse, description = "Bad position or route"),
+    )
+)]
+#[instrument(level = "debug", skip(trips, params))]
+async fn off_route(
+    State(trips): State<Option<trips::TripStore>>,
+    version: ApiVersion,
+    ValidatedJson(params): ValidatedJson<OffRouteRequest>,
+) -> Result<Negotiated<OffRouteResponse>> {
+    let route = match (params.route, params.trip_id) {
+        (Some(route), _) => route,
+        (None, Some(id)) => {
+            let store = trips.ok_or(RouteError::NotFound)?;
+            let saved = with_store(move || store.get(&id))
+                .await?
+                .ok_or(RouteError::NotFound)?;
+            saved.trip.route
+        }
+        // Validation wants one or the other
+        (None, None) => return Err(RouteError::NotFound),
+    };
+    let (distance_m, segment) = geo::distance_to_flat_line_m((params.lat, params.lon), &route)
+        .ok_or(RouteError::NotFound)?;
+    Ok(Negotiated(
+        version,
+        OffRouteResponse {
+            off_route: distance_m > params.max_off_m,
+            distance_m,
+            segment: segment as u32,
+        },
+    ))
+}
+
+/// Same in v2
+impl versioning::Versioned for GetLocationsResponse {
+    type V2 = Self;
+    fn into_v2(self) -> Self {
+        self
+    }
+}
+
+/// Used by the app to search out locations from a given position.
+///
+/// Photon has no offset, so pages are cut from one over-fetched result list, which is kept for a
+/// while so that "show more" doesn't search again. One extra result tells us if there's more.
+#[utoipa::path(
+    post,
+    path = "/get_locations",
+    request_body = GetLocationsRequest,
+    params(
+        ("cache-control" = Option<String>, Header,
+         description = "no-cache for fresh results, or max-age=<secs> to cap the cached ones' age"),
+        ("accept-language" = Option<String>, Header,
+         description = "Language for place names, unless the body has lang. Answered in content-language")
+    ),
+    responses(
+        (status = 200, body = GetLocationsResponse),
+        (status = 422, body = error::ErrorResponse, description = "Bad position or amount"),
+        (status = 500, body = error::ErrorResponse, description = "Photon failed us"),
+        (status = 503, body = error::ErrorResponse,
+         description = "Rate limited, in maintenance, or upstream is out and this isn't cached. \
+                        Has Retry-After"),
+        (status = 504, description = "X-Request-Deadline-Ms ran out"),
+    )
+)]
+#[instrument(
+    level = "debug",
+    skip(
+        client,
+        cache,
+        precision,
+        defaults,
+        ledger,
+        degraded,
+        revalidator,
+        mode,
+        credits,
+        languages
+    )
+)]
+#[allow(clippy::too_many_arguments)] // Extractors
+async fn get_locations(
+    State(client): State<Arc<dyn ExternalApi>>,
+    State(cache): State<SearchCache>,
+    State(precision): State<geo::KeyPrecision>,
+    State(defaults): State<search::SearchDefaults>,
+    State(ledger): State<usage::Ledger>,
+    State(Staleness {
+        degraded,
+        revalidator,
+    }): State<Staleness>,
+    State(mode): State<ParseMode>,
+    State(credits): State<attribution::Credits>,
+    State(languages): State<language::PhotonLanguages>,
+    version: ApiVersion,
+    directives: cache_control::CacheDirectives,
+    accepted: language::AcceptLanguage,
+    ValidatedJson(params): ValidatedJson<GetLocationsRequest>,
+) -> Result<(HeaderMap, Negotiated<GetLocationsResponse>)> {
+    let (offset, amount) = (params.offset as usize, params.amount as usize);
+    let wanted = offset + amount + 1;
+    let bias = defaults.bias(params.lat, params.lon);
+    let lang = language::photon(params.lang.as_deref(), &accepted, &languages)?;
+    let key = search_key(
+        &params.query,
+        bias,
+        params.zoom,
+        lang.as_deref(),
+        precision.search,
+    );
+    let request = |limit: usize| {
+        defaults
+            .request(limit as u8, params.query.clone(), bias, params.zoom)
+            .with_lang(lang.clone())
+    };
+    let mut headers = HeaderMap::new();
+    let enough = |s: &Search| s.exhausted || s.asked >= wanted || s.places.len() >= wanted;
+    let search = match cache
+        .get_any(&key)
+        .filter(|(_, age)| directives.accepts(*age))
+    {
+        Some((search, age)) if age < cache.ttl() && enough(&search) => {
+            tracing::debug!(offset, "search page from cache");
+            ledger.count_cache("search", true);
+            search
+        }
+        Some((search, age))
+            if revalidator.servable(age, cache.ttl())
+                && enough(&search)
+                && degraded.check().is_none() =>
+        {
+            tracing::debug!(
+                offset,
+                age_s = age.as_secs(),
+                "stale search page, revalidating"
+            );
+            ledger.count_cache("search", true);
+            let (client, cache, defaults) = (client.clone(), cache.clone(), defaults.clone());
+            let req = request(search.asked);
+            revalidator.spawn(key, async move {
+                match fetch_places(&*client, &defaults, &req, bias, mode, &credits).await {
+                    Ok(fresh) => cache.insert(key, Arc::new(fresh)),
+                    Err(e) => tracing::debug!("couldn't revalidate search: {e}"),
+                }
+            });
+            headers = degraded::stale_headers(age);
+            search
+        }
+        _ => {
+            ledger.count_cache("search", false);
+            if let Some(retry_after) = degraded.check() {
+                // Whatever pages we have, even if it's fewer than asked for
+                let (stale, age) = cache
+                    .get_any(&key)
+                    .ok_or_else(|| degraded::Degraded::miss(retry_after))?;
+                tracing::debug!(
+                    age_s = age.as_secs(),
+                    "upstream out, answering stale search"
+                );
+                headers = degraded::stale_headers(age);
+                stale
+            } else {
+                let search = Arc::new(
+                    fetch_places(&*client, &defaults, &request(wanted), bias, mode, &credits)
+                        .await?,
+                );
+                cache.insert(key, search.clone());
+                search
+            }
+        }
+    };
+    let results = search
+        .places
+        .iter()
+        .skip(offset)
+        .take(amount)
+        .cloned()
+        .collect();
+    let has_more = search.places.len() > offset + amount;
+    headers.extend(language::content_language(lang.as_deref()));
+    Ok((
+        headers,
+        Negotiated(
+            version,
+            GetLocationsResponse {
+                results,
+                has_more,
+                attribution: search.attribution.clone(),
+            },
+        ),
+    ))
+}
+
+/// Same search, same cached results, whatever the page or the query's case. FNV-1a like
+/// [cache_key]
+fn search_key(
+    query: &str,
+    bias: Option<search::Point>,
+    zoom: Option<u8>,
+    lang: Option<&str>,
+    len: Option<usize>,
+) -> u64 {
+    let bias = bias.map_or([f64::NAN; 2], |b| {
+        let point = (canonical::coord(b.lat), canonical::coord(b.lon));
+        let (lat, lon) = geo::key_position(point, len);
+        [lat, lon]
+    });
+    let query = canonical::query(query);
+    bias.iter()
+        .flat_map(|c| c.to_bits().to_le_bytes())
+        .chain([zoom.unwrap_or(u8::MAX)])
+        // Can't be confused with the query: language codes are a few letters and a nul
+        .chain(lang.unwrap_or("").bytes().chain([0]))
+        .chain(query.bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// Asks Photon for `req`'s places, skipping the cache. `bias` is the position `req` is biased
+/// towards, for scoring
+async fn fetch_places(
+    client: &dyn ExternalApi,
+    defaults: &search::SearchDefaults,
+    req: &PhotonGeocodeRequest,
+    bias: Option<search::Point>,
+    mode: ParseMode,
+    credits: &attribution::Credits,
+) -> Result<Search> {
+    let features = client.photon_send(req).await?;
+    shape::diagnose(
+        &features,
+        Expectation {
+            geometry: GeometryKind::Point,
+            non_empty: false,
+        },
+    )?;
+
+    let mut places = vec![];
+    for (rank, feature) in features.features.iter().enumerate() {
+        let coords = geojson_ext::extract_point(feature)?;
+        let props = photon::PhotonProperties::of(feature, mode)?;
+        if !defaults.allows(&props) {
+            continue;
+        }
+
+        let name = props.name.unwrap_or_else(|| "Unknown".to_owned());
+        let (lat, lon) = (coords[1], coords[0]);
+        let score = scoring::score(
+            &req.query,
+            &name,
+            rank,
+            bias.map(|b| (b.lat, b.lon)),
+            (lat, lon),
+        );
+
+        places.push(PlaceResult {
+            lat,
+            lon,
+            name,
+            score,
+        });
+    }
+    tracing::debug!(
+        found = places.len(),
+        confident = places
+            .iter()
+            .filter(|p| p.score >= scoring::CONFIDENT)
+            .count(),
+        "scored places"
+    );
+    Ok(Search {
+        places,
+        asked: req.limit.into(),
+        exhausted: features.features.len() < req.limit.into(),
+        attribution: attribution::photon(credits, attribution::of_collection(&features)),
+    })
+}
+
+/// Same in v2
+impl versioning::Versioned for ReverseResponse {
+    type V2 = Self;
+    fn into_v2(self) -> Self {
+        self
+    }
+}
+
+/// What's at a position, as specific as asked for: a place, its street, its neighbourhood, or its
+/// city.
+///
+/// Falls back to coarser answers when there's nothing that specific nearby, and says so in the
+/// answer's `granularity`.
+#[utoipa::path(
+    post,
+    path = "/reverse",
+    request_body = ReverseRequest,
+    params(
+        ("accept-language" = Option<String>, Header,
+         description = "Language for place names, unless the body has lang. Answered in content-language")
+    ),
+    responses(
+        (status = 200, body = ReverseResponse),
+        (status = 422, body = error::ErrorResponse, description = "Bad position"),
+        (status = 500, body = error::ErrorResponse, description = "Photon failed us"),
+        (status = 503, body = error::ErrorResponse,
+         description = "Rate limited or in maintenance. Has Retry-After"),
+        (status = 504, description = "X-Request-Deadline-Ms ran out"),
+    )
+)]
+#[instrument(level = "debug", skip(client, cache, ledger, mode, credits, languages))]
+#[allow(clippy::too_many_arguments)] // Extractors
+async fn reverse(
+    State(client): State<Arc<dyn ExternalApi>>,
+    State(cache): State<prefetch::ReverseCache>,
+    State(ledger): State<usage::Ledger>,
+    State(mode): State<ParseMode>,
+    State(credits): State<attribution::Credits>,
+    State(languages): State<language::PhotonLanguages>,
+    version: ApiVersion,
+    accepted: language::AcceptLanguage,
+    ValidatedJson(params): ValidatedJson<ReverseRequest>,
+) -> Result<(HeaderMap, Negotiated<ReverseResponse>)> {
+    let lang = language::photon(params.lang.as_deref(), &accepted, &languages)?;
+    let key = prefetch::reverse_key(params.lat, params.lon, lang.as_deref());
+    let (features, hit) = match cache.get_fresh(&key) {
+        Some(features) => (features, true),
+        None => {
+            let req = prefetch::reverse_request(params.lat, params.lon, lang.clone());
+            (Arc::new(client.photon_reverse_send(&req).await?), false)
+        }
+    };
+    ledger.count_cache("reverse", hit);
+    // Prefetched answers are only looked over here
+    shape::diagnose(
+        &features,
+        Expectation {
+            geometry: GeometryKind::Point,
+            non_empty: false,
+        },
+    )?;
+    if !hit {
+        cache.insert(key, features.clone());
+    }
+    let place = reverse::pick(&features.features, params.granularity, mode)?;
+    let attribution = attribution::photon(&credits, attribution::of_collection(&features));
+    Ok((
+        language::content_language(lang.as_deref()),
+        Negotiated(version, ReverseResponse { place, attribution }),
+    ))
+}
+
+/// Same in v2
+impl versioning::Versioned for FavoritesResponse {
+    type V2 = Self;
+    fn into_v2(self) -> Self {
+        self
+    }
+}
+
+/// Checks the app's saved places against OSM as it is now, so renamed, moved and deleted places
+/// can be updated.
+///
+/// Lookups are cached for a day. Places that couldn't be looked up come back `unchecked` with an
+/// error, making the answer a 207, rather than failing the lot.
+#[utoipa::path(
+    post,
+    path = "/favorites",
+    request_body = FavoritesRequest,
+    responses(
+        (status = 200, body = FavoritesResponse),
+        (status = 207, body = FavoritesResponse, description = "Some places are `unchecked`"),
+        (status = 422, body = error::ErrorResponse,
+         description = "Bad position, or not 1 to 50 places"),
+        (status = 503, body = error::ErrorResponse, description = "In maintenance. Has Retry-After"),
+        (status = 504, description = "X-Request-Deadline-Ms ran out"),
+    )
+)]
+#[instrument(level = "debug", skip_all)]
+async fn favorites(
+    State(client): State<Arc<dyn ExternalApi>>,
+    State(cache): State<favorites::FavoritesCache>,
+    State(mode): State<ParseMode>,
+    State(credits): State<attribution::Credits>,
+    version: ApiVersion,
+    ValidatedJson(params): ValidatedJson<FavoritesRequest>,
+) -> Partial<Negotiated<FavoritesResponse>> {
+    let places = favorites::refresh(client, cache, params.places, mode).await;
+    let failed = places.iter().any(|place| place.error.is_some());
+    Partial::new(
+        "/favorites",
+        failed,
+        Negotiated(
+            version,
+            FavoritesResponse {
+                places,
+                attribution: attribution::photon(&credits, None),
+            },
+        ),
+    )
+}
+
+impl versioning::Versioned for TripResponse {
+    type V2 = TripResponseV2;
+    fn has_route(&self) -> bool {
+        true
+    }
+    fn into_v2(self) -> TripResponseV2 {
+        let RouteResponseV2 { route, .. } = RouteResponse {
+            route: self.route,
+            annotations: None,
+            borders: None,
+            elevation: None,
+            snapped: None,
+            attribution: vec![],
+        }
+        .into_v2();
+        TripResponseV2 {
+            id: self.id,
+            expires_in_secs: self.expires_in_secs,
+            request: self.request,
+            route,
+            attribution: self.attribution,
+        }
+    }
+}
+
+/// A saved trip as sent back, credited with `credits`
+fn trip_response(saved: trips::Saved, credits: &attribution::Credits) -> TripResponse {
+    TripResponse {
+        id: saved.id,
+        expires_in_secs: saved.expires_in.as_secs(),
+        request: saved.trip.request,
+        route: saved.trip.route,
+        // Saved without ORS's own credit
+        attribution: attribution::ors(credits, None),
+    }
+}
+
+/// Runs a [trips::TripStore] call off the async threads
+async fn with_store<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| RouteError::Storage(e.into()))?
+}
+
+/// Routes like `/route` does, and saves the route for `GET /trips/{id}`.
+///
+/// Only served with `--trips-db`. A route still in the cache costs no quota.
+#[utoipa::path(
+    post,
+    path = "/trips",
+    request_body = RouteRequest,
+    responses(
+        (status = 201,
+         content(
+             (TripResponse = "application/json"),
+             (TripResponseV2 = "application/vnd.flipmap.v2+json"),
+         )),
+        (status = 422, body = error::ErrorResponse, description = "Bad coordinates"),
+        (status = 500, body = error::ErrorResponse, description = "ORS or the database failed us"),
+        (status = 503, body = error::ErrorResponse,
+         description = "Rate limited or in maintenance. Has Retry-After"),
+        (status = 504, description = "X-Request-Deadline-Ms ran out"),
+        (status = 507, body = error::ErrorResponse, description = "Too many trips saved"),
+    )
+)]
+#[instrument(
+    level = "debug",
+    skip(client, cache, precision, store, ledger, mode, credits)
+)]
+#[allow(clippy::too_many_arguments)] // Extractors
+async fn save_trip(
+    State(client): State<Arc<dyn ExternalApi>>,
+    State(cache): State<RouteCache>,
+    State(precision): State<geo::KeyPrecision>,
+    State(store): State<trips::TripStore>,
+    State(ledger): State<usage::Ledger>,
+    State(mode): State<ParseMode>,
+    State(credits): State<attribution::Credits>,
+    version: ApiVersion,
+    ValidatedJson(params): ValidatedJson<RouteRequest>,
+) -> Result<Response> {
+    let key = cache_key(&params, precision.route);
+    let cached = cache.get_fresh(&key);
+    ledger.count_cache("route", cached.is_some());
+    let route = match cached {
+        Some(route) => route,
+        None => {
+            let route = fetch_route(&*client, &params, mode, &credits).await?;
+            cache.insert(key, route.clone());
+            route
+        }
+    };
+    ledger.count_route(geo::flat_line_length_m(&route.route));
+    let trip = trips::Trip {
+        request: params,
+        route: route.route,
+    };
+    let saved = with_store(move || store.save(trip)).await?;
+    tracing::debug!(id = saved.id, "saved trip");
+    Ok((
+        StatusCode::CREATED,
+        Negotiated(version, trip_response(saved, &credits)),
+    )
+        .into_response())
+}
+
+/// A trip saved with `POST /trips`, if it hasn't expired
+#[utoipa::path(
+    get,
+    path = "/trips/{id}",
+    params(("id" = String, Path, description = "From POST /trips")),
+    responses(
+        (status = 200,
+         content(
+             (TripResponse = "application/json"),
+             (TripResponseV2 = "application/vnd.flipmap.v2+json"),
+         )),
+        (status = 404, body = error::ErrorResponse, description = "Never saved, or expired"),
+        (status = 500, body = error::ErrorResponse, description = "The database failed us"),
+    )
+)]
+#[instrument(level = "debug", skip(store, credits))]
+async fn get_trip(
+    State(store): State<trips::TripStore>,
+    State(credits): State<attribution::Credits>,
+    version: ApiVersion,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Negotiated<TripResponse>> {
+    let saved = with_store(move || store.get(&id))
+        .await?
+        .ok_or(RouteError::NotFound)?;
+    Ok(Negotiated(version, trip_response(saved, &credits)))
+}
+
+impl versioning::Versioned for SharedResponse {
+    type V2 = SharedResponseV2;
+    fn has_route(&self) -> bool {
+        matches!(self, SharedResponse::Trip(_))
+    }
+    fn into_v2(self) -> SharedResponseV2 {
+        match self {
+            SharedResponse::Trip(trip) => SharedResponseV2::Trip(trip.into_v2()),
+            SharedResponse::Place(place) => SharedResponseV2::Place(place),
+        }
+    }
+}
+
+/// A short link to a saved trip or a place, for sending to someone.
+///
+/// Only served with `--trips-db`. Each client can make `--shares-per-hour` of them.
+#[utoipa::path(
+    post,
+    path = "/share",
+    request_body = ShareRequest,
+    responses(
+        (status = 201, body = ShareResponse),
+        (status = 404, body = error::ErrorResponse, description = "No such trip, or it expired"),
+        (status = 422, body = error::ErrorResponse, description = "Bad place"),
+        (status = 429, body = error::ErrorResponse,
+         description = "Too many links made. Has Retry-After"),
+        (status = 500, body = error::ErrorResponse, description = "The database failed us"),
+        (status = 507, body = error::ErrorResponse, description = "Too much saved"),
+    )
+)]
+#[instrument(level = "debug", skip(store, limiter, client))]
+async fn create_share(
+    State(store): State<trips::TripStore>,
+    State(limiter): State<share::ShareLimiter>,
+    access_log::ClientId(client): access_log::ClientId,
+    ValidatedJson(params): ValidatedJson<ShareRequest>,
+) -> Result<(StatusCode, axum::Json<ShareResponse>)> {
+    // Clients we can't tell apart share one allowance, which is better than none
+    let client = client.as_deref().unwrap_or("unknown");
+    limiter
+        .try_create(client, tokio::time::Instant::now())
+        .map_err(|until| RouteError::Banned(clock::system_at(until)))?;
+    let target = match params {
+        ShareRequest::Trip { id } => trips::Shared::Trip(id),
+        ShareRequest::Place(place) => trips::Shared::Place(place),
+    };
+    let (token, lasts) = with_store(move || store.share(target)).await?;
+    Ok((
+        StatusCode::CREATED,
+        axum::Json(ShareResponse {
+            token,
+            expires_in_secs: lasts.as_secs(),
+        }),
+    ))
+}
+
+/// What a share link points at
+#[utoipa::path(
+    get,
+    path = "/share/{token}",
+    params(("token" = String, Path, description = "From POST /share")),
+    responses(
+        (status = 200,
+         content(
+             (SharedResponse = "application/json"),
+             (SharedResponseV2 = "application/vnd.flipmap.v2+json"),
+         )),
+        (status = 404, body = error::ErrorResponse, description = "Never made, or expired"),
+        (status = 500, body = error::ErrorResponse, description = "The database failed us"),
+    )
+)]
+#[instrument(level = "debug", skip(store, credits))]
+async fn get_share(
+    State(store): State<trips::TripStore>,
+    State(credits): State<attribution::Credits>,
+    version: ApiVersion,
+    axum::extract::Path(token): axum::extract::Path<String>,
+) -> Result<Negotiated<SharedResponse>> {
+    let shared = with_store(move || {
+        Ok(match store.resolve(&token)? {
+            None => None,
+            Some(trips::Shared::Place(place)) => Some(SharedResponse::Place(place)),
+            Some(trips::Shared::Trip(id)) => store
+                .get(&id)?
+                .map(|saved| SharedResponse::Trip(trip_response(saved, &credits))),
+        })
+    })
+    .await?
+    .ok_or(RouteError::NotFound)?;
+    Ok(Negotiated(version, shared))
+}
+
+// Handlers are called directly with a canned ExternalApi. Extraction/validation is axum's problem
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{collection, CannedApi};
+    use crate::vcr::Replay;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::SystemTime;
+
+    fn ledger() -> usage::Ledger {
+        usage::Ledger::new(Default::default(), Default::default())
+    }
+
+    /// Never degraded by itself
+    fn staleness(api: Arc<dyn ExternalApi>) -> Staleness {
+        Staleness {
+            degraded: degraded::Degraded::new(api),
+            revalidator: revalidate::Revalidator::new(revalidate::DEFAULT_MAX_STALE),
+        }
+    }
+
+    fn borders(api: Arc<dyn ExternalApi>) -> borders::Borders {
+        borders::Borders::new(
+            api,
+            borders::CountryCache::new(borders::COUNTRY_CACHE_TTL, 10),
+            ledger(),
+            ParseMode::Lenient,
+        )
+    }
+
+    fn route_request() -> RouteRequest {
+        RouteRequest {
+            src_lat: 44.567648,
+            src_lon: -123.279959,
+            dst_lat: 44.568763,
+            dst_lon: -123.277635,
+            heading: None,
+            annotations: false,
+            borders: false,
+            elevation: false,
+        }
+    }
+
+    /// What ORS answers for [route_request], down to its two ends
+    fn two_point_route() -> Result<geojson::FeatureCollection> {
+        Ok(collection(json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "properties": {},
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": [[-123.279959, 44.567648], [-123.277635, 44.568763]]
+                }
+            }]
+        })))
+    }
+
+    /// Answers ORS with [two_point_route], counting the calls
+    fn counting_ors() -> (Arc<dyn ExternalApi>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let api = CannedApi::default().with_ors(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            two_point_route()
+        });
+        (Arc::new(api), calls)
+    }
+
+    fn locations_request() -> GetLocationsRequest {
+        GetLocationsRequest {
+            lat: Some(44.567189),
+            lon: Some(-123.279166),
+            query: "downward".to_string(),
+            amount: 10,
+            offset: 0,
+            zoom: None,
+            lang: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn route_flattens_linestring() {
+        let api = CannedApi::default().with_ors(two_point_route);
+        let res = fetch_route(
+            &api,
+            &route_request(),
+            ParseMode::Lenient,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            res.route,
+            vec![-123.279959, 44.567648, -123.277635, 44.568763]
+        );
+        assert_eq!(res.snapped.unwrap().dst.deviation_m, 0.0);
+
+        // Asked from a thousandth of a degree south of where the road is
+        let off_road = RouteRequest {
+            src_lat: 44.566648,
+            ..route_request()
+        };
+        let res = fetch_route(&api, &off_road, ParseMode::Lenient, &Default::default())
+            .await
+            .unwrap();
+        let snapped = res.snapped.unwrap();
+        assert_eq!((snapped.src.lat, snapped.src.lon), (44.567648, -123.279959));
+        assert_eq!(snapped.src.deviation_m, 111.0);
+    }
+
+    #[tokio::test]
+    async fn route_rejects_wrong_geometry() {
+        let api = CannedApi::default().with_ors(|| {
+            Ok(collection(json!({
+                "type": "FeatureCollection",
+                "features": [{
+                    "type": "Feature",
+                    "properties": {},
+                    "geometry": { "type": "Point", "coordinates": [-123.279959, 44.567648] }
+                }]
+            })))
+        });
+        let res = fetch_route(
+            &api,
+            &route_request(),
+            ParseMode::Lenient,
+            &Default::default(),
+        )
+        .await;
+        assert!(res.is_err_and(|x| matches!(x, RouteError::ExternalAPIContent(_))));
+    }
+
+    #[tokio::test]
+    async fn route_passes_limit_through() {
+        let api =
+            CannedApi::default().with_ors(|| Err(RouteError::ExternalAPILimit(SystemTime::now())));
+        let res = fetch_route(
+            &api,
+            &route_request(),
+            ParseMode::Lenient,
+            &Default::default(),
+        )
+        .await;
+        assert!(res.is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
+    }
+
+    #[tokio::test]
+    async fn locations_swap_coords_and_default_name() {
+        let api = CannedApi::default().with_photon(|| {
+            Ok(collection(json!({
+                "type": "FeatureCollection",
+                "features": [
+                    {
+                        "type": "Feature",
+                        "properties": { "name": "Downward Dog" },
+                        "geometry": { "type": "Point", "coordinates": [-123.277884, 44.568760] }
+                    },
+                    {
+                        "type": "Feature",
+                        "properties": {},
+                        "geometry": { "type": "Point", "coordinates": [-116.617571, 48.263008] }
+                    }
+                ]
+            })))
+        });
+        let (_, Negotiated(_, res)) = get_locations(
+            State(Arc::new(api)),
+            State(SearchCache::new(SEARCH_CACHE_TTL, 10)),
+            State(Default::default()),
+            State(Default::default()),
+            State(ledger()),
+            State(staleness(Arc::new(CannedApi::default()))),
+            State(ParseMode::Lenient),
+            State(Default::default()),
+            State(Default::default()),
+            ApiVersion::V1,
+            Default::default(),
+            Default::default(),
+            ValidatedJson(locations_request()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.results.len(), 2);
+        assert_eq!(res.results[0].name, "Downward Dog");
+        assert_eq!(res.results[0].lat, 44.568760);
+        assert_eq!(res.results[0].lon, -123.277884);
+        assert_eq!(res.results[1].name, "Unknown");
+        // Matches "downward", first, and close by
+        assert!(res.results[0].score >= scoring::CONFIDENT);
+        assert!(res.results[1].score < res.results[0].score);
+    }
+
+    #[tokio::test]
+    async fn locations_page_from_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let api: Arc<dyn ExternalApi> = Arc::new(CannedApi::default().with_photon(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let place = |name: &str| {
+                json!({
+                    "type": "Feature",
+                    "properties": { "name": name },
+                    "geometry": { "type": "Point", "coordinates": [-123.27, 44.56] }
+                })
+            };
+            Ok(collection(json!({
+                "type": "FeatureCollection",
+                "features": [place("a"), place("b"), place("c")]
+            })))
+        }));
+        let cache = SearchCache::new(SEARCH_CACHE_TTL, 10);
+        let page = |offset, amount| {
+            let (api, cache) = (api.clone(), cache.clone());
+            async move {
+                let (_, Negotiated(_, res)) = get_locations(
+                    State(api.clone()),
+                    State(cache),
+                    State(Default::default()),
+                    State(Default::default()),
+                    State(ledger()),
+                    State(staleness(api)),
+                    State(ParseMode::Lenient),
+                    State(Default::default()),
+                    State(Default::default()),
+                    ApiVersion::V1,
+                    Default::default(),
+                    Default::default(),
+                    ValidatedJson(GetLocationsRequest {
+                        amount,
+                        offset,
+                        ..locations_request()
+                    }),
+                )
+                .await
+                .unwrap();
+                let names: Vec<_> = res.results.into_iter().map(|r| r.name).collect();
+                (names, res.has_more)
+            }
+        };
+
+        assert_eq!(page(0, 2).await, (vec!["a".into(), "b".into()], true));
+        // Needs a 5th result to know, so Photon is asked again and comes up short
+        assert_eq!(page(2, 2).await, (vec!["c".into()], false));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(page(1, 1).await, (vec!["b".into()], true));
+        assert_eq!(page(3, 5).await, (vec![], false));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn route_from_recorded_fixture() {
+        let res = fetch_route(
+            &Replay,
+            &route_request(),
+            ParseMode::Lenient,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        // 12 positions in the recording
+        assert_eq!(res.route.len(), 24);
+        assert_eq!(res.route[..2], [-123.279959, 44.567648]);
+
+        use crate::versioning::Versioned;
+        let v2 = res.into_v2();
+        assert_eq!(v2.route.len(), 12);
+        assert_eq!(
+            v2.route[0],
+            RoutePoint {
+                lat: 44.567648,
+                lon: -123.279959
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_route_is_304_without_quota() {
+        let (api, calls) = counting_ors();
+        let cache = RouteCache::new(ROUTE_CACHE_TTL, 10);
+        let call = |headers: HeaderMap| {
+            route(
+                State(api.clone()),
+                State(cache.clone()),
+                State(Default::default()),
+                State(ledger()),
+                State(staleness(api.clone())),
+                State(None),
+                State(None),
+                State(borders(api.clone())),
+                State(ParseMode::Lenient),
+                State(Default::default()),
+                State(Default::default()),
+                ApiVersion::V1,
+                Default::default(),
+                headers,
+                ValidatedJson(route_request()),
+            )
+        };
+
+        let first = call(HeaderMap::new()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let hash = first.headers()[ROUTE_HASH].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_ROUTE_UNCHANGED, hash.clone());
+        let second = call(headers).await.unwrap();
+        assert_eq!(second.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(second.headers()[ROUTE_HASH], hash);
+
+        // Stale hash gets the cached body
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_ROUTE_UNCHANGED, "0000000000000000".parse().unwrap());
+        let third = call(headers).await.unwrap();
+        assert_eq!(third.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_route_while_degraded() {
+        let api: Arc<dyn ExternalApi> = Arc::new(CannedApi::default().with_ors(two_point_route));
+        let cache = RouteCache::new(ROUTE_CACHE_TTL, 10);
+        let staleness = staleness(api.clone());
+        let call = |params: RouteRequest| {
+            route(
+                State(api.clone()),
+                State(cache.clone()),
+                State(Default::default()),
+                State(ledger()),
+                State(staleness.clone()),
+                State(None),
+                State(None),
+                State(borders(api.clone())),
+                State(ParseMode::Lenient),
+                State(Default::default()),
+                State(Default::default()),
+                ApiVersion::V1,
+                Default::default(),
+                HeaderMap::new(),
+                ValidatedJson(params),
+            )
+        };
+        let fresh = call(route_request()).await.unwrap();
+        assert!(fresh.headers().get(degraded::STALE_RESULT).is_none());
+
+        tokio::time::advance(ROUTE_CACHE_TTL * 2).await;
+        staleness.degraded.set(true);
+        let stale = call(route_request()).await.unwrap();
+        assert_eq!(stale.status(), StatusCode::OK);
+        assert_eq!(stale.headers()[degraded::STALE_RESULT], "true");
+        assert_eq!(stale.headers()[axum::http::header::AGE], "1200");
+
+        let uncached = RouteRequest {
+            dst_lat: 45.0,
+            ..route_request()
+        };
+        let miss = call(uncached).await.unwrap_err().into_response();
+        assert_eq!(miss.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(miss.headers().contains_key(axum::http::header::RETRY_AFTER));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slightly_stale_route_revalidates() {
+        let (api, calls) = counting_ors();
+        let cache = RouteCache::new(ROUTE_CACHE_TTL, 10);
+        let staleness = staleness(api.clone());
+        let call = || {
+            route(
+                State(api.clone()),
+                State(cache.clone()),
+                State(Default::default()),
+                State(ledger()),
+                State(staleness.clone()),
+                State(None),
+                State(None),
+                State(borders(api.clone())),
+                State(ParseMode::Lenient),
+                State(Default::default()),
+                State(Default::default()),
+                ApiVersion::V1,
+                Default::default(),
+                HeaderMap::new(),
+                ValidatedJson(route_request()),
+            )
+        };
+        call().await.unwrap();
+
+        tokio::time::advance(ROUTE_CACHE_TTL + Duration::from_secs(30)).await;
+        let stale = call().await.unwrap();
+        assert_eq!(stale.headers()[degraded::STALE_RESULT], "true");
+        while calls.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        tokio::task::yield_now().await;
+        let refreshed = call().await.unwrap();
+        assert!(refreshed.headers().get(degraded::STALE_RESULT).is_none());
+
+        // Too stale to answer with, so it waits on upstream
+        tokio::time::advance(ROUTE_CACHE_TTL + revalidate::DEFAULT_MAX_STALE).await;
+        let fetched = call().await.unwrap();
+        assert!(fetched.headers().get(degraded::STALE_RESULT).is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn no_cache_refetches_route() {
+        let (api, calls) = counting_ors();
+        let cache = RouteCache::new(ROUTE_CACHE_TTL, 10);
+        let call = |cache_control: &str| {
+            route(
+                State(api.clone()),
+                State(cache.clone()),
+                State(Default::default()),
+                State(ledger()),
+                State(staleness(api.clone())),
+                State(None),
+                State(None),
+                State(borders(api.clone())),
+                State(ParseMode::Lenient),
+                State(Default::default()),
+                State(Default::default()),
+                ApiVersion::V1,
+                cache_control::CacheDirectives::parse(cache_control),
+                HeaderMap::new(),
+                ValidatedJson(route_request()),
+            )
+        };
+        call("").await.unwrap();
+        call("max-age=60").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        call("no-cache").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn route_hash_is_stable() {
+        // Clients keep these across our restarts
+        assert_eq!(cache_key(&route_request(), None), 0x1aa693d0d92eddd6);
+        let heading = RouteRequest {
+            heading: Some(90.0),
+            ..route_request()
+        };
+        assert_ne!(cache_key(&heading, None), cache_key(&route_request(), None));
+        let noisy = RouteRequest {
+            src_lat: route_request().src_lat + 1e-12,
+            ..route_request()
+        };
+        assert_eq!(cache_key(&noisy, None), cache_key(&route_request(), None));
+        assert_eq!(
+            search_key(" Cafe  Central", None, None, None, None),
+            search_key("cafe central", None, None, None, None)
+        );
+        assert_ne!(
+            search_key("cafe central", None, None, Some("de"), None),
+            search_key("cafe central", None, None, None, None)
+        );
+    }
+
+    #[test]
+    fn coarse_keys_share_nearby_routes() {
+        let nearby = RouteRequest {
+            src_lat: route_request().src_lat + 1e-5,
+            ..route_request()
+        };
+        assert_ne!(cache_key(&nearby, None), cache_key(&route_request(), None));
+        assert_eq!(
+            cache_key(&nearby, Some(6)),
+            cache_key(&route_request(), Some(6))
+        );
+    }
+
+    #[tokio::test]
+    async fn locations_pass_request_failure_through() {
+        let api = CannedApi::default()
+            .with_photon(|| Err(RouteError::ExternalAPIRequest("connection reset".into())));
+        let res = get_locations(
+            State(Arc::new(api)),
+            State(SearchCache::new(SEARCH_CACHE_TTL, 10)),
+            State(Default::default()),
+            State(Default::default()),
+            State(ledger()),
+            State(staleness(Arc::new(CannedApi::default()))),
+            State(ParseMode::Lenient),
+            State(Default::default()),
+            State(Default::default()),
+            ApiVersion::V1,
+            Default::default(),
+            Default::default(),
+            ValidatedJson(locations_request()),
+        )
+        .await;
+        assert!(res.is_err_and(|x| matches!(x, RouteError::ExternalAPIRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn reverse_falls_back_to_street() {
+        let api = CannedApi::default().with_reverse(|| {
+            Ok(collection(json!({
+                "type": "FeatureCollection",
+                "features": [{
+                    "type": "Feature",
+                    "properties": { "type": "house", "housenumber": "230", "street": "SW 2nd St" },
+                    "geometry": { "type": "Point", "coordinates": [-123.2625, 44.5636] }
+                }]
+            })))
+        });
+        let cache = prefetch::ReverseCache::new(prefetch::REVERSE_CACHE_TTL, 10);
+        let (headers, Negotiated(_, res)) = reverse(
+            State(Arc::new(api)),
+            State(cache.clone()),
+            State(usage::Ledger::new(Default::default(), Default::default())),
+            State(ParseMode::Lenient),
+            State(Default::default()),
+            State(Default::default()),
+            ApiVersion::V1,
+            language::AcceptLanguage::parse("nl, de-AT;q=0.9"),
+            ValidatedJson(ReverseRequest {
+                lat: 44.5636,
+                lon: -123.2625,
+                granularity: Granularity::Poi,
+                lang: None,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(headers["content-language"], "de");
+        assert!(cache
+            .get_fresh(&prefetch::reverse_key(44.5636, -123.2625, Some("de")))
+            .is_some());
+        let place = res.place.unwrap();
+        assert_eq!(place.name, "SW 2nd St");
+        assert_eq!(place.granularity, Granularity::Street);
+    }
+
+    #[test]
+    fn config_from_args() {
+        use clap::Parser;
+        let config = Config::try_parse_from([
+            "flipmap-backend",
+            "127.0.0.1",
+            "1337",
+            "--ors-daily-cap",
+            "500",
+            "--photon-http",
+            "http2",
+        ])
+        .unwrap();
+        assert_eq!(config.port, Some(1337));
+        assert_eq!(config.ors_daily_cap, Some(500));
+        assert_eq!(config.photon_http, requester::HttpVersion::Http2);
+        assert_eq!(config.capture_keep, 20);
+        assert!(config.ors_key.is_none() && config.admin_listen.is_none());
+
+        assert!(Config::try_parse_from(["flipmap-backend", "127.0.0.1", "0"]).is_err());
+        assert!(Config::try_parse_from(["flipmap-backend", "localhost", "1337"]).is_err());
+    }
+
+    #[test]
+    fn every_option_has_env() {
+        use clap::CommandFactory;
+        for arg in Config::command().get_arguments() {
+            let id = arg.get_id().as_str();
+            if matches!(id, "help" | "version") {
+                continue;
+            }
+            let expected = format!("FLIPMAP_BACKEND_{}", id.to_uppercase());
+            assert_eq!(arg.get_env(), Some(expected.as_ref()), "{id}");
+        }
+    }
+
+    #[test]
+    fn listen_addrs() {
+        use clap::Parser;
+        let addrs = |args: &[&str]| {
+            Config::try_parse_from(["flipmap-backend"].iter().chain(args))
+                .map(|config| config.listen_addrs())
+        };
+        assert_eq!(
+            addrs(&[
+                "127.0.0.1",
+                "80",
+                "--listen",
+                "[::]:80",
+                "--listen",
+                "127.0.0.1:80"
+            ])
+            .unwrap(),
+            ["127.0.0.1:80".parse().unwrap(), "[::]:80".parse().unwrap()]
+        );
+        assert_eq!(addrs(&["--listen", "0.0.0.0:80,[::]:80"]).unwrap().len(), 2);
+        assert!(addrs(&[]).unwrap().is_empty());
+        assert!(addrs(&["127.0.0.1"]).is_err());
+    }
+
+    #[test]
+    fn subcommands() {
+        use clap::Parser;
+        let command = |args: &[&str]| {
+            Cli::try_parse_from(["flipmap-backend"].iter().chain(args)).map(|cli| cli.command)
+        };
+        assert!(matches!(
+            command(&["serve", "127.0.0.1", "1337"]),
+            Ok(Command::Serve(config)) if config.port == Some(1337)
+        ));
+        assert!(matches!(command(&["probe"]), Ok(Command::Probe(_))));
+        assert!(matches!(
+            command(&["print-openapi"]),
+            Ok(Command::PrintOpenapi)
+        ));
+        assert!(command(&["127.0.0.1", "1337"]).is_err());
+
+        let Ok(Command::CheckConfig(config)) = command(&[
+            "check-config",
+            "--geoip-db",
+            "/nowhere.mmdb",
+            "--admin-listen",
+            "127.0.0.1:9090",
+        ]) else {
+            panic!("check-config should parse");
+        };
+        let problems = config.check();
+        assert!(problems.iter().any(|p| p.starts_with("nowhere to listen")));
+        assert!(problems.iter().any(|p| p.contains("GeoIP")));
+        assert!(problems.iter().any(|p| p.contains("no contact")));
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("admin endpoints need a token")));
+
+        // Nobody else's policy to follow
+        let Ok(Command::CheckConfig(config)) = command(&[
+            "check-config",
+            "--photon-preset",
+            "self-hosted",
+            "--ors-base",
+            "http://ors.internal:8080",
+        ]) else {
+            panic!("check-config should parse");
+        };
+        assert!(!config.check().iter().any(|p| p.contains("no contact")));
+
+        let Ok(Command::Serve(config)) = command(&["serve", "--admin-token", "hunter2"]) else {
+            panic!("serve should parse");
+        };
+        assert!(!format!("{config:?}").contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn dual_stack_shares_port() {
+        let v4 = bind("0.0.0.0:0".parse().unwrap(), true).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind(
+            net::SocketAddr::new(net::Ipv6Addr::UNSPECIFIED.into(), port),
+            true,
+        );
+        assert!(v6.is_ok(), "{v6:?}");
+    }
+
+    /// Runs [ValidatedJson] extraction on `body` like axum would
+    fn extract<T: DeserializeOwned + Validate>(body: String) -> Result<T> {
+        let request = Request::post("/")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(ValidatedJson::<T>::from_request(request, &()))
+            .map(|ValidatedJson(t)| t)
+    }
+
+    /// Mostly sensible coordinates, with the occasional huge or tiny one
+    fn coordinate() -> impl proptest::strategy::Strategy<Value = f64> {
+        use proptest::prelude::*;
+        prop_oneof![
+            4 => -200.0..200.0f64,
+            1 => proptest::num::f64::NORMAL | proptest::num::f64::ZERO,
+        ]
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn route_coordinates_validated(
+            src_lat in coordinate(),
+            src_lon in coordinate(),
+            dst_lat in coordinate(),
+            dst_lon in coordinate(),
+        ) {
+            let body = json!({
+                "src_lat": src_lat, "src_lon": src_lon, "dst_lat": dst_lat, "dst_lon": dst_lon
+            });
+            let valid = [src_lat, dst_lat].iter().all(|l| l.abs() <= 90.0)
+                && [src_lon, dst_lon].iter().all(|l| l.abs() <= 180.0);
+            match extract::<RouteRequest>(body.to_string()) {
+                Ok(_) => proptest::prop_assert!(valid),
+                Err(e) => {
+                    proptest::prop_assert!(!valid);
+                    proptest::prop_assert_eq!(
+                        e.into_response().status(),
+                        StatusCode::UNPROCESSABLE_ENTITY
+                    );
+                }
+            }
+        }
+
+        #[test]
+        fn locations_queries_validated(query in ".*", amount: u8, lat in coordinate()) {
+            let body = json!({ "lat": lat, "lon": 0.0, "query": query, "amount": amount });
+            let valid = lat.abs() <= 90.0 && (1..=20).contains(&amount);
+            proptest::prop_assert_eq!(
+                extract::<GetLocationsRequest>(body.to_string()).is_ok(),
+                valid
+            );
+        }
+
+        #[test]
+        fn garbage_bodies_rejected(body in ".*") {
+            let res = extract::<RouteRequest>(body);
+            proptest::prop_assert!(res.is_err_and(|e| e.into_response().status().is_client_error()));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Contract tests against the real ORS and Photon, checking our typed parsers still match what
+//! they send. Off unless built with `--features live-tests`, since they need the network and
+//! spend quota:
+//!
+//! ```sh
+//! ORS_API_KEY=... cargo test --features live-tests live_tests
+//! ```
+//!
+//! Everything is parsed in [ParseMode::Strict], so a provider dropping a field fails here rather
+//! than showing up as warnings in production.
+use crate::geojson_ext::{self, ParseMode};
+use crate::ors::{OrsMetadata, OrsProperties};
+use crate::photon::PhotonProperties;
+use crate::requester::{
+    ExternalApi, ExternalRequester, OpenRouteRequest, PhotonGeocodeRequest, PhotonRevGeocodeRequest,
+};
+use geojson::FeatureCollection;
+
+fn requester() -> ExternalRequester {
+    let key = std::env::var("ORS_API_KEY").expect("live tests need ORS_API_KEY");
+    ExternalRequester::new(
+        "https://api.openrouteservice.org".parse().unwrap(),
+        "https://photon.komoot.io".parse().unwrap(),
+        key.into(),
+    )
+}
+
+fn check_places(fc: &FeatureCollection) {
+    assert!(!fc.features.is_empty(), "Photon found nothing");
+    for feature in &fc.features {
+        geojson_ext::extract_point(feature).unwrap();
+        geojson_ext::extract_properties::<PhotonProperties>(feature, ParseMode::Strict).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn ors_directions() {
+    let fc = requester()
+        .ors_send(&OpenRouteRequest {
+            coordinates: vec![
+                vec![-123.27963174780633, 44.56720205],
+                vec![-123.27788489405276, 44.5687606],
+            ],
+            instructions: true,
+            bearings: None,
+            profile: Default::default(),
+            extra_info: false,
+            elevation: false,
+        })
+        .await
+        .unwrap();
+
+    geojson_ext::extract_first_linestring(&fc).unwrap();
+    let props: OrsProperties =
+        geojson_ext::extract_properties(&fc.features[0], ParseMode::Strict).unwrap();
+    assert!(props.summary.distance > 0.0);
+    assert!(!props.segments[0].steps.is_empty());
+    let metadata = OrsMetadata::of(&fc, ParseMode::Strict)
+        .unwrap()
+        .expect("ORS stopped sending metadata");
+    assert!(!metadata.engine.graph_date.is_empty());
+}
+
+#[tokio::test]
+async fn photon_geocode() {
+    let fc = requester()
+        .photon_send(
+            &PhotonGeocodeRequest::new(5, "Corvallis".to_owned())
+                .with_location_bias(44.56, -123.27),
+        )
+        .await
+        .unwrap();
+    check_places(&fc);
+}
+
+#[tokio::test]
+async fn photon_reverse() {
+    let fc = requester()
+        .photon_reverse_send(&PhotonRevGeocodeRequest {
+            lat: 44.5687606,
+            lon: -123.27788489405276,
+            limit: None,
+            lang: None,
+        })
+        .await
+        .unwrap();
+    check_places(&fc);
+}
@@ -0,0 +1,121 @@
+//! Lets the log filter be swapped while running, so a module can be bumped to `trace` during an
+//! incident without a restart throwing away limiter and backoff state.
+//!
+//! Filters use the same syntax as `RUST_LOG`, e.g. `info,flipmap_backend::requester=trace`.
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Router state for the log level endpoints
+#[derive(Clone)]
+pub struct LogLevels {
+    handle: FilterHandle,
+    /// Whatever the filter was at startup, for resetting to
+    initial: Arc<str>,
+}
+
+impl LogLevels {
+    pub fn new(handle: FilterHandle) -> Self {
+        let initial = handle
+            .with_current(|f| f.to_string())
+            .unwrap_or_default()
+            .into();
+        LogLevels { handle, initial }
+    }
+
+    fn current(&self) -> Result<String, (StatusCode, String)> {
+        self.handle
+            .with_current(|f| f.to_string())
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    }
+
+    fn set(&self, filter: &str) -> Result<String, (StatusCode, String)> {
+        let new =
+            EnvFilter::try_new(filter).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        self.handle
+            .reload(new)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let current = self.current()?;
+        tracing::warn!("log filter changed to {current}");
+        Ok(current)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogFilter {
+    pub filter: String,
+}
+
+/// `GET`: the filter currently in effect
+pub async fn get_filter(
+    State(levels): State<LogLevels>,
+) -> Result<Json<LogFilter>, (StatusCode, String)> {
+    Ok(Json(LogFilter {
+        filter: levels.current()?,
+    }))
+}
+
+/// `PUT`: replaces the whole filter. Rejected with a 400 if it doesn't parse
+pub async fn put_filter(
+    State(levels): State<LogLevels>,
+    Json(body): Json<LogFilter>,
+) -> Result<Json<LogFilter>, (StatusCode, String)> {
+    Ok(Json(LogFilter {
+        filter: levels.set(&body.filter)?,
+    }))
+}
+
+/// `DELETE`: back to whatever was set at startup
+pub async fn reset_filter(
+    State(levels): State<LogLevels>,
+) -> Result<Json<LogFilter>, (StatusCode, String)> {
+    let initial = levels.initial.clone();
+    Ok(Json(LogFilter {
+        filter: levels.set(&initial)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn filter_can_be_changed_and_reset() {
+        // The handle only works while the layer is alive
+        let (_layer, handle) = reload::Layer::<_, Registry>::new(EnvFilter::new("info"));
+        let levels = LogLevels::new(handle);
+
+        let Json(res) = put_filter(
+            State(levels.clone()),
+            Json(LogFilter {
+                filter: "info,flipmap_backend::requester=trace".to_owned(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(res.filter.contains("flipmap_backend::requester=trace"));
+
+        let Json(res) = reset_filter(State(levels.clone())).await.unwrap();
+        assert_eq!(res.filter, "info");
+    }
+
+    #[tokio::test]
+    async fn bad_filter_is_rejected() {
+        let (_layer, handle) = reload::Layer::<_, Registry>::new(EnvFilter::new("info"));
+        let levels = LogLevels::new(handle);
+        let err = put_filter(
+            State(levels.clone()),
+            Json(LogFilter {
+                filter: "requester=loud".to_owned(),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let Json(res) = get_filter(State(levels)).await.unwrap();
+        assert_eq!(res.filter, "info");
+    }
+}
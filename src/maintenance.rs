@@ -0,0 +1,318 @@
+//! Maintenance mode for the whole service or a single provider, toggled from the admin endpoints.
+//!
+//! While on, affected requests get a 503 with the configured message and a Retry-After, without
+//! touching upstream. Handy when we already know the ORS quota is gone for the day. `/health`
+//! reports what's switched off.
+use crate::error::RouteError;
+use crate::ors::OrsRoute;
+use crate::requester::{
+    ExternalApi, Limits, OpenRouteRequest, PhotonGeocodeRequest, PhotonRevGeocodeRequest, Provider,
+};
+use crate::Result;
+use async_trait::async_trait;
+use axum::{
+    extract::{Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use geojson::FeatureCollection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::time::{Duration, Instant};
+
+/// Retry-After sent when whoever switched maintenance on didn't say
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+struct Notice {
+    message: String,
+    /// Only a hint for clients. Maintenance stays on until switched off
+    retry_after: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Switches {
+    service: Option<Notice>,
+    providers: HashMap<Provider, Notice>,
+}
+
+/// Shared maintenance switches. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct Maintenance {
+    switches: Arc<RwLock<Switches>>,
+}
+
+impl Maintenance {
+    /// Switches maintenance on for `provider`, or everything if `None`
+    pub fn enable(&self, provider: Option<Provider>, message: String, retry_after: Duration) {
+        let notice = Notice {
+            message,
+            retry_after: Instant::now() + retry_after,
+        };
+        tracing::warn!(
+            provider = provider.map(|p| p.to_string()),
+            "maintenance mode on: {}",
+            notice.message
+        );
+        let mut switches = self.switches.write().expect("maintenance lock poisoned");
+        match provider {
+            Some(provider) => {
+                switches.providers.insert(provider, notice);
+            }
+            None => switches.service = Some(notice),
+        }
+    }
+
+    /// Switches maintenance off for `provider`, or the service-wide switch if `None`
+    pub fn disable(&self, provider: Option<Provider>) {
+        tracing::warn!(
+            provider = provider.map(|p| p.to_string()),
+            "maintenance mode off"
+        );
+        let mut switches = self.switches.write().expect("maintenance lock poisoned");
+        match provider {
+            Some(provider) => {
+                switches.providers.remove(&provider);
+            }
+            None => switches.service = None,
+        }
+    }
+
+    /// `Err` if the service, or `provider` if given, is under maintenance
+    pub fn check(&self, provider: Option<Provider>) -> Result<()> {
+        let switches = self.switches.read().expect("maintenance lock poisoned");
+        let notice = switches
+            .service
+            .as_ref()
+            .or_else(|| provider.and_then(|p| switches.providers.get(&p)));
+        match notice {
+            Some(notice) => Err(RouteError::Maintenance {
+                message: notice.message.clone(),
+                retry_after: crate::clock::system_at(notice.retry_after),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn status(&self) -> MaintenanceStatus {
+        let switches = self.switches.read().expect("maintenance lock poisoned");
+        let view = |n: &Notice| NoticeView {
+            message: n.message.clone(),
+            retry_after_secs: n
+                .retry_after
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+        };
+        MaintenanceStatus {
+            service: switches.service.as_ref().map(view),
+            providers: switches
+                .providers
+                .iter()
+                .map(|(p, n)| (p.to_string(), view(n)))
+                .collect(),
+        }
+    }
+}
+
+/// Wraps an [ExternalApi] so calls to a provider under maintenance fail before going anywhere
+#[derive(Debug)]
+pub struct Gated {
+    inner: Arc<dyn ExternalApi>,
+    maintenance: Maintenance,
+}
+
+impl Gated {
+    pub fn new(inner: Arc<dyn ExternalApi>, maintenance: Maintenance) -> Self {
+        Gated { inner, maintenance }
+    }
+}
+
+#[async_trait]
+impl ExternalApi for Gated {
+    async fn ors_send(&self, req: &OpenRouteRequest) -> Result<FeatureCollection> {
+        self.maintenance.check(Some(Provider::OpenRouteService))?;
+        self.inner.ors_send(req).await
+    }
+
+    async fn ors_route(&self, req: &OpenRouteRequest) -> Result<OrsRoute> {
+        self.maintenance.check(Some(Provider::OpenRouteService))?;
+        self.inner.ors_route(req).await
+    }
+
+    async fn photon_reverse_send(
+        &self,
+        coord: &PhotonRevGeocodeRequest,
+    ) -> Result<FeatureCollection> {
+        self.maintenance.check(Some(Provider::Photon))?;
+        self.inner.photon_reverse_send(coord).await
+    }
+
+    async fn photon_send(&self, req: &PhotonGeocodeRequest) -> Result<FeatureCollection> {
+        self.maintenance.check(Some(Provider::Photon))?;
+        self.inner.photon_send(req).await
+    }
+
+    fn limits(&self) -> Limits {
+        self.inner.limits()
+    }
+}
+
+/// Middleware for the public API routes: turns everything away during service-wide maintenance
+pub async fn gate(
+    State(maintenance): State<Maintenance>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match maintenance.check(None) {
+        Ok(()) => next.run(request).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct NoticeView {
+    pub message: String,
+    pub retry_after_secs: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MaintenanceStatus {
+    pub service: Option<NoticeView>,
+    pub providers: HashMap<String, NoticeView>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    /// `ok`, `degraded` (some provider is off), or `maintenance`
+    pub status: &'static str,
+    pub maintenance: MaintenanceStatus,
+}
+
+/// `GET /health`. 503 during service-wide maintenance, so load balancers notice
+pub async fn health(State(maintenance): State<Maintenance>) -> (StatusCode, Json<Health>) {
+    let status = maintenance.status();
+    let (code, label) = if status.service.is_some() {
+        (StatusCode::SERVICE_UNAVAILABLE, "maintenance")
+    } else if !status.providers.is_empty() {
+        (StatusCode::OK, "degraded")
+    } else {
+        (StatusCode::OK, "ok")
+    };
+    (
+        code,
+        Json(Health {
+            status: label,
+            maintenance: status,
+        }),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProviderParam {
+    /// Leave out for the whole service
+    pub provider: Option<Provider>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EnableMaintenance {
+    pub provider: Option<Provider>,
+    pub message: String,
+    pub retry_after_secs: Option<u64>,
+}
+
+/// `GET /admin/maintenance`
+pub async fn get_maintenance(State(maintenance): State<Maintenance>) -> Json<MaintenanceStatus> {
+    Json(maintenance.status())
+}
+
+/// `PUT /admin/maintenance`
+pub async fn put_maintenance(
+    State(maintenance): State<Maintenance>,
+    Json(body): Json<EnableMaintenance>,
+) -> Json<MaintenanceStatus> {
+    let retry_after = body
+        .retry_after_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER);
+    maintenance.enable(body.provider, body.message, retry_after);
+    Json(maintenance.status())
+}
+
+/// `DELETE /admin/maintenance?provider=...`
+pub async fn delete_maintenance(
+    State(maintenance): State<Maintenance>,
+    Query(param): Query<ProviderParam>,
+) -> Json<MaintenanceStatus> {
+    maintenance.disable(param.provider);
+    Json(maintenance.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{collection, CannedApi};
+    use axum::http::header;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn provider_switch_blocks_only_that_provider() {
+        let maintenance = Maintenance::default();
+        let api = CannedApi::default()
+            .with_ors(|| panic!("ORS shouldn't be called under maintenance"))
+            .with_photon(|| {
+                Ok(collection(
+                    json!({ "type": "FeatureCollection", "features": [] }),
+                ))
+            });
+        let gated = Gated::new(Arc::new(api), maintenance.clone());
+        maintenance.enable(
+            Some(Provider::OpenRouteService),
+            "ORS quota used up".to_owned(),
+            Duration::from_secs(3600),
+        );
+
+        let ors = gated
+            .ors_send(&OpenRouteRequest {
+                coordinates: vec![vec![0.0, 0.0], vec![1.0, 1.0]],
+                instructions: false,
+                bearings: None,
+                profile: Default::default(),
+                extra_info: false,
+                elevation: false,
+            })
+            .await;
+        let response = ors.unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("ORS quota used up"));
+
+        let photon = gated
+            .photon_send(&PhotonGeocodeRequest::new(1, "x".to_owned()))
+            .await;
+        assert!(photon.is_ok());
+
+        let (code, Json(health)) = health(State(maintenance.clone())).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(health.status, "degraded");
+
+        maintenance.disable(Some(Provider::OpenRouteService));
+        let (_, Json(health)) = super::health(State(maintenance)).await;
+        assert_eq!(health.status, "ok");
+    }
+
+    #[tokio::test]
+    async fn service_switch_reports_unhealthy() {
+        let maintenance = Maintenance::default();
+        maintenance.enable(None, "upgrading".to_owned(), DEFAULT_RETRY_AFTER);
+        assert!(maintenance.check(Some(Provider::Photon)).is_err());
+        let (code, Json(health)) = health(State(maintenance)).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(health.status, "maintenance");
+    }
+}
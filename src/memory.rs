@@ -0,0 +1,247 @@
+//! One memory budget shared by everything that holds answers or records in memory, so a small host
+//! runs short of cache rather than of RAM.
+//!
+//! Each store ([crate::cache::TtlCache]s, the [crate::audit] trail) is charged a rough size for
+//! what it holds: JSON length for answers, which is near enough to their heap size, or a count of
+//! the strings in them. With `--memory-budget-mb`, going over it has the biggest stores shed
+//! entries, expired and oldest first, until everything fits in [LOW_WATER] of the budget. Going
+//! that far rather than just under the line means a busy cache isn't shedding on every insert.
+//! `memory_budget_used_bytes` has each store's share, and `memory_budget_evictions_total` counts
+//! what was shed by store. Without a budget, stores are still charged, so the gauge is there to
+//! size one by.
+use crate::metrics;
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Share of the budget to get back under once it's gone over
+pub const LOW_WATER: f64 = 0.75;
+
+/// Drops entries worth at least the bytes asked, if it has them, and says what went. Shouldn't
+/// touch the store's [Charge]; the [Budget] takes what was freed off it
+type Shed = Box<dyn Fn(usize) -> Freed + Send + Sync>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Freed {
+    pub bytes: usize,
+    pub entries: usize,
+}
+
+struct Member {
+    store: &'static str,
+    held: Arc<AtomicUsize>,
+    shed: Shed,
+}
+
+struct Shared {
+    /// Bytes, `usize::MAX` if there's no budget
+    limit: usize,
+    used: AtomicUsize,
+    members: Mutex<Vec<Member>>,
+}
+
+/// Cheap to clone; clones share the budget
+#[derive(Clone)]
+pub struct Budget(Arc<Shared>);
+
+impl std::fmt::Debug for Budget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Budget")
+            .field("limit", &self.0.limit)
+            .field("used", &self.used())
+            .finish()
+    }
+}
+
+impl Default for Budget {
+    /// Counts, never sheds
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+impl Budget {
+    pub fn new(limit_bytes: usize) -> Self {
+        Budget(Arc::new(Shared {
+            limit: limit_bytes,
+            used: AtomicUsize::new(0),
+            members: Mutex::new(vec![]),
+        }))
+    }
+
+    /// Bytes charged across every store
+    pub fn used(&self) -> usize {
+        self.0.used.load(Ordering::Relaxed)
+    }
+
+    /// Signs `store` up, to be asked to `shed` when the budget's over. Charge it through what
+    /// comes back
+    pub fn join(
+        &self,
+        store: &'static str,
+        shed: impl Fn(usize) -> Freed + Send + Sync + 'static,
+    ) -> Charge {
+        let held = Arc::new(AtomicUsize::new(0));
+        self.0
+            .members
+            .lock()
+            .expect("budget lock poisoned")
+            .push(Member {
+                store,
+                held: held.clone(),
+                shed: Box::new(shed),
+            });
+        Charge {
+            store,
+            held,
+            budget: self.clone(),
+        }
+    }
+
+    /// Sheds from the biggest stores down until [LOW_WATER], if over budget. Stores must not hold
+    /// their own locks while calling this. If another call is already shedding, leaves it to that
+    pub fn enforce(&self) {
+        if self.used() <= self.0.limit {
+            return;
+        }
+        let Ok(members) = self.0.members.try_lock() else {
+            return;
+        };
+        let target = (self.0.limit as f64 * LOW_WATER) as usize;
+        let mut by_size: Vec<&Member> = members.iter().collect();
+        by_size.sort_by_key(|m| std::cmp::Reverse(m.held.load(Ordering::Relaxed)));
+        for member in by_size {
+            let over = self.used().saturating_sub(target);
+            if over == 0 {
+                break;
+            }
+            let freed = (member.shed)(over);
+            member.held.fetch_sub(freed.bytes, Ordering::Relaxed);
+            self.0.used.fetch_sub(freed.bytes, Ordering::Relaxed);
+            report(member.store, &member.held);
+            if freed.entries > 0 {
+                metrics::registry().inc_counter(
+                    "memory_budget_evictions_total",
+                    &[("store", member.store)],
+                    freed.entries as u64,
+                );
+            }
+        }
+        tracing::warn!(
+            used = self.used(),
+            limit = self.0.limit,
+            "went over the memory budget, shed cached entries"
+        );
+    }
+}
+
+/// What one store has been charged. Cheap to clone
+#[derive(Clone)]
+pub struct Charge {
+    store: &'static str,
+    held: Arc<AtomicUsize>,
+    budget: Budget,
+}
+
+impl std::fmt::Debug for Charge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Charge")
+            .field("store", &self.store)
+            .field("held", &self.held())
+            .finish()
+    }
+}
+
+impl Charge {
+    pub fn held(&self) -> usize {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    pub fn add(&self, bytes: usize) {
+        self.held.fetch_add(bytes, Ordering::Relaxed);
+        self.budget.0.used.fetch_add(bytes, Ordering::Relaxed);
+        self.report();
+    }
+
+    pub fn sub(&self, bytes: usize) {
+        self.held.fetch_sub(bytes, Ordering::Relaxed);
+        self.budget.0.used.fetch_sub(bytes, Ordering::Relaxed);
+        self.report();
+    }
+
+    /// See [Budget::enforce]
+    pub fn enforce(&self) {
+        self.budget.enforce();
+    }
+
+    fn report(&self) {
+        report(self.store, &self.held);
+    }
+}
+
+fn report(store: &'static str, held: &AtomicUsize) {
+    metrics::registry().set_gauge(
+        "memory_budget_used_bytes",
+        &[("store", store)],
+        held.load(Ordering::Relaxed) as f64,
+    );
+}
+
+/// `value`'s JSON length, without writing it anywhere
+pub fn json_size<T: Serialize + ?Sized>(value: &T) -> usize {
+    struct Count(usize);
+    impl std::io::Write for Count {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut count = Count(0);
+    match serde_json::to_writer(&mut count, value) {
+        Ok(()) => count.0,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store of 10-byte entries, with a counter for what's left in it
+    fn store(budget: &Budget, name: &'static str) -> (Charge, Arc<AtomicUsize>) {
+        let held = Arc::new(AtomicUsize::new(0));
+        let left = held.clone();
+        let charge = budget.join(name, move |want| {
+            let bytes = want.div_ceil(10) * 10;
+            let bytes = bytes.min(left.load(Ordering::Relaxed));
+            left.fetch_sub(bytes, Ordering::Relaxed);
+            Freed {
+                bytes,
+                entries: bytes / 10,
+            }
+        });
+        (charge, held)
+    }
+
+    #[test]
+    fn sheds_biggest_first_to_low_water() {
+        let budget = Budget::new(1000);
+        let (big, big_left) = store(&budget, "big");
+        let (small, small_left) = store(&budget, "small");
+        for (charge, left, bytes) in [(&small, &small_left, 200), (&big, &big_left, 700)] {
+            charge.add(bytes);
+            left.fetch_add(bytes, Ordering::Relaxed);
+        }
+        budget.enforce();
+        assert_eq!(budget.used(), 900);
+
+        big.add(200);
+        big_left.fetch_add(200, Ordering::Relaxed);
+        budget.enforce();
+        assert_eq!(budget.used(), 750);
+        assert_eq!((big.held(), small.held()), (550, 200));
+    }
+}
@@ -0,0 +1,261 @@
+//! Process-wide metrics, rendered in the Prometheus text format by the `/metrics` route.
+//!
+//! Deliberately tiny: values keyed by name + labels behind a mutex. Nothing recording into it is
+//! hot enough for that to matter, and it saves pulling in a metrics ecosystem. Histograms all
+//! share one set of [BUCKETS].
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+/// Upper bounds of histogram buckets, in seconds. Prometheus' defaults, which span everything from
+/// a cache hit to an upstream timing out
+pub const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// Cumulative, like they're rendered: each counts observations at or under its bound
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    values: Mutex<BTreeMap<Key, (Kind, f64)>>,
+    histograms: Mutex<BTreeMap<Key, Histogram>>,
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+/// The registry everything records into
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+fn key(name: &'static str, labels: &[(&'static str, &str)]) -> Key {
+    let mut labels: Vec<_> = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+    labels.sort();
+    (name, labels)
+}
+
+impl Registry {
+    /// Adds `by` to a counter, creating it at zero first if needed
+    pub fn inc_counter(&self, name: &'static str, labels: &[(&'static str, &str)], by: u64) {
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        let entry = values
+            .entry(key(name, labels))
+            .or_insert((Kind::Counter, 0.0));
+        entry.1 += by as f64;
+    }
+
+    /// Overwrites a gauge
+    pub fn set_gauge(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        values.insert(key(name, labels), (Kind::Gauge, value));
+    }
+
+    /// Moves a gauge by `delta`, creating it at zero first if needed
+    pub fn add_gauge(&self, name: &'static str, labels: &[(&'static str, &str)], delta: f64) {
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        let entry = values
+            .entry(key(name, labels))
+            .or_insert((Kind::Gauge, 0.0));
+        entry.1 += delta;
+    }
+
+    /// Records one observation (usually seconds) in a histogram, creating it if needed
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut histograms = self.histograms.lock().expect("metrics lock poisoned");
+        let histogram = histograms.entry(key(name, labels)).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += value;
+    }
+
+    /// Everything recorded so far, in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let values = self.values.lock().expect("metrics lock poisoned");
+        let mut out = String::new();
+        let mut last_name = "";
+        for ((name, labels), (kind, value)) in values.iter() {
+            if *name != last_name {
+                let _ = writeln!(out, "# TYPE {name} {}", kind.name());
+                last_name = name;
+            }
+            let _ = writeln!(out, "{name}{} {value}", render_labels(labels, None));
+        }
+        drop(values);
+
+        let histograms = self.histograms.lock().expect("metrics lock poisoned");
+        for ((name, labels), histogram) in histograms.iter() {
+            if *name != last_name {
+                let _ = writeln!(out, "# TYPE {name} histogram");
+                last_name = name;
+            }
+            for (count, bound) in histogram.buckets.iter().zip(BUCKETS) {
+                let le = bound.to_string();
+                let labels = render_labels(labels, Some(&le));
+                let _ = writeln!(out, "{name}_bucket{labels} {count}");
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{} {}",
+                render_labels(labels, Some("+Inf")),
+                histogram.count
+            );
+            let labels = render_labels(labels, None);
+            let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+            let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+        }
+        out
+    }
+}
+
+/// `{k="v",...}`, with `le` last if given, or nothing at all if there are no labels
+fn render_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut rendered: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+        .collect();
+    if let Some(le) = le {
+        rendered.push(format!("le=\"{le}\""));
+    }
+    if rendered.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", rendered.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Snapshots the Tokio runtime's own metrics into gauges. Called on scrape rather than on a timer.
+///
+/// Poll and busy-time figures only exist when built with `--cfg tokio_unstable`, which the
+/// `console` feature needs anyway.
+pub fn record_runtime(registry: &Registry) {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    registry.set_gauge("tokio_workers", &[], metrics.num_workers() as f64);
+    registry.set_gauge("tokio_alive_tasks", &[], metrics.num_alive_tasks() as f64);
+    registry.set_gauge(
+        "tokio_global_queue_depth",
+        &[],
+        metrics.global_queue_depth() as f64,
+    );
+
+    #[cfg(tokio_unstable)]
+    for worker in 0..metrics.num_workers() {
+        let worker_label = worker.to_string();
+        let labels = [("worker", worker_label.as_str())];
+        registry.set_gauge(
+            "tokio_worker_polls",
+            &labels,
+            metrics.worker_poll_count(worker) as f64,
+        );
+        registry.set_gauge(
+            "tokio_worker_mean_poll_seconds",
+            &labels,
+            metrics.worker_mean_poll_time(worker).as_secs_f64(),
+        );
+        registry.set_gauge(
+            "tokio_worker_busy_seconds",
+            &labels,
+            metrics.worker_total_busy_duration(worker).as_secs_f64(),
+        );
+    }
+}
+
+/// Handler for `/metrics`
+pub async fn serve_metrics() -> String {
+    record_runtime(registry());
+    registry().render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let registry = Registry::default();
+        registry.inc_counter("requests", &[("route", "/route"), ("status", "200")], 2);
+        registry.inc_counter("requests", &[("status", "200"), ("route", "/route")], 1);
+        registry.set_gauge("in_flight", &[], 4.0);
+        registry.set_gauge("in_flight", &[], 3.0);
+        assert_eq!(
+            registry.render(),
+            "# TYPE in_flight gauge\n\
+             in_flight 3\n\
+             # TYPE requests counter\n\
+             requests{route=\"/route\",status=\"200\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn gauges_move_both_ways() {
+        let registry = Registry::default();
+        registry.add_gauge("in_flight", &[("provider", "Photon")], 1.0);
+        registry.add_gauge("in_flight", &[("provider", "Photon")], 1.0);
+        registry.add_gauge("in_flight", &[("provider", "Photon")], -1.0);
+        assert!(registry
+            .render()
+            .contains("in_flight{provider=\"Photon\"} 1\n"));
+    }
+
+    #[test]
+    fn renders_histograms() {
+        let registry = Registry::default();
+        let labels = [("endpoint", "photon")];
+        registry.observe("upstream_seconds", &labels, 0.2);
+        registry.observe("upstream_seconds", &labels, 3.0);
+        registry.observe("upstream_seconds", &labels, 60.0);
+        let rendered = registry.render();
+        assert!(rendered.starts_with("# TYPE upstream_seconds histogram\n"));
+        for line in [
+            "upstream_seconds_bucket{endpoint=\"photon\",le=\"0.1\"} 0\n",
+            "upstream_seconds_bucket{endpoint=\"photon\",le=\"0.25\"} 1\n",
+            "upstream_seconds_bucket{endpoint=\"photon\",le=\"5\"} 2\n",
+            "upstream_seconds_bucket{endpoint=\"photon\",le=\"10\"} 2\n",
+            "upstream_seconds_bucket{endpoint=\"photon\",le=\"+Inf\"} 3\n",
+            "upstream_seconds_sum{endpoint=\"photon\"} 63.2\n",
+            "upstream_seconds_count{endpoint=\"photon\"} 3\n",
+        ] {
+            assert!(rendered.contains(line), "{line} missing from {rendered}");
+        }
+    }
+
+    #[tokio::test]
+    async fn runtime_gauges_present() {
+        let registry = Registry::default();
+        record_runtime(&registry);
+        assert!(registry.render().contains("tokio_alive_tasks"));
+    }
+}
@@ -0,0 +1,171 @@
+//! A stand-in for ORS and Photon that remembers what it's been asked, for tests that need the
+//! upstream to change its mind: a quota running out, a 429 followed by a 200, a body that isn't
+//! GeoJSON. Serves on a real port, so requests go through the real URL paths and clients.
+//!
+//! Each path gets a script of [Reply]s played in order, the last repeating forever. A path can
+//! also get a quota, after which every call gets the quota's reply until [MockUpstream::refill].
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use reqwest::Url;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+/// One canned answer
+#[derive(Clone, Debug)]
+pub struct Reply {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: String,
+}
+
+impl Reply {
+    pub fn status(status: StatusCode) -> Self {
+        Reply {
+            status,
+            headers: vec![],
+            body: String::new(),
+        }
+    }
+
+    /// A 200 with `body` as GeoJSON
+    pub fn geojson(body: impl Into<String>) -> Self {
+        Reply {
+            body: body.into(),
+            ..Self::status(StatusCode::OK)
+        }
+        .with_header("content-type", "application/geo+json;charset=UTF-8")
+    }
+
+    /// A 200 that claims to be JSON but isn't
+    pub fn malformed() -> Self {
+        Reply {
+            body: r#"{"type": "FeatureCollection", "features": [{"#.to_owned(),
+            ..Self::status(StatusCode::OK)
+        }
+        .with_header("content-type", "application/json")
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl AsRef<str>) -> Self {
+        self.headers.push((
+            HeaderName::from_static(name),
+            HeaderValue::from_str(value.as_ref()).expect("test header should be valid"),
+        ));
+        self
+    }
+}
+
+impl IntoResponse for Reply {
+    fn into_response(self) -> Response {
+        let mut res = (self.status, Body::from(self.body)).into_response();
+        res.headers_mut().extend(self.headers);
+        res
+    }
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    replies: VecDeque<Reply>,
+    quota: Option<(u32, Reply)>,
+    hits: usize,
+}
+
+impl Script {
+    fn next(&mut self) -> Reply {
+        self.hits += 1;
+        if let Some((remaining, exhausted)) = &mut self.quota {
+            if *remaining == 0 {
+                return exhausted.clone();
+            }
+            *remaining -= 1;
+        }
+        match self.replies.len() {
+            0 => Reply::status(StatusCode::NOT_FOUND),
+            1 => self.replies[0].clone(),
+            _ => self.replies.pop_front().unwrap(),
+        }
+    }
+}
+
+type Scripts = Arc<Mutex<HashMap<String, Script>>>;
+
+#[derive(Debug)]
+pub struct MockUpstream {
+    base: Url,
+    scripts: Scripts,
+    task: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let scripts = Scripts::default();
+        let app = Router::new()
+            .fallback(Self::answer)
+            .with_state(scripts.clone());
+        let task = tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        MockUpstream {
+            base,
+            scripts,
+            task,
+        }
+    }
+
+    async fn answer(State(scripts): State<Scripts>, req: Request) -> Reply {
+        let mut scripts = scripts.lock().unwrap();
+        match scripts.get_mut(req.uri().path()) {
+            Some(script) => script.next(),
+            None => Reply::status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    /// Where to point [ExternalRequesterBuilder](crate::requester::ExternalRequesterBuilder)
+    pub fn base(&self) -> Url {
+        self.base.clone()
+    }
+
+    /// Answers `path` with `replies` in order, then keeps repeating the last one
+    pub fn on(&self, path: &str, replies: impl IntoIterator<Item = Reply>) -> &Self {
+        let mut scripts = self.scripts.lock().unwrap();
+        let script = scripts.entry(path.to_owned()).or_default();
+        script.replies = replies.into_iter().collect();
+        self
+    }
+
+    /// Lets `calls` more calls to `path` through, answering `exhausted` after that
+    pub fn quota(&self, path: &str, calls: u32, exhausted: Reply) -> &Self {
+        let mut scripts = self.scripts.lock().unwrap();
+        scripts.entry(path.to_owned()).or_default().quota = Some((calls, exhausted));
+        self
+    }
+
+    /// A new day: `path` gets `calls` more
+    pub fn refill(&self, path: &str, calls: u32) {
+        let mut scripts = self.scripts.lock().unwrap();
+        if let Some((remaining, _)) = &mut scripts.get_mut(path).expect("no such script").quota {
+            *remaining = calls;
+        }
+    }
+
+    /// How many calls `path` has had, whatever they were answered with
+    pub fn hits(&self, path: &str) -> usize {
+        self.scripts.lock().unwrap().get(path).map_or(0, |s| s.hits)
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
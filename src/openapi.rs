@@ -0,0 +1,186 @@
+//! The OpenAPI spec for the public endpoints, printed by `print-openapi`. Generated from the
+//! request and response types and the `#[utoipa::path]` on each handler, so it can't drift from
+//! what's served.
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Routes and place search for the flipmap app"),
+    paths(
+        crate::route,
+        crate::compare_routes,
+        crate::reroute,
+        crate::off_route,
+        crate::get_locations,
+        crate::reverse,
+        crate::favorites,
+        crate::save_trip,
+        crate::get_trip,
+        crate::create_share,
+        crate::get_share,
+        crate::about::get_about
+    ),
+    components(schemas(crate::error::ErrorResponse))
+)]
+struct ApiDoc;
+
+/// The spec as pretty-printed JSON
+pub fn spec_json() -> String {
+    ApiDoc::openapi()
+        .to_pretty_json()
+        .expect("OpenAPI spec should serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    fn spec() -> Value {
+        serde_json::from_str(&spec_json()).unwrap()
+    }
+
+    /// Every field and enum value in `schema`, one per line as `name.field: kind`, with a `?`
+    /// for optional fields
+    fn shape(name: &str, schema: &Value, lines: &mut BTreeSet<String>) {
+        if let Some(properties) = schema["properties"].as_object() {
+            let required = schema["required"].as_array();
+            for (field, property) in properties {
+                let optional = !required.is_some_and(|r| r.iter().any(|f| f == field));
+                let mark = if optional { "?" } else { "" };
+                lines.insert(format!("{name}.{field}{mark}: {}", kind(property)));
+                shape(&format!("{name}.{field}"), property, lines);
+            }
+        }
+        // Fields' own values are on their lines already
+        if schema["enum"].is_array() && !name.contains('.') {
+            lines.insert(format!("{name} = {}", kind(schema)));
+        }
+        for (variants, sep) in [("oneOf", "|"), ("allOf", "&"), ("anyOf", "|")] {
+            for variant in schema[variants].as_array().into_iter().flatten() {
+                if variant["$ref"].is_string() {
+                    lines.insert(format!("{name} {sep} {}", kind(variant)));
+                }
+                shape(name, variant, lines);
+            }
+        }
+    }
+
+    fn kind(schema: &Value) -> String {
+        let join = |kinds: Vec<String>| kinds.join(" | ");
+        if let Some(reference) = schema["$ref"].as_str() {
+            return reference.rsplit('/').next().unwrap_or(reference).to_owned();
+        }
+        if let Some(values) = schema["enum"].as_array() {
+            return join(values.iter().map(Value::to_string).collect());
+        }
+        if let Some(variants) = schema["oneOf"].as_array() {
+            return join(variants.iter().map(kind).collect());
+        }
+        let types = match &schema["type"] {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => vec!["any"],
+        };
+        join(
+            types
+                .into_iter()
+                .map(|t| match t {
+                    "array" => format!("[{}]", kind(&schema["items"])),
+                    "object" if schema["additionalProperties"].is_object() => {
+                        format!("{{string: {}}}", kind(&schema["additionalProperties"]))
+                    }
+                    t => t.to_owned(),
+                })
+                .collect(),
+        )
+    }
+
+    fn shapes() -> BTreeSet<String> {
+        let mut lines = BTreeSet::new();
+        for (name, schema) in spec()["components"]["schemas"].as_object().unwrap() {
+            shape(name, schema, &mut lines);
+        }
+        lines
+    }
+
+    // The app reads every name as it's sent, so one odd one out means special-casing it there
+    #[test]
+    fn names_are_snake_case() {
+        let snake = |name: &str| {
+            name.starts_with(|c: char| c.is_ascii_lowercase())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        };
+        for (name, schema) in spec()["components"]["schemas"].as_object().unwrap() {
+            let (mut fields, mut values) = (vec![], vec![]);
+            names(schema, &mut fields, &mut values);
+            for field in fields {
+                assert!(snake(&field), "{name}.{field}");
+            }
+            // Passed through as upstream spells them
+            if name == "OsmType" {
+                continue;
+            }
+            for value in values {
+                assert!(snake(&value), "{name} = {value:?}");
+            }
+        }
+    }
+
+    /// Every field name and enum value anywhere in `schema`
+    fn names(schema: &Value, fields: &mut Vec<String>, values: &mut Vec<String>) {
+        match schema {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    match (key.as_str(), value) {
+                        ("properties", Value::Object(properties)) => {
+                            fields.extend(properties.keys().cloned());
+                            properties.values().for_each(|p| names(p, fields, values));
+                        }
+                        ("enum", Value::Array(enums)) => {
+                            values.extend(enums.iter().filter_map(Value::as_str).map(Into::into))
+                        }
+                        _ => names(value, fields, values),
+                    }
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|item| names(item, fields, values)),
+            _ => {}
+        }
+    }
+
+    // A change here is a change to what the app gets. If it's meant, update the snapshot with
+    // UPDATE_EXPECT=1 and tell the app team
+    #[test]
+    fn shapes_unchanged() {
+        let shapes: Vec<_> = shapes().into_iter().collect();
+        expect_test::expect_file!["../snapshots/api_shapes.txt"]
+            .assert_eq(&(shapes.join("\n") + "\n"));
+    }
+
+    #[test]
+    fn spec_has_public_routes() {
+        let spec: serde_json::Value = serde_json::from_str(&spec_json()).unwrap();
+        for path in [
+            "/route",
+            "/route/compare",
+            "/reroute",
+            "/off_route",
+            "/get_locations",
+            "/reverse",
+            "/favorites",
+            "/trips",
+        ] {
+            assert!(spec["paths"][path]["post"].is_object(), "{path}");
+        }
+        for path in ["/trips/{id}", "/share/{token}", "/about"] {
+            assert!(spec["paths"][path]["get"].is_object(), "{path}");
+        }
+        let schemas = &spec["components"]["schemas"];
+        assert!(schemas["RouteRequest"]["properties"]["src_lat"].is_object());
+        assert!(schemas["PlaceResult"].is_object());
+    }
+}
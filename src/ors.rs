@@ -0,0 +1,693 @@
+//! What OpenRouteService puts in its route features' properties and in the collection's
+//! `metadata`, typed.
+//!
+//! ORS leaves things out rather than sending zeros (an empty `summary` for a zero-length route,
+//! no `steps` without instructions), so everything defaults. Strict mode only insists on the
+//! top-level keys ORS always sends.
+//!
+//! [OrsRoute] reads a directions answer straight off the wire, without a [FeatureCollection] in
+//! between. Long routes are mostly coordinates, and as one `Vec` per position they take several
+//! times the memory of the flat list handlers want in the end.
+use crate::error::RouteError;
+use crate::geojson_ext::{self, ParseMode, Properties};
+use crate::Result;
+use flipmap_api_types::{GradeStretch, NamedStretch, RouteAnnotations};
+use geojson::{Feature, FeatureCollection, JsonObject};
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+/// Totals for a route or one of its segments
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Summary {
+    /// Meters
+    pub distance: f64,
+    /// Seconds
+    pub duration: f64,
+}
+
+/// One turn-by-turn instruction. Only sent when instructions were asked for
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Step {
+    pub distance: f64,
+    pub duration: f64,
+    /// ORS instruction type: 0 is left, 1 right, 10 arrival, 11 departure. See their docs for
+    /// the rest
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub instruction: String,
+    /// Street name, `-` if it has none
+    pub name: String,
+    /// First and last index into the route's coordinates
+    pub way_points: [usize; 2],
+}
+
+/// The part of a route between two requested coordinates
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Segment {
+    pub distance: f64,
+    pub duration: f64,
+    pub steps: Vec<Step>,
+}
+
+/// Properties of a route feature
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct OrsProperties {
+    pub summary: Summary,
+    pub segments: Vec<Segment>,
+    /// Indices into the route's coordinates of each requested coordinate
+    pub way_points: Vec<usize>,
+    /// By extra_info name. Only what was asked for
+    pub extras: std::collections::HashMap<String, Extra>,
+}
+
+/// One kind of extra_info along the route
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Extra {
+    /// `[from, to, value]`, with `from` and `to` indices into the route's coordinates
+    pub values: Vec<[i64; 3]>,
+}
+
+/// What ORS's `surface` extra codes mean, by code
+const SURFACES: &[&str] = &[
+    "unknown",
+    "paved",
+    "unpaved",
+    "asphalt",
+    "concrete",
+    "cobblestone",
+    "metal",
+    "wood",
+    "compacted_gravel",
+    "fine_gravel",
+    "gravel",
+    "dirt",
+    "ground",
+    "ice",
+    "paving_stones",
+    "sand",
+    "woodchips",
+    "grass",
+    "grass_paver",
+];
+
+/// Ditto for `waytype`
+const WAYTYPES: &[&str] = &[
+    "unknown",
+    "state_road",
+    "road",
+    "street",
+    "path",
+    "track",
+    "cycleway",
+    "footway",
+    "steps",
+    "ferry",
+    "construction",
+];
+
+impl OrsProperties {
+    /// The extras asked for with [crate::requester::OpenRouteRequest::extra_info], as the app
+    /// gets them. Codes ORS adds later come out as `unknown`
+    pub fn annotations(&self) -> RouteAnnotations {
+        let stretches = |name: &str| {
+            self.extras
+                .get(name)
+                .map(|extra| extra.values.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|&[from, to, value]| (from as u32, to as u32, value))
+        };
+        let named = |name: &str, names: &[&str]| {
+            stretches(name)
+                .map(|(from, to, value)| NamedStretch {
+                    from,
+                    to,
+                    name: usize::try_from(value)
+                        .ok()
+                        .and_then(|i| names.get(i))
+                        .unwrap_or(&"unknown")
+                        .to_string(),
+                })
+                .collect()
+        };
+        RouteAnnotations {
+            surface: named("surface", SURFACES),
+            waytype: named("waytype", WAYTYPES),
+            steepness: stretches("steepness")
+                .map(|(from, to, grade)| GradeStretch {
+                    from,
+                    to,
+                    grade: grade.clamp(-5, 5) as i8,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Properties for OrsProperties {
+    const WHAT: &'static str = "ORS route properties";
+    const EXPECTED: &'static [&'static str] = &["summary", "segments", "way_points"];
+}
+
+impl OrsProperties {
+    pub fn of(feature: &Feature, mode: ParseMode) -> Result<Self> {
+        geojson_ext::extract_properties(feature, mode)
+    }
+}
+
+/// Which ORS build answered and how old its map is
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Engine {
+    pub version: String,
+    pub build_date: String,
+    /// When the OSM data was imported. Old dates on a self-hosted instance mean a stale graph
+    pub graph_date: String,
+}
+
+/// The collection's `metadata` member
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct OrsMetadata {
+    pub attribution: String,
+    pub service: String,
+    /// Unix milliseconds
+    pub timestamp: u64,
+    pub engine: Engine,
+}
+
+impl Properties for OrsMetadata {
+    const WHAT: &'static str = "ORS metadata";
+    const EXPECTED: &'static [&'static str] = &["engine"];
+}
+
+impl OrsMetadata {
+    /// `None` if there's no `metadata` at all
+    pub fn of(fc: &FeatureCollection, mode: ParseMode) -> Result<Option<Self>> {
+        let Some(metadata) = fc.foreign_members.as_ref().and_then(|m| m.get("metadata")) else {
+            return Ok(None);
+        };
+        geojson_ext::parse_object(metadata, mode).map(Some)
+    }
+}
+
+/// The first route of a directions answer, with its geometry already [flattened]
+/// (geojson_ext::flatten), heights split off, and the rest left for later
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OrsRoute {
+    /// Positions one after another, as the app wants them. Always two numbers each
+    pub route: Vec<f64>,
+    /// Each position's height in meters, if ORS was asked for them. Empty if any is missing
+    pub heights: Vec<f64>,
+    properties: JsonObject,
+    metadata: Option<serde_json::Value>,
+}
+
+impl OrsRoute {
+    /// The slow way round, for anything that already has a [FeatureCollection]
+    pub fn from_collection(fc: &FeatureCollection) -> Result<Self> {
+        let feature = fc.features.first().ok_or_else(|| {
+            RouteError::new_external_parse_failure("feature collection is empty".to_owned())
+        })?;
+        let line = geojson_ext::extract_linestring(feature)?;
+        let heights = if line.iter().all(|p| p.len() > 2) {
+            line.iter().map(|p| p[2]).collect()
+        } else {
+            vec![]
+        };
+        Ok(OrsRoute {
+            route: line
+                .iter()
+                .flat_map(|p| p.iter().take(2))
+                .copied()
+                .collect(),
+            heights,
+            properties: feature.properties.clone().unwrap_or_default(),
+            metadata: fc
+                .foreign_members
+                .as_ref()
+                .and_then(|m| m.get("metadata"))
+                .cloned(),
+        })
+    }
+
+    pub fn properties(&self, mode: ParseMode) -> Result<OrsProperties> {
+        geojson_ext::parse_object(&serde_json::Value::Object(self.properties.clone()), mode)
+    }
+
+    /// `None` if there's no `metadata` at all
+    pub fn metadata(&self, mode: ParseMode) -> Result<Option<OrsMetadata>> {
+        self.metadata
+            .as_ref()
+            .map(|m| geojson_ext::parse_object(m, mode))
+            .transpose()
+    }
+}
+
+/// What comes off the wire, before checking it's actually a route. Anything that isn't GeoJSON
+/// at all is a deserialization error; GeoJSON without a usable route is sorted out in
+/// [OrsRoute::try_from], as a content error like [geojson_ext] gives
+#[derive(Debug, Default)]
+pub struct RawRoute {
+    first: Option<RawFeature>,
+    metadata: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Default)]
+struct RawFeature {
+    /// `None` for a null or missing geometry
+    geometry: Option<RawGeometry>,
+    properties: Option<JsonObject>,
+}
+
+#[derive(Debug, Default)]
+struct RawGeometry {
+    kind: String,
+    /// Just the first two numbers of each position
+    coordinates: Vec<f64>,
+    /// The third, where there is one
+    heights: Vec<f64>,
+    /// Fewest numbers in any position
+    min_dims: Option<usize>,
+}
+
+impl TryFrom<RawRoute> for OrsRoute {
+    type Error = RouteError;
+
+    fn try_from(raw: RawRoute) -> Result<Self> {
+        let fail = |msg: String| Err(RouteError::new_external_parse_failure(msg));
+        let Some(feature) = raw.first else {
+            return fail("feature collection is empty".to_owned());
+        };
+        let Some(geometry) = feature.geometry else {
+            return fail("feature has no geometry".to_owned());
+        };
+        if geometry.kind != "LineString" {
+            return fail(format!(
+                "found {} geojson datatype instead of LineString",
+                geometry.kind
+            ));
+        }
+        if let Some(dims) = geometry.min_dims.filter(|d| *d < 2) {
+            return fail(format!(
+                "linestring has a position with {dims} dimension(s)"
+            ));
+        }
+        let mut heights = geometry.heights;
+        if heights.len() * 2 != geometry.coordinates.len() {
+            heights.clear();
+        }
+        Ok(OrsRoute {
+            route: geometry.coordinates,
+            heights,
+            properties: feature.properties.unwrap_or_default(),
+            metadata: raw.metadata,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for RawRoute {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct CollectionVisitor;
+        impl<'de> Visitor<'de> for CollectionVisitor {
+            type Value = RawRoute;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a GeoJSON FeatureCollection")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> std::result::Result<RawRoute, A::Error> {
+                let mut raw = RawRoute::default();
+                let mut seen_features = false;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "type" => {
+                            let kind: String = map.next_value()?;
+                            if kind != "FeatureCollection" {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Str(&kind),
+                                    &"FeatureCollection",
+                                ));
+                            }
+                        }
+                        "features" => {
+                            raw.first = map.next_value_seed(FirstFeature)?;
+                            seen_features = true;
+                        }
+                        "metadata" => raw.metadata = Some(map.next_value()?),
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                if !seen_features {
+                    return Err(de::Error::missing_field("features"));
+                }
+                Ok(raw)
+            }
+        }
+        deserializer.deserialize_map(CollectionVisitor)
+    }
+}
+
+/// Keeps the first feature of the array and skips the rest
+struct FirstFeature;
+
+impl<'de> DeserializeSeed<'de> for FirstFeature {
+    type Value = Option<RawFeature>;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for FirstFeature {
+    type Value = Option<RawFeature>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of features")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let first = seq.next_element::<RawFeature>()?;
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(first)
+    }
+}
+
+impl<'de> Deserialize<'de> for RawFeature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct FeatureVisitor;
+        impl<'de> Visitor<'de> for FeatureVisitor {
+            type Value = RawFeature;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a GeoJSON Feature")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> std::result::Result<RawFeature, A::Error> {
+                let mut feature = RawFeature::default();
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "geometry" => feature.geometry = map.next_value()?,
+                        "properties" => feature.properties = map.next_value()?,
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(feature)
+            }
+        }
+        deserializer.deserialize_map(FeatureVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for RawGeometry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct GeometryVisitor;
+        impl<'de> Visitor<'de> for GeometryVisitor {
+            type Value = RawGeometry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a GeoJSON geometry")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> std::result::Result<RawGeometry, A::Error> {
+                let mut geometry = RawGeometry::default();
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "type" => geometry.kind = map.next_value()?,
+                        // Flattened as it's read. Only LineStrings have this shape, so anything
+                        // else comes out wrong here but is rejected on its type afterwards
+                        "coordinates" => map.next_value_seed(Flatten(&mut geometry))?,
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(geometry)
+            }
+        }
+        deserializer.deserialize_map(GeometryVisitor)
+    }
+}
+
+/// Appends every position's numbers to the geometry's coordinates. A bare number counts as a
+/// one-number position, so Points make it through to be rejected by type
+struct Flatten<'a>(&'a mut RawGeometry);
+
+impl<'de> DeserializeSeed<'de> for Flatten<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Flatten<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of positions")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        while seq.next_element_seed(Position(&mut *self.0))?.is_some() {}
+        Ok(())
+    }
+}
+
+struct Position<'a>(&'a mut RawGeometry);
+
+impl<'de> DeserializeSeed<'de> for Position<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Position<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a position")
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<(), E> {
+        self.0.coordinates.push(v);
+        self.0.min_dims = Some(self.0.min_dims.map_or(1, |d| d.min(1)));
+        Ok(())
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<(), E> {
+        self.visit_f64(v as f64)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<(), E> {
+        self.visit_f64(v as f64)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        let mut dims = 0;
+        while let Some(n) = seq.next_element::<f64>()? {
+            match dims {
+                0 | 1 => self.0.coordinates.push(n),
+                2 => self.0.heights.push(n),
+                _ => {}
+            }
+            dims += 1;
+        }
+        self.0.min_dims = Some(self.0.min_dims.map_or(dims, |d| d.min(dims)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcr::fixture;
+
+    #[test]
+    fn reads_fixture() {
+        let fc: FeatureCollection = serde_json::from_str(&fixture("ors_directions")).unwrap();
+        let props = OrsProperties::of(&fc.features[0], ParseMode::Lenient).unwrap();
+        assert_eq!(props.summary.distance, 493.8);
+        assert_eq!(props.way_points, vec![0, 11]);
+        let steps = &props.segments[0].steps;
+        assert_eq!(steps[0].kind, 11);
+        assert_eq!(steps.last().unwrap().way_points, [11, 11]);
+
+        let metadata = OrsMetadata::of(&fc, ParseMode::Lenient).unwrap().unwrap();
+        assert_eq!(metadata.engine.graph_date, "2025-05-04T17:44:45Z");
+    }
+
+    #[test]
+    fn zero_length_route() {
+        let fc = crate::test_utils::collection(serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "properties": { "summary": {}, "segments": [{ "steps": [] }] },
+                "geometry": { "type": "LineString", "coordinates": [[1.0, 2.0], [1.0, 2.0]] }
+            }],
+            "metadata": { "engine": 9 }
+        }));
+        let props = OrsProperties::of(&fc.features[0], ParseMode::Lenient).unwrap();
+        assert_eq!(props.summary, Summary::default());
+        // Lenient unless main says otherwise
+        assert_eq!(
+            OrsMetadata::of(&fc, ParseMode::Lenient).unwrap(),
+            Some(OrsMetadata::default())
+        );
+    }
+
+    #[test]
+    fn annotations_from_extras() {
+        let fc = crate::test_utils::collection(serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "properties": {
+                    "summary": {}, "segments": [], "way_points": [0, 3],
+                    "extras": {
+                        "surface": { "values": [[0, 2, 3], [2, 3, 11]], "summary": [] },
+                        "waytype": { "values": [[0, 3, 99]] },
+                        "steepness": { "values": [[0, 1, -2], [1, 3, 7]] }
+                    }
+                },
+                "geometry": { "type": "LineString", "coordinates": [[1.0, 2.0], [1.0, 2.1]] }
+            }]
+        }));
+        let annotations = OrsProperties::of(&fc.features[0], ParseMode::Lenient)
+            .unwrap()
+            .annotations();
+        let names = |stretches: &[NamedStretch]| {
+            stretches
+                .iter()
+                .map(|s| (s.from, s.to, s.name.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&annotations.surface),
+            [(0, 2, "asphalt".into()), (2, 3, "dirt".into())]
+        );
+        // A code newer than us
+        assert_eq!(names(&annotations.waytype), [(0, 3, "unknown".into())]);
+        let grades: Vec<_> = annotations.steepness.iter().map(|s| s.grade).collect();
+        assert_eq!(grades, [-2, 5]);
+    }
+
+    fn streamed(body: serde_json::Value) -> Result<OrsRoute> {
+        serde_json::from_value::<RawRoute>(body)?.try_into()
+    }
+
+    #[test]
+    fn streamed_matches_collection() {
+        let body = fixture("ors_directions");
+        let fc: FeatureCollection = serde_json::from_str(&body).unwrap();
+        let streamed: OrsRoute = serde_json::from_str::<RawRoute>(&body)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(streamed, OrsRoute::from_collection(&fc).unwrap());
+        assert_eq!(streamed.route.len(), 24);
+        assert_eq!(
+            streamed.properties(ParseMode::Lenient).unwrap().way_points,
+            vec![0, 11]
+        );
+        assert!(streamed.metadata(ParseMode::Lenient).unwrap().is_some());
+    }
+
+    #[test]
+    fn heights_kept_apart() {
+        let fc = |coordinates: serde_json::Value| {
+            serde_json::json!({
+                "type": "FeatureCollection",
+                "features": [{
+                    "type": "Feature",
+                    "properties": {},
+                    "geometry": { "type": "LineString", "coordinates": coordinates }
+                }]
+            })
+        };
+        let both = |body: serde_json::Value| {
+            let streamed = streamed(body.clone()).unwrap();
+            let fc = crate::test_utils::collection(body);
+            assert_eq!(streamed, OrsRoute::from_collection(&fc).unwrap());
+            streamed
+        };
+        let route = both(fc(serde_json::json!([[1.0, 2.0, 30.5], [1.1, 2.1, 28.0]])));
+        assert_eq!(route.route, [1.0, 2.0, 1.1, 2.1]);
+        assert_eq!(route.heights, [30.5, 28.0]);
+        let route = both(fc(serde_json::json!([[1.0, 2.0, 30.5], [1.1, 2.1]])));
+        assert_eq!(route.route, [1.0, 2.0, 1.1, 2.1]);
+        assert!(route.heights.is_empty());
+    }
+
+    #[test]
+    fn streamed_errors() {
+        let content = |res: Result<OrsRoute>| {
+            assert!(
+                matches!(res, Err(RouteError::ExternalAPIContent(_))),
+                "{res:?}"
+            )
+        };
+        let json = |res: Result<OrsRoute>| {
+            assert!(
+                matches!(res, Err(RouteError::ExternalAPIJson(_))),
+                "{res:?}"
+            )
+        };
+        let fc = |features: serde_json::Value| serde_json::json!({ "type": "FeatureCollection", "features": features });
+
+        content(streamed(fc(serde_json::json!([]))));
+        content(streamed(fc(serde_json::json!([
+            { "type": "Feature", "properties": {}, "geometry": null }
+        ]))));
+        content(streamed(fc(serde_json::json!([{
+            "type": "Feature",
+            "geometry": { "coordinates": [1.0, 2.0], "type": "Point" }
+        }]))));
+        content(streamed(fc(serde_json::json!([{
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": [[1.0, 2.0], [3.0]] }
+        }]))));
+        json(streamed(serde_json::json!({ "error": { "code": 2010 } })));
+        json(streamed(
+            serde_json::json!({ "type": "Feature", "features": [] }),
+        ));
+        json(streamed(fc(serde_json::json!([{
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": [["a", "b"]] }
+        }]))));
+    }
+}
@@ -0,0 +1,197 @@
+//! What goes out to ORS's directions endpoint for an [OpenRouteRequest].
+//!
+//! ORS answers a body it doesn't like with a 400 that rarely says which option was the problem,
+//! and [OpenRouteRequest] keeps growing options. So the body is checked here before it's sent,
+//! options at ORS's defaults are left out so it only says what we changed, and the exact body
+//! is logged at trace level with the coordinates blurred, as [crate::audit] does.
+use crate::geo;
+use crate::requester::OpenRouteRequest;
+use serde::Serialize;
+
+/// Geohash length coordinates are blurred to in the trace log. 6 is roughly a kilometer
+const COORD_GEOHASH: usize = 6;
+
+/// Why a request wasn't sent. Always our bug, never the client's
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum InvalidOrsRequest {
+    #[error("{0} coordinates, ORS wants at least 2")]
+    TooFewCoordinates(usize),
+    #[error("coordinate {0} isn't a finite lon, lat pair")]
+    BadCoordinate(usize),
+    #[error("{bearings} bearings for {coordinates} coordinates, ORS wants one each")]
+    BearingCount { bearings: usize, coordinates: usize },
+    #[error("bearing {0} isn't [] or [0-360, 0-180]")]
+    BadBearing(usize),
+}
+
+/// The body as sent
+#[derive(Serialize, Debug, PartialEq)]
+pub struct OrsBody<'a> {
+    coordinates: &'a [geojson::Position],
+    /// ORS sends instructions unless told not to
+    #[serde(skip_serializing_if = "is_true")]
+    instructions: bool,
+    /// Left out if every coordinate may be reached any way, which is what ORS does without them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bearings: Option<&'a [Vec<f64>]>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    extra_info: &'static [&'static str],
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    elevation: bool,
+}
+
+/// What [OpenRouteRequest::extra_info] asks for
+const EXTRA_INFO: &[&str] = &["surface", "waytype", "steepness"];
+
+fn is_true(b: &bool) -> bool {
+    *b
+}
+
+impl<'a> OrsBody<'a> {
+    /// `req`'s body, if ORS would take it
+    pub fn of(req: &'a OpenRouteRequest) -> Result<Self, InvalidOrsRequest> {
+        let coordinates = &req.coordinates;
+        if coordinates.len() < 2 {
+            return Err(InvalidOrsRequest::TooFewCoordinates(coordinates.len()));
+        }
+        if let Some(i) = coordinates
+            .iter()
+            .position(|c| c.len() != 2 || !c.iter().all(|n| n.is_finite()))
+        {
+            return Err(InvalidOrsRequest::BadCoordinate(i));
+        }
+        let bearings = match req.bearings.as_deref() {
+            Some(bearings) if bearings.iter().all(Vec::is_empty) => None,
+            Some(bearings) if bearings.len() != coordinates.len() => {
+                return Err(InvalidOrsRequest::BearingCount {
+                    bearings: bearings.len(),
+                    coordinates: coordinates.len(),
+                })
+            }
+            Some(bearings) => {
+                let fine = |b: &Vec<f64>| match b[..] {
+                    [] => true,
+                    [bearing, deviation] => {
+                        (0.0..=360.0).contains(&bearing) && (0.0..=180.0).contains(&deviation)
+                    }
+                    _ => false,
+                };
+                if let Some(i) = bearings.iter().position(|b| !fine(b)) {
+                    return Err(InvalidOrsRequest::BadBearing(i));
+                }
+                Some(bearings)
+            }
+            None => None,
+        };
+        Ok(OrsBody {
+            coordinates,
+            instructions: req.instructions,
+            bearings,
+            extra_info: if req.extra_info { EXTRA_INFO } else { &[] },
+            elevation: req.elevation,
+        })
+    }
+
+    /// The body as JSON, with each coordinate swapped for its geohash cell. Long routes keep
+    /// only their ends
+    pub fn redacted(&self) -> String {
+        let mut cells: Vec<String> = self
+            .coordinates
+            .iter()
+            .map(|c| geo::geohash((c[1], c[0]), COORD_GEOHASH))
+            .collect();
+        if cells.len() > 4 {
+            let hidden = cells.len() - 2;
+            cells.splice(1..cells.len() - 1, [format!("...{hidden} more")]);
+        }
+        let mut body = serde_json::to_value(self).expect("ORS body should serialize");
+        body["coordinates"] = cells.into();
+        body.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> OpenRouteRequest {
+        OpenRouteRequest {
+            coordinates: vec![vec![-123.2796, 44.5672], vec![-123.2778, 44.5687]],
+            instructions: true,
+            bearings: None,
+            profile: Default::default(),
+            extra_info: false,
+            elevation: false,
+        }
+    }
+
+    #[test]
+    fn defaults_left_out() {
+        let req = request();
+        let body = serde_json::to_value(OrsBody::of(&req).unwrap()).unwrap();
+        assert_eq!(body.as_object().unwrap().len(), 1, "{body}");
+
+        let req = OpenRouteRequest {
+            instructions: false,
+            bearings: Some(vec![vec![], vec![]]),
+            ..request()
+        };
+        let body = serde_json::to_value(OrsBody::of(&req).unwrap()).unwrap();
+        assert_eq!(body["instructions"], false);
+        assert!(body.get("bearings").is_none());
+
+        let req = OpenRouteRequest {
+            extra_info: true,
+            elevation: true,
+            ..request()
+        };
+        let body = serde_json::to_value(OrsBody::of(&req).unwrap()).unwrap();
+        assert_eq!(body["extra_info"][0], "surface");
+        assert_eq!(body["elevation"], true);
+    }
+
+    #[test]
+    fn bad_combinations_caught() {
+        let one_bearing = OpenRouteRequest {
+            bearings: Some(vec![vec![90.0, 45.0]]),
+            ..request()
+        };
+        assert_eq!(
+            OrsBody::of(&one_bearing),
+            Err(InvalidOrsRequest::BearingCount {
+                bearings: 1,
+                coordinates: 2
+            })
+        );
+        let backwards = OpenRouteRequest {
+            bearings: Some(vec![vec![45.0, 400.0], vec![]]),
+            ..request()
+        };
+        assert_eq!(
+            OrsBody::of(&backwards),
+            Err(InvalidOrsRequest::BadBearing(0))
+        );
+        let nowhere = OpenRouteRequest {
+            coordinates: vec![vec![f64::NAN, 0.0], vec![0.0, 0.0]],
+            ..request()
+        };
+        assert_eq!(
+            OrsBody::of(&nowhere),
+            Err(InvalidOrsRequest::BadCoordinate(0))
+        );
+    }
+
+    #[test]
+    fn redacted_blurs_coordinates() {
+        let req = OpenRouteRequest {
+            coordinates: vec![vec![-123.2796, 44.5672]; 6],
+            ..request()
+        };
+        let logged = OrsBody::of(&req).unwrap().redacted();
+        assert!(!logged.contains("44.5"), "{logged}");
+        assert!(
+            logged.contains(r#"["9rbmd9","...4 more","9rbmd9"]"#),
+            "{logged}"
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Turns handler panics into 500 responses rather than dropped connections.
+//!
+//! [tower_http::catch_panic] only hands over the payload, so a panic hook stashes the backtrace and
+//! location for [handle_panic] to log and report. Both run on the panicking thread, which is what
+//! makes the hand-off work.
+use crate::error::RouteError;
+use crate::report::ErrorSummary;
+use axum::response::{IntoResponse, Response};
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+
+thread_local! {
+    /// The last panic's backtrace and location
+    static LAST_PANIC: RefCell<Option<(Backtrace, Option<String>)>> = const { RefCell::new(None) };
+}
+
+/// Chains onto the existing panic hook to capture a backtrace for [handle_panic]. Captures even
+/// without `RUST_BACKTRACE` set; panics should be rare enough that the cost doesn't matter.
+pub fn install_backtrace_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let location = info.location().map(|l| l.to_string());
+        LAST_PANIC.with(|last| *last.borrow_mut() = Some((Backtrace::force_capture(), location)));
+        previous(info);
+    }));
+}
+
+/// What `panic!` was given. Anything but a string, which is rare, isn't worth showing
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "non-string panic payload".to_owned()
+    }
+}
+
+/// Used with [tower_http::catch_panic::CatchPanicLayer::custom]. Runs inside the request span, so
+/// the trace carries the request id, method, and URI. The response carries an [ErrorSummary] for
+/// [crate::report::report_errors], which adds the same.
+pub fn handle_panic(payload: Box<dyn Any + Send + 'static>) -> Response {
+    let message = panic_message(&*payload);
+    let (backtrace, location) = match LAST_PANIC.with(|last| last.borrow_mut().take()) {
+        Some((backtrace, location)) => (backtrace.to_string(), location),
+        None => ("unavailable".to_owned(), None),
+    };
+    tracing::error!("handler panicked: {message}\nbacktrace:\n{backtrace}");
+    let mut response = RouteError::Panicked.into_response();
+    response
+        .extensions_mut()
+        .insert(ErrorSummary::panicked(message, location));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn panic_becomes_500() {
+        let payload = std::panic::catch_unwind(|| panic!("index out of bounds")).unwrap_err();
+        let response = handle_panic(payload);
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let summary = response.extensions().get::<ErrorSummary>().unwrap();
+        assert!(summary.panicked);
+        assert_eq!(summary.message, "index out of bounds");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["message"], "internal server error");
+    }
+
+    #[test]
+    fn messages_of_any_payload() {
+        let formatted = std::panic::catch_unwind(|| panic!("{} out of bounds", 3)).unwrap_err();
+        assert_eq!(panic_message(&*formatted), "3 out of bounds");
+        let other = std::panic::catch_unwind(|| std::panic::panic_any(3)).unwrap_err();
+        assert_eq!(panic_message(&*other), "non-string panic payload");
+    }
+}
@@ -0,0 +1,59 @@
+//! Answers for endpoints made of several upstream calls, like `/route/compare` and `/favorites`.
+//!
+//! One call tripping a limit shouldn't fail the rest, so each part carries its own `error`, as
+//! [RouteError::public](crate::error::RouteError::public) would send it, and the answer as a whole
+//! is an HTTP 207 when some parts failed. Whether a part failing fails the lot is up to the
+//! endpoint.
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+/// `R`, as a 207 if some of its parts failed
+pub struct Partial<R> {
+    /// For `partial_responses_total`, which counts the 207s
+    route: &'static str,
+    failed: bool,
+    response: R,
+}
+
+impl<R> Partial<R> {
+    pub fn new(route: &'static str, failed: bool, response: R) -> Self {
+        Partial {
+            route,
+            failed,
+            response,
+        }
+    }
+}
+
+impl<R: IntoResponse> IntoResponse for Partial<R> {
+    fn into_response(self) -> Response {
+        let mut response = self.response.into_response();
+        if self.failed && response.status() == StatusCode::OK {
+            *response.status_mut() = StatusCode::MULTI_STATUS;
+            crate::metrics::registry().inc_counter(
+                "partial_responses_total",
+                &[("route", self.route)],
+                1,
+            );
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_status_only_when_some_failed() {
+        let ok = Partial::new("/test", false, "all there").into_response();
+        assert_eq!(ok.status(), StatusCode::OK);
+        let partial = Partial::new("/test", true, "some there").into_response();
+        assert_eq!(partial.status(), StatusCode::MULTI_STATUS);
+        // Errors are left alone
+        let failed = Partial::new("/test", true, StatusCode::NOT_FOUND).into_response();
+        assert_eq!(failed.status(), StatusCode::NOT_FOUND);
+    }
+}
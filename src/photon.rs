@@ -0,0 +1,66 @@
+//! What Photon puts in its features' properties, typed.
+//!
+//! Photon's properties are OSM tags and address parts, and which ones show up depends on the
+//! place. Everything is optional; unknown keys are ignored. Only the OSM identifiers are always
+//! there, so only those count as missing in strict mode.
+use crate::geojson_ext::{self, ParseMode};
+use crate::Result;
+use geojson::Feature;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PhotonProperties {
+    pub name: Option<String>,
+    pub housenumber: Option<String>,
+    pub street: Option<String>,
+    pub postcode: Option<String>,
+    pub district: Option<String>,
+    pub locality: Option<String>,
+    pub city: Option<String>,
+    pub county: Option<String>,
+    pub state: Option<String>,
+    pub country: Option<String>,
+    /// ISO 3166-1 alpha-2, upper case
+    pub countrycode: Option<String>,
+    pub osm_id: Option<u64>,
+    /// `N`ode, `W`ay or `R`elation
+    pub osm_type: Option<String>,
+    /// The OSM tag that made this a place, e.g. `amenity`=`restaurant`
+    pub osm_key: Option<String>,
+    pub osm_value: Option<String>,
+    /// Photon's own classification: `house`, `street`, `city`, ...
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// Bounding box as `[min_lon, max_lat, max_lon, min_lat]`. Only for things bigger than a point
+    pub extent: Option<[f64; 4]>,
+}
+
+impl geojson_ext::Properties for PhotonProperties {
+    const WHAT: &'static str = "Photon feature properties";
+    const EXPECTED: &'static [&'static str] = &["osm_id", "osm_type", "osm_key", "osm_value"];
+}
+
+impl PhotonProperties {
+    pub fn of(feature: &Feature, mode: ParseMode) -> Result<Self> {
+        geojson_ext::extract_properties(feature, mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcr::fixture;
+
+    #[test]
+    fn reads_fixture() {
+        let fc: geojson::FeatureCollection =
+            serde_json::from_str(&fixture("photon_geocode")).unwrap();
+        let first = PhotonProperties::of(&fc.features[0], ParseMode::Lenient).unwrap();
+        assert_eq!(first.name.as_deref(), Some("Downward Dog"));
+        assert_eq!(first.city.as_deref(), Some("Corvallis"));
+        assert_eq!(first.osm_id, Some(384119068));
+        assert_eq!(first.kind.as_deref(), Some("house"));
+        assert!(first.extent.is_some());
+        assert!(first.housenumber.is_none());
+    }
+}
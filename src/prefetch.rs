@@ -0,0 +1,183 @@
+//! Looking up where a route ends before the app asks, so its arrival screen loads straight away.
+//!
+//! With `--prefetch-destinations`, each route fetched from ORS has its destination reverse
+//! geocoded in the background, filling the cache `/reverse` reads. Nothing is sent to the app.
+//! It only happens while at least the given percent of Photon's quota is left, both in our
+//! politeness limits and under `--photon-daily-cap`, so it never crowds out searches. Every
+//! attempt is counted in `reverse_prefetch_total` by outcome.
+use crate::cache::TtlCache;
+use crate::canonical;
+use crate::metrics;
+use crate::requester::{ExternalApi, PhotonRevGeocodeRequest};
+use crate::reverse;
+use crate::usage::Ledger;
+use crate::workers;
+use crate::RouteRequest;
+use geojson::FeatureCollection;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::time::Duration;
+
+/// Places don't move much. Long enough to cover the trip to them
+pub const REVERSE_CACHE_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+pub const REVERSE_CACHE_CAPACITY: usize = 10_000;
+/// Prefetches in flight at once. Anything past it is skipped
+const SLOTS: usize = 2;
+
+/// Photon's answers by [reverse_key], whatever granularity they're picked at
+pub type ReverseCache = TtlCache<(u64, u64, Option<String>), Arc<FeatureCollection>>;
+
+/// `(lat, lon)` as a cache key, so positions that mean the same share one
+pub fn reverse_key(lat: f64, lon: f64, lang: Option<&str>) -> (u64, u64, Option<String>) {
+    (
+        canonical::coord(lat).to_bits(),
+        canonical::coord(lon).to_bits(),
+        lang.map(str::to_owned),
+    )
+}
+
+/// What `/reverse` asks Photon for `(lat, lon)`. Prefetches ask the same, so either answers both
+pub fn reverse_request(lat: f64, lon: f64, lang: Option<String>) -> PhotonRevGeocodeRequest {
+    PhotonRevGeocodeRequest {
+        lat,
+        lon,
+        limit: Some(reverse::NEARBY),
+        lang,
+    }
+}
+
+/// Cheap to clone; clones share the slots
+#[derive(Clone)]
+pub struct Prefetch {
+    client: Arc<dyn ExternalApi>,
+    cache: ReverseCache,
+    ledger: Ledger,
+    /// Share of Photon's quota, 0 to 1, that must be left
+    min_left: f64,
+    slots: Arc<Semaphore>,
+}
+
+impl Prefetch {
+    /// Prefetches through `client` into `cache` while at least `min_left_percent` of Photon's
+    /// quota is left
+    pub fn new(
+        client: Arc<dyn ExternalApi>,
+        cache: ReverseCache,
+        ledger: Ledger,
+        min_left_percent: f64,
+    ) -> Self {
+        tracing::info!(
+            "prefetching route destinations while {min_left_percent}% of Photon's quota is left"
+        );
+        Prefetch {
+            client,
+            cache,
+            ledger,
+            min_left: (min_left_percent / 100.0).clamp(0.0, 1.0),
+            slots: Arc::new(Semaphore::new(workers::slots(SLOTS))),
+        }
+    }
+
+    /// Looks up where `params` ends in the background, in `lang` (see [crate::language]), unless
+    /// it's cached or quota is short
+    pub fn destination(&self, params: &RouteRequest, lang: Option<String>) {
+        let key = reverse_key(params.dst_lat, params.dst_lon, lang.as_deref());
+        if self.cache.get_fresh(&key).is_some() {
+            return count("cached");
+        }
+        if self.ledger.photon_left(&*self.client) < self.min_left {
+            return count("low_quota");
+        }
+        let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+            return count("busy");
+        };
+        let (client, cache) = (self.client.clone(), self.cache.clone());
+        let req = reverse_request(params.dst_lat, params.dst_lon, lang);
+        tokio::spawn(async move {
+            let _slot = slot;
+            match client.photon_reverse_send(&req).await {
+                Ok(features) => {
+                    cache.insert(key, Arc::new(features));
+                    count("fetched");
+                }
+                Err(e) => {
+                    tracing::debug!("couldn't prefetch destination: {e}");
+                    count("failed");
+                }
+            }
+        });
+    }
+}
+
+fn count(outcome: &'static str) {
+    metrics::registry().inc_counter("reverse_prefetch_total", &[("outcome", outcome)], 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintenance::Maintenance;
+    use crate::requester::Provider;
+    use crate::test_utils::{collection, CannedApi};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn route() -> RouteRequest {
+        RouteRequest {
+            src_lat: 44.5672,
+            src_lon: -123.2796,
+            dst_lat: 44.5687,
+            dst_lon: -123.2778,
+            heading: None,
+            annotations: false,
+            borders: false,
+            elevation: false,
+        }
+    }
+
+    fn counting_api(calls: Arc<AtomicUsize>) -> Arc<dyn ExternalApi> {
+        Arc::new(CannedApi::default().with_reverse(move || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(collection(
+                json!({ "type": "FeatureCollection", "features": [] }),
+            ))
+        }))
+    }
+
+    #[tokio::test]
+    async fn fills_cache_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = ReverseCache::new(REVERSE_CACHE_TTL, 10);
+        let ledger = Ledger::new(HashMap::new(), Maintenance::default());
+        let prefetch = Prefetch::new(counting_api(calls.clone()), cache.clone(), ledger, 50.0);
+
+        prefetch.destination(&route(), Some("de".to_owned()));
+        while cache.len() == 0 {
+            tokio::task::yield_now().await;
+        }
+        prefetch.destination(&route(), Some("de".to_owned()));
+        tokio::task::yield_now().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(cache
+            .get_fresh(&reverse_key(44.5687, -123.2778, Some("de")))
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn holds_off_when_quota_is_short() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = ReverseCache::new(REVERSE_CACHE_TTL, 10);
+        // Used up, as far as the ledger's concerned
+        let ledger = Ledger::new(
+            HashMap::from([(Provider::Photon, 0)]),
+            Maintenance::default(),
+        );
+        let prefetch = Prefetch::new(counting_api(calls.clone()), cache.clone(), ledger, 50.0);
+
+        prefetch.destination(&route(), Some("de".to_owned()));
+        tokio::task::yield_now().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,110 @@
+//! The `probe` subcommand: one real call to each upstream with the configured client, so a deploy
+//! pipeline can tell a bad key or unreachable provider apart from a bad build.
+use crate::requester::{ExternalApi, OpenRouteRequest, PhotonGeocodeRequest, Provider};
+use std::fmt;
+use tokio::time::{Duration, Instant};
+
+/// How one upstream call went
+#[derive(Debug)]
+pub struct Outcome {
+    pub provider: Provider,
+    pub elapsed: Duration,
+    /// A few words about the answer, or the whole error chain
+    pub result: Result<String, String>,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (verdict, detail) = match &self.result {
+            Ok(detail) => ("ok", detail),
+            Err(detail) => ("FAILED", detail),
+        };
+        write!(
+            f,
+            "{} {verdict} in {}ms: {detail}",
+            self.provider,
+            self.elapsed.as_millis()
+        )
+    }
+}
+
+/// The error and everything under it, since the top level alone is deliberately vague
+fn chain(err: &dyn std::error::Error) -> String {
+    let mut out = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        out.push_str(": ");
+        out.push_str(&err.to_string());
+        source = err.source();
+    }
+    out
+}
+
+async fn timed<T>(
+    provider: Provider,
+    call: impl std::future::Future<Output = crate::Result<T>>,
+    describe: impl FnOnce(T) -> String,
+) -> Outcome {
+    let started = Instant::now();
+    let result = call.await;
+    Outcome {
+        provider,
+        elapsed: started.elapsed(),
+        result: result.map(describe).map_err(|e| chain(&e)),
+    }
+}
+
+/// Asks ORS for a short route and Photon for a place, both around campus
+pub async fn call_each(client: &dyn ExternalApi) -> Vec<Outcome> {
+    let route = OpenRouteRequest {
+        coordinates: vec![
+            vec![-123.27963174780633, 44.56720205],
+            vec![-123.27788489405276, 44.5687606],
+        ],
+        instructions: false,
+        bearings: None,
+        profile: Default::default(),
+        extra_info: false,
+        elevation: false,
+    };
+    let search = PhotonGeocodeRequest::new(1, "Corvallis".to_owned());
+    vec![
+        timed(
+            Provider::OpenRouteService,
+            client.ors_route(&route),
+            |route| format!("{} points", route.route.len() / 2),
+        )
+        .await,
+        timed(Provider::Photon, client.photon_send(&search), |places| {
+            format!("{} places", places.features.len())
+        })
+        .await,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{MockUpstream, Reply};
+    use crate::requester::{ExternalRequester, ORS_DIRECTIONS_PATH, PHOTON_PATH};
+    use crate::vcr::fixture;
+    use reqwest::StatusCode;
+
+    #[tokio::test]
+    async fn reports_each_provider() {
+        let upstream = MockUpstream::start().await;
+        upstream
+            .on(
+                ORS_DIRECTIONS_PATH,
+                [Reply::geojson(fixture("ors_directions"))],
+            )
+            .on(PHOTON_PATH, [Reply::status(StatusCode::FORBIDDEN)]);
+        let client = ExternalRequester::new(upstream.base(), upstream.base(), "foo".into());
+
+        let outcomes = call_each(&client).await;
+        assert!(outcomes[0].result.is_ok(), "{}", outcomes[0]);
+        let photon = outcomes[1].to_string();
+        assert!(photon.starts_with("Photon FAILED"), "{photon}");
+        assert!(photon.contains("403"), "{photon}");
+    }
+}
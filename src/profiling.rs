@@ -0,0 +1,125 @@
+//! CPU and heap profiles from the running service, for chasing slowdowns in parsing and
+//! flattening on staging without attaching a profiler.
+//!
+//! Only built with the `pprof` feature, which also swaps the allocator for jemalloc with sampling
+//! turned on (see `main.rs`), and only served on the admin listener, behind the admin token:
+//!
+//! - `GET /debug/pprof/profile?seconds=<n>` samples every thread for `n` seconds (default 30, at
+//!   most [MAX_SECONDS]) and answers with a pprof protobuf, for `go tool pprof`. `format=flamegraph`
+//!   gets an SVG instead. One profile at a time; others get a 409.
+//! - `GET /debug/pprof/heap` dumps jemalloc's sampled heap profile, for `jeprof`.
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::Duration;
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 120;
+/// Samples a second. Enough to see hot paths without slowing them down much
+const FREQUENCY: i32 = 99;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/debug/pprof/profile", get(get_profile))
+        .route("/debug/pprof/heap", get(get_heap))
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Pprof,
+    Flamegraph,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+    #[serde(default)]
+    format: Format,
+}
+
+fn failed(status: StatusCode, message: impl std::fmt::Display) -> Response {
+    (status, Json(json!({ "message": message.to_string() }))).into_response()
+}
+
+/// `GET /debug/pprof/profile`
+async fn get_profile(Query(query): Query<ProfileQuery>) -> Response {
+    let seconds = query
+        .seconds
+        .unwrap_or(DEFAULT_SECONDS)
+        .clamp(1, MAX_SECONDS);
+    let guard = match pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(e) => {
+            return failed(
+                StatusCode::CONFLICT,
+                format!("couldn't start profiling: {e}"),
+            )
+        }
+    };
+    tracing::info!(seconds, "CPU profile started from admin endpoint");
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+    let report = match guard.report().build() {
+        Ok(report) => report,
+        Err(e) => return failed(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    let mut body = Vec::new();
+    let written = match query.format {
+        Format::Pprof => report
+            .pprof()
+            .map_err(|e| e.to_string())
+            .and_then(|profile| {
+                use pprof::protos::Message;
+                profile.write_to_vec(&mut body).map_err(|e| e.to_string())
+            }),
+        Format::Flamegraph => report.flamegraph(&mut body).map_err(|e| e.to_string()),
+    };
+    if let Err(e) = written {
+        return failed(StatusCode::INTERNAL_SERVER_ERROR, e);
+    }
+    let content_type = match query.format {
+        Format::Pprof => "application/octet-stream",
+        Format::Flamegraph => "image/svg+xml",
+    };
+    ([(header::CONTENT_TYPE, content_type)], body).into_response()
+}
+
+/// `GET /debug/pprof/heap`
+async fn get_heap() -> Response {
+    match tokio::task::spawn_blocking(dump_heap).await {
+        Ok(Ok(body)) => {
+            ([(header::CONTENT_TYPE, "application/octet-stream")], body).into_response()
+        }
+        Ok(Err(e)) => failed(StatusCode::SERVICE_UNAVAILABLE, e),
+        Err(e) => failed(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Has jemalloc write its profile to a temporary file, and reads it back
+fn dump_heap() -> Result<Vec<u8>, String> {
+    use tikv_jemalloc_ctl::raw;
+    // SAFETY: opt.prof is a bool
+    let profiling = unsafe { raw::read::<bool>(b"opt.prof\0") }.map_err(|e| e.to_string())?;
+    if !profiling {
+        return Err("heap profiling is off. is jemalloc the allocator?".to_owned());
+    }
+    let path = std::env::temp_dir().join(format!("flipmap-heap-{}.prof", std::process::id()));
+    let c_path =
+        std::ffi::CString::new(path.to_string_lossy().into_owned()).map_err(|e| e.to_string())?;
+    // SAFETY: prof.dump takes a NUL-terminated path, which outlives the call
+    unsafe { raw::write(b"prof.dump\0", c_path.as_ptr()) }.map_err(|e| e.to_string())?;
+    let body = std::fs::read(&path).map_err(|e| e.to_string());
+    let _ = std::fs::remove_file(&path);
+    body
+}
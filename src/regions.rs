@@ -0,0 +1,189 @@
+//! Picking between ORS instances, like a self-hosted one nearby with the hosted API as fallback
+//! (`--ors-base` plus `--ors-fallback`).
+//!
+//! Every [PROBE_INTERVAL], each instance's health endpoint is fetched, which spends no quota.
+//! Routes go to the fastest instance that answered, and the results are exported as the
+//! `ors_region_up` and `ors_region_latency_seconds` metrics. A call that gets no answer marks its
+//! instance down straight away; the next probe brings it back. With one instance there's nothing
+//! to pick, so nothing is probed.
+use crate::metrics;
+use reqwest::Url;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+/// Slower than this counts as down. Anything that slow isn't worth routing through
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Answered by self-hosted ORS. The hosted API wants a key for it, but any answer shows it's up
+const HEALTH_PATH: &str = "/v2/health";
+
+#[derive(Debug)]
+pub struct Region {
+    base: Url,
+    up: AtomicBool,
+    /// Of the last probe that got an answer. Until the first, the order they were given in
+    latency_us: AtomicU64,
+}
+
+impl Region {
+    /// For logs and metric labels. Never has a path or credentials
+    pub fn name(&self) -> String {
+        self.base.origin().ascii_serialization()
+    }
+
+    /// Where calls to `path` on this instance go
+    pub fn url(&self, path: &str) -> Url {
+        self.base
+            .join(path)
+            .unwrap_or_else(|e| panic!("couldn't join {path} onto {}: {e:?}", self.base))
+    }
+}
+
+/// Cheap to clone; clones share probe results
+#[derive(Debug, Clone)]
+pub struct OrsRegions {
+    regions: Arc<[Region]>,
+}
+
+impl OrsRegions {
+    /// `bases` in order of preference
+    ///
+    /// # Panics
+    /// If `bases` is empty
+    pub fn new(bases: &[Url]) -> Self {
+        assert!(!bases.is_empty(), "need at least one ORS base");
+        let regions = bases
+            .iter()
+            .enumerate()
+            .map(|(i, base)| Region {
+                base: base.clone(),
+                up: AtomicBool::new(true),
+                latency_us: AtomicU64::new(i as u64),
+            })
+            .collect();
+        OrsRegions { regions }
+    }
+
+    /// The fastest region that's up, or the first if none are; something has to be tried
+    pub fn pick(&self) -> &Region {
+        self.regions
+            .iter()
+            .filter(|r| r.up.load(Ordering::Relaxed))
+            .min_by_key(|r| r.latency_us.load(Ordering::Relaxed))
+            .unwrap_or(&self.regions[0])
+    }
+
+    /// For calls that got no answer from `region`. Only matters with somewhere else to go
+    pub fn mark_down(&self, region: &Region) {
+        if self.regions.len() > 1 && region.up.swap(false, Ordering::Relaxed) {
+            tracing::warn!(region = region.name(), "ORS region down until next probe");
+            metrics::registry().set_gauge("ors_region_up", &[("region", &region.name())], 0.0);
+        }
+    }
+
+    /// Probes every region forever. Does nothing with only one
+    pub fn spawn_prober(&self, user_agent: String) {
+        if self.regions.len() < 2 {
+            return;
+        }
+        let client = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(PROBE_TIMEOUT)
+            .build()
+            .expect("probe client should build");
+        let regions = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROBE_INTERVAL);
+            loop {
+                interval.tick().await;
+                regions.probe(&client).await;
+            }
+        });
+    }
+
+    /// One round of probes, all at once
+    async fn probe(&self, client: &reqwest::Client) {
+        let probes = self.regions.iter().map(|region| async move {
+            let url = region.url(HEALTH_PATH);
+            let started = Instant::now();
+            // Any answer will do, but a 5xx is the instance saying it's not fine
+            let up = match client.get(url).send().await {
+                Ok(res) => !res.status().is_server_error(),
+                Err(e) => {
+                    tracing::debug!(region = region.name(), "ORS probe failed: {e}");
+                    false
+                }
+            };
+            record(region, up, started.elapsed());
+        });
+        futures_util::future::join_all(probes).await;
+    }
+}
+
+fn record(region: &Region, up: bool, took: Duration) {
+    let was_up = region.up.swap(up, Ordering::Relaxed);
+    if was_up != up {
+        tracing::warn!(region = region.name(), up, "ORS region changed state");
+    }
+    let name = region.name();
+    let labels = [("region", name.as_str())];
+    metrics::registry().set_gauge("ors_region_up", &labels, if up { 1.0 } else { 0.0 });
+    if up {
+        region
+            .latency_us
+            .store(took.as_micros() as u64, Ordering::Relaxed);
+        metrics::registry().set_gauge("ors_region_latency_seconds", &labels, took.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{MockUpstream, Reply};
+    use axum::http::StatusCode;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn picks_fastest_up() {
+        let regions = OrsRegions::new(&[url("http://eu.example"), url("https://api.example")]);
+        assert_eq!(
+            regions.pick().url("/v2/directions"),
+            url("http://eu.example/v2/directions")
+        );
+
+        record(&regions.regions[0], true, Duration::from_millis(80));
+        record(&regions.regions[1], true, Duration::from_millis(30));
+        assert_eq!(regions.pick().name(), "https://api.example");
+
+        regions.mark_down(regions.pick());
+        assert_eq!(regions.pick().name(), "http://eu.example");
+        regions.mark_down(regions.pick());
+        // All down still goes somewhere
+        assert_eq!(regions.pick().name(), "http://eu.example");
+    }
+
+    #[test]
+    fn lone_region_never_down() {
+        let regions = OrsRegions::new(&[url("http://eu.example")]);
+        regions.mark_down(regions.pick());
+        assert!(regions.regions[0].up.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn probes_mark_state() {
+        let up = MockUpstream::start().await;
+        up.on(HEALTH_PATH, [Reply::status(StatusCode::UNAUTHORIZED)]);
+        let broken = MockUpstream::start().await;
+        broken.on(HEALTH_PATH, [Reply::status(StatusCode::BAD_GATEWAY)]);
+        let regions = OrsRegions::new(&[broken.base(), up.base()]);
+
+        regions.probe(&reqwest::Client::new()).await;
+        assert!(!regions.regions[0].up.load(Ordering::Relaxed));
+        assert_eq!(regions.pick().name(), regions.regions[1].name());
+        assert_eq!(up.hits(HEALTH_PATH), 1);
+    }
+}
@@ -0,0 +1,315 @@
+//! Optional reporting of external API failures and panics to a webhook, so operators find out
+//! about things like a revoked API key without tailing logs.
+//!
+//! Reports are JSON POSTed from a background task. Reporting is best-effort: if the queue is full
+//! or the webhook is down, reports are dropped with a warning rather than slowing requests down.
+use crate::error::RouteError;
+use crate::panics::panic_message;
+use crate::requester::ProviderError;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::error::Error;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use tower_http::request_id::RequestId;
+
+/// Reports waiting to be sent past this are dropped. An incident shouldn't turn into an OOM
+const QUEUE_LEN: usize = 64;
+
+tokio::task_local! {
+    /// Set while [report_errors] runs a request. Its panics come back as responses to report
+    static IN_REQUEST: ();
+}
+
+/// What gets POSTed to the webhook
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    /// `error` or `panic`
+    pub kind: &'static str,
+    pub service: &'static str,
+    pub message: String,
+    /// Display of each error in the cause chain, outermost first
+    pub causes: Vec<String>,
+    pub request_id: Option<String>,
+    pub route: Option<String>,
+    pub provider: Option<String>,
+    pub upstream_status: Option<u16>,
+}
+
+const SERVICE: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// The reportable parts of a [RouteError] or panic, stashed in the response extensions by
+/// [RouteError::into_response](axum::response::IntoResponse::into_response) and
+/// [crate::panics::handle_panic] for [report_errors] to pick up along with the request context.
+#[derive(Debug, Clone)]
+pub struct ErrorSummary {
+    pub message: String,
+    pub causes: Vec<String>,
+    pub provider: Option<String>,
+    pub upstream_status: Option<u16>,
+    /// The upstream answered, but with something we couldn't parse or use. See [crate::capture]
+    pub unusable_response: bool,
+    /// A handler panicked, rather than returning an error
+    pub panicked: bool,
+}
+
+impl ErrorSummary {
+    /// Only failures involving the external APIs are worth reporting. Bad client input isn't our
+    /// problem, and ratelimits are expected (and would flood the webhook when they happen).
+    /// Panics have no [RouteError] to go on; see [ErrorSummary::panicked].
+    pub fn of(err: &RouteError) -> Option<Self> {
+        match err {
+            RouteError::ExternalAPIJson(_)
+            | RouteError::ExternalAPIContent(_)
+            | RouteError::ExternalAPIRequest(_)
+            | RouteError::ExternalAPITooLarge(_) => {}
+            _ => return None,
+        }
+        let mut summary = ErrorSummary {
+            message: err.to_string(),
+            causes: vec![],
+            provider: None,
+            upstream_status: None,
+            unusable_response: matches!(
+                err,
+                RouteError::ExternalAPIJson(_) | RouteError::ExternalAPIContent(_)
+            ),
+            panicked: false,
+        };
+        let mut cause = err.source();
+        while let Some(c) = cause {
+            if let Some(provider_err) = c.downcast_ref::<ProviderError>() {
+                summary.provider = Some(provider_err.provider.to_string());
+                summary.upstream_status = provider_err.status.map(|s| s.as_u16());
+            }
+            summary.causes.push(c.to_string());
+            cause = c.source();
+        }
+        Some(summary)
+    }
+
+    /// For a handler that panicked with `message` at `location`
+    pub fn panicked(message: String, location: Option<String>) -> Self {
+        ErrorSummary {
+            message,
+            causes: location.into_iter().collect(),
+            provider: None,
+            upstream_status: None,
+            unusable_response: false,
+            panicked: true,
+        }
+    }
+}
+
+/// Handle for queueing reports. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Reporter {
+    queue: mpsc::Sender<Report>,
+}
+
+impl Reporter {
+    /// Spawns the task that sends reports to `webhook`
+    pub fn new(webhook: reqwest::Url) -> Self {
+        let (queue, rx) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(Self::send_task(rx, webhook));
+        Reporter { queue }
+    }
+
+    /// Queues a report without waiting. Safe to call from a panic hook.
+    pub fn report(&self, report: Report) {
+        if let Err(e) = self.queue.try_send(report) {
+            tracing::warn!("dropping error report: {e}");
+        }
+    }
+
+    /// Chains onto the existing panic hook so panics outside requests, like in background tasks,
+    /// are reported as well as printed. [report_errors] reports the ones in requests, with their
+    /// request id and route
+    pub fn install_panic_hook(&self) {
+        let reporter = self.clone();
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if IN_REQUEST.try_with(|_| ()).is_ok() {
+                previous(info);
+                return;
+            }
+            reporter.report(Report {
+                kind: "panic",
+                service: SERVICE,
+                message: panic_message(info.payload()),
+                causes: info.location().map(|l| l.to_string()).into_iter().collect(),
+                request_id: None,
+                route: None,
+                provider: None,
+                upstream_status: None,
+            });
+            previous(info);
+        }));
+    }
+
+    async fn send_task(mut rx: mpsc::Receiver<Report>, webhook: reqwest::Url) {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap_or_else(|e| panic!("couldn't build reqwest Client for reporting: {:?}", e));
+        while let Some(report) = rx.recv().await {
+            let res = client
+                .post(webhook.clone())
+                .json(&report)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            if let Err(e) = res {
+                tracing::warn!("couldn't deliver error report: {}", e.without_url());
+            }
+        }
+    }
+}
+
+/// Middleware that reports any response carrying an [ErrorSummary], adding the request id and
+/// matched route. Goes outside [tower_http::catch_panic] so panics come through as responses.
+pub async fn report_errors(
+    State(reporter): State<Reporter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_owned);
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned());
+
+    let response = IN_REQUEST.scope((), next.run(request)).await;
+    if let Some(summary) = response.extensions().get::<ErrorSummary>() {
+        reporter.report(Report {
+            kind: if summary.panicked { "panic" } else { "error" },
+            service: SERVICE,
+            message: summary.message.clone(),
+            causes: summary.causes.clone(),
+            request_id,
+            route,
+            provider: summary.provider.clone(),
+            upstream_status: summary.upstream_status,
+        });
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requester::Provider;
+    use httpmock::prelude::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn summary_finds_provider_in_chain() {
+        let cause = ProviderError::new(
+            Provider::OpenRouteService,
+            Some(StatusCode::FORBIDDEN),
+            "Access to this API has been disallowed".into(),
+        );
+        let err = RouteError::ExternalAPIRequest(Box::new(cause));
+        let summary = ErrorSummary::of(&err).unwrap();
+        assert_eq!(summary.provider.as_deref(), Some("OpenRouteService"));
+        assert_eq!(summary.upstream_status, Some(403));
+        assert_eq!(
+            summary.causes,
+            vec![
+                "OpenRouteService responded 403 Forbidden",
+                "Access to this API has been disallowed"
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_are_not_reported() {
+        let err = RouteError::ExternalAPILimit(std::time::SystemTime::now());
+        assert!(ErrorSummary::of(&err).is_none());
+    }
+
+    #[tokio::test]
+    async fn report_reaches_webhook() {
+        let server = MockServer::start_async().await;
+        let hook = server
+            .mock_async(|when, then| {
+                when.method(POST)
+                    .path("/hook")
+                    .json_body_partial(r#"{"kind":"error","provider":"Photon"}"#);
+                then.status(204);
+            })
+            .await;
+
+        let reporter = Reporter::new(server.url("/hook").parse().unwrap());
+        reporter.report(Report {
+            kind: "error",
+            service: SERVICE,
+            message: "external API request failed".to_owned(),
+            causes: vec![],
+            request_id: Some("abc".to_owned()),
+            route: Some("/get_locations".to_owned()),
+            provider: Some("Photon".to_owned()),
+            upstream_status: Some(500),
+        });
+
+        // Delivery is in the background
+        for _ in 0..50 {
+            if hook.hits_async().await == 1 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("webhook never got the report");
+    }
+
+    #[tokio::test]
+    async fn panics_reported_with_request_context() {
+        use axum::{body::Body, routing::get, Router};
+        use tower::ServiceExt;
+        use tower_http::{catch_panic::CatchPanicLayer, request_id::*};
+
+        async fn boom() -> &'static str {
+            panic!("boom")
+        }
+        let server = MockServer::start_async().await;
+        let hook = server
+            .mock_async(|when, then| {
+                when.method(POST).path("/hook").json_body_partial(
+                    r#"{"kind":"panic","message":"boom","request_id":"abc","route":"/boom"}"#,
+                );
+                then.status(204);
+            })
+            .await;
+        let reporter = Reporter::new(server.url("/hook").parse().unwrap());
+        let app = Router::new()
+            .route("/boom", get(boom))
+            .layer(CatchPanicLayer::custom(crate::panics::handle_panic))
+            .layer(axum::middleware::from_fn_with_state(
+                reporter,
+                report_errors,
+            ))
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+        let request = Request::get("/boom")
+            .header("x-request-id", "abc")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        for _ in 0..50 {
+            if hook.hits_async().await == 1 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("webhook never got the report");
+    }
+}
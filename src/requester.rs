@@ -1,14 +1,28 @@
 //! Wraps [reqwest] to make external API calls to OpenRouteService and Komoot easier.
 //! *Not a stable API.*
 use crate::{
-    error::RouteError,
-    ratelimit::{LimitChain, RateLimit},
-    retry_after::{self, BackerOff},
-    Result,
+    audit::Audit,
+    clock::{self, Clock},
+    deadline,
+    error::{BoxError, RouteError},
+    etiquette,
+    ors::{self, OrsRoute},
+    ors_body::OrsBody,
+    ratelimit::{LimitChain, LimitStatus, RateLimit, WindowSummary},
+    regions::OrsRegions,
+    retry_after::{self, BackerOff, BackoffFile},
+    shape::{self, Expectation, GeometryKind},
+    stream_json, timing, upstream_error,
+    vcr::Recorder,
+    Profile, Result,
 };
+use async_trait::async_trait;
 use reqwest::{header, StatusCode, Url};
 use secrecy::{ExposeSecret, SecretString};
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 use tokio::time::Duration;
 use tracing::instrument;
 
@@ -18,22 +32,110 @@ const HTTPS_ONLY: bool = false;
 #[cfg(not(test))]
 const HTTPS_ONLY: bool = true;
 
-/// Sent over the wire when [ExternalRequester] makes requests.
-const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),);
+/// Sent over the wire when [ExternalRequester] makes requests, followed by a [Contact] when
+/// there is one
+pub const DEFAULT_PRODUCT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
 // Hoisted because these are used in test code and normal code
-const ORS_DIRECTIONS_PATH: &str = "/v2/directions/driving-car/geojson";
-const PHOTON_PATH: &str = "/api/";
-const PHOTON_REVERSE_PATH: &str = "/reverse";
+pub(crate) const ORS_DIRECTIONS_PATH: &str = "/v2/directions/driving-car/geojson";
+pub(crate) const PHOTON_PATH: &str = "/api/";
+pub(crate) const PHOTON_REVERSE_PATH: &str = "/reverse";
+
+/// HTTP/2 pings on open connections this often
+const H2_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Upstream bodies longer than this are dropped. Real routes are a few hundred KiB at most
+pub const DEFAULT_MAX_BODY: usize = 32 * 1024 * 1024;
+
+/// Which external API a call went to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Provider {
+    OpenRouteService,
+    Photon,
+}
+
+impl std::fmt::Display for Provider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Provider::OpenRouteService => f.write_str("OpenRouteService"),
+            Provider::Photon => f.write_str("Photon"),
+        }
+    }
+}
+
+/// Cause attached to every error an [ExternalRequester] call produces, so whoever inspects the
+/// chain later can tell which provider failed and how.
+#[derive(Debug)]
+pub struct ProviderError {
+    pub provider: Provider,
+    /// `None` if the call never got a response
+    pub status: Option<StatusCode>,
+    source: BoxError,
+}
+
+impl ProviderError {
+    pub fn new(provider: Provider, status: Option<StatusCode>, source: BoxError) -> Self {
+        ProviderError {
+            provider,
+            status,
+            source,
+        }
+    }
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} responded {status}", self.provider),
+            None => write!(f, "{} call failed", self.provider),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// The call never reached the provider, or it never answered
+fn got_no_answer(err: &RouteError) -> bool {
+    let RouteError::ExternalAPIRequest(source) = err else {
+        return false;
+    };
+    source
+        .downcast_ref::<ProviderError>()
+        .is_some_and(|e| e.status.is_none())
+}
 
 /// Serializable payload for OpenRouteService routing v2 requests.
 ///
 /// **Very unstable.** Implements a tiny subset of options that are immediately useful to the program.
 /// See the [Open Route Service API documentation](https://openrouteservice.org/dev/#/api-docs/v2/directions/{profile}/geojson/post) for more.
-#[derive(Serialize, Debug)]
+/// Goes out as an [OrsBody], which checks it first.
+#[derive(Debug)]
 pub struct OpenRouteRequest {
     pub coordinates: Vec<geojson::Position>,
     pub instructions: bool,
+    /// `[bearing, deviation]` in degrees per coordinate, or `[]` for any way. Only roads that way
+    /// are snapped to
+    pub bearings: Option<Vec<Vec<f64>>>,
+    /// Picks the endpoint rather than going in the body
+    pub profile: Profile,
+    /// Also ask for the surface, way type and steepness along the route. See
+    /// [crate::ors::OrsProperties::annotations]
+    pub extra_info: bool,
+    /// Also ask for each position's height, for [crate::elevation]
+    pub elevation: bool,
+}
+
+/// ORS's directions endpoint for `profile`
+fn directions_path(profile: Profile) -> &'static str {
+    match profile {
+        Profile::Driving => ORS_DIRECTIONS_PATH,
+        Profile::Cycling => "/v2/directions/cycling-regular/geojson",
+        Profile::Walking => "/v2/directions/foot-walking/geojson",
+    }
 }
 
 /// Serializable payload for Photon geocoding requests (hosted by Komoot)
@@ -47,6 +149,12 @@ pub struct PhotonGeocodeRequest {
     pub query: String, // Might be possible to use str here
     lat: Option<f64>,
     lon: Option<f64>,
+    /// `min_lon,min_lat,max_lon,max_lat`
+    bbox: Option<String>,
+    /// How far the bias reaches, as a map zoom. Photon assumes 16
+    zoom: Option<u8>,
+    /// Names in this language. Photon's default is as they're known locally
+    lang: Option<String>,
 }
 
 impl PhotonGeocodeRequest {
@@ -54,10 +162,30 @@ impl PhotonGeocodeRequest {
     /// Not necessarily an 'anchor' in strong terms. Influences results, though.
     pub fn with_location_bias(self, lat: f64, lon: f64) -> Self {
         PhotonGeocodeRequest {
-            limit: self.limit,
-            query: self.query,
             lat: Some(lat),
             lon: Some(lon),
+            ..self
+        }
+    }
+
+    /// Spreads the location bias over what's visible at map zoom `zoom` (0-18)
+    pub fn with_zoom(self, zoom: u8) -> Self {
+        PhotonGeocodeRequest {
+            zoom: Some(zoom),
+            ..self
+        }
+    }
+
+    /// Place names in `lang`, if there's one. See [crate::language]
+    pub fn with_lang(self, lang: Option<String>) -> Self {
+        PhotonGeocodeRequest { lang, ..self }
+    }
+
+    /// Only results inside `bbox`. Unlike the bias, this is strict
+    pub fn with_bbox(self, bbox: crate::search::Bbox) -> Self {
+        PhotonGeocodeRequest {
+            bbox: Some(bbox.to_string()),
+            ..self
         }
     }
 
@@ -68,6 +196,9 @@ impl PhotonGeocodeRequest {
             query,
             lat: None,
             lon: None,
+            bbox: None,
+            zoom: None,
+            lang: None,
         }
     }
 }
@@ -79,6 +210,12 @@ impl PhotonGeocodeRequest {
 pub struct PhotonRevGeocodeRequest {
     pub lat: f64,
     pub lon: f64,
+    /// Photon answers with just the closest place unless told otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u8>,
+    /// As in [PhotonGeocodeRequest]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
 }
 
 impl PhotonRevGeocodeRequest {
@@ -88,10 +225,201 @@ impl PhotonRevGeocodeRequest {
         PhotonRevGeocodeRequest {
             lon: pos[0],
             lat: pos[1],
+            limit: None,
+            lang: None,
+        }
+    }
+}
+
+/// How long calls to one provider may take. `total` is applied per request, so it can be cut
+/// shorter by the request's [deadline]; the others are set on that provider's client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    /// Between reads of the response, not for the whole thing
+    pub read: Duration,
+    pub total: Duration,
+}
+
+impl Timeouts {
+    /// Routing can take a while for long trips
+    pub const ORS_DEFAULT: Timeouts = Timeouts {
+        connect: Duration::from_secs(3),
+        read: Duration::from_secs(10),
+        total: Duration::from_secs(10),
+    };
+    /// Geocoding should be quick, and the user is waiting on every keystroke
+    pub const PHOTON_DEFAULT: Timeouts = Timeouts {
+        connect: Duration::from_secs(2),
+        read: Duration::from_secs(3),
+        total: Duration::from_secs(3),
+    };
+}
+
+/// Parses `<connect_ms>,<read_ms>,<total_ms>`
+impl std::str::FromStr for Timeouts {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let ms: Vec<u64> = s
+            .split(',')
+            .map(|part| part.trim().parse::<u64>())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|e| format!("timeouts should be milliseconds: {e}"))?;
+        let [connect, read, total] = ms[..] else {
+            return Err("expected <connect_ms>,<read_ms>,<total_ms>".to_owned());
+        };
+        Ok(Timeouts {
+            connect: Duration::from_millis(connect),
+            read: Duration::from_millis(read),
+            total: Duration::from_millis(total),
+        })
+    }
+}
+
+/// Who to get in touch with about our traffic, as Photon and Nominatim usage policies ask for: an
+/// email address or an http(s) URL
+#[derive(Clone, Debug, PartialEq)]
+pub struct Contact(String);
+
+impl std::str::FromStr for Contact {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with("http://") || s.starts_with("https://") {
+            let url = Url::parse(s).map_err(|e| format!("contact URL doesn't parse: {e}"))?;
+            if url.host_str().is_none_or(|h| !h.contains('.')) {
+                return Err(format!("contact URL {s} should be somewhere public"));
+            }
+            return Ok(Contact(url.to_string()));
+        }
+        let email = s.strip_prefix("mailto:").unwrap_or(s);
+        match email.split_once('@') {
+            Some((user, domain))
+                if !user.is_empty()
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+                    && !email.contains(|c: char| c.is_whitespace() || c.is_control())
+                    && !domain.contains('@') =>
+            {
+                Ok(Contact(email.to_owned()))
+            }
+            _ => Err(format!("expected an email address or http(s) URL, got {s}")),
+        }
+    }
+}
+
+/// `<product> (+<contact>)`, the way crawlers usually put it
+pub fn user_agent(product: &str, contact: Option<&Contact>) -> String {
+    match contact {
+        Some(Contact(contact)) => format!("{product} (+{contact})"),
+        None => product.to_owned(),
+    }
+}
+
+/// Connection reuse settings, shared by every provider's client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolOptions {
+    /// Idle connections kept open per upstream host
+    pub max_idle_per_host: usize,
+    /// Idle connections are closed after this
+    pub idle_timeout: Duration,
+    /// TCP keepalive probes on open connections, so middleboxes don't silently drop them
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_idle_per_host: 32,
+            idle_timeout: Duration::from_secs(90),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+/// Which HTTP version to speak to a provider
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/2 if the server offers it during the TLS handshake, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+    Http1,
+    /// HTTP/2 without asking first. Needed for self-hosted upstreams speaking plain-text HTTP/2.
+    /// If the upstream turns out not to, the client falls back to [HttpVersion::Auto] for good
+    Http2,
+}
+
+impl std::str::FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(HttpVersion::Auto),
+            "http1" | "1" | "1.1" => Ok(HttpVersion::Http1),
+            "http2" | "2" | "h2c" => Ok(HttpVersion::Http2),
+            other => Err(format!("expected auto, http1 or http2, got {other}")),
         }
     }
 }
 
+/// A provider's client, plus one speaking [HttpVersion::Auto] if the first forces HTTP/2
+#[derive(Debug)]
+struct UpstreamClient {
+    primary: reqwest::Client,
+    fallback: Option<reqwest::Client>,
+    fell_back: AtomicBool,
+}
+
+impl UpstreamClient {
+    fn current(&self) -> &reqwest::Client {
+        match &self.fallback {
+            Some(fallback) if self.fell_back.load(Ordering::Relaxed) => fallback,
+            _ => &self.primary,
+        }
+    }
+
+    /// The client to retry a failed call with, if switching protocols might help. Only ever
+    /// returns one once; after that the fallback is simply [UpstreamClient::current]
+    fn fall_back(&self, provider: Provider, e: &reqwest::Error) -> Option<&reqwest::Client> {
+        let fallback = self.fallback.as_ref()?;
+        if e.is_timeout() || !(e.is_connect() || e.is_request()) {
+            return None;
+        }
+        if self.fell_back.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        tracing::warn!("{provider} doesn't seem to speak HTTP/2, falling back: {e}");
+        Some(fallback)
+    }
+}
+
+/// Counts a call as in flight for as long as it's alive, cancellation included
+struct InFlight(Provider);
+
+impl InFlight {
+    fn start(provider: Provider) -> Self {
+        crate::metrics::registry().add_gauge(
+            "upstream_in_flight",
+            &[("provider", &provider.to_string())],
+            1.0,
+        );
+        InFlight(provider)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        crate::metrics::registry().add_gauge(
+            "upstream_in_flight",
+            &[("provider", &self.0.to_string())],
+            -1.0,
+        );
+    }
+}
+
 /// Used to construct [ExternalRequester]. Niche and opinionated defaults are deployed for endpoint
 /// URLs and Photon rate-limiting if the setters are not used.
 #[derive(Clone, Debug)]
@@ -102,9 +430,27 @@ pub struct ExternalRequesterBuilder {
     ors_base: Url,
     photon_base: Url,
 
-    // Sue me. It's internal
-    photon_limit_params: Vec<(u32, Duration, String)>,
-    // BackerOffs are not configurable.
+    // Sue me. It's internal. None for the komoot-public preset's
+    photon_limit_params: Option<Vec<(u32, Duration, String)>>,
+    /// Calls in flight at once. Uncapped if unset
+    ors_concurrency: Option<usize>,
+    photon_concurrency: Option<usize>,
+    /// See [RateLimit::with_undo_compensation]
+    photon_undo_compensation: bool,
+    /// Just `ors_base` unless set
+    ors_regions: Option<OrsRegions>,
+    user_agent: String,
+    // BackerOffs are not configurable, except for where they're kept across restarts
+    backoff_file: Option<BackoffFile>,
+    recorder: Option<Recorder>,
+    ors_timeouts: Timeouts,
+    photon_timeouts: Timeouts,
+    pool: PoolOptions,
+    ors_http: HttpVersion,
+    photon_http: HttpVersion,
+    audit: Option<Audit>,
+    clock: Arc<dyn Clock>,
+    max_body: usize,
 }
 
 impl ExternalRequesterBuilder {
@@ -113,51 +459,268 @@ impl ExternalRequesterBuilder {
             open_route_service_key,
             ors_base,
             photon_base,
-            photon_limit_params: vec![],
+            photon_limit_params: None,
+            ors_concurrency: None,
+            photon_concurrency: None,
+            photon_undo_compensation: false,
+            ors_regions: None,
+            user_agent: DEFAULT_PRODUCT.to_owned(),
+            backoff_file: None,
+            recorder: None,
+            ors_timeouts: Timeouts::ORS_DEFAULT,
+            photon_timeouts: Timeouts::PHOTON_DEFAULT,
+            pool: PoolOptions::default(),
+            ors_http: HttpVersion::Auto,
+            photon_http: HttpVersion::Auto,
+            audit: None,
+            clock: clock::system(),
+            max_body: DEFAULT_MAX_BODY,
+        }
+    }
+
+    /// Note every outbound call down in `audit`. See [crate::audit]
+    pub fn with_audit(mut self, audit: Audit) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn with_http_version(mut self, provider: Provider, version: HttpVersion) -> Self {
+        match provider {
+            Provider::OpenRouteService => self.ors_http = version,
+            Provider::Photon => self.photon_http = version,
+        }
+        self
+    }
+
+    pub fn with_pool(mut self, pool: PoolOptions) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    pub fn with_timeouts(mut self, provider: Provider, timeouts: Timeouts) -> Self {
+        match provider {
+            Provider::OpenRouteService => self.ors_timeouts = timeouts,
+            Provider::Photon => self.photon_timeouts = timeouts,
+        }
+        self
+    }
+
+    /// Identify as `product`, with `contact` for the providers to reach us at. See [user_agent]
+    pub fn with_user_agent(mut self, product: &str, contact: &Contact) -> Self {
+        self.user_agent = user_agent(product, Some(contact));
+        self
+    }
+
+    /// Each provider gets its own client so connect and read timeouts can differ
+    fn build_client(
+        provider: Provider,
+        user_agent: &str,
+        timeouts: &Timeouts,
+        pool: &PoolOptions,
+        http: HttpVersion,
+    ) -> UpstreamClient {
+        let build = |http| {
+            let builder = Self::client_builder(user_agent, timeouts, pool);
+            let builder = match http {
+                HttpVersion::Auto => builder,
+                HttpVersion::Http1 => builder.http1_only(),
+                HttpVersion::Http2 => builder.http2_prior_knowledge(),
+            };
+            builder
+                .build()
+                .unwrap_or_else(|e| panic!("couldn't build reqwest Client for {provider}: {:?}", e))
+        };
+        UpstreamClient {
+            primary: build(http),
+            fallback: (http == HttpVersion::Http2).then(|| build(HttpVersion::Auto)),
+            fell_back: AtomicBool::new(false),
         }
     }
 
+    fn client_builder(
+        user_agent: &str,
+        timeouts: &Timeouts,
+        pool: &PoolOptions,
+    ) -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            .user_agent(user_agent)
+            .connect_timeout(timeouts.connect)
+            .read_timeout(timeouts.read)
+            .timeout(timeouts.total)
+            .pool_max_idle_per_host(pool.max_idle_per_host)
+            .pool_idle_timeout(pool.idle_timeout)
+            .tcp_keepalive(pool.tcp_keepalive)
+            // Only matter once a connection is HTTP/2. Lets one connection carry a burst of
+            // geocode calls without stalling on the default window, and notices dead ones
+            .http2_adaptive_window(true)
+            .http2_keep_alive_interval(H2_KEEPALIVE)
+            .http2_keep_alive_while_idle(true)
+            .https_only(HTTPS_ONLY)
+    }
+
     pub fn with_photon_ratelimiter(
         mut self,
         requests_allowed: u32,
         reset_time: Duration,
         name: String,
     ) -> Self {
-        self.photon_limit_params
-            .push((requests_allowed, reset_time, name));
+        self.photon_limit_params.get_or_insert_with(Vec::new).push((
+            requests_allowed,
+            reset_time,
+            name,
+        ));
+        self
+    }
+
+    /// Hold back from each Photon limit window as many calls as were given back in the one before.
+    /// See [crate::ratelimit]
+    pub fn with_undo_compensation(mut self, on: bool) -> Self {
+        self.photon_undo_compensation = on;
+        self
+    }
+
+    /// Send routes to whichever of `regions` is fastest, instead of just the ORS base. See
+    /// [crate::regions]
+    pub fn with_ors_regions(mut self, regions: OrsRegions) -> Self {
+        self.ors_regions = Some(regions);
+        self
+    }
+
+    /// Photon limits and in-flight cap from `preset`, replacing any set before
+    pub fn with_photon_etiquette(mut self, preset: etiquette::Preset) -> Self {
+        let etiquette = preset.etiquette();
+        self.photon_limit_params = Some(etiquette.limits);
+        self.photon_concurrency = etiquette.concurrency;
+        self
+    }
+
+    /// At most `calls` to `provider` in flight at once, whatever its rate limits allow. Later
+    /// calls wait for a slot. For Photon, replaces the cap from [Self::with_photon_etiquette]
+    pub fn with_concurrency(mut self, provider: Provider, calls: usize) -> Self {
+        match provider {
+            Provider::OpenRouteService => self.ors_concurrency = Some(calls),
+            Provider::Photon => self.photon_concurrency = Some(calls),
+        }
+        self
+    }
+
+    /// Replaces the key given to [ExternalRequesterBuilder::new]
+    pub fn with_ors_key(mut self, key: SecretString) -> Self {
+        self.open_route_service_key = key;
+        self
+    }
+
+    /// Where rate limits and backoffs get the time from. Tests use a mock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Upstream bodies longer than `bytes` fail with [RouteError::ExternalAPITooLarge] instead of
+    /// being read any further
+    pub fn with_max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    /// Keep backoffs the providers ask for in `file`, so they outlast a restart
+    pub fn with_backoff_file(mut self, file: BackoffFile) -> Self {
+        self.backoff_file = Some(file);
+        self
+    }
+
+    /// Development only: save every successful upstream response body into `dir`. See [crate::vcr]
+    pub fn with_recorder(mut self, dir: std::path::PathBuf) -> Self {
+        self.recorder = Some(Recorder::new(dir));
         self
     }
 
     pub fn build(self) -> ExternalRequester {
-        let ratelimit_params = if self.photon_limit_params.is_empty() {
-            vec![
-                // Parity with OpenRouteService limits (may or may not be a good idea)
-                (40, Duration::from_secs(60), "Photon Minutely".to_string()),
-                (2000, Duration::from_secs(86400), "Photon Daily".to_string()),
-            ]
-        } else {
-            self.photon_limit_params
+        let photon_limiter = self.photon_limiter();
+        let backer_off = |name: &str| {
+            let backer_off = BackerOff::new()
+                .with_name(name.to_string())
+                .with_clock(self.clock.clone());
+            Arc::new(match &self.backoff_file {
+                Some(file) => backer_off.with_file(file.clone()),
+                None => backer_off,
+            })
+        };
+        let ors_retry_after = backer_off("OpenRouteService");
+        let photon_retry_after = backer_off("Photon");
+        let slots = |calls: Option<usize>| calls.map(|n| Arc::new(Semaphore::new(n)));
+        let ors_slots = slots(self.ors_concurrency);
+        let photon_slots = slots(self.photon_concurrency);
+        self.finish(
+            photon_limiter,
+            ors_retry_after,
+            photon_retry_after,
+            [ors_slots, photon_slots],
+        )
+    }
+
+    /// Like [ExternalRequesterBuilder::build], but carrying on `previous`'s Photon limits and
+    /// backoffs, so swapping in a new key (see [crate::rotation]) doesn't forget them
+    pub fn rebuild(self, previous: &ExternalRequester) -> ExternalRequester {
+        self.finish(
+            previous.photon_limiter.clone(),
+            previous.ors_retry_after.clone(),
+            previous.photon_retry_after.clone(),
+            [previous.ors_slots.clone(), previous.photon_slots.clone()],
+        )
+    }
+
+    fn photon_limiter(&self) -> LimitChain<'static> {
+        let ratelimit_params = match &self.photon_limit_params {
+            Some(params) => params.clone(),
+            None => etiquette::Preset::default().etiquette().limits,
         };
 
         let photon_limits: Vec<RateLimit> = ratelimit_params
             .iter()
-            .map(|truple| RateLimit::new(truple.0, truple.1, truple.2.clone()))
+            .map(|truple| {
+                let limit = RateLimit::new(truple.0, truple.1, truple.2.clone())
+                    .with_clock(self.clock.clone());
+                if self.photon_undo_compensation {
+                    limit.with_undo_compensation()
+                } else {
+                    limit
+                }
+            })
             .collect();
         // Not sure if optimal, but making this static here makes life way easier
-        let photon_limiter = LimitChain::new_from(Box::leak(photon_limits.into_boxed_slice()));
+        LimitChain::new_from(Box::leak(photon_limits.into_boxed_slice()))
+    }
 
+    fn finish(
+        self,
+        photon_limiter: LimitChain<'static>,
+        ors_retry_after: Arc<BackerOff>,
+        photon_retry_after: Arc<BackerOff>,
+        [ors_slots, photon_slots]: [Option<Arc<Semaphore>>; 2],
+    ) -> ExternalRequester {
         ExternalRequester {
-            client: reqwest::Client::builder()
-                .user_agent(USER_AGENT)
-                .timeout(Duration::from_secs(10))
-                .https_only(HTTPS_ONLY)
-                .build()
-                .unwrap_or_else(|e| panic!("couldn't build reqwest Client: {:?}", e)),
+            ors_client: Self::build_client(
+                Provider::OpenRouteService,
+                &self.user_agent,
+                &self.ors_timeouts,
+                &self.pool,
+                self.ors_http,
+            ),
+            photon_client: Self::build_client(
+                Provider::Photon,
+                &self.user_agent,
+                &self.photon_timeouts,
+                &self.pool,
+                self.photon_http,
+            ),
+            ors_timeout: self.ors_timeouts.total,
+            photon_timeout: self.photon_timeouts.total,
             open_route_service_key: self.open_route_service_key,
-            ors_directions: self
-                .ors_base
-                .join(ORS_DIRECTIONS_PATH)
-                .unwrap_or_else(|e| panic!("couldn't assemble ors directions full URL: {:?}", e)),
+            ors_regions: self
+                .ors_regions
+                .unwrap_or_else(|| OrsRegions::new(std::slice::from_ref(&self.ors_base))),
+            photon_base: self.photon_base.clone(),
             photon: self
                 .photon_base
                 .join(PHOTON_PATH)
@@ -169,8 +732,14 @@ impl ExternalRequesterBuilder {
                     panic!("couldn't assemble photon rev geocoding full URL: {:?}", e)
                 }),
             photon_limiter,
-            ors_retry_after: BackerOff::new().with_name("OpenRouteService".to_string()),
-            photon_retry_after: BackerOff::new().with_name("Photon".to_string()),
+            ors_slots,
+            photon_slots,
+            ors_retry_after,
+            photon_retry_after,
+            recorder: self.recorder,
+            audit: self.audit,
+            max_body: self.max_body,
+            clock: self.clock,
         }
     }
 }
@@ -178,22 +747,42 @@ impl ExternalRequesterBuilder {
 /// Wraps [reqwest::Client] to provide opinionated execution and parsing of external API endpoints.
 #[derive(Debug)]
 pub struct ExternalRequester {
-    /// Wrapped client. Will be created for you, against your will. You're welcome.
-    client: reqwest::Client,
+    /// Wrapped clients, one per provider. Will be created for you, against your will. You're
+    /// welcome.
+    ors_client: UpstreamClient,
+    photon_client: UpstreamClient,
+    /// Upper bound on each call, before any [deadline] shortens it
+    ors_timeout: Duration,
+    photon_timeout: Duration,
     // Shouldn't leak to logs unless Reqwest traces headers? Won't get sent over wire in response either way
     open_route_service_key: SecretString,
 
     // client.post() won't take &Url but .clone() is no worse than passing &str and front-loads error checking
-    ors_directions: Url,
+    /// Where directions go. Usually just the one
+    ors_regions: OrsRegions,
+    /// Just for [crate::keepalive]
+    photon_base: Url,
     photon: Url,
     photon_reverse: Url,
 
     /// They don't enforce limits so we do this to be polite
     photon_limiter: LimitChain<'static>,
-    /// If present, a time after which the next request is allowed, according to ORS
-    ors_retry_after: BackerOff,
+    /// Calls in flight at once, if capped by `--ors-concurrency`, `--photon-concurrency` or the
+    /// [etiquette]. Shared like the limiter
+    ors_slots: Option<Arc<Semaphore>>,
+    photon_slots: Option<Arc<Semaphore>>,
+    /// If present, a time after which the next request is allowed, according to ORS. Shared with
+    /// whatever gets [rebuilt](ExternalRequesterBuilder::rebuild) from this
+    ors_retry_after: Arc<BackerOff>,
     /// If present, a time after which the next request is allowed, according to Komoot
-    photon_retry_after: BackerOff,
+    photon_retry_after: Arc<BackerOff>,
+    /// Dumps response bodies to disk when developing. Never set in production
+    recorder: Option<Recorder>,
+    /// Trail of recent outbound calls for the admin endpoint, if switched on
+    audit: Option<Audit>,
+    /// Most bytes of any one response body we'll read
+    max_body: usize,
+    clock: Arc<dyn Clock>,
 }
 
 impl ExternalRequester {
@@ -208,91 +797,378 @@ impl ExternalRequester {
         ExternalRequesterBuilder::new(ors_base, photon_base, open_route_service_key).build()
     }
 
-    /// Prepare *and execute* a request to OpenRouteService v2 directions endpoint.
-    ///
-    /// # Errors
-    /// [ExternalAPIRequest][crate::error::RouteError::ExternalAPIRequest]: if [reqwest] fails for network reasons
-    ///
-    /// [ExternalAPIJson][crate::error::RouteError::ExternalAPIJson]: if [reqwest] tries to use [serde] to deserialize into
-    /// [geojson::FeatureCollection] and fails
-    #[instrument(skip(self))]
-    pub async fn ors_send(&self, req: &OpenRouteRequest) -> Result<geojson::FeatureCollection> {
+    /// Summarizes Photon limit windows as they end. See [LimitChain::spawn_reporter]. Rebuilt
+    /// requesters share the limits, so once is enough
+    pub fn spawn_window_reports(&self) {
+        self.photon_limiter.clone().spawn_reporter();
+    }
+
+    /// `HEAD`s each provider's base with its own client, so its pooled connection stays open. Goes
+    /// around limits, quotas and the audit trail, since nothing's asked for. Providers backing off
+    /// are left alone
+    pub async fn keepalive(&self) {
+        let pings = [
+            (
+                Provider::OpenRouteService,
+                &self.ors_client,
+                self.ors_regions.pick().url("/"),
+                &self.ors_retry_after,
+                self.ors_timeout,
+            ),
+            (
+                Provider::Photon,
+                &self.photon_client,
+                self.photon_base.clone(),
+                &self.photon_retry_after,
+                self.photon_timeout,
+            ),
+        ]
+        .map(|(provider, client, url, backer_off, timeout)| async move {
+            if backer_off.can_request().is_err() {
+                return;
+            }
+            // Any answer at all means the connection's up
+            let outcome = match client.current().head(url).timeout(timeout).send().await {
+                Ok(_) => "ok",
+                Err(e) => {
+                    tracing::debug!("{provider} keepalive failed: {e}");
+                    "failed"
+                }
+            };
+            crate::metrics::registry().inc_counter(
+                "upstream_keepalive_total",
+                &[("provider", &provider.to_string()), ("outcome", outcome)],
+                1,
+            );
+        });
+        futures_util::future::join_all(pings).await;
+    }
+
+    /// Calls the ORS directions endpoint, reading the answer as `T`
+    async fn ors_execute<T: DeserializeOwned + Send + 'static>(
+        &self,
+        req: &OpenRouteRequest,
+    ) -> Result<T> {
+        let body = OrsBody::of(req).map_err(|e| {
+            tracing::error!("not sending ORS a request it would reject: {e}");
+            RouteError::ExternalAPIRequest(e.into())
+        })?;
+        tracing::trace!(body = body.redacted(), "ORS directions request");
         self.ors_retry_after.can_request()?;
+        let _slot = self.slot(Provider::OpenRouteService).await?;
+        let region = self.ors_regions.pick();
+        let prepare = |client: &reqwest::Client| {
+            client
+                .post(region.url(directions_path(req.profile)))
+                .header("Content-Type", "application/json")
+                .header("Authorization", self.open_route_service_key.expose_secret())
+                .json(&body)
+        };
         let res = self
-            .client
-            .post(self.ors_directions.clone())
-            .header("Content-Type", "application/json")
-            .header("Authorization", self.open_route_service_key.expose_secret())
-            .json(req)
-            .send()
-            .await?;
-
-        let good_res = Self::check_limiting_status(res, &self.ors_retry_after)?;
-        let obj = good_res.json::<geojson::FeatureCollection>().await?;
-        Ok(obj)
+            .execute(
+                prepare,
+                Provider::OpenRouteService,
+                "ors_directions",
+                &self.ors_retry_after,
+            )
+            .await;
+        if res.as_ref().is_err_and(got_no_answer) {
+            self.ors_regions.mark_down(region);
+        }
+        res
     }
 
-    /// Prepare *and execute* a request to Photon's reverse geocoding endpoint.
+    /// Sends a prepared request and reads a `T` (usually a [geojson::FeatureCollection]) out of
+    /// the answer, setting `backer_off` if the provider says to back off.
     ///
-    /// # Errors
-    /// [ExternalAPIRequest][crate::error::RouteError::ExternalAPIRequest]: if [reqwest] fails for network reasons
+    /// If the request being handled has a [deadline], the call gets no more than what's left of it
+    /// and is noted down for the 504 if it runs out.
     ///
-    /// [ExternalAPIJson][crate::error::RouteError::ExternalAPIJson]: if [reqwest] tries to use [serde] to deserialize into
-    /// [geojson::FeatureCollection] and fails
-    #[instrument(skip(self))]
-    pub async fn photon_reverse_send(
+    /// `prepare` gets the provider's client, and may be called twice if it has to fall back from
+    /// HTTP/2.
+    async fn execute<T: DeserializeOwned + Send + 'static>(
         &self,
-        coord: &PhotonRevGeocodeRequest,
-    ) -> Result<geojson::FeatureCollection> {
-        self.photon_retry_after.can_request()?; // Checks for backoff period
-        self.check_photon_limit(1)?; // Checks our own ratelimiter
-        let q = [("lon", coord.lon), ("lat", coord.lat)];
-        let res = self
-            .client
-            .get(self.photon_reverse.clone())
-            .query(&q)
-            .send()
-            .await?;
+        prepare: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+        provider: Provider,
+        endpoint: &'static str,
+        backer_off: &BackerOff,
+    ) -> Result<T> {
+        let (client, timeout) = match provider {
+            Provider::OpenRouteService => (&self.ors_client, self.ors_timeout),
+            Provider::Photon => (&self.photon_client, self.photon_timeout),
+        };
+        let remaining = deadline::remaining();
+        let with_timeout = |req: reqwest::RequestBuilder| match deadline::remaining() {
+            Some(left) => req.timeout(left.min(timeout)),
+            None => req.timeout(timeout),
+        };
+        if remaining.is_some_and(|left| left.is_zero()) {
+            return Err(deadline::exceeded());
+        }
 
-        // This checks if we need to set a backoff period in response to this call
-        let good_res = Self::check_limiting_status(res, &self.photon_retry_after)?;
-        let obj = good_res.json::<geojson::FeatureCollection>().await?;
-        Ok(obj)
+        let _in_flight = InFlight::start(provider);
+        let started = tokio::time::Instant::now();
+        let sent = match self
+            .send(with_timeout(prepare(client.current())), provider)
+            .await
+        {
+            Err(e) => match client.fall_back(provider, &e) {
+                Some(fallback) => self.send(with_timeout(prepare(fallback)), provider).await,
+                None => Err(e),
+            },
+            sent => sent,
+        };
+        let res = match sent {
+            Ok(res) => Self::check_limiting_status(res, backer_off),
+            Err(e) if e.is_timeout() && deadline::remaining().is_some_and(|l| l.is_zero()) => {
+                deadline::record_call(endpoint, started.elapsed(), "timed out".to_owned());
+                let err = deadline::exceeded();
+                Self::observe(provider, endpoint, started.elapsed(), Err(&err));
+                return Err(err);
+            }
+            Err(e) => Err(Self::request_failure(provider, e)),
+        };
+        let res = match res {
+            Ok(res) => self.read_json(res, provider, endpoint).await,
+            Err(e) => Err(e),
+        };
+        Self::observe(
+            provider,
+            endpoint,
+            started.elapsed(),
+            res.as_ref().map(|_| ()),
+        );
+        if remaining.is_some() {
+            let outcome = match &res {
+                Ok(_) => "ok".to_owned(),
+                Err(e) => e.to_string(),
+            };
+            deadline::record_call(endpoint, started.elapsed(), outcome);
+        }
+        res
     }
 
-    /// Prepare *and execute* a request to Photon's geocoding endpoint.
-    ///
-    /// # Errors
-    /// [ExternalAPIRequest][crate::error::RouteError::ExternalAPIRequest]: if [reqwest] fails for network reasons
+    /// Latency histogram and error counters per endpoint, so slowness and failures can be put down
+    /// to one provider or the other (or us, going by `http_request_duration_seconds`)
+    fn observe(
+        provider: Provider,
+        endpoint: &'static str,
+        elapsed: Duration,
+        res: std::result::Result<(), &RouteError>,
+    ) {
+        timing::record_upstream(endpoint, elapsed);
+        let provider = provider.to_string();
+        let labels = [("provider", provider.as_str()), ("endpoint", endpoint)];
+        let registry = crate::metrics::registry();
+        registry.observe(
+            "upstream_request_duration_seconds",
+            &labels,
+            elapsed.as_secs_f64(),
+        );
+        let Err(err) = res else { return };
+        let kind = match err {
+            RouteError::ExternalAPIRequest(_) => "request",
+            RouteError::ExternalAPIJson(_) => "json",
+            RouteError::ExternalAPIContent(_) => "content",
+            RouteError::ExternalAPITooLarge(_) => "too_large",
+            RouteError::ExternalAPILimit(_) => "limited",
+            RouteError::DeadlineExceeded(_) => "deadline",
+            _ => "other",
+        };
+        let [provider, endpoint] = labels;
+        registry.inc_counter(
+            "upstream_errors_total",
+            &[provider, endpoint, ("kind", kind)],
+            1,
+        );
+    }
+
+    /// Sends `req`, noting it down in the [Audit] trail if there is one
+    async fn send(
+        &self,
+        req: reqwest::RequestBuilder,
+        provider: Provider,
+    ) -> reqwest::Result<reqwest::Response> {
+        let Some(audit) = &self.audit else {
+            return req.send().await;
+        };
+        let (client, req) = req.build_split();
+        let req = req?;
+        let pending = audit.start(provider, &req);
+        let res = client.execute(req).await;
+        pending.finish(&res);
+        res
+    }
+
+    /// Reads the answer into a `T`. Usually the body is parsed as it arrives (see
+    /// [stream_json]), but error statuses, and every answer while recording or capturing, are read
+    /// in full first. The full body goes to the [Recorder] if there is one. Either way, reading
+    /// stops once the body passes the size limit.
     ///
-    /// [ExternalAPIJson][crate::error::RouteError::ExternalAPIJson]: if [reqwest] tries to use [serde] to deserialize into
-    /// [geojson::FeatureCollection] and fails
-    #[instrument(skip(self))]
-    pub async fn photon_send(
+    /// Non-2xx statuses and bodies that don't deserialize are checked for the provider's error
+    /// format, so whatever it said ends up in the trace. Failing that, a snippet of the body does.
+    async fn read_json<T: DeserializeOwned + Send + 'static>(
         &self,
-        req: &PhotonGeocodeRequest,
-    ) -> Result<geojson::FeatureCollection> {
-        self.photon_retry_after.can_request()?;
-        self.check_photon_limit(1)?;
-        let res = self
-            .client
-            .get(self.photon.clone())
-            .query(req)
-            .send()
-            .await?;
+        resp: reqwest::Response,
+        provider: Provider,
+        endpoint: &str,
+    ) -> Result<T> {
+        let status = resp.status();
+        crate::metrics::registry().inc_counter(
+            "upstream_responses_total",
+            &[("endpoint", endpoint), ("status", status.as_str())],
+            1,
+        );
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("none")
+            .to_owned();
+        let redact = [self.open_route_service_key.expose_secret()];
+
+        if status.is_success() && self.recorder.is_none() && !crate::capture::capturing() {
+            return match stream_json::from_response(resp, self.max_body).await {
+                Ok(value) => Ok(value),
+                Err(stream_json::Failure::Body(e)) => Err(Self::request_failure(provider, e)),
+                Err(stream_json::Failure::TooLarge) => {
+                    Err(Self::too_large(provider, status, self.max_body))
+                }
+                Err(stream_json::Failure::Json { error, head }) => Err(Self::unparsable(
+                    provider,
+                    status,
+                    &content_type,
+                    &head,
+                    &redact,
+                    error,
+                )),
+            };
+        }
+
+        let url = resp.url().clone();
+        let body = match stream_json::read_all(resp, self.max_body).await {
+            Ok(body) => body,
+            Err(stream_json::Failure::TooLarge) => {
+                return Err(Self::too_large(provider, status, self.max_body))
+            }
+            Err(stream_json::Failure::Body(e)) => return Err(Self::request_failure(provider, e)),
+            Err(stream_json::Failure::Json { .. }) => unreachable!("read_all doesn't parse"),
+        };
+        crate::capture::note_upstream(provider, endpoint, &url, status, &body, &redact);
+
+        if !status.is_success() {
+            let upstream: BoxError = match upstream_error::parse(provider, &body) {
+                Some(err) => {
+                    tracing::error!(
+                        %provider,
+                        %status,
+                        upstream_code = err.code,
+                        upstream_message = %err.message,
+                        "external API returned an error"
+                    );
+                    Box::new(err)
+                }
+                None => {
+                    tracing::error!(
+                        %provider,
+                        %status,
+                        content_type,
+                        body = upstream_error::snippet(&body, &redact),
+                        "external API returned an error"
+                    );
+                    format!("{provider} returned {status}").into()
+                }
+            };
+            let cause = ProviderError::new(provider, Some(status), upstream);
+            return Err(RouteError::ExternalAPIRequest(Box::new(cause)));
+        }
+
+        if let Some(recorder) = &self.recorder {
+            recorder.record(endpoint, &body, &redact).await;
+        }
+        serde_json::from_slice(&body)
+            .map_err(|e| Self::unparsable(provider, status, &content_type, &body, &redact, e))
+    }
 
-        let good_res = Self::check_limiting_status(res, &self.photon_retry_after)?;
-        let obj = good_res.json::<geojson::FeatureCollection>().await?;
-        Ok(obj)
+    /// For a success status with a body that isn't what we wanted. `body` may be just the start
+    fn unparsable(
+        provider: Provider,
+        status: StatusCode,
+        content_type: &str,
+        body: &[u8],
+        redact: &[&str],
+        e: serde_json::Error,
+    ) -> RouteError {
+        match upstream_error::parse(provider, body) {
+            // Some errors come back as 200s
+            Some(err) => tracing::error!(
+                %provider,
+                %status,
+                upstream_code = err.code,
+                upstream_message = %err.message,
+                "external API sent an error body with a success status"
+            ),
+            None => tracing::error!(
+                %provider,
+                %status,
+                content_type,
+                body = upstream_error::snippet(body, redact),
+                "external API response JSON deserializing error: {e}"
+            ),
+        }
+        let cause = ProviderError::new(provider, Some(status), Box::new(e));
+        RouteError::ExternalAPIJson(Box::new(cause))
+    }
+
+    /// For a body we stopped reading partway, because it's over `limit`
+    fn too_large(provider: Provider, status: StatusCode, limit: usize) -> RouteError {
+        tracing::error!(%provider, %status, limit, "external API response too large, dropped");
+        let source = format!("{provider} response over {limit} bytes").into();
+        let cause = ProviderError::new(provider, Some(status), source);
+        RouteError::ExternalAPITooLarge(Box::new(cause))
+    }
+
+    /// For when [reqwest] fails before we have a body to look at
+    fn request_failure(provider: Provider, err: reqwest::Error) -> RouteError {
+        tracing::error!(%provider, "external API call error: {}", err);
+        // The URL has user search terms and locations in it. The trace above is enough
+        let cause = ProviderError::new(provider, err.status(), Box::new(err.without_url()));
+        RouteError::ExternalAPIRequest(Box::new(cause))
     }
 
     // Originally this was intended for pub use in routes where we may know that we want more than
     // 1 request, but that's bad ergonomics and we have no routes which even use that yet
     // Wraps the generic [Instant] error in something usable by the web server directly
     fn check_photon_limit(&self, n: u32) -> Result<()> {
-        self.photon_limiter
-            .try_consume(n)
-            .map_err(RouteError::new_external_api_limit_failure)
+        self.photon_limiter.try_consume(n).map_err(|reset| {
+            RouteError::new_external_api_limit_failure(self.clock.system_at(reset))
+        })
+    }
+
+    /// Waits for a call to `provider` to finish if as many are in flight as it's allowed. The
+    /// wait counts against the request's [deadline] like the call itself, and is no longer than
+    /// the call would be allowed to take; past that we're too busy, and say so like a rate limit
+    async fn slot(&self, provider: Provider) -> Result<Option<tokio::sync::SemaphorePermit<'_>>> {
+        let (slots, timeout) = match provider {
+            Provider::OpenRouteService => (&self.ors_slots, self.ors_timeout),
+            Provider::Photon => (&self.photon_slots, self.photon_timeout),
+        };
+        let Some(slots) = slots else {
+            return Ok(None);
+        };
+        let wait = deadline::remaining().map_or(timeout, |left| left.min(timeout));
+        match tokio::time::timeout(wait, slots.acquire()).await {
+            Ok(permit) => Ok(Some(permit.expect("slots are never closed"))),
+            Err(_) if deadline::remaining().is_some_and(|left| left.is_zero()) => {
+                Err(deadline::exceeded())
+            }
+            Err(_) => {
+                tracing::warn!("waited {wait:?} for a free {provider} slot");
+                Err(RouteError::new_external_api_limit_failure(
+                    self.clock.system_now(),
+                ))
+            }
+        }
     }
 
     /// Checks if the response indicates a rate limit (429/503) and sets the backoff accordingly.
@@ -327,8 +1203,8 @@ impl ExternalRequester {
                 backer_off.set_without_header();
             };
 
-            match backer_off.get_retry_until() {
-                Some(inst) => Err(RouteError::ExternalAPILimit(inst)),
+            match backer_off.get_retry_at() {
+                Some(at) => Err(RouteError::ExternalAPILimit(at)),
                 None => {
                     tracing::error!("attempted to set retry-after, but query afterwards found none! passing request...");
                     Ok(resp) // Good luck lil' buddy
@@ -341,27 +1217,162 @@ impl ExternalRequester {
     }
 }
 
-// These are more like janky partial-integration tests rather than real unit tests.
-// Problem is, using external unit testing requires making more internals changable at runtime
-// AND syncing internal test state + external mock state (because these mocks must be stateful)
-// TODO: Wiremock (real one, not the Rust crate), Yaak, Postman, who knows?
+/// The calls route handlers make to external APIs. Implemented by [ExternalRequester] for real
+/// use; exists so handlers can be tested with canned responses instead of standing up mock servers.
+#[async_trait]
+pub trait ExternalApi: Send + Sync + std::fmt::Debug {
+    /// See [ExternalRequester]'s implementation
+    async fn ors_send(&self, req: &OpenRouteRequest) -> Result<geojson::FeatureCollection>;
+    /// The first route ORS finds. By default this picks apart [ExternalApi::ors_send]'s answer;
+    /// [ExternalRequester] reads it straight off the wire instead
+    async fn ors_route(&self, req: &OpenRouteRequest) -> Result<OrsRoute> {
+        let features = self.ors_send(req).await?;
+        shape::diagnose(
+            &features,
+            Expectation {
+                geometry: GeometryKind::LineString,
+                non_empty: true,
+            },
+        )?;
+        OrsRoute::from_collection(&features)
+    }
+    /// See [ExternalRequester]'s implementation
+    async fn photon_reverse_send(
+        &self,
+        coord: &PhotonRevGeocodeRequest,
+    ) -> Result<geojson::FeatureCollection>;
+    /// See [ExternalRequester]'s implementation
+    async fn photon_send(&self, req: &PhotonGeocodeRequest) -> Result<geojson::FeatureCollection>;
+    /// What's holding calls back right now. Nothing, unless overridden
+    fn limits(&self) -> Limits {
+        Limits::default()
+    }
+}
+
+/// What's holding calls back right now, for `GET /admin/limits`
+#[derive(Serialize, Debug, Default)]
+pub struct Limits {
+    /// Our own politeness limits on Photon
+    pub photon: Vec<LimitStatus>,
+    /// How their last few windows went, newest first
+    pub photon_history: Vec<WindowSummary>,
+    /// Seconds until each provider that sent a Retry-After (or a bare 429/503) can be called again
+    pub backing_off: std::collections::BTreeMap<Provider, u64>,
+}
+
+#[async_trait]
+impl ExternalApi for ExternalRequester {
+    /// Prepare *and execute* a request to OpenRouteService v2 directions endpoint.
+    ///
+    /// # Errors
+    /// [ExternalAPIRequest][crate::error::RouteError::ExternalAPIRequest]: if [reqwest] fails for network reasons, or the
+    /// API answers with an error status (other than the ratelimiting ones)
+    ///
+    /// [ExternalAPIJson][crate::error::RouteError::ExternalAPIJson]: if [serde] fails to deserialize the body into
+    /// [geojson::FeatureCollection]
+    #[instrument(skip(self))]
+    async fn ors_send(&self, req: &OpenRouteRequest) -> Result<geojson::FeatureCollection> {
+        self.ors_execute(req).await
+    }
+
+    /// [ExternalRequester::ors_send], but only the first route is kept, and its geometry is
+    /// flattened as the body comes in rather than after building a [geojson::FeatureCollection].
+    ///
+    /// # Errors
+    /// As for [ExternalRequester::ors_send], plus
+    /// [ExternalAPIContent][crate::error::RouteError::ExternalAPIContent] if there's no usable
+    /// LineString route in the answer
+    #[instrument(skip(self))]
+    async fn ors_route(&self, req: &OpenRouteRequest) -> Result<OrsRoute> {
+        self.ors_execute::<ors::RawRoute>(req).await?.try_into()
+    }
+
+    /// Prepare *and execute* a request to Photon's reverse geocoding endpoint.
+    ///
+    /// # Errors
+    /// [ExternalAPIRequest][crate::error::RouteError::ExternalAPIRequest]: if [reqwest] fails for network reasons, or the
+    /// API answers with an error status (other than the ratelimiting ones)
+    ///
+    /// [ExternalAPIJson][crate::error::RouteError::ExternalAPIJson]: if [serde] fails to deserialize the body into
+    /// [geojson::FeatureCollection]
+    #[instrument(skip(self))]
+    async fn photon_reverse_send(
+        &self,
+        coord: &PhotonRevGeocodeRequest,
+    ) -> Result<geojson::FeatureCollection> {
+        self.photon_retry_after.can_request()?; // Checks for backoff period
+        self.check_photon_limit(1)?; // Checks our own ratelimiter
+        let _slot = self.slot(Provider::Photon).await?;
+        let prepare =
+            |client: &reqwest::Client| client.get(self.photon_reverse.clone()).query(coord);
+        self.execute(
+            prepare,
+            Provider::Photon,
+            "photon_reverse",
+            &self.photon_retry_after,
+        )
+        .await
+    }
+
+    /// Prepare *and execute* a request to Photon's geocoding endpoint.
+    ///
+    /// # Errors
+    /// [ExternalAPIRequest][crate::error::RouteError::ExternalAPIRequest]: if [reqwest] fails for network reasons, or the
+    /// API answers with an error status (other than the ratelimiting ones)
+    ///
+    /// [ExternalAPIJson][crate::error::RouteError::ExternalAPIJson]: if [serde] fails to deserialize the body into
+    /// [geojson::FeatureCollection]
+    #[instrument(skip(self))]
+    async fn photon_send(&self, req: &PhotonGeocodeRequest) -> Result<geojson::FeatureCollection> {
+        self.photon_retry_after.can_request()?;
+        self.check_photon_limit(1)?;
+        let _slot = self.slot(Provider::Photon).await?;
+        let prepare = |client: &reqwest::Client| client.get(self.photon.clone()).query(req);
+        self.execute(
+            prepare,
+            Provider::Photon,
+            "photon_geocode",
+            &self.photon_retry_after,
+        )
+        .await
+    }
+
+    fn limits(&self) -> Limits {
+        let now = self.clock.now();
+        let backing_off = [
+            (Provider::OpenRouteService, &self.ors_retry_after),
+            (Provider::Photon, &self.photon_retry_after),
+        ]
+        .into_iter()
+        .filter_map(|(provider, backer_off)| {
+            let until = backer_off.get_retry_until().filter(|until| *until > now)?;
+            Some((provider, (until - now).as_secs()))
+        })
+        .collect();
+        Limits {
+            photon: self.photon_limiter.status(),
+            photon_history: self.photon_limiter.history(),
+            backing_off,
+        }
+    }
+}
+
+// These are more like partial-integration tests than real unit tests. Anything where the upstream
+// has to change its answer goes through [MockUpstream] on a [MockClock]; httpmock is still fine
+// for one-answer mocks.
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock_upstream::{MockUpstream, Reply};
     use crate::retry_after;
-    use crate::test_utils::{LONG_WAIT, SHORT_WAIT};
+    use crate::test_utils::{MockClock, LONG_WAIT, SHORT_WAIT};
+    use crate::vcr::fixture;
 
     use httpdate::fmt_http_date;
     use httpmock::prelude::*;
     use serde_json::Value;
-    use std::time::SystemTime;
     use tokio::{task, time};
 
-    // We have to convert these into json at runtime because serde_json is !const
-    const ORS_DIRECTIONS_EXAMPLE: &str = "{\"type\":\"FeatureCollection\",\"bbox\":[-123.280691,44.567643,-123.277631,44.569025],\"features\":[{\"bbox\":[-123.280691,44.567643,-123.277631,44.569025],\"type\":\"Feature\",\"properties\":{\"segments\":[{\"distance\":493.8,\"duration\":94.6,\"steps\":[{\"distance\":89.8,\"duration\":21.5,\"type\":11,\"instruction\":\"Head west\",\"name\":\"-\",\"way_points\":[0,4]},{\"distance\":176.5,\"duration\":42.4,\"type\":1,\"instruction\":\"Turn right onto Northwest Orchard Avenue\",\"name\":\"Northwest Orchard Avenue\",\"way_points\":[4,6]},{\"distance\":198.9,\"duration\":23.9,\"type\":3,\"instruction\":\"Turn sharp right onto Monroe Avenue\",\"name\":\"Monroe Avenue\",\"way_points\":[6,10]},{\"distance\":28.6,\"duration\":6.9,\"type\":2,\"instruction\":\"Turn sharp left onto Northwest 23rd Street\",\"name\":\"Northwest 23rd Street\",\"way_points\":[10,11]},{\"distance\":0.0,\"duration\":0.0,\"type\":10,\"instruction\":\"Arrive at Northwest 23rd Street, on the left\",\"name\":\"-\",\"way_points\":[11,11]}]}],\"way_points\":[0,11],\"summary\":{\"distance\":493.8,\"duration\":94.6}},\"geometry\":{\"coordinates\":[[-123.279959,44.567648],[-123.280643,44.567643],[-123.280691,44.567669],[-123.28069,44.567765],[-123.280687,44.567946],[-123.279971,44.567948],[-123.280034,44.569025],[-123.27941,44.568886],[-123.278941,44.568796],[-123.278441,44.568689],[-123.277631,44.568506],[-123.277635,44.568763]],\"type\":\"LineString\"}}],\"metadata\":{\"attribution\":\"openrouteservice.org | OpenStreetMap contributors\",\"service\":\"routing\",\"timestamp\":1746670734315,\"query\":{\"coordinates\":[[-123.27963174780633,44.56720205],[-123.27788489405276,44.5687606]],\"profile\":\"driving-car\",\"profileName\":\"driving-car\",\"format\":\"geojson\",\"instructions\":true},\"engine\":{\"version\":\"9.1.2\",\"build_date\":\"2025-04-10T21:25:30Z\",\"graph_date\":\"2025-05-04T17:44:45Z\"}}}";
-    const PHOTON_EXAMPLE: &str = "{\"features\":[{\"geometry\":{\"coordinates\":[-123.27788489405276,44.5687606],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":384119068,\"extent\":[-123.2780056,44.5688366,-123.277764,44.5686895],\"country\":\"United States\",\"city\":\"Corvallis\",\"countrycode\":\"US\",\"postcode\":\"97331\",\"county\":\"Benton\",\"type\":\"house\",\"osm_type\":\"W\",\"osm_key\":\"amenity\",\"street\":\"Northwest Monroe Avenue\",\"osm_value\":\"restaurant\",\"name\":\"Downward Dog\",\"state\":\"OR\"}},{\"geometry\":{\"coordinates\":[-116.617571,48.2630081],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":1069025747,\"extent\":[-116.6195304,48.2642298,-116.6166758,48.2622937],\"country\":\"United States\",\"city\":\"Dover\",\"countrycode\":\"US\",\"postcode\":\"83825\",\"county\":\"Bonner\",\"type\":\"street\",\"osm_type\":\"W\",\"osm_key\":\"highway\",\"osm_value\":\"path\",\"name\":\"Downward Dog\",\"state\":\"Idaho\"}},{\"geometry\":{\"coordinates\":[-114.2002596,51.0727856],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":932224045,\"extent\":[-114.2003584,51.0732352,-114.1999291,51.0722682],\"country\":\"Canada\",\"city\":\"Calgary\",\"countrycode\":\"CA\",\"postcode\":\"T3H 4X5\",\"type\":\"street\",\"osm_type\":\"W\",\"osm_key\":\"highway\",\"district\":\"Cougar Ridge\",\"osm_value\":\"path\",\"name\":\"Downward Facing Duck\",\"state\":\"Alberta\"}},{\"geometry\":{\"coordinates\":[-111.9946922,40.3417988],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":1118748795,\"extent\":[-111.997409,40.3445907,-111.9918981,40.3388893],\"country\":\"United States\",\"city\":\"Eagle Mountain\",\"countrycode\":\"US\",\"postcode\":\"84005\",\"county\":\"Utah County\",\"type\":\"street\",\"osm_type\":\"W\",\"osm_key\":\"highway\",\"osm_value\":\"cycleway\",\"name\":\"The Downward Spiral\",\"state\":\"Utah\"}},{\"geometry\":{\"coordinates\":[-111.4847386,40.6889075],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":667244116,\"extent\":[-111.4874303,40.692321,-111.4815622,40.6841203],\"country\":\"United States\",\"city\":\"Park City\",\"countrycode\":\"US\",\"postcode\":\"84068\",\"county\":\"Summit\",\"type\":\"street\",\"osm_type\":\"W\",\"osm_key\":\"highway\",\"osm_value\":\"path\",\"name\":\"Downward Dog\",\"state\":\"Utah\"}},{\"geometry\":{\"coordinates\":[-1.2341656982784492,51.01181699999999],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":368200709,\"extent\":[-1.2368335,51.0145445,-1.2311141,51.0091299],\"country\":\"United Kingdom\",\"city\":\"Winchester\",\"countrycode\":\"GB\",\"county\":\"Hampshire\",\"type\":\"other\",\"osm_type\":\"W\",\"osm_key\":\"natural\",\"district\":\"Owslebury\",\"osm_value\":\"wood\",\"name\":\"Downwards Plantation\",\"state\":\"England\"}},{\"geometry\":{\"coordinates\":[-1.2357489,51.0110353],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":12696053772,\"country\":\"United Kingdom\",\"city\":\"Winchester\",\"countrycode\":\"GB\",\"postcode\":\"SO21 1JP\",\"county\":\"Hampshire\",\"type\":\"locality\",\"osm_type\":\"N\",\"osm_key\":\"place\",\"district\":\"Owslebury\",\"osm_value\":\"locality\",\"name\":\"Downwards Copse\",\"state\":\"England\"}},{\"geometry\":{\"coordinates\":[-3.0450202,53.4331984],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":2618779466,\"country\":\"United Kingdom\",\"city\":\"Wallasey\",\"countrycode\":\"GB\",\"postcode\":\"CH45 5BG\",\"county\":\"Liverpool City Region\",\"type\":\"house\",\"osm_type\":\"N\",\"osm_key\":\"amenity\",\"street\":\"Field Road\",\"district\":\"New Brighton\",\"osm_value\":\"doctors\",\"name\":\"Field Road Health Centre - Dc Downward\",\"state\":\"England\"}},{\"geometry\":{\"coordinates\":[-91.2526733,46.168124],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_type\":\"W\",\"osm_id\":992209374,\"extent\":[-91.2539443,46.1682781,-91.2510665,46.1675571],\"country\":\"United States\",\"osm_key\":\"highway\",\"city\":\"Cable\",\"countrycode\":\"US\",\"osm_value\":\"cycleway\",\"name\":\"Downward Spiral\",\"county\":\"Bayfield\",\"state\":\"Wisconsin\",\"type\":\"street\"}},{\"geometry\":{\"coordinates\":[-85.7417642,38.1860092],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":531319755,\"extent\":[-85.7417642,38.1860092,-85.7416771,38.1858811],\"country\":\"United States\",\"city\":\"Louisville\",\"countrycode\":\"US\",\"postcode\":\"40221\",\"county\":\"Jefferson\",\"type\":\"street\",\"osm_type\":\"W\",\"osm_key\":\"highway\",\"osm_value\":\"steps\",\"name\":\"Main Downward Escalator\",\"state\":\"Kentucky\"}},{\"geometry\":{\"coordinates\":[-79.901113,40.4327109],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":342659442,\"extent\":[-79.9021076,40.4327594,-79.9002589,40.4323901],\"country\":\"United States\",\"city\":\"Pittsburgh\",\"countrycode\":\"US\",\"postcode\":\"15218\",\"locality\":\"Squirrel Hill South\",\"county\":\"Allegheny\",\"type\":\"street\",\"osm_type\":\"W\",\"osm_key\":\"highway\",\"osm_value\":\"path\",\"name\":\"Downward Dog Trail\",\"state\":\"Pennsylvania\"}},{\"geometry\":{\"coordinates\":[121.7392837,25.1372142],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":896829126,\"extent\":[121.7391349,25.1373835,121.7392837,25.1372142],\"country\":\"臺灣\",\"city\":\"基隆市\",\"countrycode\":\"TW\",\"postcode\":\"20343\",\"locality\":\"中興里\",\"type\":\"street\",\"osm_type\":\"W\",\"osm_key\":\"highway\",\"district\":\"中山區\",\"osm_value\":\"service\",\"name\":\"虎仔山迴車塔(下行)\"}},{\"geometry\":{\"coordinates\":[115.8901352,38.4483478],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":388418518,\"extent\":[115.8873444,38.4529023,115.8933623,38.4455405],\"country\":\"中国\",\"city\":\"沧州市\",\"countrycode\":\"CN\",\"postcode\":\"062300\",\"type\":\"house\",\"osm_type\":\"W\",\"osm_key\":\"railway\",\"street\":\"黄榆线\",\"district\":\"肃宁县\",\"osm_value\":\"rail\",\"name\":\"王佐下联线\",\"state\":\"河北省\"}},{\"geometry\":{\"coordinates\":[115.8678597,38.4415208],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":388418516,\"extent\":[115.8678597,38.4415208,115.8681994,38.4412515],\"country\":\"中国\",\"city\":\"沧州市\",\"countrycode\":\"CN\",\"postcode\":\"062300\",\"type\":\"house\",\"osm_type\":\"W\",\"osm_key\":\"railway\",\"street\":\"德善街\",\"district\":\"肃宁县\",\"osm_value\":\"rail\",\"name\":\"肃宁下联线\",\"state\":\"河北省\"}},{\"geometry\":{\"coordinates\":[115.8665264,38.4338899],\"type\":\"Point\"},\"type\":\"Feature\",\"properties\":{\"osm_id\":388418517,\"extent\":[115.8611995,38.4412515,115.869729,38.4284719],\"country\":\"中国\",\"city\":\"沧州市\",\"countrycode\":\"CN\",\"postcode\":\"062300\",\"type\":\"house\",\"osm_type\":\"W\",\"osm_key\":\"railway\",\"street\":\"德善街\",\"district\":\"肃宁县\",\"osm_value\":\"rail\",\"name\":\"肃宁下联线\",\"state\":\"河北省\"}}],\"type\":\"FeatureCollection\"}";
-    // Nothing's really added by testing reverse geocoding
-
     fn gen_tester_requester(stringly_base: String) -> ExternalRequester {
         let stringly_base = format!("http://{}", stringly_base);
         let base = reqwest::Url::parse(&stringly_base)
@@ -379,6 +1390,9 @@ mod tests {
             query: "downward".to_string(),
             lat: Some(-123.279166),
             lon: Some(44.567189),
+            bbox: None,
+            zoom: None,
+            lang: None,
         }
     }
 
@@ -389,6 +1403,10 @@ mod tests {
                 vec![-123.27788489405276, 44.5687606],
             ],
             instructions: true,
+            bearings: None,
+            profile: Default::default(),
+            extra_info: false,
+            elevation: false,
         }
     }
 
@@ -397,7 +1415,7 @@ mod tests {
     #[tokio::test()]
     async fn photon_ratelimit_test() {
         let server = MockServer::start_async().await;
-        let resp_body: Value = serde_json::from_str(PHOTON_EXAMPLE).unwrap();
+        let resp_body: Value = serde_json::from_str(&fixture("photon_geocode")).unwrap();
         server
             .mock_async(|when, then| {
                 when.method(GET)
@@ -433,109 +1451,433 @@ mod tests {
             .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
     }
 
-    // Get a 429 with valid retry-after. Ensure a request made within the time fails, and one after
-    // doesn't. In reality we have Access-Control-Expose-Headers we could use, but we don't
+    // ORS explains itself on bad requests. We can't check the trace here, but we can check the
+    // error isn't mistaken for a JSON problem
     #[tokio::test()]
-    async fn overloaded_ors() {
-        // We're going to fake a stateful mock by swapping in different mocks on the same port
+    async fn ors_error_status() {
         let server = MockServer::start_async().await;
-        let resp_body: Value = serde_json::from_str(ORS_DIRECTIONS_EXAMPLE).unwrap();
-        // In truth, I don't know what the real server will exactly respond.
-        let tired_server = server
+        server
             .mock_async(|when, then| {
                 when.method(POST).path(ORS_DIRECTIONS_PATH);
-                then.status(429)
-                    .header("Retry-After", fmt_http_date(SystemTime::now() + SHORT_WAIT));
+                then.status(404)
+                    .header("Content-Type", "application/json;charset=UTF-8")
+                    .body(r#"{"error":{"code":2010,"message":"Could not find routable point"}}"#);
             })
             .await;
 
-        let mut reqr = gen_tester_requester(tired_server.server_address().to_string());
-        let or = route_request();
-
-        // Get backer-offed
+        let reqr = gen_tester_requester(server.address().to_string());
         assert!(reqr
-            .ors_send(&or)
+            .ors_send(&route_request())
             .await
-            .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
-        time::pause();
+            .is_err_and(|x| matches!(x, RouteError::ExternalAPIRequest(_))));
+    }
 
-        // Pretend this is a stateful mock and not just two mocks in a trenchcoat
-        tired_server.delete_async().await;
-        let wired_server = server
+    // Bearings only go to ORS when the app sent a heading
+    #[tokio::test()]
+    async fn ors_gets_bearings() {
+        let server = MockServer::start_async().await;
+        let with = server
             .mock_async(|when, then| {
-                when.method(POST).path(ORS_DIRECTIONS_PATH);
+                when.method(POST)
+                    .path(ORS_DIRECTIONS_PATH)
+                    .json_body_partial(r#"{"bearings": [[90.0, 45.0], []]}"#);
+                then.status(200).body(fixture("ors_directions"));
+            })
+            .await;
+
+        let reqr = gen_tester_requester(server.address().to_string());
+        let _ = reqr.ors_send(&route_request()).await;
+        assert_eq!(with.hits_async().await, 0);
+        let headed = OpenRouteRequest {
+            bearings: Some(vec![vec![90.0, 45.0], vec![]]),
+            ..route_request()
+        };
+        assert!(reqr.ors_send(&headed).await.is_ok());
+        assert_eq!(with.hits_async().await, 1);
+    }
+
+    // Garbage with a 200 is still a JSON problem, not a request problem
+    #[tokio::test()]
+    async fn photon_garbage_body() {
+        let server = MockServer::start_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method(GET).path(PHOTON_PATH);
                 then.status(200)
-                    .header("Content-Type", "application/geo+json;charset=UTF-8")
-                    .json_body(resp_body);
+                    .header("Content-Type", "text/html")
+                    .body("<html>definitely not geojson</html>");
             })
             .await;
-        // This wouldn't work in a real intergration test, and it wouldn't be needed either
-        reqr.ors_directions =
-            Url::parse(format!("http://{}", wired_server.server_address()).as_str())
-                .unwrap_or_else(|e| panic!("couldn't parse mock base address: {e:?}"))
-                .join(ORS_DIRECTIONS_PATH)
-                .unwrap_or_else(|e| panic!("couldn't merge mock base address with path: {e:?}"));
 
-        time::resume();
+        let reqr = gen_tester_requester(server.address().to_string());
         assert!(reqr
-            .ors_send(&or)
+            .photon_send(&geocode_request())
             .await
-            .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
-        time::pause();
-        time::advance(SHORT_WAIT).await;
-        task::yield_now().await; // httpmock doesn't like this buffoonery
-        time::resume();
-        assert!(reqr.ors_send(&or).await.is_ok());
+            .is_err_and(|x| matches!(x, RouteError::ExternalAPIJson(_))));
     }
 
-    // Get a 503 with no retry-after. Ensure a request made within the time fails, and one after
-    // doesn't.
-    #[tokio::test()]
-    async fn headerless_overload() {
+    #[tokio::test]
+    async fn deadline_cuts_call_short() {
         let server = MockServer::start_async().await;
-        let resp_body: Value = serde_json::from_str(ORS_DIRECTIONS_EXAMPLE).unwrap();
-        // In truth, I don't know what the real server will exactly respond.
-        let mut tired_server = server.mock(|when, then| {
-            when.method(POST).path(ORS_DIRECTIONS_PATH);
-            then.status(503);
-        });
+        server
+            .mock_async(|when, then| {
+                when.method(GET).path(PHOTON_PATH);
+                then.status(200)
+                    .delay(Duration::from_secs(5))
+                    .body(fixture("photon_geocode"));
+            })
+            .await;
 
-        let mut reqr = gen_tester_requester(tired_server.server_address().to_string());
-        let or = route_request();
+        let reqr = gen_tester_requester(server.address().to_string());
+        let started = time::Instant::now();
+        let res = deadline::with_budget(
+            Duration::from_millis(200),
+            reqr.photon_send(&geocode_request()),
+        )
+        .await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let Err(RouteError::DeadlineExceeded(report)) = res else {
+            panic!("expected a blown deadline, got {res:?}");
+        };
+        assert_eq!(report.calls[0].endpoint, "photon_geocode");
+        assert_eq!(report.calls[0].outcome, "timed out");
+    }
 
-        // Get backer-offed
-        assert!(reqr
-            .ors_send(&or)
-            .await
-            .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
-        time::pause();
+    #[tokio::test]
+    async fn photon_total_timeout() {
+        let server = MockServer::start_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method(GET).path(PHOTON_PATH);
+                then.status(200)
+                    .delay(Duration::from_secs(5))
+                    .body(fixture("photon_geocode"));
+            })
+            .await;
+
+        let base = reqwest::Url::parse(&format!("http://{}", server.address())).unwrap();
+        let reqr = ExternalRequesterBuilder::new(base.clone(), base, SecretString::from("foo"))
+            .with_timeouts(Provider::Photon, "100,100,200".parse().unwrap())
+            .build();
+        let started = time::Instant::now();
+        let res = reqr.photon_send(&geocode_request()).await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(res.is_err_and(|x| matches!(x, RouteError::ExternalAPIRequest(_))));
+    }
+
+    // komoot-public lets 4 calls out at once. The rest queue rather than fail
+    #[tokio::test]
+    async fn photon_calls_queue_for_slots() {
+        let server = MockServer::start_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method(GET).path(PHOTON_PATH);
+                then.status(200)
+                    .delay(Duration::from_millis(300))
+                    .body(fixture("photon_geocode"));
+            })
+            .await;
+
+        let base = reqwest::Url::parse(&format!("http://{}", server.address())).unwrap();
+        let reqr = ExternalRequesterBuilder::new(base.clone(), base, SecretString::from("foo"))
+            .with_photon_etiquette(etiquette::Preset::KomootPublic)
+            .build();
+        let req = geocode_request();
+        let started = time::Instant::now();
+        let results = futures_util::future::join_all((0..8).map(|_| reqr.photon_send(&req))).await;
+        assert!(results.iter().all(|res| res.is_ok()));
+        assert!(started.elapsed() >= Duration::from_millis(600));
+    }
 
-        // Pretend this is a stateful mock and not just two mocks in a trenchcoat
-        tired_server.delete();
-        let wired_server = server
+    // Pings don't touch our own limits
+    #[tokio::test]
+    async fn keepalive_heads_both_bases() {
+        let server = MockServer::start_async().await;
+        let head = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::HEAD).path("/");
+                then.status(200);
+            })
+            .await;
+
+        let reqr = gen_tester_requester(server.address().to_string());
+        reqr.keepalive().await;
+        head.assert_hits_async(2).await;
+        assert!(reqr.limits().photon.iter().all(|status| status.used == 0));
+    }
+
+    // Slots cap ORS too when asked, though it has no rate limits of ours
+    #[tokio::test]
+    async fn ors_calls_queue_for_slots() {
+        let server = MockServer::start_async().await;
+        server
             .mock_async(|when, then| {
                 when.method(POST).path(ORS_DIRECTIONS_PATH);
                 then.status(200)
-                    .header("Content-Type", "application/geo+json;charset=UTF-8")
-                    .json_body(resp_body);
+                    .delay(Duration::from_millis(300))
+                    .body(fixture("ors_directions"));
             })
             .await;
-        // This wouldn't work in a real intergration test, and it wouldn't be needed either
-        reqr.ors_directions =
-            Url::parse(format!("http://{}", wired_server.server_address()).as_str())
-                .unwrap_or_else(|e| panic!("couldn't parse mock base address: {e:?}"))
-                .join(ORS_DIRECTIONS_PATH)
-                .unwrap_or_else(|e| panic!("couldn't merge mock base address with path: {e:?}"));
 
-        time::resume();
+        let base = reqwest::Url::parse(&format!("http://{}", server.address())).unwrap();
+        let reqr = ExternalRequesterBuilder::new(base.clone(), base, SecretString::from("foo"))
+            .with_concurrency(Provider::OpenRouteService, 1)
+            .build();
+        let req = route_request();
+        let started = time::Instant::now();
+        let results = futures_util::future::join_all((0..2).map(|_| reqr.ors_send(&req))).await;
+        assert!(results.iter().all(|res| res.is_ok()));
+        assert!(started.elapsed() >= Duration::from_millis(600));
+    }
+
+    #[test]
+    fn timeouts_parse() {
+        assert_eq!(
+            "1000, 2000,3000".parse::<Timeouts>(),
+            Ok(Timeouts {
+                connect: Duration::from_secs(1),
+                read: Duration::from_secs(2),
+                total: Duration::from_secs(3),
+            })
+        );
+        assert!("1000,2000".parse::<Timeouts>().is_err());
+        assert!("1s,2s,3s".parse::<Timeouts>().is_err());
+    }
+
+    #[test]
+    fn contacts_parse() {
+        let contact = |s: &str| {
+            s.parse::<Contact>()
+                .map(|c| user_agent("flipmap/1", Some(&c)))
+        };
+        assert_eq!(
+            contact("mailto:ops@flipmap.example").unwrap(),
+            "flipmap/1 (+ops@flipmap.example)"
+        );
+        assert_eq!(
+            contact(" https://flipmap.example/contact ").unwrap(),
+            "flipmap/1 (+https://flipmap.example/contact)"
+        );
+        for bad in [
+            "",
+            "ops",
+            "ops@localhost",
+            "@flipmap.example",
+            "a b@c.d",
+            "http://localhost",
+        ] {
+            assert!(contact(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_user_agent() {
+        let server = MockServer::start_async().await;
+        let mock = server
+            .mock_async(|when, then| {
+                when.method(GET)
+                    .path(PHOTON_PATH)
+                    .header("user-agent", "flipmap/1 (+ops@flipmap.example)");
+                then.status(200).body(fixture("photon_geocode"));
+            })
+            .await;
+        let base = reqwest::Url::parse(&format!("http://{}", server.address())).unwrap();
+        let reqr = ExternalRequesterBuilder::new(base.clone(), base, SecretString::from("foo"))
+            .with_user_agent("flipmap/1", &"ops@flipmap.example".parse().unwrap())
+            .build();
+        assert!(reqr.photon_send(&geocode_request()).await.is_ok());
+        mock.assert_async().await;
+    }
+
+    // The mock server only speaks HTTP/1.1, so forcing HTTP/2 has to fall back to get anywhere
+    #[tokio::test]
+    async fn http2_falls_back() {
+        let server = MockServer::start_async().await;
+        let mock = server
+            .mock_async(|when, then| {
+                when.method(GET).path(PHOTON_PATH);
+                then.status(200).body(fixture("photon_geocode"));
+            })
+            .await;
+
+        let base = reqwest::Url::parse(&format!("http://{}", server.address())).unwrap();
+        let reqr = ExternalRequesterBuilder::new(base.clone(), base, SecretString::from("foo"))
+            .with_http_version(Provider::Photon, HttpVersion::Http2)
+            .build();
+        assert!(reqr.photon_send(&geocode_request()).await.is_ok());
+        assert!(reqr.photon_send(&geocode_request()).await.is_ok());
+        assert_eq!(mock.hits_async().await, 2);
+    }
+
+    #[test]
+    fn http_version_parse() {
+        assert_eq!("HTTP2".parse(), Ok(HttpVersion::Http2));
+        assert_eq!("1.1".parse(), Ok(HttpVersion::Http1));
+        assert_eq!("auto".parse(), Ok(HttpVersion::Auto));
+        assert!("http3".parse::<HttpVersion>().is_err());
+    }
+
+    fn mock_requester(upstream: &MockUpstream, clock: &Arc<MockClock>) -> ExternalRequester {
+        ExternalRequesterBuilder::new(upstream.base(), upstream.base(), SecretString::from("foo"))
+            .with_photon_ratelimiter(2, SHORT_WAIT, "short boy".to_string())
+            .with_clock(clock.clone())
+            .build()
+    }
+
+    fn limited<T>(res: Result<T>) -> bool {
+        res.is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_)))
+    }
+
+    // Get a 429 with valid retry-after. Ensure a request made within the time fails without
+    // reaching ORS, and one after gets through. In reality we have Access-Control-Expose-Headers
+    // we could use, but we don't
+    #[tokio::test]
+    async fn overloaded_ors() {
+        let clock = MockClock::new();
+        let upstream = MockUpstream::start().await;
+        // In truth, I don't know what the real server will exactly respond.
+        upstream.on(
+            ORS_DIRECTIONS_PATH,
+            [
+                Reply::status(StatusCode::TOO_MANY_REQUESTS).with_header(
+                    "retry-after",
+                    fmt_http_date(clock.system_now() + SHORT_WAIT),
+                ),
+                Reply::geojson(fixture("ors_directions")),
+            ],
+        );
+        let reqr = mock_requester(&upstream, &clock);
+        let or = route_request();
+
+        assert!(limited(reqr.ors_send(&or).await));
+        clock.advance(SHORT_WAIT - Duration::from_secs(1));
+        assert!(limited(reqr.ors_send(&or).await));
+        assert_eq!(upstream.hits(ORS_DIRECTIONS_PATH), 1);
+        let limits = reqr.limits();
+        assert_eq!(limits.backing_off[&Provider::OpenRouteService], 1);
+        assert_eq!(limits.photon[0].name, "short boy");
+
+        clock.advance(Duration::from_secs(1));
+        assert!(reqr.ors_send(&or).await.is_ok());
+        assert_eq!(upstream.hits(ORS_DIRECTIONS_PATH), 2);
+    }
+
+    // Get a 503 with no retry-after. Ensure a request made within the time fails, and one after
+    // doesn't.
+    #[tokio::test]
+    async fn headerless_overload() {
+        let clock = MockClock::new();
+        let upstream = MockUpstream::start().await;
+        upstream.on(
+            ORS_DIRECTIONS_PATH,
+            [
+                Reply::status(StatusCode::SERVICE_UNAVAILABLE),
+                Reply::geojson(fixture("ors_directions")),
+            ],
+        );
+        let reqr = mock_requester(&upstream, &clock);
+        let or = route_request();
+
+        assert!(limited(reqr.ors_send(&or).await));
+        assert!(limited(reqr.ors_send(&or).await));
+        clock.advance(retry_after::HEADERLESS_BACKOFF_TIME);
+        assert!(reqr.ors_send(&or).await.is_ok());
+        assert_eq!(upstream.hits(ORS_DIRECTIONS_PATH), 2);
+    }
+
+    // ORS's daily quota runs out mid-conversation. Its Retry-After is honoured until the quota
+    // comes back
+    #[tokio::test]
+    async fn ors_quota_runs_out() {
+        let clock = MockClock::new();
+        let upstream = MockUpstream::start().await;
+        upstream
+            .on(
+                ORS_DIRECTIONS_PATH,
+                [Reply::geojson(fixture("ors_directions"))],
+            )
+            .quota(
+                ORS_DIRECTIONS_PATH,
+                2,
+                Reply::status(StatusCode::TOO_MANY_REQUESTS).with_header("retry-after", "3600"),
+            );
+        let reqr = mock_requester(&upstream, &clock);
+        let or = route_request();
+
+        assert!(reqr.ors_send(&or).await.is_ok());
+        assert!(reqr.ors_send(&or).await.is_ok());
+        assert!(limited(reqr.ors_send(&or).await));
+        clock.advance(Duration::from_secs(1800));
+        assert!(limited(reqr.ors_send(&or).await));
+        assert_eq!(upstream.hits(ORS_DIRECTIONS_PATH), 3);
+
+        upstream.refill(ORS_DIRECTIONS_PATH, 1);
+        clock.advance(Duration::from_secs(1800));
+        assert!(reqr.ors_send(&or).await.is_ok());
+    }
+
+    // Photon limits are our own, so they reset on our clock without Photon noticing
+    #[tokio::test]
+    async fn photon_limit_resets_on_clock() {
+        let clock = MockClock::new();
+        let upstream = MockUpstream::start().await;
+        upstream.on(PHOTON_PATH, [Reply::geojson(fixture("photon_geocode"))]);
+        let reqr = mock_requester(&upstream, &clock);
+        let gr = geocode_request();
+
+        assert!(reqr.photon_send(&gr).await.is_ok());
+        assert!(reqr.photon_send(&gr).await.is_ok());
+        assert!(limited(reqr.photon_send(&gr).await));
+        clock.advance(SHORT_WAIT);
+        assert!(reqr.photon_send(&gr).await.is_ok());
+        assert_eq!(upstream.hits(PHOTON_PATH), 3);
+    }
+
+    // A bad body one call doesn't poison the next
+    #[tokio::test]
+    async fn photon_malformed_then_fine() {
+        let clock = MockClock::new();
+        let upstream = MockUpstream::start().await;
+        upstream.on(
+            PHOTON_PATH,
+            [
+                Reply::malformed(),
+                Reply::geojson(fixture("photon_geocode")),
+            ],
+        );
+        let reqr = mock_requester(&upstream, &clock);
+
         assert!(reqr
-            .ors_send(&or)
+            .photon_send(&geocode_request())
             .await
-            .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
-        time::pause();
-        time::advance(retry_after::HEADERLESS_BACKOFF_TIME).await;
-        task::yield_now().await; // httpmock doesn't like this buffoonery
-        time::resume();
-        assert!(reqr.ors_send(&or).await.is_ok());
+            .is_err_and(|x| matches!(x, RouteError::ExternalAPIJson(_))));
+        assert!(reqr.photon_send(&geocode_request()).await.is_ok());
+
+        let metrics = crate::metrics::registry().render();
+        assert!(metrics.contains(
+            "upstream_errors_total{endpoint=\"photon_geocode\",kind=\"json\",provider=\"Photon\"}"
+        ));
+        assert!(metrics.contains(
+            "upstream_request_duration_seconds_count{endpoint=\"photon_geocode\",provider=\"Photon\"}"
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_body_dropped() {
+        let upstream = MockUpstream::start().await;
+        let body = fixture("ors_directions");
+        let reqr = ExternalRequesterBuilder::new(upstream.base(), upstream.base(), "foo".into())
+            .with_max_body(body.len() - 1)
+            .build();
+        let too_large = |res: Result<OrsRoute>| {
+            res.is_err_and(|x| matches!(x, RouteError::ExternalAPITooLarge(_)))
+        };
+
+        upstream.on(ORS_DIRECTIONS_PATH, [Reply::geojson(body.clone())]);
+        assert!(too_large(reqr.ors_route(&route_request()).await));
+        // Error bodies are read whole, but not past the limit either
+        let error = Reply::geojson(body).with_status(StatusCode::BAD_GATEWAY);
+        upstream.on(ORS_DIRECTIONS_PATH, [error]);
+        assert!(too_large(reqr.ors_route(&route_request()).await));
     }
 }
@@ -0,0 +1,9 @@
+//! Re-routing during navigation, for `POST /reroute`.
+//!
+//! The app sends the route it's following and where the user is. Close enough to the route is
+//! "still on route", and costs no ORS call. Otherwise ORS is asked for a new route to the same
+//! destination, which usually rejoins the old one somewhere. Only the new part goes back, with
+//! where on the old route to carry on from, so a long route isn't sent twice.
+//!
+//! `flipmap_geometry::route` has the math, shared with the app.
+pub use flipmap_geometry::route::{on_route, rejoin, DEFAULT_TOLERANCE_M};
@@ -1,25 +1,42 @@
 //! Implements lock-free state keeping for when to allow the next request after an HTTP 503 or 429
 //! response. Uses supplied time from Retry-After, or a TBD backoff algorithm otherwise
+//!
+//! With a [BackoffFile], backoffs are also written down as wall-clock times, so a restart in the
+//! middle of one doesn't send the provider a request it asked us not to.
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
+use crate::clock::{self, Clock};
 use crate::error::RouteError;
 use arc_swap::ArcSwapOption;
 use httpdate::parse_http_date;
-use std::time::SystemTime;
 use tokio::time::{Duration, Instant};
 use tracing::instrument;
 
 /// In lieu of a proper algorithm, we wait this long if the server sends a backoff worthy response
 /// without a Retry-After header
 pub const HEADERLESS_BACKOFF_TIME: Duration = Duration::from_secs(30);
+/// Longer Retry-After values are cut down to this. Absurd ones would overflow [Instant]
+pub const MAX_BACKOFF_TIME: Duration = Duration::from_secs(24 * 60 * 60);
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct BackerOff {
     /// Solely for logging
     name: Option<String>,
     //Note: <T> here is actually Arc<T> :think:
     until: ArcSwapOption<Instant>,
+    clock: Arc<dyn Clock>,
+    /// Where backoffs are written down, under [BackerOff::name]
+    file: Option<BackoffFile>,
+}
+
+impl Default for BackerOff {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -37,15 +54,43 @@ impl BackerOff {
         BackerOff {
             name: None,
             until: ArcSwapOption::new(None),
+            clock: clock::system(),
+            file: None,
         }
     }
 
+    /// Takes time from `clock` instead of the system
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Sets an optional name for this backoff instance, for logging.
     pub fn with_name(mut self, name: String) -> Self {
         self.name = Some(name);
         self
     }
 
+    /// Writes backoffs to `file`, and picks back up one written there before a restart. Goes
+    /// after [BackerOff::with_name] and [BackerOff::with_clock], since it's kept by name and
+    /// converted with the clock
+    pub fn with_file(mut self, file: BackoffFile) -> Self {
+        let name = self.name.clone().unwrap_or_default();
+        if let Some(until) = file.get(&name) {
+            // Instants can't be stored, so this goes by the wall clock. A deadline that's passed
+            // (or that the clock jumped past) is dropped; one absurdly far off is capped like a
+            // header would be
+            let left = until.duration_since(self.clock.system_now()).ok();
+            if let Some(left) = left.filter(|left| !left.is_zero()) {
+                let left = left.min(MAX_BACKOFF_TIME);
+                tracing::info!(name, "still backing off for {left:?} from before restart");
+                self.until = ArcSwapOption::new(Some(Arc::new(self.clock.now() + left)));
+            }
+        }
+        self.file = Some(file);
+        self
+    }
+
     /// Parses the value of a `Retry-After` header and blocks further requests until time, if it's
     /// in the future.
     ///
@@ -54,10 +99,10 @@ impl BackerOff {
     ///
     /// Returns [Error] if parsing fails or the value represents a time in the past
     ///
-    /// Returns Ok if a future instant was set
+    /// Returns Ok if a future instant was set. It's never more than [MAX_BACKOFF_TIME] away
     pub fn parse_maybe_set(&self, value: &str) -> Result<(), Error> {
-        let delay = self.parse_retry_value(value)?;
-        let monotonically_later = Instant::now() + delay;
+        let delay = self.parse_retry_value(value)?.min(MAX_BACKOFF_TIME);
+        let monotonically_later = self.clock.now() + delay;
         self.set_retry_until(monotonically_later);
         Ok(())
     }
@@ -68,7 +113,7 @@ impl BackerOff {
     /// so currently it's just a 30s pause.
     pub fn set_without_header(&self) {
         //TODO: Stateful backoff?
-        let later = Instant::now() + HEADERLESS_BACKOFF_TIME;
+        let later = self.clock.now() + HEADERLESS_BACKOFF_TIME;
         self.set_retry_until(later);
     }
 
@@ -84,7 +129,7 @@ impl BackerOff {
         match *guard {
             None => Ok(()), // No backoff active
             Some(ref until_instant) => {
-                let now = Instant::now();
+                let now = self.clock.now();
                 if now >= **until_instant {
                     // Backoff period has passed. Try to clear it.
                     // Another thread may have already done this, or set a new backoff period
@@ -95,7 +140,9 @@ impl BackerOff {
                     Ok(())
                 } else {
                     // Backoff period still active
-                    Err(RouteError::ExternalAPILimit(**until_instant))
+                    Err(RouteError::ExternalAPILimit(
+                        self.clock.system_at(**until_instant),
+                    ))
                 }
             }
         }
@@ -106,6 +153,11 @@ impl BackerOff {
         Some(*self.until.load_full()?)
     }
 
+    /// [BackerOff::get_retry_until] on the wall clock, for anything leaving the process
+    pub fn get_retry_at(&self) -> Option<SystemTime> {
+        Some(self.clock.system_at(self.get_retry_until()?))
+    }
+
     /// If Stores the calculated `Instant` until which requests should be blocked
     #[instrument(fields(name = self.name))]
     fn set_retry_until(&self, instant: Instant) {
@@ -116,9 +168,13 @@ impl BackerOff {
         // We'll assume that doesn't happen regularly. A stray-cosmic ray isn't a show-stopper.
         tracing::info!(
             "setting backoff until {:?}",
-            instant.duration_since(Instant::now())
+            instant.duration_since(self.clock.now())
         );
         self.until.store(Some(Arc::new(instant)));
+        if let Some(file) = &self.file {
+            let name = self.name.as_deref().unwrap_or_default();
+            file.set(name, self.clock.system_at(instant));
+        }
     }
 
     #[instrument()]
@@ -129,7 +185,7 @@ impl BackerOff {
         if let Ok(datetime) = parse_http_date(value) {
             // We have a datetime, but no guarantee if it's in the future!
             // We need to check if this has passed according to our local system time.
-            let now = SystemTime::now();
+            let now = self.clock.system_now();
 
             // Find out if it's from the future or not
             return match datetime.duration_since(now) {
@@ -148,47 +204,198 @@ impl BackerOff {
     }
 }
 
+/// Backoff deadlines kept on disk, as milliseconds since the epoch by [BackerOff] name. Cheap to
+/// clone; clones share the file
+#[derive(Debug, Clone)]
+pub struct BackoffFile {
+    path: Arc<PathBuf>,
+    deadlines: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl BackoffFile {
+    /// Reads what's at `path`. A missing or unreadable file means no backoffs; it's overwritten
+    /// on the next one
+    pub fn load(path: PathBuf) -> Self {
+        let deadlines = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                tracing::warn!("ignoring unreadable backoff file {path:?}: {e}");
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                tracing::warn!("couldn't read backoff file {path:?}: {e}");
+                HashMap::new()
+            }
+        };
+        BackoffFile {
+            path: Arc::new(path),
+            deadlines: Arc::new(Mutex::new(deadlines)),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<SystemTime> {
+        let deadlines = self.deadlines.lock().expect("backoff file lock poisoned");
+        let ms = *deadlines.get(name)?;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_millis(ms))
+    }
+
+    /// Writes the file with `name` backing off until `until`. Blocks, but backoffs are rare and
+    /// the file is tiny. Failures are logged; the backoff still holds until a restart
+    fn set(&self, name: &str, until: SystemTime) {
+        let ms = until
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut deadlines = self.deadlines.lock().expect("backoff file lock poisoned");
+        deadlines.insert(name.to_owned(), ms);
+        let text = serde_json::to_string(&*deadlines).expect("backoffs should serialize");
+        if let Err(e) = write_atomically(&self.path, &text) {
+            tracing::warn!("couldn't write backoff file {:?}: {e}", self.path);
+        }
+    }
+}
+
+/// So a crash mid-write can't leave half a file
+fn write_atomically(path: &Path, text: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, text)?;
+    std::fs::rename(tmp, path)
+}
+
 #[cfg(test)]
 mod tests {
     use httpdate::fmt_http_date;
-    use tokio::time;
+    use proptest::prelude::*;
 
     use super::*;
+    use crate::test_utils::MockClock;
 
-    #[tokio::test(start_paused = true)]
-    async fn block_without_header() {
-        let backer = BackerOff::new();
-        backer.set_without_header();
-        dbg!(&backer, tokio::time::Instant::now());
-        assert!(backer
+    fn backer(clock: &Arc<MockClock>) -> BackerOff {
+        BackerOff::new().with_clock(clock.clone())
+    }
+
+    fn limited(backer: &BackerOff) -> bool {
+        backer
             .can_request()
-            .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
-        time::advance(HEADERLESS_BACKOFF_TIME + Duration::from_millis(100)).await;
+            .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_)))
+    }
+
+    #[test]
+    fn block_without_header() {
+        let clock = MockClock::new();
+        let backer = backer(&clock);
+        backer.set_without_header();
+        assert!(limited(&backer));
+        clock.advance(HEADERLESS_BACKOFF_TIME - Duration::from_millis(1));
+        assert!(limited(&backer));
+        clock.advance(Duration::from_millis(1));
         assert!(backer.can_request().is_ok());
+        assert!(backer.get_retry_until().is_none());
     }
 
-    #[tokio::test(start_paused = true)]
-    async fn block_with_int_header() {
-        let backer = BackerOff::new();
+    #[test]
+    fn block_with_int_header() {
+        let clock = MockClock::new();
+        let backer = backer(&clock);
         assert!(backer.parse_maybe_set("60").is_ok());
-        assert!(backer
-            .can_request()
-            .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
-        time::advance(Duration::from_secs(60)).await;
+        assert_eq!(
+            backer.get_retry_until(),
+            Some(clock.now() + Duration::from_secs(60))
+        );
+        assert!(limited(&backer));
+        clock.advance(Duration::from_secs(60));
         assert!(backer.can_request().is_ok());
     }
 
-    #[tokio::test(start_paused = true)]
-    async fn block_with_httpdate_header() {
-        let until = SystemTime::now() + Duration::from_secs(20);
-        let str_until = fmt_http_date(until);
+    #[test]
+    fn block_with_httpdate_header() {
+        let clock = MockClock::new();
+        let str_until = fmt_http_date(clock.system_now() + Duration::from_secs(20));
 
-        let backer = BackerOff::new();
+        let backer = backer(&clock);
         assert!(backer.parse_maybe_set(str_until.as_str()).is_ok());
-        assert!(backer
-            .can_request()
-            .is_err_and(|x| matches!(x, RouteError::ExternalAPILimit(_))));
-        time::advance(Duration::from_secs(20)).await;
+        assert_eq!(
+            backer.get_retry_until(),
+            Some(clock.now() + Duration::from_secs(20))
+        );
+        assert!(limited(&backer));
+        clock.advance(Duration::from_secs(20));
         assert!(backer.can_request().is_ok());
     }
+
+    #[test]
+    fn backoffs_survive_restart() {
+        let path =
+            std::env::temp_dir().join(format!("flipmap-backoffs-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = MockClock::new();
+        let named = |name: &str, clock: &Arc<MockClock>| {
+            backer(clock)
+                .with_name(name.to_owned())
+                .with_file(BackoffFile::load(path.clone()))
+        };
+        let ors = named("ors", &clock);
+        assert!(ors.get_retry_until().is_none());
+        ors.parse_maybe_set("60").unwrap();
+
+        // A restarted process has a new monotonic clock, but the same wall clock
+        let restarted = MockClock::new();
+        restarted.advance(Duration::from_secs(20));
+        let ors = named("ors", &restarted);
+        assert_eq!(
+            ors.get_retry_until(),
+            Some(restarted.now() + Duration::from_secs(40))
+        );
+        assert!(named("photon", &restarted).get_retry_until().is_none());
+
+        restarted.advance(Duration::from_secs(40));
+        assert!(named("ors", &restarted).get_retry_until().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn garbage_backoff_file_ignored() {
+        let path =
+            std::env::temp_dir().join(format!("flipmap-backoffs-bad-{}.json", std::process::id()));
+        std::fs::write(&path, "not json").unwrap();
+        let backer = BackerOff::new()
+            .with_name("ors".to_owned())
+            .with_file(BackoffFile::load(path.clone()));
+        assert!(backer.get_retry_until().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    proptest! {
+        #[test]
+        fn any_header_is_handled(value in ".*") {
+            let _ = BackerOff::new().parse_maybe_set(&value);
+        }
+
+        #[test]
+        fn seconds_are_capped(secs: u64) {
+            let clock = MockClock::new();
+            let backer = backer(&clock);
+            prop_assert!(backer.parse_maybe_set(&secs.to_string()).is_ok());
+            prop_assert_eq!(
+                backer.get_retry_until(),
+                Some(clock.now() + Duration::from_secs(secs).min(MAX_BACKOFF_TIME))
+            );
+        }
+
+        #[test]
+        fn http_dates_round_trip(offset in -1_000_000i64..1_000_000) {
+            let clock = MockClock::new();
+            let now = clock.system_now();
+            let date = if offset >= 0 {
+                now + Duration::from_secs(offset as u64)
+            } else {
+                now - Duration::from_secs(offset.unsigned_abs())
+            };
+            let res = backer(&clock).parse_maybe_set(&fmt_http_date(date));
+            if offset >= 0 {
+                prop_assert!(res.is_ok());
+            } else {
+                prop_assert!(matches!(res, Err(Error::FromPast)));
+            }
+        }
+    }
 }
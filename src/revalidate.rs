@@ -0,0 +1,105 @@
+//! Stale-while-revalidate for the route and search caches, so popular queries don't wait on
+//! upstream just because their entry expired a moment ago.
+//!
+//! Entries up to `--max-stale-secs` past their freshness are answered straight away, marked with
+//! [STALE_RESULT](crate::degraded::STALE_RESULT) and an `Age`, and refreshed in the background.
+//! Refreshes get a small budget of their own ([BACKGROUND_SLOTS] at once), and one refresh per
+//! key. Anything over budget is simply served stale; the next request past it tries again.
+use crate::workers;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::time::Duration;
+
+pub const DEFAULT_MAX_STALE: Duration = Duration::from_secs(60);
+/// Refreshes in flight at once. Requests come first, so this stays small
+const BACKGROUND_SLOTS: usize = 2;
+
+/// Cheap to clone; clones share the budget
+#[derive(Debug, Clone)]
+pub struct Revalidator {
+    max_stale: Duration,
+    slots: Arc<Semaphore>,
+    /// Keys being refreshed right now
+    pending: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl Revalidator {
+    /// Zero `max_stale` turns it off
+    pub fn new(max_stale: Duration) -> Self {
+        Revalidator {
+            max_stale,
+            slots: Arc::new(Semaphore::new(workers::slots(BACKGROUND_SLOTS))),
+            pending: Default::default(),
+        }
+    }
+
+    /// Whether an entry `age` old, fresh for `ttl`, may be answered with while it's refreshed
+    pub fn servable(&self, age: Duration, ttl: Duration) -> bool {
+        age < ttl + self.max_stale
+    }
+
+    /// Runs `refresh` in the background, unless `key` is already being refreshed or the budget's
+    /// spent. Failures are `refresh`'s to log
+    pub fn spawn<F>(&self, key: u64, refresh: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+            tracing::debug!(key, "no budget to revalidate, serving stale");
+            return;
+        };
+        if !self
+            .pending
+            .lock()
+            .expect("pending lock poisoned")
+            .insert(key)
+        {
+            return;
+        }
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            refresh.await;
+            pending.lock().expect("pending lock poisoned").remove(&key);
+            drop(slot);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn one_refresh_per_key_within_budget() {
+        let revalidator = Revalidator::new(DEFAULT_MAX_STALE);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let (release, hold) = tokio::sync::watch::channel(false);
+        let refresh = || {
+            let (ran, mut hold) = (ran.clone(), hold.clone());
+            async move {
+                let _ = hold.wait_for(|go| *go).await;
+                ran.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        revalidator.spawn(1, refresh());
+        // Same key, already underway
+        revalidator.spawn(1, refresh());
+        revalidator.spawn(2, refresh());
+        // Over budget
+        revalidator.spawn(3, refresh());
+        release.send(true).unwrap();
+        while revalidator.slots.available_permits() < BACKGROUND_SLOTS {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+        assert!(revalidator.pending.lock().unwrap().is_empty());
+
+        let ttl = Duration::from_secs(300);
+        assert!(revalidator.servable(ttl + Duration::from_secs(59), ttl));
+        assert!(!revalidator.servable(ttl + DEFAULT_MAX_STALE, ttl));
+        assert!(!Revalidator::new(Duration::ZERO).servable(ttl, ttl));
+    }
+}
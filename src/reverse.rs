@@ -0,0 +1,129 @@
+//! Picks the answer to a reverse lookup out of the places Photon finds nearby, at the
+//! [Granularity] the app asked for.
+//!
+//! Photon lists nearby places closest first, each with a `type` and its address parts. A place of
+//! the right type wins. Failing that, the closest place's address usually names the street,
+//! district, or city it's in. Failing that too, the next coarser granularity is tried.
+use crate::geojson_ext::{self, ParseMode};
+use crate::photon::PhotonProperties;
+use crate::Result;
+use flipmap_api_types::{Granularity, ReversePlace};
+use geojson::Feature;
+
+/// Nearby places asked of Photon, enough to usually include a street and a POI
+pub const NEARBY: u8 = 10;
+
+const ALL: [Granularity; 4] = [
+    Granularity::Poi,
+    Granularity::Street,
+    Granularity::Locality,
+    Granularity::City,
+];
+
+/// What a Photon place is, if it's anything we answer with
+fn granularity_of(props: &PhotonProperties) -> Option<Granularity> {
+    match props.kind.as_deref()? {
+        "street" => Some(Granularity::Street),
+        "locality" | "district" => Some(Granularity::Locality),
+        "city" => Some(Granularity::City),
+        // Named houses are buildings and businesses. Unnamed ones are just addresses
+        "house" | "other" if props.name.is_some() => Some(Granularity::Poi),
+        _ => None,
+    }
+}
+
+/// The part of `props`'s address at `granularity`
+fn address_part(props: &PhotonProperties, granularity: Granularity) -> Option<&String> {
+    match granularity {
+        Granularity::Poi => None,
+        Granularity::Street => props.street.as_ref(),
+        Granularity::Locality => props.locality.as_ref().or(props.district.as_ref()),
+        Granularity::City => props.city.as_ref(),
+    }
+}
+
+/// The best answer at `wanted` or coarser, if any
+pub fn pick(
+    features: &[Feature],
+    wanted: Granularity,
+    mode: ParseMode,
+) -> Result<Option<ReversePlace>> {
+    let places = features
+        .iter()
+        .map(|f| {
+            Ok((
+                geojson_ext::extract_point(f)?,
+                PhotonProperties::of(f, mode)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for granularity in ALL.into_iter().filter(|g| *g >= wanted) {
+        let found = places
+            .iter()
+            .find(|(_, props)| granularity_of(props) == Some(granularity))
+            .and_then(|(point, props)| Some((point, props.name.as_ref()?)))
+            .or_else(|| {
+                let (point, props) = places.first()?;
+                Some((point, address_part(props, granularity)?))
+            });
+        if let Some((point, name)) = found {
+            if granularity != wanted {
+                tracing::debug!(?wanted, ?granularity, "reverse lookup fell back");
+            }
+            return Ok(Some(ReversePlace {
+                lat: point[1],
+                lon: point[0],
+                name: name.clone(),
+                granularity,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::collection;
+    use serde_json::json;
+
+    fn nearby() -> Vec<Feature> {
+        let place = |lon: f64, props: serde_json::Value| {
+            json!({
+                "type": "Feature",
+                "properties": props,
+                "geometry": { "type": "Point", "coordinates": [lon, 44.56] }
+            })
+        };
+        collection(json!({
+            "type": "FeatureCollection",
+            "features": [
+                place(-123.1, json!({ "type": "house", "housenumber": "230",
+                    "street": "SW 2nd St", "city": "Corvallis" })),
+                place(-123.2, json!({ "type": "house", "name": "Downward Dog",
+                    "street": "SW 2nd St", "city": "Corvallis" })),
+                place(-123.3, json!({ "type": "street", "name": "SW 2nd Street" })),
+            ]
+        }))
+        .features
+    }
+
+    #[test]
+    fn picks_by_granularity() {
+        let at = |g| pick(&nearby(), g, ParseMode::Lenient).unwrap().unwrap();
+        assert_eq!(at(Granularity::Poi).name, "Downward Dog");
+        assert_eq!(at(Granularity::Street).name, "SW 2nd Street");
+        assert_eq!(at(Granularity::Street).lon, -123.3);
+        // From the closest place's address
+        let city = at(Granularity::City);
+        assert_eq!((city.name.as_str(), city.lon), ("Corvallis", -123.1));
+
+        // No district anywhere, so the city it is
+        let locality = at(Granularity::Locality);
+        assert_eq!(locality.granularity, Granularity::City);
+        assert_eq!(
+            pick(&[], Granularity::Poi, ParseMode::Lenient).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Swapping in a new ORS key while running, from `PUT /admin/ors_key`.
+//!
+//! The requester is rebuilt around the new key and swapped in behind an [ArcSwap]. Photon limits
+//! and backoffs carry over (see [ExternalRequesterBuilder::rebuild]), so a rotation isn't a
+//! chance to hammer anyone. Calls already underway finish on the old requester and key.
+use crate::ors::OrsRoute;
+use crate::requester::{
+    ExternalApi, ExternalRequester, ExternalRequesterBuilder, Limits, OpenRouteRequest,
+    PhotonGeocodeRequest, PhotonRevGeocodeRequest,
+};
+use crate::Result;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use axum::{extract::State, http::StatusCode, Json};
+use geojson::FeatureCollection;
+use secrecy::SecretString;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// An [ExternalRequester] whose ORS key can be changed
+#[derive(Debug)]
+pub struct Rotating {
+    /// What the current requester was built from, bar the key
+    builder: ExternalRequesterBuilder,
+    current: ArcSwap<ExternalRequester>,
+}
+
+impl Rotating {
+    pub fn new(builder: ExternalRequesterBuilder) -> Self {
+        Rotating {
+            current: ArcSwap::from_pointee(builder.clone().build()),
+            builder,
+        }
+    }
+
+    /// See [ExternalRequester::spawn_window_reports]
+    pub fn spawn_window_reports(&self) {
+        self.current.load().spawn_window_reports();
+    }
+
+    /// See [ExternalRequester::keepalive]
+    pub async fn keepalive(&self) {
+        self.current.load_full().keepalive().await;
+    }
+
+    /// Rebuilds the requester around `key` and swaps it in
+    pub fn rotate(&self, key: SecretString) {
+        let previous = self.current.load();
+        let next = self.builder.clone().with_ors_key(key).rebuild(&previous);
+        self.current.store(Arc::new(next));
+        tracing::warn!("ORS key rotated");
+    }
+}
+
+#[async_trait]
+impl ExternalApi for Rotating {
+    async fn ors_send(&self, req: &OpenRouteRequest) -> Result<FeatureCollection> {
+        self.current.load_full().ors_send(req).await
+    }
+
+    async fn ors_route(&self, req: &OpenRouteRequest) -> Result<OrsRoute> {
+        self.current.load_full().ors_route(req).await
+    }
+
+    async fn photon_reverse_send(
+        &self,
+        coord: &PhotonRevGeocodeRequest,
+    ) -> Result<FeatureCollection> {
+        self.current.load_full().photon_reverse_send(coord).await
+    }
+
+    async fn photon_send(&self, req: &PhotonGeocodeRequest) -> Result<FeatureCollection> {
+        self.current.load_full().photon_send(req).await
+    }
+
+    fn limits(&self) -> Limits {
+        self.current.load().limits()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewKey {
+    key: String,
+}
+
+/// `PUT /admin/ors_key` with `{"key": "..."}`. 204 once it's in use for new calls
+pub async fn put_key(
+    State(rotating): State<Arc<Rotating>>,
+    Json(body): Json<NewKey>,
+) -> std::result::Result<StatusCode, (StatusCode, &'static str)> {
+    if body.key.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "empty key"));
+    }
+    rotating.rotate(body.key.into());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requester::{Provider, ORS_DIRECTIONS_PATH};
+    use crate::test_utils::{MockClock, SHORT_WAIT};
+    use crate::vcr::fixture;
+    use httpmock::prelude::*;
+
+    #[tokio::test]
+    async fn keeps_backoff_and_uses_new_key() {
+        let server = MockServer::start_async().await;
+        let old = server
+            .mock_async(|when, then| {
+                when.path(ORS_DIRECTIONS_PATH)
+                    .header("Authorization", "old");
+                then.status(429)
+                    .header("Retry-After", SHORT_WAIT.as_secs().to_string());
+            })
+            .await;
+        let new = server
+            .mock_async(|when, then| {
+                when.path(ORS_DIRECTIONS_PATH)
+                    .header("Authorization", "new");
+                then.status(200)
+                    .header("Content-Type", "application/json")
+                    .body(fixture("ors_directions"));
+            })
+            .await;
+        let clock = MockClock::new();
+        let base: reqwest::Url = server.base_url().parse().unwrap();
+        let rotating = Rotating::new(
+            ExternalRequesterBuilder::new(base.clone(), base, SecretString::from("old"))
+                .with_clock(clock.clone()),
+        );
+        let request = OpenRouteRequest {
+            coordinates: vec![vec![0.0, 0.0], vec![1.0, 1.0]],
+            instructions: false,
+            bearings: None,
+            profile: Default::default(),
+            extra_info: false,
+            elevation: false,
+        };
+
+        assert!(rotating.ors_send(&request).await.is_err());
+        rotating.rotate(SecretString::from("new"));
+        // Still backing off, so nothing is sent
+        assert!(rotating.ors_send(&request).await.is_err());
+        assert!(rotating.limits().backing_off[&Provider::OpenRouteService] > 0);
+        clock.advance(SHORT_WAIT);
+        assert!(rotating.ors_send(&request).await.is_ok());
+        assert_eq!((old.hits_async().await, new.hits_async().await), (1, 1));
+    }
+}
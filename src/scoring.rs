@@ -0,0 +1,83 @@
+//! How confident we are that a search result is what the user meant, from 0 to 1.
+//!
+//! Blends how closely the name matches the query (Jaro-Winkler, so typos and unfinished words
+//! still score well), where Photon ranked it, and how far it is from the bias point if there is
+//! one. A single
+//! result scoring above [CONFIDENT] is safe for the app to pick without asking.
+use crate::geo;
+
+/// Scores at or above this are a confident match
+pub const CONFIDENT: f64 = 0.85;
+
+const NAME_WEIGHT: f64 = 0.5;
+const RANK_WEIGHT: f64 = 0.3;
+const DISTANCE_WEIGHT: f64 = 0.2;
+/// Each place down Photon's list keeps this much of the one before's rank score
+const RANK_DECAY: f64 = 0.85;
+/// Distance at which the distance score has halved
+const HALF_DISTANCE_M: f64 = 10_000.0;
+
+/// `rank` is the place's position in Photon's answer, from 0. Points are `(lat, lon)`
+pub fn score(
+    query: &str,
+    name: &str,
+    rank: usize,
+    bias: Option<(f64, f64)>,
+    place: (f64, f64),
+) -> f64 {
+    let name_score = name_similarity(query, name);
+    let rank_score = RANK_DECAY.powi(rank.min(i32::MAX as usize) as i32);
+    let score = NAME_WEIGHT * name_score + RANK_WEIGHT * rank_score;
+    let score = match bias {
+        Some(bias) => {
+            let distance_score = 1.0 / (1.0 + geo::haversine_m(bias, place) / HALF_DISTANCE_M);
+            score + DISTANCE_WEIGHT * distance_score
+        }
+        // Nothing to be close to
+        None => score / (NAME_WEIGHT + RANK_WEIGHT),
+    };
+    // More digits would just be noise to the app
+    (score * 1000.0).round() / 1000.0
+}
+
+/// Jaro-Winkler between the query and the name, or the best-matching run of the name's words as
+/// long as the query, so "dog" still matches "Downward Dog" well
+fn name_similarity(query: &str, name: &str) -> f64 {
+    let query = query.trim().to_lowercase();
+    let name = name.to_lowercase();
+    let words: Vec<&str> = name.split_whitespace().collect();
+    let span = query
+        .split_whitespace()
+        .count()
+        .clamp(1, words.len().max(1));
+    let best_run = words
+        .windows(span)
+        .map(|run| strsim::jaro_winkler(&query, &run.join(" ")))
+        .fold(0.0, f64::max);
+    strsim::jaro_winkler(&query, &name).max(best_run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HERE: (f64, f64) = (44.5646, -123.2620);
+
+    #[test]
+    fn exact_nearby_first_result_is_confident() {
+        assert!(score("Downward Dog", "Downward Dog", 0, Some(HERE), HERE) >= CONFIDENT);
+        assert!(score("downward do", "Downward Dog", 0, Some(HERE), HERE) >= CONFIDENT);
+        assert!(score("dog", "Downward Dog", 0, Some(HERE), HERE) >= CONFIDENT);
+    }
+
+    #[test]
+    fn worse_on_each_count() {
+        let best = score("Downward Dog", "Downward Dog", 0, Some(HERE), HERE);
+        assert!(score("Downward Dog", "Unknown", 0, Some(HERE), HERE) < best);
+        assert!(score("Downward Dog", "Downward Dog", 5, Some(HERE), HERE) < best);
+        let far = (45.5152, -122.6784);
+        assert!(score("Downward Dog", "Downward Dog", 0, Some(HERE), far) < best);
+        assert!(score("Downward Dog", "Walmart", 9, Some(HERE), far) < 0.5);
+        assert!(score("Downward Dog", "Downward Dog", 0, None, far) >= CONFIDENT);
+    }
+}
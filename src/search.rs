@@ -0,0 +1,192 @@
+//! Per-deployment defaults for location search, so an instance set up for one city answers
+//! sensibly even when the app doesn't say where it is.
+//!
+//! The bias point stands in for a missing `lat`/`lon`. The bounding box goes to Photon with every
+//! search. Photon can't filter by country, so results from elsewhere are dropped afterwards.
+use crate::photon::PhotonProperties;
+use crate::requester::PhotonGeocodeRequest;
+
+/// `<lat>,<lon>` on the command line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl std::str::FromStr for Point {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((lat, lon)) = s.split_once(',') else {
+            return Err("expected <lat>,<lon>".to_owned());
+        };
+        let parse = |c: &str| c.trim().parse::<f64>().map_err(|e| e.to_string());
+        let (lat, lon) = (parse(lat)?, parse(lon)?);
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(format!("{lat},{lon} isn't on Earth"));
+        }
+        Ok(Point { lat, lon })
+    }
+}
+
+/// `<min_lon>,<min_lat>,<max_lon>,<max_lat>`, the order Photon takes it in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bbox {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl std::str::FromStr for Bbox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords: Vec<f64> = s
+            .split(',')
+            .map(|c| c.trim().parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("bbox should be numbers: {e}"))?;
+        let [min_lon, min_lat, max_lon, max_lat] = coords[..] else {
+            return Err("expected <min_lon>,<min_lat>,<max_lon>,<max_lat>".to_owned());
+        };
+        if min_lon >= max_lon || min_lat >= max_lat {
+            return Err("bbox minimums should be below its maximums".to_owned());
+        }
+        Ok(Bbox {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        })
+    }
+}
+
+impl std::fmt::Display for Bbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Bbox {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        } = self;
+        write!(f, "{min_lon},{min_lat},{max_lon},{max_lat}")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SearchDefaults {
+    /// Used when the client doesn't send a position
+    pub bias: Option<Point>,
+    /// Sent with every search
+    pub bbox: Option<Bbox>,
+    /// ISO 3166-1 alpha-2, upper case. Results elsewhere are dropped
+    pub country: Option<String>,
+}
+
+impl SearchDefaults {
+    /// The client's position if it sent one, or the default
+    pub fn bias(&self, lat: Option<f64>, lon: Option<f64>) -> Option<Point> {
+        match (lat, lon) {
+            (Some(lat), Some(lon)) => Some(Point { lat, lon }),
+            _ => self.bias,
+        }
+    }
+
+    /// The Photon search for `query` with these defaults filled in. `zoom` only means anything
+    /// with a `bias`
+    pub fn request(
+        &self,
+        limit: u8,
+        query: String,
+        bias: Option<Point>,
+        zoom: Option<u8>,
+    ) -> PhotonGeocodeRequest {
+        let mut req = PhotonGeocodeRequest::new(limit, query);
+        if let Some(bias) = bias {
+            req = req.with_location_bias(bias.lat, bias.lon);
+            if let Some(zoom) = zoom {
+                req = req.with_zoom(zoom);
+            }
+        }
+        if let Some(bbox) = self.bbox {
+            req = req.with_bbox(bbox);
+        }
+        req
+    }
+
+    /// Whether a place belongs in the results
+    pub fn allows(&self, props: &PhotonProperties) -> bool {
+        match (&self.country, &props.countrycode) {
+            (Some(wanted), Some(found)) => wanted.eq_ignore_ascii_case(found),
+            // Nowhere in particular is better than dropping it
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "44.56, -123.27".parse(),
+            Ok(Point {
+                lat: 44.56,
+                lon: -123.27
+            })
+        );
+        assert!("-123.27,44.56,".parse::<Point>().is_err());
+        assert!("-123.27,144.56".parse::<Point>().is_err());
+
+        let bbox: Bbox = "-123.4,44.4,-123.1,44.7".parse().unwrap();
+        assert_eq!(bbox.to_string(), "-123.4,44.4,-123.1,44.7");
+        assert!("-123.1,44.4,-123.4,44.7".parse::<Bbox>().is_err());
+        assert!("-123.4,44.4,-123.1".parse::<Bbox>().is_err());
+    }
+
+    #[test]
+    fn defaults_fill_in() {
+        let here = Point {
+            lat: 44.56,
+            lon: -123.27,
+        };
+        let defaults = SearchDefaults {
+            bias: Some(here),
+            bbox: Some("-123.4,44.4,-123.1,44.7".parse().unwrap()),
+            country: None,
+        };
+        assert_eq!(defaults.bias(None, None), Some(here));
+        assert_eq!(defaults.bias(Some(1.0), Some(2.0)).unwrap().lon, 2.0);
+        assert_eq!(SearchDefaults::default().bias(None, None), None);
+
+        let req = defaults.request(5, "dog".to_owned(), Some(here), Some(12));
+        let req = serde_json::to_value(req).unwrap();
+        assert_eq!(req["bbox"], "-123.4,44.4,-123.1,44.7");
+        assert_eq!(req["lat"], 44.56);
+        assert_eq!(req["zoom"], 12);
+        let req = SearchDefaults::default().request(5, "dog".to_owned(), None, Some(12));
+        assert_eq!(
+            serde_json::to_value(req).unwrap()["zoom"],
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn country_filter() {
+        let defaults = SearchDefaults {
+            country: Some("US".to_owned()),
+            ..Default::default()
+        };
+        let place = |countrycode: Option<&str>| PhotonProperties {
+            countrycode: countrycode.map(Into::into),
+            ..Default::default()
+        };
+        assert!(defaults.allows(&place(Some("us"))));
+        assert!(!defaults.allows(&place(Some("CA"))));
+        assert!(defaults.allows(&place(None)));
+        assert!(SearchDefaults::default().allows(&place(Some("CA"))));
+    }
+}
@@ -0,0 +1,364 @@
+//! Shadow traffic, for trying out another routing engine before switching to it.
+//!
+//! With `--shadow-osrm`, a share of the routes fetched from ORS (`--shadow-percent`) are also
+//! asked of an OSRM-compatible engine in the background, and the two compared: distance, duration,
+//! and how much of each route runs along the other. Nothing from the shadow reaches the app.
+//! Differences past [DIVERGENT] are logged at info and counted in `shadow_divergent_total`; every
+//! attempt is counted in `shadow_routes_total` by outcome.
+//!
+//! The shadow gets its own daily quota (`--shadow-daily-cap`) and a couple of calls at a time, so
+//! a slow or strict engine never holds up real requests or runs up a bill.
+use crate::geo;
+use crate::metrics;
+use crate::ratelimit::RateLimit;
+use crate::workers;
+use crate::RouteRequest;
+use reqwest::Url;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::time::Duration;
+
+pub const DEFAULT_PERCENT: f64 = 1.0;
+pub const DEFAULT_DAILY_CAP: u32 = 1000;
+/// Shadow calls in flight at once. Anything past it is skipped
+const SLOTS: usize = 2;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Positions of a route closer than this to the other count as shared
+const SHARED_M: f64 = 25.0;
+/// Positions of each route looked at for overlap, at most. Evenly spread
+const OVERLAP_SAMPLES: usize = 200;
+
+/// Past these, the routes count as different: relative distance and duration differences, and
+/// overlap
+pub const DIVERGENT: Comparison = Comparison {
+    distance_delta: 0.1,
+    duration_delta: 0.2,
+    overlap: 0.8,
+};
+
+/// What a route came to, from either engine
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub distance_m: f64,
+    pub duration_s: f64,
+    /// Flattened `[lon, lat, ...]`, as in [crate::RouteResponse]
+    pub route: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    /// Shadow's distance over ORS's, minus one
+    pub distance_delta: f64,
+    pub duration_delta: f64,
+    /// Share of either route's positions near the other, whichever's less
+    pub overlap: f64,
+}
+
+impl Comparison {
+    pub fn of(primary: &Summary, shadow: &Summary) -> Self {
+        let delta = |a: f64, b: f64| if a > 0.0 { b / a - 1.0 } else { 0.0 };
+        Comparison {
+            distance_delta: delta(primary.distance_m, shadow.distance_m),
+            duration_delta: delta(primary.duration_s, shadow.duration_s),
+            overlap: overlap(&primary.route, &shadow.route)
+                .min(overlap(&shadow.route, &primary.route)),
+        }
+    }
+
+    /// Which measures are past [DIVERGENT]
+    pub fn divergent(&self) -> Vec<&'static str> {
+        [
+            (
+                "distance",
+                self.distance_delta.abs() > DIVERGENT.distance_delta,
+            ),
+            (
+                "duration",
+                self.duration_delta.abs() > DIVERGENT.duration_delta,
+            ),
+            ("overlap", self.overlap < DIVERGENT.overlap),
+        ]
+        .into_iter()
+        .filter_map(|(measure, past)| past.then_some(measure))
+        .collect()
+    }
+}
+
+/// Share of `line`'s positions within [SHARED_M] of `other`
+fn overlap(line: &[f64], other: &[f64]) -> f64 {
+    let positions: Vec<_> = line.chunks_exact(2).collect();
+    if positions.is_empty() {
+        return 0.0;
+    }
+    let step = positions.len().div_ceil(OVERLAP_SAMPLES);
+    let sampled: Vec<_> = positions.iter().step_by(step).collect();
+    let shared = sampled
+        .iter()
+        .filter(|p| {
+            geo::distance_to_flat_line_m((p[1], p[0]), other).is_some_and(|(d, _)| d <= SHARED_M)
+        })
+        .count();
+    shared as f64 / sampled.len() as f64
+}
+
+/// Cheap to clone; clones share the quota and sampling
+#[derive(Debug, Clone)]
+pub struct Shadow {
+    client: reqwest::Client,
+    base: Url,
+    percent: f64,
+    /// Routes fetched so far, for sampling
+    seen: Arc<AtomicU64>,
+    quota: Arc<RateLimit>,
+    slots: Arc<Semaphore>,
+}
+
+impl Shadow {
+    /// `percent` of routes go to the OSRM-compatible engine at `base`, up to `daily_cap` a day
+    pub fn new(base: Url, percent: f64, daily_cap: u32, user_agent: &str) -> Self {
+        tracing::info!(
+            "shadowing {percent}% of routes to {}",
+            base.origin().ascii_serialization()
+        );
+        Shadow {
+            client: reqwest::Client::builder()
+                .user_agent(user_agent)
+                .timeout(TIMEOUT)
+                .build()
+                .expect("couldn't build shadow client"),
+            base,
+            percent: percent.clamp(0.0, 100.0),
+            seen: Default::default(),
+            quota: Arc::new(RateLimit::new(
+                daily_cap,
+                Duration::from_secs(86400),
+                "Shadow Daily".to_owned(),
+            )),
+            slots: Arc::new(Semaphore::new(workers::slots(SLOTS))),
+        }
+    }
+
+    /// Whether this route's turn has come. Spreads `percent` evenly rather than at random
+    fn sampled(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        let share = self.percent / 100.0;
+        ((n + 1.0) * share).floor() > (n * share).floor()
+    }
+
+    /// Compares `primary`, just fetched from ORS for `params`, with the shadow's route, if it's
+    /// this one's turn. In the background; the outcome only goes to logs and metrics
+    pub fn compare(&self, params: &RouteRequest, primary: Summary) {
+        if !self.sampled() {
+            return;
+        }
+        let outcome = |outcome| {
+            metrics::registry().inc_counter("shadow_routes_total", &[("outcome", outcome)], 1)
+        };
+        let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+            outcome("busy");
+            return;
+        };
+        if self.quota.try_consume(1).is_err() {
+            outcome("over_quota");
+            return;
+        }
+        let (shadow, params) = (self.clone(), params.clone());
+        tokio::spawn(async move {
+            let fetched = shadow.fetch(&params).await;
+            drop(slot);
+            let theirs = match fetched {
+                Ok(theirs) => theirs,
+                Err(e) => {
+                    tracing::debug!("shadow route failed: {e}");
+                    outcome("failed");
+                    return;
+                }
+            };
+            outcome("compared");
+            let comparison = Comparison::of(&primary, &theirs);
+            let divergent = comparison.divergent();
+            for measure in &divergent {
+                metrics::registry().inc_counter(
+                    "shadow_divergent_total",
+                    &[("measure", measure)],
+                    1,
+                );
+            }
+            let Comparison {
+                distance_delta,
+                duration_delta,
+                overlap,
+            } = comparison;
+            if divergent.is_empty() {
+                tracing::debug!(
+                    distance_delta,
+                    duration_delta,
+                    overlap,
+                    "shadow route agrees"
+                );
+            } else {
+                tracing::info!(
+                    distance_delta,
+                    duration_delta,
+                    overlap,
+                    divergent = ?divergent,
+                    "shadow route differs"
+                );
+            }
+        });
+    }
+
+    async fn fetch(&self, params: &RouteRequest) -> Result<Summary, String> {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .map_err(|_| "shadow base can't have a path".to_owned())?
+            .pop_if_empty()
+            .extend([
+                "route",
+                "v1",
+                "driving",
+                &format!(
+                    "{},{};{},{}",
+                    params.src_lon, params.src_lat, params.dst_lon, params.dst_lat
+                ),
+            ]);
+        url.query_pairs_mut()
+            .append_pair("overview", "full")
+            .append_pair("geometries", "geojson");
+        let answer: OsrmAnswer = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        answer.summary()
+    }
+}
+
+/// The little of an OSRM `/route/v1` answer we look at
+#[derive(Deserialize, Debug)]
+struct OsrmAnswer {
+    code: String,
+    #[serde(default)]
+    routes: Vec<OsrmRoute>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OsrmRoute {
+    distance: f64,
+    duration: f64,
+    geometry: OsrmGeometry,
+}
+
+#[derive(Deserialize, Debug)]
+struct OsrmGeometry {
+    coordinates: Vec<[f64; 2]>,
+}
+
+impl OsrmAnswer {
+    fn summary(self) -> Result<Summary, String> {
+        if self.code != "Ok" {
+            return Err(format!("shadow answered {}", self.code));
+        }
+        let route = self
+            .routes
+            .into_iter()
+            .next()
+            .ok_or("shadow found no route")?;
+        Ok(Summary {
+            distance_m: route.distance,
+            duration_s: route.duration,
+            route: route.geometry.coordinates.into_iter().flatten().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(distance_m: f64, duration_s: f64, route: &[f64]) -> Summary {
+        Summary {
+            distance_m,
+            duration_s,
+            route: route.to_vec(),
+        }
+    }
+
+    #[test]
+    fn compares_routes() {
+        let line = [0.0, 0.0, 0.001, 0.0, 0.002, 0.0, 0.003, 0.0];
+        let primary = summary(330.0, 60.0, &line);
+        let same = Comparison::of(&primary, &summary(340.0, 66.0, &line));
+        assert!(same.divergent().is_empty(), "{same:?}");
+        assert_eq!(same.overlap, 1.0);
+
+        // Half of it a street over
+        let detour = [0.0, 0.0, 0.001, 0.0, 0.002, 0.001, 0.003, 0.001];
+        let other = Comparison::of(&primary, &summary(500.0, 60.0, &detour));
+        assert_eq!(other.divergent(), ["distance", "overlap"]);
+        assert_eq!(other.overlap, 0.5);
+    }
+
+    #[test]
+    fn samples_evenly() {
+        let shadow = Shadow::new("http://osrm.test".parse().unwrap(), 25.0, 10, "test");
+        let picked = (0..100).filter(|_| shadow.sampled()).count();
+        assert_eq!(picked, 25);
+        let none = Shadow::new("http://osrm.test".parse().unwrap(), 0.0, 10, "test");
+        assert!(!(0..100).any(|_| none.sampled()));
+    }
+
+    #[tokio::test]
+    async fn asks_osrm_route_service() {
+        use httpmock::prelude::*;
+        let server = MockServer::start_async().await;
+        let mock = server
+            .mock_async(|when, then| {
+                when.method(GET)
+                    .path("/route/v1/driving/2,1;4,3")
+                    .query_param("geometries", "geojson");
+                then.status(200).json_body(serde_json::json!({
+                    "code": "Ok",
+                    "routes": [{"distance": 1.0, "duration": 2.0,
+                        "geometry": {"type": "LineString", "coordinates": [[2.0, 1.0], [4.0, 3.0]]}}]
+                }));
+            })
+            .await;
+        let shadow = Shadow::new(server.base_url().parse().unwrap(), 100.0, 10, "test");
+        let params = RouteRequest {
+            src_lat: 1.0,
+            src_lon: 2.0,
+            dst_lat: 3.0,
+            dst_lon: 4.0,
+            heading: None,
+            annotations: false,
+            borders: false,
+            elevation: false,
+        };
+        assert_eq!(shadow.fetch(&params).await.unwrap().duration_s, 2.0);
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn reads_osrm() {
+        let answer: OsrmAnswer = serde_json::from_str(
+            r#"{"code": "Ok", "routes": [{"distance": 120.5, "duration": 30.1, "weight": 30.1,
+                "geometry": {"type": "LineString", "coordinates": [[13.4, 52.5], [13.41, 52.5]]}}],
+                "waypoints": []}"#,
+        )
+        .unwrap();
+        let summary = answer.summary().unwrap();
+        assert_eq!(summary.route, [13.4, 52.5, 13.41, 52.5]);
+        assert_eq!(summary.distance_m, 120.5);
+
+        let none: OsrmAnswer = serde_json::from_str(r#"{"code": "NoRoute"}"#).unwrap();
+        assert!(none.summary().is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Sanity checks upstream [FeatureCollection]s before handlers pick them apart.
+//!
+//! Handlers used to bail on the first thing they tripped over with a one-line message. This walks
+//! the whole collection and reports everything wrong with it at once, which is what you want when
+//! a provider quietly changes what it sends.
+use crate::error::RouteError;
+use geojson::{FeatureCollection, Value};
+use std::fmt;
+
+/// Don't let one absurd response flood the logs
+const MAX_PROBLEMS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeometryKind {
+    Point,
+    LineString,
+}
+
+impl GeometryKind {
+    fn name(&self) -> &'static str {
+        match self {
+            GeometryKind::Point => "Point",
+            GeometryKind::LineString => "LineString",
+        }
+    }
+}
+
+/// What a handler needs from a collection before it'll touch it
+#[derive(Debug, Clone, Copy)]
+pub struct Expectation {
+    pub geometry: GeometryKind,
+    /// Photon finding nothing is a legit answer. ORS finding no route is not
+    pub non_empty: bool,
+}
+
+/// One thing wrong with a collection. Feature and position indices are zero-based.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    NoFeatures,
+    MissingGeometry {
+        feature: usize,
+    },
+    WrongGeometry {
+        feature: usize,
+        expected: &'static str,
+        found: &'static str,
+    },
+    ShortPosition {
+        feature: usize,
+        position: usize,
+        len: usize,
+    },
+    NonFinite {
+        feature: usize,
+        position: usize,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::NoFeatures => write!(f, "no features"),
+            Problem::MissingGeometry { feature } => write!(f, "feature {feature} has no geometry"),
+            Problem::WrongGeometry {
+                feature,
+                expected,
+                found,
+            } => write!(f, "feature {feature} is a {found}, expected {expected}"),
+            Problem::ShortPosition {
+                feature,
+                position,
+                len,
+            } => write!(
+                f,
+                "feature {feature} position {position} has {len} dimension(s)"
+            ),
+            Problem::NonFinite { feature, position } => write!(
+                f,
+                "feature {feature} position {position} has a non-finite coordinate"
+            ),
+        }
+    }
+}
+
+/// Everything found wrong with a collection, plus how many features it had for context
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnosis {
+    pub expected: GeometryKind,
+    pub feature_count: usize,
+    pub problems: Vec<Problem>,
+    /// Problems found past [MAX_PROBLEMS] that weren't kept
+    pub truncated: usize,
+}
+
+impl Diagnosis {
+    fn push(&mut self, problem: Problem) {
+        if self.problems.len() < MAX_PROBLEMS {
+            self.problems.push(problem);
+        } else {
+            self.truncated += 1;
+        }
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected {} features, got {} feature(s): ",
+            self.expected.name(),
+            self.feature_count
+        )?;
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{problem}")?;
+        }
+        if self.truncated > 0 {
+            write!(f, "; and {} more", self.truncated)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnosis {}
+
+impl From<Diagnosis> for RouteError {
+    fn from(diagnosis: Diagnosis) -> Self {
+        // Runs inside the request span, so the request id comes along for free
+        tracing::error!(
+            expected = diagnosis.expected.name(),
+            feature_count = diagnosis.feature_count,
+            problem_count = diagnosis.problems.len() + diagnosis.truncated,
+            "external API content failed shape check: {diagnosis}"
+        );
+        RouteError::ExternalAPIContent(Box::new(diagnosis))
+    }
+}
+
+/// Checks every feature of `fc` against `expect`. Returns all problems found, not just the first.
+pub fn diagnose(fc: &FeatureCollection, expect: Expectation) -> Result<(), Diagnosis> {
+    let mut diagnosis = Diagnosis {
+        expected: expect.geometry,
+        feature_count: fc.features.len(),
+        problems: vec![],
+        truncated: 0,
+    };
+    if expect.non_empty && fc.features.is_empty() {
+        diagnosis.push(Problem::NoFeatures);
+    }
+
+    for (feature, f) in fc.features.iter().enumerate() {
+        let Some(geometry) = f.geometry.as_ref() else {
+            diagnosis.push(Problem::MissingGeometry { feature });
+            continue;
+        };
+        let positions = match (&geometry.value, expect.geometry) {
+            (Value::Point(p), GeometryKind::Point) => std::slice::from_ref(p),
+            (Value::LineString(ps), GeometryKind::LineString) => ps.as_slice(),
+            (v, kind) => {
+                diagnosis.push(Problem::WrongGeometry {
+                    feature,
+                    expected: kind.name(),
+                    found: v.type_name(),
+                });
+                continue;
+            }
+        };
+        for (position, p) in positions.iter().enumerate() {
+            if p.len() < 2 {
+                diagnosis.push(Problem::ShortPosition {
+                    feature,
+                    position,
+                    len: p.len(),
+                });
+            } else if p.iter().any(|c| !c.is_finite()) {
+                diagnosis.push(Problem::NonFinite { feature, position });
+            }
+        }
+    }
+
+    if diagnosis.problems.is_empty() {
+        Ok(())
+    } else {
+        Err(diagnosis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geojson::{Feature, Geometry};
+
+    fn collection_of(values: Vec<Option<Value>>) -> FeatureCollection {
+        FeatureCollection {
+            bbox: None,
+            features: values
+                .into_iter()
+                .map(|v| Feature {
+                    geometry: v.map(Geometry::new),
+                    ..Default::default()
+                })
+                .collect(),
+            foreign_members: None,
+        }
+    }
+
+    const LINE: Expectation = Expectation {
+        geometry: GeometryKind::LineString,
+        non_empty: true,
+    };
+    const POINTS: Expectation = Expectation {
+        geometry: GeometryKind::Point,
+        non_empty: false,
+    };
+
+    #[test]
+    fn good_collections_pass() {
+        let line = collection_of(vec![Some(Value::LineString(vec![
+            vec![1.0, 2.0],
+            vec![3.0, 4.0],
+        ]))]);
+        assert!(diagnose(&line, LINE).is_ok());
+        assert!(diagnose(&collection_of(vec![]), POINTS).is_ok());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let fc = collection_of(vec![
+            None,
+            Some(Value::Point(vec![1.0, 2.0])),
+            Some(Value::LineString(vec![vec![1.0], vec![f64::NAN, 2.0]])),
+        ]);
+        let diagnosis = diagnose(&fc, LINE).unwrap_err();
+        assert_eq!(
+            diagnosis.problems,
+            vec![
+                Problem::MissingGeometry { feature: 0 },
+                Problem::WrongGeometry {
+                    feature: 1,
+                    expected: "LineString",
+                    found: "Point"
+                },
+                Problem::ShortPosition {
+                    feature: 2,
+                    position: 0,
+                    len: 1
+                },
+                Problem::NonFinite {
+                    feature: 2,
+                    position: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_route_is_a_problem() {
+        let diagnosis = diagnose(&collection_of(vec![]), LINE).unwrap_err();
+        assert_eq!(diagnosis.problems, vec![Problem::NoFeatures]);
+    }
+
+    #[test]
+    fn caps_problem_list() {
+        let fc = collection_of((0..25).map(|_| None).collect());
+        let diagnosis = diagnose(&fc, POINTS).unwrap_err();
+        assert_eq!(diagnosis.problems.len(), MAX_PROBLEMS);
+        assert_eq!(diagnosis.truncated, 15);
+        assert!(diagnosis.to_string().ends_with("and 15 more"));
+    }
+}
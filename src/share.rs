@@ -0,0 +1,65 @@
+//! Short links for sending trips and places to other people, from `POST /share`.
+//!
+//! Tokens and what they point at are kept by [TripStore](crate::trips::TripStore). This keeps
+//! anyone from filling it with links: each client (by [AccessLog](crate::access_log::AccessLog)
+//! pseudonym, so no IPs are kept) may only make so many per hour. Going over is a 429 until the
+//! hour is up.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+pub const DEFAULT_PER_HOUR: u32 = 20;
+const WINDOW: Duration = Duration::from_secs(3600);
+/// Past this many clients, ones whose window is over are forgotten
+const PRUNE_AT: usize = 10_000;
+
+/// Cheap to clone; clones share counts
+#[derive(Debug, Clone)]
+pub struct ShareLimiter {
+    per_hour: u32,
+    /// When each client's window started, and links made in it
+    clients: Arc<Mutex<HashMap<String, (Instant, u32)>>>,
+}
+
+impl ShareLimiter {
+    pub fn new(per_hour: u32) -> Self {
+        ShareLimiter {
+            per_hour,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Counts a link made by `client`. `Err` with when they can make more if they're over
+    pub fn try_create(&self, client: &str, now: Instant) -> Result<(), Instant> {
+        let mut clients = self.clients.lock().expect("share limiter lock poisoned");
+        if clients.len() >= PRUNE_AT {
+            clients.retain(|_, (start, _)| now - *start < WINDOW);
+        }
+        let (start, made) = clients.entry(client.to_owned()).or_insert((now, 0));
+        if now - *start >= WINDOW {
+            (*start, *made) = (now, 0);
+        }
+        if *made >= self.per_hour {
+            tracing::warn!(client, "share link limit reached");
+            return Err(*start + WINDOW);
+        }
+        *made += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_client_hourly() {
+        let limiter = ShareLimiter::new(2);
+        let now = Instant::now();
+        assert!(limiter.try_create("a", now).is_ok());
+        assert!(limiter.try_create("a", now).is_ok());
+        assert_eq!(limiter.try_create("a", now), Err(now + WINDOW));
+        assert!(limiter.try_create("b", now).is_ok());
+        assert!(limiter.try_create("a", now + WINDOW).is_ok());
+    }
+}
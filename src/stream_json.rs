@@ -0,0 +1,179 @@
+//! Deserializes upstream bodies as they arrive instead of buffering them first.
+//!
+//! [serde_json] only parses from a blocking [Read], so the parse runs on a blocking thread fed
+//! chunks over a short channel. Peak memory is then a few chunks plus whatever `T` keeps, rather
+//! than the whole body plus `T`.
+//!
+//! The start of the body is kept for error logs, since there's no full body to snippet.
+//!
+//! Bodies over a size limit are abandoned as soon as they cross it, here and in [read_all] for
+//! when the whole body is needed after all.
+use axum::body::Bytes;
+use serde::de::DeserializeOwned;
+use std::io::{self, Read};
+use tokio::sync::mpsc;
+
+/// Chunks in flight between the network and the parser
+const CHANNEL_CHUNKS: usize = 4;
+/// How much of the start of the body is kept when it doesn't parse
+pub const HEAD_BYTES: usize = 4096;
+
+#[derive(Debug)]
+pub enum Failure {
+    /// The body didn't arrive in full
+    Body(reqwest::Error),
+    /// The body is longer than the limit, going by Content-Length or what's arrived so far
+    TooLarge,
+    /// It arrived, but isn't a `T`
+    Json {
+        error: serde_json::Error,
+        /// Up to [HEAD_BYTES] from the start of the body
+        head: Vec<u8>,
+    },
+}
+
+/// Reads `resp`'s body into a `T` without holding all of it at once, giving up after `limit`
+/// bytes
+pub async fn from_response<T>(mut resp: reqwest::Response, limit: usize) -> Result<T, Failure>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    check_length(&resp, limit)?;
+    let (tx, rx) = mpsc::channel(CHANNEL_CHUNKS);
+    let parser = tokio::task::spawn_blocking(move || {
+        let mut reader = ChunkReader {
+            rx,
+            current: Bytes::new(),
+            head: Vec::new(),
+        };
+        let parsed = serde_json::from_reader(&mut reader);
+        (parsed, reader.head)
+    });
+
+    let mut failed = None;
+    let mut read = 0;
+    loop {
+        match resp.chunk().await {
+            Ok(Some(chunk)) if read + chunk.len() > limit => {
+                failed = Some(Failure::TooLarge);
+                break;
+            }
+            Ok(Some(chunk)) => {
+                read += chunk.len();
+                // The parser only hangs up early when it's already failed
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                failed = Some(Failure::Body(e));
+                break;
+            }
+        }
+    }
+    drop(tx);
+
+    let (parsed, head) = parser.await.expect("JSON parsing task panicked");
+    match (failed, parsed) {
+        (Some(failure), _) => Err(failure),
+        (None, Ok(value)) => Ok(value),
+        (None, Err(error)) => Err(Failure::Json { error, head }),
+    }
+}
+
+/// Reads `resp`'s whole body, giving up after `limit` bytes
+pub async fn read_all(mut resp: reqwest::Response, limit: usize) -> Result<Vec<u8>, Failure> {
+    check_length(&resp, limit)?;
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await.map_err(Failure::Body)? {
+        if body.len() + chunk.len() > limit {
+            return Err(Failure::TooLarge);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Turns away bodies that say up front they're too long
+fn check_length(resp: &reqwest::Response, limit: usize) -> Result<(), Failure> {
+    match resp.content_length() {
+        Some(len) if len > limit as u64 => Err(Failure::TooLarge),
+        _ => Ok(()),
+    }
+}
+
+/// [Read] over chunks from the channel. Ends when the sender is dropped
+struct ChunkReader {
+    rx: mpsc::Receiver<Bytes>,
+    current: Bytes,
+    head: Vec<u8>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    let room = HEAD_BYTES.saturating_sub(self.head.len());
+                    self.head.extend_from_slice(&chunk[..room.min(chunk.len())]);
+                    self.current = chunk;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{MockUpstream, Reply};
+
+    async fn fetch(reply: Reply) -> reqwest::Response {
+        let upstream = MockUpstream::start().await;
+        upstream.on("/", [reply]);
+        reqwest::get(upstream.base()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn parses_and_keeps_head() {
+        let big = format!("[{}1]", "1,".repeat(100_000));
+        let parsed: Vec<u32> = from_response(fetch(Reply::geojson(big)).await, usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(parsed.len(), 100_001);
+
+        let bad = format!("[{}\"x\"]", "1,".repeat(10_000));
+        let Err(Failure::Json { head, .. }) =
+            from_response::<Vec<u32>>(fetch(Reply::geojson(bad)).await, usize::MAX).await
+        else {
+            panic!("should fail on the string");
+        };
+        assert_eq!(head.len(), HEAD_BYTES);
+        assert!(head.starts_with(b"[1,1,"));
+    }
+
+    #[tokio::test]
+    async fn size_limit() {
+        let body = format!("[{}1]", "1,".repeat(1000));
+        let limit = body.len();
+        let reply = || Reply::geojson(body.clone());
+
+        assert!(read_all(fetch(reply()).await, limit).await.is_ok());
+        assert!(matches!(
+            read_all(fetch(reply()).await, limit - 1).await,
+            Err(Failure::TooLarge)
+        ));
+        assert!(from_response::<Vec<u32>>(fetch(reply()).await, limit)
+            .await
+            .is_ok());
+        assert!(matches!(
+            from_response::<Vec<u32>>(fetch(reply()).await, limit - 1).await,
+            Err(Failure::TooLarge)
+        ));
+    }
+}
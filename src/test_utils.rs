@@ -1,21 +1,117 @@
 //! Functions used in unit tests across modules.
+use crate::clock::Clock;
+use crate::requester::{
+    ExternalApi, OpenRouteRequest, PhotonGeocodeRequest, PhotonRevGeocodeRequest,
+};
+use crate::Result;
+use async_trait::async_trait;
+use geojson::FeatureCollection;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use tokio::time::{Duration, Instant};
 
-/// They say that monotonic clocks are monotonic. Duh. I say: why do two calls in my test code jump
-/// back hundreds of nanoseconds?
-///
-/// This function checks if two instants are equal *enough*
-pub fn timey_wime_check(a: Instant, b: Instant) -> bool {
-    // Not cool: In isolation, we just need a ~1000ns factor to compensate for Rust trying to
-    // take a monotonic clock read with higher res than is actually available causing jump back
-    //
-    // However, the time between these two instants being taken can be delayed further by noisy neighboring
-    // tests. How much further? Hopefully no more than 50ms.
-    const WIBBLE_FACTOR: Duration = Duration::from_millis(50);
-    let before = b - WIBBLE_FACTOR;
-    let after = b + WIBBLE_FACTOR;
-    a > before && a < after
+/// A [Clock] that only moves when told to. Wall-clock time starts on a whole second, so HTTP
+/// dates built from it round-trip exactly
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    system_start: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(MockClock {
+            start: Instant::now(),
+            system_start: SystemTime::UNIX_EPOCH + Duration::from_secs(1_750_000_000),
+            elapsed: Mutex::new(Duration::ZERO),
+        })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + *self.elapsed.lock().unwrap()
+    }
 }
 
 pub const SHORT_WAIT: Duration = Duration::from_secs(30);
 pub const LONG_WAIT: Duration = Duration::from_secs(90);
+
+/// Produces a fresh result for each call, because [RouteError](crate::error::RouteError) isn't [Clone]
+type Canned = Box<dyn Fn() -> Result<FeatureCollection> + Send + Sync>;
+
+/// Stand-in for [ExternalRequester](crate::requester::ExternalRequester) so handlers can be
+/// exercised without a mock server. Any endpoint not given a response panics when called.
+#[derive(Default)]
+pub struct CannedApi {
+    ors: Option<Canned>,
+    photon: Option<Canned>,
+    reverse: Option<Canned>,
+}
+
+impl std::fmt::Debug for CannedApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CannedApi")
+    }
+}
+
+impl CannedApi {
+    pub fn with_ors(
+        mut self,
+        f: impl Fn() -> Result<FeatureCollection> + Send + Sync + 'static,
+    ) -> Self {
+        self.ors = Some(Box::new(f));
+        self
+    }
+
+    pub fn with_photon(
+        mut self,
+        f: impl Fn() -> Result<FeatureCollection> + Send + Sync + 'static,
+    ) -> Self {
+        self.photon = Some(Box::new(f));
+        self
+    }
+
+    pub fn with_reverse(
+        mut self,
+        f: impl Fn() -> Result<FeatureCollection> + Send + Sync + 'static,
+    ) -> Self {
+        self.reverse = Some(Box::new(f));
+        self
+    }
+}
+
+#[async_trait]
+impl ExternalApi for CannedApi {
+    async fn ors_send(&self, _req: &OpenRouteRequest) -> Result<FeatureCollection> {
+        (self.ors.as_ref().expect("no canned ORS response"))()
+    }
+
+    async fn photon_reverse_send(
+        &self,
+        _coord: &PhotonRevGeocodeRequest,
+    ) -> Result<FeatureCollection> {
+        (self
+            .reverse
+            .as_ref()
+            .expect("no canned Photon reverse response"))()
+    }
+
+    async fn photon_send(&self, _req: &PhotonGeocodeRequest) -> Result<FeatureCollection> {
+        (self.photon.as_ref().expect("no canned Photon response"))()
+    }
+}
+
+/// Deserializes a [FeatureCollection] from a `serde_json::json!` literal
+pub fn collection(value: serde_json::Value) -> FeatureCollection {
+    serde_json::from_value(value).expect("test collection should be valid GeoJSON")
+}
@@ -0,0 +1,146 @@
+//! Per-route request timing: how many requests each route has in flight, how long they take, and
+//! a warning for any request slower than a threshold.
+//!
+//! The warning says which upstream endpoint the request spent longest waiting on, so a slow tail
+//! can be pinned on ORS, Photon, or us. [ExternalRequester] notes its calls with
+//! [record_upstream]; they're kept in a task-local for the rest of the request.
+//!
+//! [ExternalRequester]: crate::requester::ExternalRequester
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// Requests taking longer than this get logged, unless configured otherwise
+pub const DEFAULT_SLOW_REQUEST: Duration = Duration::from_secs(2);
+
+tokio::task_local! {
+    static UPSTREAM: Arc<Mutex<Vec<(&'static str, Duration)>>>;
+}
+
+/// Notes an upstream call against the current request. Does nothing outside [track]
+pub fn record_upstream(endpoint: &'static str, elapsed: Duration) {
+    let _ = UPSTREAM.try_with(|calls| {
+        calls
+            .lock()
+            .expect("timing lock poisoned")
+            .push((endpoint, elapsed))
+    });
+}
+
+/// The endpoint with the most time spent on it across `calls`, and that time
+fn dominant(calls: &[(&'static str, Duration)]) -> Option<(&'static str, Duration)> {
+    let mut totals: Vec<(&'static str, Duration)> = vec![];
+    for &(endpoint, elapsed) in calls {
+        match totals.iter_mut().find(|(e, _)| *e == endpoint) {
+            Some((_, total)) => *total += elapsed,
+            None => totals.push((endpoint, elapsed)),
+        }
+    }
+    totals.into_iter().max_by_key(|(_, total)| *total)
+}
+
+/// Keeps `http_in_flight` right even if the request is dropped partway
+struct InFlight(String);
+
+impl InFlight {
+    fn start(route: String) -> Self {
+        crate::metrics::registry().add_gauge("http_in_flight", &[("route", &route)], 1.0);
+        InFlight(route)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        crate::metrics::registry().add_gauge("http_in_flight", &[("route", &self.0)], -1.0);
+    }
+}
+
+/// Route middleware counting in-flight requests per route, timing them, and warning about ones
+/// slower than `threshold`
+pub async fn track(
+    State(threshold): State<Duration>,
+    path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = path.as_str().to_owned();
+    let _in_flight = InFlight::start(route.clone());
+    let calls = Arc::new(Mutex::new(vec![]));
+    let started = Instant::now();
+    let response = UPSTREAM.scope(calls.clone(), next.run(request)).await;
+
+    let elapsed = started.elapsed();
+    crate::metrics::registry().observe(
+        "http_request_duration_seconds",
+        &[("route", &route)],
+        elapsed.as_secs_f64(),
+    );
+    if elapsed > threshold {
+        let calls = calls.lock().expect("timing lock poisoned");
+        let upstream_ms: u128 = calls.iter().map(|(_, e)| e.as_millis()).sum();
+        let (slowest_endpoint, slowest_ms) = match dominant(&calls) {
+            Some((endpoint, total)) => (endpoint, total.as_millis()),
+            None => ("none", 0),
+        };
+        tracing::warn!(
+            route,
+            status = response.status().as_u16(),
+            elapsed_ms = elapsed.as_millis(),
+            upstream_calls = calls.len(),
+            upstream_ms,
+            slowest_endpoint,
+            slowest_ms,
+            "slow request"
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn dominant_sums_per_endpoint() {
+        let ms = Duration::from_millis;
+        assert_eq!(dominant(&[]), None);
+        let calls = [
+            ("photon", ms(300)),
+            ("ors_directions", ms(200)),
+            ("ors_directions", ms(200)),
+        ];
+        assert_eq!(dominant(&calls), Some(("ors_directions", ms(400))));
+    }
+
+    #[tokio::test]
+    async fn in_flight_per_route() {
+        async fn busy() -> String {
+            record_upstream("photon", Duration::from_millis(1));
+            crate::metrics::registry().render()
+        }
+        let app = Router::new()
+            .route("/timing_test/{id}", get(busy))
+            .route_layer(axum::middleware::from_fn_with_state(Duration::ZERO, track));
+        let request = Request::builder()
+            .uri("/timing_test/7")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let during = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        // Labelled by the route, not the path
+        let gauge = "http_in_flight{route=\"/timing_test/{id}\"}";
+        assert!(String::from_utf8_lossy(&during).contains(&format!("{gauge} 1\n")));
+        assert!(crate::metrics::registry()
+            .render()
+            .contains(&format!("{gauge} 0\n")));
+    }
+}
@@ -0,0 +1,301 @@
+//! Saved trips, for share links and for the app picking navigation back up after a restart.
+//!
+//! Trips live in SQLite, under a random id, until they expire. What's stored is the request and
+//! the route we computed for it, never anything a client made up. The total size is capped, and
+//! expired trips are cleared out whenever one is saved; when it's full even after that, saving
+//! fails rather than dropping trips someone may still be following.
+//!
+//! Share links ([crate::share]) are kept here too, under shorter tokens. One for a trip expires
+//! with the trip; one for a place lasts as long as a trip would. They count towards the same cap.
+use crate::clock::Clock;
+use crate::error::RouteError;
+use crate::{Result, RouteRequest, SharedPlace};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub const DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS trips (
+    id TEXT PRIMARY KEY,
+    expires INTEGER NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS trips_expires ON trips (expires);
+CREATE TABLE IF NOT EXISTS shares (
+    token TEXT PRIMARY KEY,
+    expires INTEGER NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS shares_expires ON shares (expires);";
+
+/// Share tokens are this many base62 digits, about 59 bits
+const TOKEN_LEN: usize = 10;
+
+/// What's kept of a trip
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Trip {
+    pub request: RouteRequest,
+    pub route: Vec<f64>,
+}
+
+/// A trip as stored
+#[derive(Debug)]
+pub struct Saved {
+    pub id: String,
+    pub trip: Trip,
+    pub expires_in: Duration,
+}
+
+/// What a share link points at
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Shared {
+    Trip(String),
+    Place(SharedPlace),
+}
+
+/// Cheap to clone; clones share the database connection
+#[derive(Debug, Clone)]
+pub struct TripStore {
+    conn: Arc<Mutex<Connection>>,
+    ttl: Duration,
+    max_bytes: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl TripStore {
+    /// Opens the database at `path`, creating it if needed
+    pub fn open(path: &Path, ttl: Duration, max_bytes: u64) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?, ttl, max_bytes)
+    }
+
+    fn with_connection(conn: Connection, ttl: Duration, max_bytes: u64) -> rusqlite::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(TripStore {
+            conn: Arc::new(Mutex::new(conn)),
+            ttl,
+            max_bytes,
+            clock: crate::clock::system(),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn in_memory(ttl: Duration, max_bytes: u64, clock: Arc<dyn Clock>) -> Self {
+        let mut store =
+            Self::with_connection(Connection::open_in_memory().unwrap(), ttl, max_bytes)
+                .expect("in-memory trip store should open");
+        store.clock = clock;
+        store
+    }
+
+    /// Seconds since the epoch, which is what's stored
+    fn now(&self) -> i64 {
+        self.clock
+            .system_now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64)
+    }
+
+    /// Blocks on SQLite, so call from [tokio::task::spawn_blocking]
+    pub fn save(&self, trip: Trip) -> Result<Saved> {
+        let body = serde_json::to_string(&trip).expect("trips should serialize");
+        let now = self.now();
+        let conn = self.conn.lock().expect("trip store lock poisoned");
+        self.make_room(&conn, now, body.len())?;
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        conn.execute(
+            "INSERT INTO trips (id, expires, body) VALUES (?1, ?2, ?3)",
+            params![id, now + self.ttl.as_secs() as i64, body],
+        )?;
+        Ok(Saved {
+            id,
+            trip,
+            expires_in: self.ttl,
+        })
+    }
+
+    /// Clears out what's expired, then checks `bytes` more fit under the cap
+    fn make_room(&self, conn: &Connection, now: i64, bytes: usize) -> Result<()> {
+        let cleared = conn.execute("DELETE FROM trips WHERE expires <= ?1", params![now])?
+            + conn.execute("DELETE FROM shares WHERE expires <= ?1", params![now])?;
+        if cleared > 0 {
+            tracing::debug!(cleared, "cleared expired trips and shares");
+        }
+        let used: i64 = conn.query_row(
+            "SELECT (SELECT COALESCE(SUM(LENGTH(body)), 0) FROM trips)
+                  + (SELECT COALESCE(SUM(LENGTH(body)), 0) FROM shares)",
+            [],
+            |row| row.get(0),
+        )?;
+        if used as u64 + bytes as u64 > self.max_bytes {
+            tracing::warn!(used, "trip storage full");
+            return Err(RouteError::StorageFull);
+        }
+        Ok(())
+    }
+
+    /// A new share token for `target`, and how long it lasts. [RouteError::NotFound] for
+    /// trips that have expired. Blocks on SQLite like [TripStore::save]
+    pub fn share(&self, target: Shared) -> Result<(String, Duration)> {
+        let now = self.now();
+        let conn = self.conn.lock().expect("trip store lock poisoned");
+        let expires = match &target {
+            Shared::Trip(id) => conn
+                .query_row(
+                    "SELECT expires FROM trips WHERE id = ?1 AND expires > ?2",
+                    params![id, now],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .ok_or(RouteError::NotFound)?,
+            Shared::Place(_) => now + self.ttl.as_secs() as i64,
+        };
+        let body = serde_json::to_string(&target).expect("shares should serialize");
+        self.make_room(&conn, now, body.len())?;
+        // Collisions are unlikely, but the tokens are short
+        for _ in 0..3 {
+            let token = token();
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO shares (token, expires, body) VALUES (?1, ?2, ?3)",
+                params![token, expires, body],
+            )?;
+            if inserted == 1 {
+                return Ok((token, Duration::from_secs((expires - now) as u64)));
+            }
+        }
+        Err(RouteError::Storage("no free share token".into()))
+    }
+
+    /// What `token` points at, unless it's expired. Blocks on SQLite like [TripStore::save]
+    pub fn resolve(&self, token: &str) -> Result<Option<Shared>> {
+        let now = self.now();
+        let conn = self.conn.lock().expect("trip store lock poisoned");
+        let body: Option<String> = conn
+            .query_row(
+                "SELECT body FROM shares WHERE token = ?1 AND expires > ?2",
+                params![token, now],
+                |row| row.get(0),
+            )
+            .optional()?;
+        body.map(|b| serde_json::from_str(&b).map_err(|e| RouteError::Storage(e.into())))
+            .transpose()
+    }
+
+    /// The trip saved as `id`, unless it's expired. Blocks on SQLite like [TripStore::save]
+    pub fn get(&self, id: &str) -> Result<Option<Saved>> {
+        let now = self.now();
+        let conn = self.conn.lock().expect("trip store lock poisoned");
+        let found: Option<(i64, String)> = conn
+            .query_row(
+                "SELECT expires, body FROM trips WHERE id = ?1 AND expires > ?2",
+                params![id, now],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((expires, body)) = found else {
+            return Ok(None);
+        };
+        let trip = serde_json::from_str(&body).map_err(|e| RouteError::Storage(e.into()))?;
+        Ok(Some(Saved {
+            id: id.to_owned(),
+            trip,
+            expires_in: Duration::from_secs((expires - now) as u64),
+        }))
+    }
+}
+
+/// A random share token, in base62
+fn token() -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut n = uuid::Uuid::new_v4().as_u64_pair().0;
+    (0..TOKEN_LEN)
+        .map(|_| {
+            let digit = DIGITS[(n % 62) as usize] as char;
+            n /= 62;
+            digit
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockClock;
+
+    fn trip() -> Trip {
+        Trip {
+            request: RouteRequest {
+                src_lat: 44.56,
+                src_lon: -123.27,
+                dst_lat: 44.57,
+                dst_lon: -123.28,
+                heading: None,
+                annotations: false,
+                borders: false,
+                elevation: false,
+            },
+            route: vec![-123.27, 44.56, -123.28, 44.57],
+        }
+    }
+
+    #[test]
+    fn save_and_expire() {
+        let clock = MockClock::new();
+        let store = TripStore::in_memory(Duration::from_secs(60), 1 << 20, clock.clone());
+        let saved = store.save(trip()).unwrap();
+        assert_eq!(saved.id.len(), 32);
+
+        let got = store.get(&saved.id).unwrap().unwrap();
+        assert_eq!(got.trip, trip());
+        clock.advance(Duration::from_secs(20));
+        let got = store.get(&saved.id).unwrap().unwrap();
+        assert_eq!(got.expires_in, Duration::from_secs(40));
+        assert!(store.get("nope").unwrap().is_none());
+
+        clock.advance(Duration::from_secs(40));
+        assert!(store.get(&saved.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn shares_expire_with_their_trip() {
+        let clock = MockClock::new();
+        let store = TripStore::in_memory(Duration::from_secs(60), 1 << 20, clock.clone());
+        let trip = store.save(trip()).unwrap();
+        clock.advance(Duration::from_secs(20));
+        let (token, lasts) = store.share(Shared::Trip(trip.id.clone())).unwrap();
+        assert_eq!(token.len(), TOKEN_LEN);
+        assert_eq!(lasts, Duration::from_secs(40));
+        let place = Shared::Place(SharedPlace {
+            lat: 44.56,
+            lon: -123.27,
+            name: "Downward Dog".to_owned(),
+        });
+        let (place_token, lasts) = store.share(place.clone()).unwrap();
+        assert_eq!(lasts, Duration::from_secs(60));
+
+        assert_eq!(store.resolve(&token).unwrap(), Some(Shared::Trip(trip.id)));
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(store.resolve(&token).unwrap(), None);
+        assert_eq!(store.resolve(&place_token).unwrap(), Some(place));
+        assert!(matches!(
+            store.share(Shared::Trip("nope".to_owned())),
+            Err(RouteError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn full_until_expired_trips_clear() {
+        let clock = MockClock::new();
+        let size = serde_json::to_string(&trip()).unwrap().len() as u64;
+        let store = TripStore::in_memory(Duration::from_secs(60), size * 2, clock.clone());
+        store.save(trip()).unwrap();
+        store.save(trip()).unwrap();
+        assert!(matches!(store.save(trip()), Err(RouteError::StorageFull)));
+
+        clock.advance(Duration::from_secs(60));
+        assert!(store.save(trip()).is_ok());
+    }
+}
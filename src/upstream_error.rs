@@ -0,0 +1,158 @@
+//! Error bodies the external APIs send back, so failures can be traced with what the provider
+//! actually said instead of just a status code.
+use crate::requester::Provider;
+use serde::Deserialize;
+use std::fmt;
+
+/// OpenRouteService wraps its errors like `{"error": {"code": 2003, "message": "..."}}`. Its API
+/// gateway (bad key, quota) sometimes just sends `{"error": "..."}` instead.
+#[derive(Deserialize, Debug)]
+struct OrsErrorBody {
+    error: OrsErrorDetail,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum OrsErrorDetail {
+    Coded { code: u32, message: String },
+    Plain(String),
+}
+
+/// Photon sends `{"message": "..."}` for bad requests, or sometimes plain text
+#[derive(Deserialize, Debug)]
+struct PhotonErrorBody {
+    message: String,
+}
+
+/// Whatever could be made of an upstream's complaint
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamError {
+    /// Only ORS has these. See their docs for the (long) list
+    pub code: Option<u32>,
+    pub message: String,
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{code}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Plain-text bodies longer than this are probably an HTML error page, not a message
+const MAX_PLAIN_MESSAGE: usize = 200;
+
+/// How much of a body makes it into the logs when it can't be made sense of
+const SNIPPET_LEN: usize = 512;
+
+/// Lossily decodes `body` with every string in `secrets` replaced by `<redacted>`
+pub fn redact(body: &[u8], secrets: &[&str]) -> String {
+    let mut text = String::from_utf8_lossy(body).into_owned();
+    for secret in secrets.iter().filter(|s| !s.is_empty()) {
+        text = text.replace(secret, "<redacted>");
+    }
+    text
+}
+
+/// A log-safe preview of `body`: [redact]ed and cut to [SNIPPET_LEN] bytes (on a char boundary).
+pub fn snippet(body: &[u8], secrets: &[&str]) -> String {
+    let text = redact(body, secrets);
+    if text.len() <= SNIPPET_LEN {
+        return text;
+    }
+    let mut end = SNIPPET_LEN;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...({} more bytes)", &text[..end], text.len() - end)
+}
+
+/// Tries to read `body` as the error format `provider` uses. `None` if it isn't one.
+pub fn parse(provider: Provider, body: &[u8]) -> Option<UpstreamError> {
+    match provider {
+        Provider::OpenRouteService => {
+            let parsed: OrsErrorBody = serde_json::from_slice(body).ok()?;
+            Some(match parsed.error {
+                OrsErrorDetail::Coded { code, message } => UpstreamError {
+                    code: Some(code),
+                    message,
+                },
+                OrsErrorDetail::Plain(message) => UpstreamError {
+                    code: None,
+                    message,
+                },
+            })
+        }
+        Provider::Photon => {
+            if let Ok(parsed) = serde_json::from_slice::<PhotonErrorBody>(body) {
+                return Some(UpstreamError {
+                    code: None,
+                    message: parsed.message,
+                });
+            }
+            let text = std::str::from_utf8(body).ok()?.trim();
+            let looks_like_json = text.starts_with('{') || text.starts_with('[');
+            if text.is_empty() || text.len() > MAX_PLAIN_MESSAGE || looks_like_json {
+                return None;
+            }
+            Some(UpstreamError {
+                code: None,
+                message: text.to_owned(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ors_coded_error() {
+        let body = br#"{"error":{"code":2010,"message":"Could not find routable point within a radius of 350.0 meters of specified coordinate 0: 0.0000000 0.0000000."},"info":{"engine":{"version":"9.1.2"},"timestamp":1746670734315}}"#;
+        let err = parse(Provider::OpenRouteService, body).unwrap();
+        assert_eq!(err.code, Some(2010));
+        assert!(err.message.starts_with("Could not find routable point"));
+    }
+
+    #[test]
+    fn ors_gateway_error() {
+        let body = br#"{"error": "Access to this API has been disallowed"}"#;
+        let err = parse(Provider::OpenRouteService, body).unwrap();
+        assert_eq!(err.code, None);
+        assert_eq!(err.message, "Access to this API has been disallowed");
+    }
+
+    #[test]
+    fn photon_errors() {
+        let json = br#"{"message":"missing search term 'q': /?q=berlin"}"#;
+        assert_eq!(
+            parse(Provider::Photon, json).unwrap().message,
+            "missing search term 'q': /?q=berlin"
+        );
+        assert_eq!(
+            parse(Provider::Photon, b"Bad Request\n").unwrap().message,
+            "Bad Request"
+        );
+    }
+
+    #[test]
+    fn snippet_redacts_and_truncates() {
+        assert_eq!(snippet(b"key=hunter2", &["hunter2"]), "key=<redacted>");
+        // Multi-byte chars straddling the cut shouldn't panic
+        let long = "北".repeat(SNIPPET_LEN);
+        let cut = snippet(long.as_bytes(), &[]);
+        assert!(cut.ends_with(&format!("({} more bytes)", long.len() - 510)));
+    }
+
+    #[test]
+    fn not_an_error_body() {
+        assert!(parse(Provider::OpenRouteService, b"<html>502</html>").is_none());
+        assert!(parse(Provider::Photon, br#"{"features":[]}"#).is_none());
+        assert!(parse(Provider::Photon, b"").is_none());
+    }
+}
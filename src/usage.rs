@@ -0,0 +1,682 @@
+//! Ledger of upstream calls per provider per UTC day, with optional hard daily caps.
+//!
+//! Caps are separate from the politeness limiters in [crate::ratelimit]: they're there to stop a
+//! runaway client costing us money. Hitting one puts that provider into [Maintenance] until the
+//! next UTC day, with a loud log line and metric.
+//!
+//! The ledger also projects each day's usage from the burn rate so far, and warns when a provider
+//! is on pace to run out of its daily allowance before the day's over.
+//!
+//! It also keeps anonymous per-day stats for the app team, for the last [STATS_DAYS] days:
+//! requests per route, cache hit rates, and how long routes are. Nothing about who asked.
+use crate::error::RouteError;
+use crate::maintenance::Maintenance;
+use crate::metrics;
+use crate::ors::OrsRoute;
+use crate::requester::{
+    ExternalApi, Limits, OpenRouteRequest, PhotonGeocodeRequest, PhotonRevGeocodeRequest, Provider,
+};
+use crate::Result;
+use async_trait::async_trait;
+use axum::{
+    extract::{MatchedPath, Query, Request, State},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use geojson::FeatureCollection;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Duration;
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// Daily allowances when there's no lower cap. ORS's free plan allows 2000 directions calls a day,
+/// and Photon's politeness limiter defaults to the same
+const DEFAULT_ALLOWANCE: u64 = 2000;
+/// Projections from the first few minutes of a day are noise
+const MIN_FORECAST_ELAPSED: Duration = Duration::from_secs(3600);
+/// Share of the allowance that gets a warning once used
+const USED_WARNING: f64 = 0.8;
+const FORECAST_INTERVAL: Duration = Duration::from_secs(300);
+/// Days of stats kept, today included
+pub const STATS_DAYS: u64 = 30;
+/// Route lengths kept per day for the median. Past this the median is of the day's first ones
+const MAX_LENGTH_SAMPLES: usize = 10_000;
+
+/// UTC day number, and how long until the next one
+fn utc_day() -> (u64, Duration) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (
+        secs / SECS_PER_DAY,
+        Duration::from_secs(SECS_PER_DAY - secs % SECS_PER_DAY),
+    )
+}
+
+#[derive(Debug)]
+struct Day {
+    day: u64,
+    counts: HashMap<Provider, u64>,
+    /// Providers we put into maintenance for hitting their cap, to undo at rollover
+    capped: HashSet<Provider>,
+    /// Already warned today about being on pace to run out
+    warned_pace: HashSet<Provider>,
+    /// Already warned today about passing [USED_WARNING]
+    warned_used: HashSet<Provider>,
+}
+
+/// Where a provider's day is heading if calls keep coming at the same rate
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Forecast {
+    pub used: u64,
+    pub allowance: u64,
+    pub projected: u64,
+    /// Seconds after UTC midnight the allowance runs out, if it will today
+    pub exhausted_at_secs: Option<u64>,
+}
+
+/// `elapsed` is time since UTC midnight
+fn forecast(used: u64, allowance: u64, elapsed: Duration) -> Forecast {
+    let elapsed = elapsed.as_secs().max(1);
+    let projected = used.saturating_mul(SECS_PER_DAY) / elapsed;
+    let exhausted_at_secs = (used > 0 && projected > allowance)
+        .then(|| allowance.saturating_mul(elapsed) / used)
+        .filter(|&at| at < SECS_PER_DAY);
+    Forecast {
+        used,
+        allowance,
+        projected,
+        exhausted_at_secs,
+    }
+}
+
+fn clock(secs: u64) -> String {
+    format!("{:02}:{:02}", secs / 3600, secs % 3600 / 60)
+}
+
+/// One day's stats, as counted
+#[derive(Debug, Default)]
+struct DayStats {
+    /// By matched route, like `/trips/{id}`
+    requests: HashMap<String, u64>,
+    /// Hits and misses by cache
+    caches: HashMap<&'static str, (u64, u64)>,
+    routes: u64,
+    route_lengths_m: Vec<f64>,
+}
+
+/// UTC date of day number `day`, as YYYY-MM-DD
+fn date(day: u64) -> String {
+    // Howard Hinnant's civil_from_days
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let mid = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((sorted[mid - 1] + sorted[mid]) / 2.0),
+        _ => Some(sorted[mid]),
+    }
+}
+
+/// Shared usage ledger. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    caps: Arc<HashMap<Provider, u64>>,
+    maintenance: Maintenance,
+    today: Arc<Mutex<Day>>,
+    /// By UTC day number
+    stats: Arc<Mutex<BTreeMap<u64, DayStats>>>,
+}
+
+impl Ledger {
+    /// Spawns a task that starts a fresh day at UTC midnight
+    pub fn new(caps: HashMap<Provider, u64>, maintenance: Maintenance) -> Self {
+        for (provider, cap) in &caps {
+            tracing::info!("hard daily cap for {provider}: {cap} calls");
+        }
+        let ledger = Ledger {
+            caps: Arc::new(caps),
+            maintenance,
+            today: Arc::new(Mutex::new(Day {
+                day: utc_day().0,
+                counts: HashMap::new(),
+                capped: HashSet::new(),
+                warned_pace: HashSet::new(),
+                warned_used: HashSet::new(),
+            })),
+            stats: Default::default(),
+        };
+        tokio::spawn(Self::rollover_task(ledger.clone()));
+        tokio::spawn(Self::forecast_task(ledger.clone()));
+        ledger
+    }
+
+    async fn forecast_task(ledger: Ledger) {
+        let mut interval = tokio::time::interval(FORECAST_INTERVAL);
+        loop {
+            interval.tick().await;
+            let (_, left) = utc_day();
+            let elapsed = Duration::from_secs(SECS_PER_DAY) - left;
+            for provider in [Provider::OpenRouteService, Provider::Photon] {
+                ledger.check_forecast(provider, elapsed);
+            }
+        }
+    }
+
+    /// The lower of the cap and the provider's own allowance
+    pub fn allowance(&self, provider: Provider) -> u64 {
+        self.cap(provider)
+            .map_or(DEFAULT_ALLOWANCE, |cap| cap.min(DEFAULT_ALLOWANCE))
+    }
+
+    pub fn forecast(&self, provider: Provider, elapsed: Duration) -> Forecast {
+        forecast(self.used(provider), self.allowance(provider), elapsed)
+    }
+
+    /// Updates the forecast metrics for `provider`, warning (once a day each) if it's past
+    /// [USED_WARNING] or on pace to run out
+    fn check_forecast(&self, provider: Provider, elapsed: Duration) {
+        let f = self.forecast(provider, elapsed);
+        let label = provider.to_string();
+        let registry = metrics::registry();
+        registry.set_gauge(
+            "upstream_daily_projected",
+            &[("provider", &label)],
+            f.projected as f64,
+        );
+        let on_pace = elapsed >= MIN_FORECAST_ELAPSED && f.exhausted_at_secs.is_some();
+        registry.set_gauge(
+            "upstream_daily_exhaustion_warning",
+            &[("provider", &label)],
+            if on_pace { 1.0 } else { 0.0 },
+        );
+
+        let mut today = self.today.lock().expect("usage ledger lock poisoned");
+        let share = f.used as f64 / f.allowance as f64;
+        if share >= USED_WARNING && today.warned_used.insert(provider) {
+            tracing::warn!(
+                %provider,
+                used = f.used,
+                allowance = f.allowance,
+                "{:.0}% of {provider} daily allowance used by {} UTC",
+                share * 100.0,
+                clock(elapsed.as_secs())
+            );
+        }
+        if let (true, Some(at)) = (on_pace, f.exhausted_at_secs) {
+            if today.warned_pace.insert(provider) {
+                tracing::warn!(
+                    %provider,
+                    used = f.used,
+                    allowance = f.allowance,
+                    projected = f.projected,
+                    "{provider} on pace to use its daily allowance by {} UTC",
+                    clock(at)
+                );
+            }
+        }
+    }
+
+    async fn rollover_task(ledger: Ledger) {
+        loop {
+            let (_, left) = utc_day();
+            // A little slack so we're definitely on the other side of midnight
+            tokio::time::sleep(left + Duration::from_secs(1)).await;
+            ledger.roll_over(utc_day().0);
+        }
+    }
+
+    /// Starts `day` afresh if it's not the one being counted, lifting any cap maintenance
+    fn roll_over(&self, day: u64) {
+        let mut today = self.today.lock().expect("usage ledger lock poisoned");
+        if today.day == day {
+            return;
+        }
+        for provider in today.capped.drain() {
+            tracing::warn!("new day, lifting daily cap on {provider}");
+            self.maintenance.disable(Some(provider));
+            metrics::registry().set_gauge(
+                "upstream_daily_cap_reached",
+                &[("provider", &provider.to_string())],
+                0.0,
+            );
+        }
+        today.day = day;
+        today.counts.clear();
+        today.warned_pace.clear();
+        today.warned_used.clear();
+    }
+
+    /// Calls made to `provider` so far today
+    pub fn used(&self, provider: Provider) -> u64 {
+        let today = self.today.lock().expect("usage ledger lock poisoned");
+        today.counts.get(&provider).copied().unwrap_or(0)
+    }
+
+    /// The hard cap for `provider`, if there is one
+    pub fn cap(&self, provider: Provider) -> Option<u64> {
+        self.caps.get(&provider).copied()
+    }
+
+    /// The smallest share of any Photon quota left, 0 to 1: in `client`'s politeness limits and
+    /// under the daily cap
+    pub fn photon_left(&self, client: &dyn ExternalApi) -> f64 {
+        let share = |left: u64, limit: u64| {
+            if limit == 0 {
+                0.0
+            } else {
+                left as f64 / limit as f64
+            }
+        };
+        let windows = client.limits().photon.into_iter().map(|status| {
+            let allowed = status.limit.saturating_sub(status.held_back);
+            share(
+                allowed.saturating_sub(status.used).into(),
+                status.limit.into(),
+            )
+        });
+        let daily = self
+            .cap(Provider::Photon)
+            .map(|cap| share(cap.saturating_sub(self.used(Provider::Photon)), cap));
+        windows.chain(daily).fold(1.0, f64::min)
+    }
+
+    /// Counts a call about to go to `provider`. `Err` if the cap has been reached
+    fn spend(&self, provider: Provider) -> Result<()> {
+        self.roll_over(utc_day().0);
+        let mut today = self.today.lock().expect("usage ledger lock poisoned");
+        let used = today.counts.entry(provider).or_insert(0);
+        let cap = self.caps.get(&provider).copied();
+        if cap.is_some_and(|cap| *used >= cap) {
+            drop(today);
+            self.trip(provider);
+            return self.maintenance.check(Some(provider));
+        }
+        *used += 1;
+        let used = *used;
+        metrics::registry().set_gauge(
+            "upstream_daily_used",
+            &[("provider", &provider.to_string())],
+            used as f64,
+        );
+        drop(today);
+        if cap == Some(used) {
+            self.trip(provider);
+        }
+        Ok(())
+    }
+
+    /// Gives back a call that never reached upstream
+    fn refund(&self, provider: Provider) {
+        let mut today = self.today.lock().expect("usage ledger lock poisoned");
+        if let Some(used) = today.counts.get_mut(&provider) {
+            *used = used.saturating_sub(1);
+        }
+    }
+
+    /// Changes today's stats, starting a new day and forgetting the oldest if need be
+    fn with_stats(&self, f: impl FnOnce(&mut DayStats)) {
+        let day = utc_day().0;
+        let mut stats = self.stats.lock().expect("usage stats lock poisoned");
+        if !stats.contains_key(&day) {
+            stats.retain(|&d, _| d + STATS_DAYS > day);
+        }
+        f(stats.entry(day).or_default());
+    }
+
+    pub fn count_request(&self, route: &str) {
+        self.with_stats(|s| *s.requests.entry(route.to_owned()).or_insert(0) += 1);
+    }
+
+    pub fn count_cache(&self, cache: &'static str, hit: bool) {
+        self.with_stats(|s| {
+            let (hits, misses) = s.caches.entry(cache).or_insert((0, 0));
+            *if hit { hits } else { misses } += 1;
+        });
+    }
+
+    /// Counts a route served, cached or not
+    pub fn count_route(&self, length_m: f64) {
+        self.with_stats(|s| {
+            s.routes += 1;
+            if s.route_lengths_m.len() < MAX_LENGTH_SAMPLES {
+                s.route_lengths_m.push(length_m);
+            }
+        });
+    }
+
+    /// Stats for the last `days` days, newest first
+    pub fn stats(&self, days: u64) -> Vec<DayReport> {
+        let today = utc_day().0;
+        let stats = self.stats.lock().expect("usage stats lock poisoned");
+        stats
+            .range(today.saturating_sub(days.saturating_sub(1))..=today)
+            .rev()
+            .map(|(&day, s)| DayReport {
+                date: date(day),
+                requests: s.requests.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+                caches: s
+                    .caches
+                    .iter()
+                    .map(|(&cache, &(hits, misses))| {
+                        let rate = hits as f64 / (hits + misses).max(1) as f64;
+                        (
+                            cache,
+                            CacheReport {
+                                hits,
+                                misses,
+                                hit_rate: rate,
+                            },
+                        )
+                    })
+                    .collect(),
+                routes: s.routes,
+                median_route_m: median(&s.route_lengths_m),
+            })
+            .collect()
+    }
+
+    fn trip(&self, provider: Provider) {
+        let mut today = self.today.lock().expect("usage ledger lock poisoned");
+        if !today.capped.insert(provider) {
+            return;
+        }
+        drop(today);
+        tracing::error!(
+            %provider,
+            cap = self.cap(provider),
+            "HARD DAILY CAP REACHED, {provider} is off until UTC midnight"
+        );
+        metrics::registry().set_gauge(
+            "upstream_daily_cap_reached",
+            &[("provider", &provider.to_string())],
+            1.0,
+        );
+        self.maintenance.enable(
+            Some(provider),
+            format!("{provider} daily quota used up, back after UTC midnight"),
+            utc_day().1,
+        );
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProviderUsage {
+    pub provider: Provider,
+    pub used: u64,
+    pub cap: Option<u64>,
+    pub forecast: Forecast,
+}
+
+/// `GET /admin/usage`: calls so far today against each provider's cap, and where they're heading
+pub async fn get_usage(State(ledger): State<Ledger>) -> Json<Vec<ProviderUsage>> {
+    let (_, left) = utc_day();
+    let elapsed = Duration::from_secs(SECS_PER_DAY) - left;
+    Json(
+        [Provider::OpenRouteService, Provider::Photon]
+            .into_iter()
+            .map(|provider| ProviderUsage {
+                provider,
+                used: ledger.used(provider),
+                cap: ledger.cap(provider),
+                forecast: ledger.forecast(provider, elapsed),
+            })
+            .collect(),
+    )
+}
+
+#[derive(Serialize, Debug)]
+pub struct DayReport {
+    /// UTC
+    pub date: String,
+    pub requests: BTreeMap<String, u64>,
+    pub caches: BTreeMap<&'static str, CacheReport>,
+    /// Routes served, from the cache or not
+    pub routes: u64,
+    pub median_route_m: Option<f64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CacheReport {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    days: Option<u64>,
+}
+
+/// `GET /admin/stats?days=N`: anonymous usage for the last N days (7 by default), newest first
+pub async fn get_stats(
+    State(ledger): State<Ledger>,
+    Query(query): Query<StatsQuery>,
+) -> Json<Vec<DayReport>> {
+    let days = query.days.unwrap_or(7).clamp(1, STATS_DAYS);
+    Json(ledger.stats(days))
+}
+
+/// Middleware counting requests to each route for [Ledger::stats]
+pub async fn count_requests(
+    State(ledger): State<Ledger>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(path) = request.extensions().get::<MatchedPath>() {
+        ledger.count_request(path.as_str());
+    }
+    next.run(request).await
+}
+
+/// Wraps an [ExternalApi] to count calls in a [Ledger] and stop at the caps
+#[derive(Debug)]
+pub struct Metered {
+    inner: Arc<dyn ExternalApi>,
+    ledger: Ledger,
+}
+
+impl Metered {
+    pub fn new(inner: Arc<dyn ExternalApi>, ledger: Ledger) -> Self {
+        Metered { inner, ledger }
+    }
+
+    fn settle<T>(&self, provider: Provider, res: Result<T>) -> Result<T> {
+        // Our own limiters turned it away, so upstream never saw it
+        if matches!(res, Err(RouteError::ExternalAPILimit(_))) {
+            self.ledger.refund(provider);
+        }
+        res
+    }
+}
+
+#[async_trait]
+impl ExternalApi for Metered {
+    async fn ors_send(&self, req: &OpenRouteRequest) -> Result<FeatureCollection> {
+        self.ledger.spend(Provider::OpenRouteService)?;
+        let res = self.inner.ors_send(req).await;
+        self.settle(Provider::OpenRouteService, res)
+    }
+
+    async fn ors_route(&self, req: &OpenRouteRequest) -> Result<OrsRoute> {
+        self.ledger.spend(Provider::OpenRouteService)?;
+        let res = self.inner.ors_route(req).await;
+        self.settle(Provider::OpenRouteService, res)
+    }
+
+    async fn photon_reverse_send(
+        &self,
+        coord: &PhotonRevGeocodeRequest,
+    ) -> Result<FeatureCollection> {
+        self.ledger.spend(Provider::Photon)?;
+        let res = self.inner.photon_reverse_send(coord).await;
+        self.settle(Provider::Photon, res)
+    }
+
+    async fn photon_send(&self, req: &PhotonGeocodeRequest) -> Result<FeatureCollection> {
+        self.ledger.spend(Provider::Photon)?;
+        let res = self.inner.photon_send(req).await;
+        self.settle(Provider::Photon, res)
+    }
+
+    fn limits(&self) -> Limits {
+        self.inner.limits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{collection, CannedApi};
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    fn empty() -> Result<FeatureCollection> {
+        Ok(collection(
+            json!({ "type": "FeatureCollection", "features": [] }),
+        ))
+    }
+
+    fn search() -> PhotonGeocodeRequest {
+        PhotonGeocodeRequest::new(1, "x".to_owned())
+    }
+
+    #[tokio::test]
+    async fn cap_trips_maintenance_until_rollover() {
+        let maintenance = Maintenance::default();
+        let ledger = Ledger::new(HashMap::from([(Provider::Photon, 2)]), maintenance.clone());
+        let api = Metered::new(
+            Arc::new(CannedApi::default().with_photon(empty)),
+            ledger.clone(),
+        );
+
+        assert!(api.photon_send(&search()).await.is_ok());
+        assert!(maintenance.check(Some(Provider::Photon)).is_ok());
+        // The call that reaches the cap still goes through
+        assert!(api.photon_send(&search()).await.is_ok());
+        assert!(maintenance.check(Some(Provider::Photon)).is_err());
+        let res = api.photon_send(&search()).await;
+        assert!(res.is_err_and(|e| matches!(e, RouteError::Maintenance { .. })));
+        assert_eq!(ledger.used(Provider::Photon), 2);
+        assert!(maintenance.check(Some(Provider::OpenRouteService)).is_ok());
+
+        let tomorrow = utc_day().0 + 1;
+        ledger.roll_over(tomorrow);
+        assert_eq!(ledger.used(Provider::Photon), 0);
+        assert!(maintenance.check(Some(Provider::Photon)).is_ok());
+    }
+
+    #[test]
+    fn burn_rate_projection() {
+        // 1000 calls by 06:00 is 4000 by midnight, and 2000 gone by noon
+        let f = forecast(1000, 2000, Duration::from_secs(6 * 3600));
+        assert_eq!(f.projected, 4000);
+        assert_eq!(f.exhausted_at_secs, Some(12 * 3600));
+        assert_eq!(clock(12 * 3600 + 90), "12:01");
+
+        let f = forecast(100, 2000, Duration::from_secs(12 * 3600));
+        assert_eq!(f.projected, 200);
+        assert_eq!(f.exhausted_at_secs, None);
+        assert_eq!(forecast(0, 2000, Duration::ZERO).projected, 0);
+    }
+
+    #[tokio::test]
+    async fn warnings_only_once_a_day() {
+        let ledger = Ledger::new(
+            HashMap::from([(Provider::Photon, 10)]),
+            Maintenance::default(),
+        );
+        assert_eq!(ledger.allowance(Provider::Photon), 10);
+        assert_eq!(
+            ledger.allowance(Provider::OpenRouteService),
+            DEFAULT_ALLOWANCE
+        );
+        for _ in 0..9 {
+            ledger.spend(Provider::Photon).unwrap();
+        }
+        let elapsed = Duration::from_secs(2 * 3600);
+        ledger.check_forecast(Provider::Photon, elapsed);
+        {
+            let today = ledger.today.lock().unwrap();
+            assert!(today.warned_used.contains(&Provider::Photon));
+            assert!(today.warned_pace.contains(&Provider::Photon));
+        }
+        ledger.roll_over(utc_day().0 + 1);
+        assert!(ledger.today.lock().unwrap().warned_pace.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stats_by_day() {
+        let ledger = Ledger::new(HashMap::new(), Maintenance::default());
+        // Long gone, so dropped when today starts
+        ledger.stats.lock().unwrap().insert(1, DayStats::default());
+        ledger.count_request("/route");
+        assert_eq!(ledger.stats.lock().unwrap().len(), 1);
+        ledger.count_request("/route");
+        ledger.count_cache("route", true);
+        ledger.count_cache("route", false);
+        ledger.count_cache("route", false);
+        ledger.count_cache("route", false);
+        for m in [300.0, 100.0, 2000.0, 500.0] {
+            ledger.count_route(m);
+        }
+        ledger.count_request("/get_locations");
+
+        let stats = ledger.stats(7);
+        assert_eq!(stats.len(), 1);
+        let today = &stats[0];
+        assert_eq!(today.date, date(utc_day().0));
+        assert_eq!(today.requests["/route"], 2);
+        assert_eq!(today.requests["/get_locations"], 1);
+        assert_eq!(
+            today.caches["route"],
+            CacheReport {
+                hits: 1,
+                misses: 3,
+                hit_rate: 0.25
+            }
+        );
+        assert_eq!(today.median_route_m, Some(400.0));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(11016), "2000-02-29");
+        assert_eq!(date(20_742), "2026-10-16");
+        assert_eq!(median(&[3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn own_limits_are_refunded() {
+        let ledger = Ledger::new(HashMap::new(), Maintenance::default());
+        let api = Metered::new(
+            Arc::new(
+                CannedApi::default()
+                    .with_photon(|| Err(RouteError::ExternalAPILimit(SystemTime::now()))),
+            ),
+            ledger.clone(),
+        );
+        assert!(api.photon_send(&search()).await.is_err());
+        assert_eq!(ledger.used(Provider::Photon), 0);
+    }
+}
@@ -0,0 +1,107 @@
+//! Records upstream response bodies into fixture files, and (in tests) replays them.
+//!
+//! Recording is a development aid: point a debug run at the real APIs with `--record-fixtures`,
+//! make a few requests, and the latest body per endpoint lands in that directory. Replay serves
+//! whatever is checked into `fixtures/` so tests don't carry giant inline JSON strings.
+use std::path::PathBuf;
+
+/// Writes the most recent response body of each endpoint to `<dir>/<endpoint>.json`
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    dir: PathBuf,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf) -> Self {
+        Recorder { dir }
+    }
+
+    /// Saves `body` under `endpoint`, with every string in `redact` scrubbed out first.
+    ///
+    /// JSON bodies are pretty-printed so fixture diffs are readable. Failures are logged and
+    /// otherwise ignored; recording should never be why a request fails.
+    pub async fn record(&self, endpoint: &str, body: &[u8], redact: &[&str]) {
+        let text = crate::upstream_error::redact(body, redact);
+        let text = match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(value) => serde_json::to_string_pretty(&value).unwrap_or(text),
+            Err(_) => text,
+        };
+
+        let path = self.dir.join(format!("{endpoint}.json"));
+        match tokio::fs::write(&path, text + "\n").await {
+            Ok(_) => tracing::debug!("recorded {endpoint} response to {}", path.display()),
+            Err(e) => tracing::warn!("couldn't record {endpoint} response to {path:?}: {e}"),
+        }
+    }
+}
+
+/// Reads a checked-in fixture body by endpoint name
+#[cfg(test)]
+pub fn fixture(endpoint: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(format!("{endpoint}.json"));
+    std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("couldn't read fixture {path:?}: {e}"))
+}
+
+/// [ExternalApi](crate::requester::ExternalApi) that answers every call with the checked-in
+/// fixture for that endpoint, regardless of what was asked.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct Replay;
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl crate::requester::ExternalApi for Replay {
+    async fn ors_send(
+        &self,
+        _req: &crate::requester::OpenRouteRequest,
+    ) -> crate::Result<geojson::FeatureCollection> {
+        Ok(serde_json::from_str(&fixture("ors_directions"))?)
+    }
+
+    async fn photon_reverse_send(
+        &self,
+        _coord: &crate::requester::PhotonRevGeocodeRequest,
+    ) -> crate::Result<geojson::FeatureCollection> {
+        Ok(serde_json::from_str(&fixture("photon_reverse"))?)
+    }
+
+    async fn photon_send(
+        &self,
+        _req: &crate::requester::PhotonGeocodeRequest,
+    ) -> crate::Result<geojson::FeatureCollection> {
+        Ok(serde_json::from_str(&fixture("photon_geocode"))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn record_redacts_and_pretty_prints() {
+        let dir = std::env::temp_dir().join(format!("flipmap-vcr-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let recorder = Recorder::new(dir.clone());
+
+        recorder
+            .record("echo", br#"{"query":{"api_key":"hunter2"}}"#, &["hunter2"])
+            .await;
+
+        let written = std::fs::read_to_string(dir.join("echo.json")).unwrap();
+        assert!(!written.contains("hunter2"));
+        assert!(written.contains("<redacted>"));
+        assert!(written.lines().count() > 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_reverse_lookups() {
+        use crate::requester::{ExternalApi, PhotonRevGeocodeRequest};
+        let req = PhotonRevGeocodeRequest::from_position(vec![-123.2778, 44.5687]);
+        let features = Replay.photon_reverse_send(&req).await.unwrap();
+        let name = &features.features[0].properties.as_ref().unwrap()["name"];
+        assert_eq!(name, "Downward Dog");
+    }
+}
@@ -0,0 +1,170 @@
+//! Response versions, picked by the client's Accept header so old app builds keep getting the
+//! shapes they were written against.
+//!
+//! `Accept: application/vnd.flipmap.v2+json` gets v2. Anything else, including no Accept or plain
+//! `application/json`, gets v1. v2 answers say so in their Content-Type. Every versioned answer
+//! has `Vary: Accept` so caches in between keep them apart.
+//!
+//! v1's flat route is on its way out. Answers with one count which route format went out in
+//! `route_format_total`, and v1 ones carry [FLAT_ROUTE_DEPRECATED], so we know when it's safe to
+//! drop.
+use crate::metrics;
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::convert::Infallible;
+
+pub const V1_MEDIA_TYPE: &str = "application/vnd.flipmap.v1+json";
+pub const V2_MEDIA_TYPE: &str = "application/vnd.flipmap.v2+json";
+/// On v1 answers with a flat route, saying what to ask for instead
+pub const FLAT_ROUTE_DEPRECATED: HeaderName = HeaderName::from_static("x-deprecated");
+const FLAT_ROUTE_NUDGE: &str = "flat route; send Accept: application/vnd.flipmap.v2+json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum ApiVersion {
+    /// What the app has always had
+    #[default]
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    /// The highest version `accept` lists that we know, ignoring any with `q=0`. V1 if none
+    pub fn negotiate(accept: &str) -> Self {
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media_type = parts.next()?;
+                let refused = parts.any(|param| {
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                });
+                if refused {
+                    None
+                } else if media_type.eq_ignore_ascii_case(V2_MEDIA_TYPE) {
+                    Some(ApiVersion::V2)
+                } else if media_type.eq_ignore_ascii_case(V1_MEDIA_TYPE) {
+                    Some(ApiVersion::V1)
+                } else {
+                    None
+                }
+            })
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ApiVersion {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let accept = parts
+            .headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        Ok(ApiVersion::negotiate(&accept))
+    }
+}
+
+/// A response body that may look different from v2 on
+pub trait Versioned: Serialize {
+    type V2: Serialize;
+    fn into_v2(self) -> Self::V2;
+    /// Whether this has a route, flat in v1 and structured from v2
+    fn has_route(&self) -> bool {
+        false
+    }
+}
+
+/// `T`, serialized the way the client's version wants it
+pub struct Negotiated<T>(pub ApiVersion, pub T);
+
+impl<T: Versioned> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        let Negotiated(version, body) = self;
+        let route_format = body.has_route().then_some(match version {
+            ApiVersion::V1 => "flat",
+            ApiVersion::V2 => "structured",
+        });
+        let mut response = match version {
+            // Plain application/json, as before versions were a thing
+            ApiVersion::V1 => Json(body).into_response(),
+            ApiVersion::V2 => {
+                let mut response = Json(body.into_v2()).into_response();
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(V2_MEDIA_TYPE),
+                );
+                response
+            }
+        };
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept"));
+        if let Some(format) = route_format {
+            metrics::registry().inc_counter("route_format_total", &[("format", format)], 1);
+            if format == "flat" {
+                response.headers_mut().insert(
+                    FLAT_ROUTE_DEPRECATED,
+                    HeaderValue::from_static(FLAT_ROUTE_NUDGE),
+                );
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(ApiVersion::negotiate(""), ApiVersion::V1);
+        assert_eq!(ApiVersion::negotiate("application/json"), ApiVersion::V1);
+        assert_eq!(ApiVersion::negotiate(V2_MEDIA_TYPE), ApiVersion::V2);
+        assert_eq!(
+            ApiVersion::negotiate(
+                "application/json;q=0.5, Application/VND.flipmap.v2+json; q=0.9, */*"
+            ),
+            ApiVersion::V2
+        );
+        assert_eq!(
+            ApiVersion::negotiate("application/vnd.flipmap.v2+json;q=0, application/json"),
+            ApiVersion::V1
+        );
+        // Too new for us
+        assert_eq!(
+            ApiVersion::negotiate("application/vnd.flipmap.v9+json"),
+            ApiVersion::V1
+        );
+    }
+
+    #[derive(Serialize)]
+    struct Routed;
+
+    impl Versioned for Routed {
+        type V2 = Routed;
+        fn into_v2(self) -> Routed {
+            self
+        }
+        fn has_route(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn flat_route_nudge() {
+        let v1 = Negotiated(ApiVersion::V1, Routed).into_response();
+        assert!(v1.headers().contains_key(FLAT_ROUTE_DEPRECATED));
+        let v2 = Negotiated(ApiVersion::V2, Routed).into_response();
+        assert!(!v2.headers().contains_key(FLAT_ROUTE_DEPRECATED));
+    }
+}
@@ -0,0 +1,39 @@
+//! Files a deployment can edit without a restart: [crate::flags] and [crate::about]. Each is
+//! checked every [POLL] and re-read when its modification time moves. One that can't be read or
+//! parsed is warned about and leaves things as they were.
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+/// How often files are checked for changes
+const POLL: Duration = Duration::from_secs(10);
+
+/// Spawns a task handing `store` what `read` makes of `path` each time it changes. The first read
+/// is left to the caller, so a bad file can fail startup
+pub fn spawn<T: Send + 'static>(
+    path: PathBuf,
+    what: &'static str,
+    read: fn(&Path) -> Result<T, String>,
+    store: impl Fn(T) + Send + 'static,
+) {
+    tokio::spawn(async move {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut seen: Option<SystemTime> = modified(&path);
+        let mut interval = tokio::time::interval(POLL);
+        loop {
+            interval.tick().await;
+            let now = modified(&path);
+            if now == seen {
+                continue;
+            }
+            seen = now;
+            match read(&path) {
+                Ok(value) => {
+                    tracing::info!("reloaded {what} from {path:?}");
+                    store(value);
+                }
+                Err(e) => tracing::warn!("keeping {what} as they were: {e}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,34 @@
+//! Sizing the runtime to the host, from `--workers`.
+//!
+//! Tokio's defaults suit a big box: a worker thread per core and up to 512 blocking threads. On a
+//! 1-vCPU VPS that's mostly memory spent on threads that never run. `--workers` sets how many
+//! worker threads there are, and the blocking pool scales with it. Background jobs that take
+//! slots ([crate::revalidate], [crate::prefetch], [crate::shadow]) get no more slots than there are
+//! workers, so they can't crowd out requests. Unset, everything is as tokio and each job decide.
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, Runtime};
+
+/// Blocking threads allowed per worker. Each upstream body being read holds one while it's
+/// parsed (see [crate::stream_json]), as does each trip database call, so it's well above the
+/// worker count
+const BLOCKING_PER_WORKER: usize = 32;
+
+static WORKERS: OnceLock<usize> = OnceLock::new();
+
+/// The runtime to serve on: `workers` worker threads if set, tokio's defaults otherwise
+pub fn runtime(workers: Option<usize>) -> std::io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(workers) = workers {
+        WORKERS.get_or_init(|| workers);
+        builder
+            .worker_threads(workers)
+            .max_blocking_threads(workers * BLOCKING_PER_WORKER);
+    }
+    builder.build()
+}
+
+/// Slots for a background job that would take `wanted`, given the workers there are
+pub fn slots(wanted: usize) -> usize {
+    WORKERS.get().map_or(wanted, |&workers| wanted.min(workers))
+}